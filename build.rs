@@ -0,0 +1,23 @@
+use std::process::Command;
+
+/// Generate the gRPC code if enabled, and record the git commit being
+/// built for `/about`.  Packagers building from a tarball can set
+/// `KOJI_API_GIT_COMMIT` instead.
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/koji_sane_json_api.proto")
+        .expect("Compiling proto/koji_sane_json_api.proto");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=KOJI_API_GIT_COMMIT");
+    if std::env::var_os("KOJI_API_GIT_COMMIT").is_some() {
+        return;
+    }
+    let out = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Ok(out) = out {
+        if out.status.success() {
+            let commit = String::from_utf8_lossy(&out.stdout);
+            println!("cargo:rustc-env=KOJI_API_GIT_COMMIT={}", commit.trim());
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Arbitrary `koji buildinfo` output must never panic the parser.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // koji output that isn't UTF-8 is rejected before parsing
+    if let Ok(output) = std::str::from_utf8(data) {
+        koji_sane_json_api::koji::fuzz_buildinfo(output);
+    }
+});
@@ -0,0 +1,105 @@
+//! Server metadata served at `/about`.
+
+use std::sync::RwLock;
+use std::time::Instant;
+
+use actix_web::{web, HttpResponse};
+use serde_derive::Serialize;
+
+use crate::config::Config;
+use crate::health::Readiness;
+use crate::koji::Hub;
+
+/// Facts fixed at startup.
+pub(crate) struct About {
+    started: Instant,
+    features: Vec<&'static str>,
+}
+
+impl About {
+    /// Note which optional parts of the service are configured.
+    pub(crate) fn new(config: &Config) -> Self {
+        let auth = &config.auth;
+        let rate_limit = &config.rate_limit;
+        let enabled = [
+            ("tls", config.server.tls.cert.is_some()),
+            ("client-certs", config.server.tls.client_ca.is_some()),
+            (
+                "auth",
+                auth.require_key
+                    || !auth.keys.is_empty()
+                    || auth.keys_file.is_some()
+                    || auth.oidc.issuer.is_some(),
+            ),
+            ("oidc", auth.oidc.issuer.is_some()),
+            (
+                "rate-limit",
+                [rate_limit.build, rate_limit.admin, rate_limit.other]
+                    .iter()
+                    .any(|l| l.rate > 0.0),
+            ),
+            ("cors", !config.cors.allowed_origins.is_empty()),
+            ("prefetch", !config.prefetch.tags.is_empty()),
+            ("tracing", config.tracing.otlp_endpoint.is_some()),
+            ("error-reporting", config.report.sentry_dsn.is_some()),
+            ("audit-file", config.audit.path.is_some()),
+            ("grpc", config.grpc.bind.is_some()),
+            ("bus", config.bus.url.is_some()),
+            ("export", config.export.url.is_some()),
+            ("gating", config.gating.url.is_some()),
+            ("vulnerabilities", config.vulnerabilities.enabled()),
+            ("download", config.download.enabled),
+            ("hubs", !config.hubs.is_empty()),
+        ];
+        Self {
+            started: Instant::now(),
+            features: enabled
+                .iter()
+                .filter(|(_, on)| *on)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct AboutResponse<'a> {
+    name: &'static str,
+    version: &'static str,
+    git_commit: Option<&'static str>,
+    source: &'static str,
+    hub: Option<String>,
+    hub_profile: Option<String>,
+    topurl: String,
+    /// As of the last background hub check.
+    hub_api_version: Option<u32>,
+    features: &'a [&'static str],
+    uptime_secs: u64,
+}
+
+/// Also served at `/`.
+async fn about(
+    about: web::Data<About>,
+    hub: web::Data<RwLock<Hub>>,
+    readiness: web::Data<Readiness>,
+) -> HttpResponse {
+    let hub = hub.read().unwrap().clone();
+    HttpResponse::Ok().json(AboutResponse {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("KOJI_API_GIT_COMMIT"),
+        source: "https://github.com/cgwalters/koji-sane-json-api",
+        hub: hub.server,
+        hub_profile: hub.profile,
+        topurl: hub.topurl,
+        hub_api_version: readiness.hub_api_version(),
+        features: &about.features,
+        uptime_secs: about.started.elapsed().as_secs(),
+    })
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/about", web::get().to(about))
+        .route("/", web::get().to(about));
+}
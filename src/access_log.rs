@@ -0,0 +1,175 @@
+//! One log line per HTTP request.
+
+use std::str::FromStr;
+use std::time::Instant;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, HttpMessage, HttpRequest};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::proxy::TrustedProxies;
+use crate::tls;
+
+/// Target of access log events, for filtering e.g. `access=off`.
+const TARGET: &str = "access";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AccessLogFormat {
+    Off,
+    /// An event with one field per attribute, rendered per `log-format`.
+    Structured,
+    /// Apache/nginx "combined" format, for existing log tooling.
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "off" => Ok(AccessLogFormat::Off),
+            "structured" => Ok(AccessLogFormat::Structured),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => anyhow::bail!(
+                "Unknown access log format {}; expected off, structured or combined",
+                s
+            ),
+        }
+    }
+}
+
+/// How a request was answered with respect to the cache; set by handlers
+/// that consult it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CacheStatus {
+    Hit,
+    /// An expired entry confirmed unchanged by the hub.
+    Revalidated,
+    Miss,
+    /// The client asked to bypass the cache.
+    Bypass,
+}
+
+impl CacheStatus {
+    /// For an answer fetched from the hub; `refresh` if the client asked
+    /// to bypass the cache.
+    pub(crate) fn fetched(refresh: bool) -> Self {
+        if refresh {
+            CacheStatus::Bypass
+        } else {
+            CacheStatus::Miss
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Revalidated => "revalidated",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Bypass => "bypass",
+        }
+    }
+}
+
+/// Record the cache outcome of `req` for its access log line.
+pub(crate) fn set_cache_status(req: &HttpRequest, status: CacheStatus) {
+    req.extensions_mut().insert(status);
+}
+
+/// Request attributes captured before the request is handed on, since a
+/// failed request doesn't give it back.
+pub(crate) struct Pending {
+    format: AccessLogFormat,
+    start: Instant,
+    method: String,
+    uri: String,
+    version: String,
+    client: String,
+    user: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+fn header_str(req: &ServiceRequest, name: header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// Escape a header value for a quoted combined log field.
+fn quote(v: Option<&str>) -> String {
+    v.map(|s| s.replace('\\', "\\\\").replace('"', "\\\""))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl Pending {
+    pub(crate) fn new(format: AccessLogFormat, req: &ServiceRequest) -> Option<Self> {
+        if format == AccessLogFormat::Off {
+            return None;
+        }
+        let client = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|p| p.client_ip(req.request()))
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        Some(Self {
+            format,
+            start: Instant::now(),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            client,
+            user: tls::client_identity(req.request()).map(|c| c.0),
+            referer: header_str(req, header::REFERER),
+            user_agent: header_str(req, header::USER_AGENT),
+        })
+    }
+
+    /// Emit the log line for the finished request.
+    pub(crate) fn finish<B: MessageBody>(self, res: &Result<ServiceResponse<B>, actix_web::Error>) {
+        let (status, bytes, cache) = match res {
+            Ok(r) => (
+                r.status(),
+                match r.response().body().size() {
+                    BodySize::Sized(n) => Some(n),
+                    _ => None,
+                },
+                r.request().extensions().get::<CacheStatus>().copied(),
+            ),
+            Err(e) => (e.as_response_error().status_code(), None, None),
+        };
+        let elapsed = self.start.elapsed();
+        match self.format {
+            AccessLogFormat::Off => {}
+            AccessLogFormat::Structured => tracing::info!(
+                target: TARGET,
+                method = %self.method,
+                path = %self.uri,
+                status = status.as_u16(),
+                duration_ms = elapsed.as_millis() as u64,
+                client = %self.client,
+                user = self.user.as_deref().unwrap_or("-"),
+                user_agent = self.user_agent.as_deref().unwrap_or("-"),
+                cache = cache.map(CacheStatus::as_str).unwrap_or("-"),
+            ),
+            AccessLogFormat::Combined => tracing::info!(
+                target: TARGET,
+                "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+                self.client,
+                self.user.as_deref().unwrap_or("-"),
+                chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                self.uri,
+                self.version,
+                status.as_u16(),
+                bytes.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string()),
+                quote(self.referer.as_deref()),
+                quote(self.user_agent.as_deref()),
+            ),
+        }
+    }
+}
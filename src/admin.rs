@@ -0,0 +1,241 @@
+//! Administrative endpoints, mounted under `/admin`.
+
+#[cfg(feature = "webhooks")]
+use actix_web::delete;
+#[cfg(feature = "webhooks")]
+use actix_web::error::ErrorNotFound;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde_derive::Deserialize;
+
+use crate::audit::AuditLog;
+use crate::auth::{self, Permission};
+use crate::cache::{Cache, ExportedEntry, NvrMap};
+use crate::error;
+use crate::proxy::TrustedProxies;
+use crate::reload::Reloader;
+use crate::request_id;
+use crate::tls;
+use crate::usage::Usage;
+#[cfg(feature = "webhooks")]
+use crate::webhooks::Webhooks;
+
+/// Who made an admin request, and from where, for the audit log.
+fn actor(req: &HttpRequest) -> (String, Option<String>) {
+    let client = req
+        .app_data::<web::Data<TrustedProxies>>()
+        .and_then(|p| p.client_ip(req))
+        .map(|ip| ip.to_string());
+    let who = auth::principal(req)
+        .map(|p| p.name)
+        .or_else(|| tls::client_identity(req).map(|id| id.0))
+        .unwrap_or_else(|| "anonymous".to_string());
+    (who, client)
+}
+
+/// Record an admin action in the audit log.
+fn audit<T>(
+    req: &HttpRequest,
+    log: &AuditLog,
+    action: &'static str,
+    params: serde_json::Value,
+    outcome: &anyhow::Result<T>,
+) {
+    let (who, client) = actor(req);
+    let id = request_id::get(req).map(|id| id.0);
+    log.record(who, client, id, action, params, outcome);
+}
+
+/// Dump the cache as a JSON array which can be fed to `/admin/cache/import`
+/// on another instance.
+#[get("/admin/cache/export")]
+async fn cache_export(
+    req: HttpRequest,
+    cache: web::Data<Cache>,
+    log: web::Data<AuditLog>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Cache)?;
+    let entries = cache.export();
+    let params = serde_json::json!({ "entries": entries.len() });
+    audit(&req, &log, "cache-export", params, &Ok(()));
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn cache_import(
+    req: HttpRequest,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+    log: web::Data<AuditLog>,
+    entries: web::Json<Vec<ExportedEntry>>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Cache)?;
+    let offered = entries.len();
+    let n = cache.import(&nvrs, entries.into_inner());
+    let params = serde_json::json!({ "offered": offered, "imported": n });
+    audit(&req, &log, "cache-import", params, &Ok(()));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "imported": n })))
+}
+
+/// Re-read the configuration and apply its reloadable settings, like SIGHUP.
+#[post("/admin/reload")]
+async fn reload(
+    req: HttpRequest,
+    reloader: web::Data<Reloader>,
+    log: web::Data<AuditLog>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Reload)?;
+    let result = reloader.reload();
+    audit(&req, &log, "reload", serde_json::json!({}), &result);
+    Ok(match result {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "reloaded": true })),
+        Err(e) => HttpResponse::InternalServerError().json(error::body(
+            &format!("{:#}", e),
+            request_id::get(&req).as_ref(),
+        )),
+    })
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/// The most recent audit records, newest first.
+#[get("/admin/audit")]
+async fn audit_log(
+    req: HttpRequest,
+    log: web::Data<AuditLog>,
+    query: web::Query<AuditQuery>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Audit)?;
+    Ok(HttpResponse::Ok().json(log.recent(query.limit)))
+}
+
+/// The merged configuration in effect, with secrets redacted.
+#[get("/admin/config")]
+async fn config(
+    req: HttpRequest,
+    reloader: web::Data<Reloader>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Config)?;
+    Ok(HttpResponse::Ok().json(reloader.effective_config()))
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    #[serde(default = "default_stats_top")]
+    top: usize,
+}
+
+fn default_stats_top() -> usize {
+    20
+}
+
+/// Request counts per route, the most requested builds and packages, and
+/// what the cache saved, since startup.
+#[get("/admin/stats")]
+async fn stats(
+    req: HttpRequest,
+    usage: web::Data<Usage>,
+    query: web::Query<StatsQuery>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Stats)?;
+    Ok(HttpResponse::Ok().json(usage.stats(query.top)))
+}
+
+/// Every registered webhook, including fired and disabled ones.
+#[cfg(feature = "webhooks")]
+#[get("/admin/webhooks")]
+async fn webhooks_list(
+    req: HttpRequest,
+    webhooks: web::Data<Webhooks>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Subscriptions)?;
+    Ok(HttpResponse::Ok().json(webhooks.list()))
+}
+
+/// Answer for an admin change to a webhook, recording it.
+#[cfg(feature = "webhooks")]
+fn webhook_changed(
+    req: &HttpRequest,
+    log: &AuditLog,
+    action: &'static str,
+    id: &str,
+    result: anyhow::Result<bool>,
+) -> actix_web::Result<HttpResponse> {
+    audit(req, log, action, serde_json::json!({ "id": id }), &result);
+    match result {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Err(ErrorNotFound("No such webhook")),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(error::body(
+            &format!("{:#}", e),
+            request_id::get(req).as_ref(),
+        ))),
+    }
+}
+
+/// Stop checking a webhook's trigger, without removing it.
+#[cfg(feature = "webhooks")]
+#[post("/admin/webhooks/{id}/disable")]
+async fn webhook_disable(
+    req: HttpRequest,
+    webhooks: web::Data<Webhooks>,
+    log: web::Data<AuditLog>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Subscriptions)?;
+    let result = webhooks.set_disabled(&path.0, true);
+    webhook_changed(&req, &log, "webhook-disable", &path.0, result)
+}
+
+#[cfg(feature = "webhooks")]
+#[post("/admin/webhooks/{id}/enable")]
+async fn webhook_enable(
+    req: HttpRequest,
+    webhooks: web::Data<Webhooks>,
+    log: web::Data<AuditLog>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Subscriptions)?;
+    let result = webhooks.set_disabled(&path.0, false);
+    webhook_changed(&req, &log, "webhook-enable", &path.0, result)
+}
+
+#[cfg(feature = "webhooks")]
+#[delete("/admin/webhooks/{id}")]
+async fn webhook_delete(
+    req: HttpRequest,
+    webhooks: web::Data<Webhooks>,
+    log: web::Data<AuditLog>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    auth::require(&req, Permission::Subscriptions)?;
+    let result = webhooks.remove(&path.0);
+    webhook_changed(&req, &log, "webhook-delete", &path.0, result)
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(reload)
+        .service(audit_log)
+        .service(stats)
+        .service(config);
+    #[cfg(feature = "webhooks")]
+    cfg.service(webhooks_list)
+        .service(webhook_disable)
+        .service(webhook_enable)
+        .service(webhook_delete);
+}
+
+/// `/admin/cache`, taking imports up to twice `max_bytes`, the cache's
+/// budget, since escaping its bodies inflates an export.
+pub(crate) fn configure_cache(cfg: &mut web::ServiceConfig, max_bytes: usize) {
+    cfg.service(cache_export).service(
+        web::resource("/admin/cache/import")
+            .app_data(web::JsonConfig::default().limit(max_bytes.saturating_mul(2)))
+            .route(web::post().to(cache_import)),
+    );
+}
@@ -0,0 +1,324 @@
+//! `GET /buildinfo/{id}/arches`: which of a tag's arches a build has RPMs
+//! for, and of the rest, which were excluded (by the spec, or by koji not
+//! building them) and which failed.
+
+use std::collections::BTreeSet;
+
+use actix_web::error::{ErrorBadGateway, ErrorBadRequest};
+use actix_web::{get, web, HttpResponse};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::koji::{base_arch, Backend, Hub, KojiBuildInfo};
+use crate::server::run_blocking;
+use crate::watch::Sources;
+
+#[derive(Deserialize)]
+struct ArchesQuery {
+    /// The tag whose arches are expected; the build's first tag if unset.
+    tag: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ArchReport {
+    nvr: String,
+    tag: String,
+    /// The tag's arches, as its build tag configures them.
+    expected: Vec<String>,
+    /// Expected arches with binary RPMs.
+    built: Vec<String>,
+    /// Expected arches koji built for but which have no RPMs, e.g. as
+    /// their task failed.
+    missing: Vec<String>,
+    /// Expected arches the spec rules out, or koji didn't build for.
+    excluded: Vec<String>,
+    /// The spec's `ExcludeArch` and `ExclusiveArch`, from the source RPM.
+    exclude_arch: Vec<String>,
+    exclusive_arch: Vec<String>,
+    /// Only `noarch` RPMs, which serve every arch.
+    noarch: bool,
+    /// Nothing is missing.
+    complete: bool,
+}
+
+/// What is asked of the hub for a report.
+struct HubArches {
+    tag: String,
+    expected: Vec<String>,
+    /// Arches of the build task's `buildArch` subtasks, unless the build
+    /// has no task (e.g. it was imported).
+    tasks: Option<Vec<String>>,
+    exclude_arch: Vec<String>,
+    exclusive_arch: Vec<String>,
+}
+
+fn kwargs(pairs: &[(&str, Value)]) -> Map<String, Value> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect()
+}
+
+/// Split koji's space-separated arch list.
+fn parse_arches(v: &Value) -> Vec<String> {
+    v.as_str()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// An RPM header's string list; koji gives a lone string for single
+/// values and `null` for missing headers.
+fn header_list(v: &Value) -> Vec<String> {
+    match v {
+        Value::Array(a) => a
+            .iter()
+            .filter_map(|s| s.as_str().map(String::from))
+            .collect(),
+        Value::String(s) => s.split_whitespace().map(String::from).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A tag's arches; destination tags such as `f34-updates` have none of
+/// their own, so those of the build tag of a target into it are used.
+fn tag_arches(hub: &Hub, tag: &str) -> Result<Vec<String>> {
+    let config = hub.call("getBuildConfig", &[tag.into()], &Map::new())?;
+    let arches = parse_arches(&config["arches"]);
+    if !arches.is_empty() {
+        return Ok(arches);
+    }
+    let targets = hub.call(
+        "getBuildTargets",
+        &[],
+        &kwargs(&[("destTagName", tag.into())]),
+    )?;
+    let build_tag = targets
+        .as_array()
+        .and_then(|t| t.iter().find_map(|t| t["build_tag_name"].as_str()));
+    match build_tag {
+        Some(build_tag) => {
+            let config = hub.call("getBuildConfig", &[build_tag.into()], &Map::new())?;
+            Ok(parse_arches(&config["arches"]))
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// `None` if no tag is given and the build isn't tagged.
+fn query_hub(
+    hub: &Hub,
+    id: u64,
+    srpm: Option<String>,
+    tag: Option<String>,
+) -> Result<Option<HubArches>> {
+    let tag = match tag {
+        Some(tag) => tag,
+        None => {
+            let tags = hub.call("listTags", &[], &kwargs(&[("build", id.into())]))?;
+            let first = tags
+                .as_array()
+                .and_then(|t| t.first())
+                .and_then(|t| t["name"].as_str());
+            match first {
+                Some(tag) => tag.to_string(),
+                None => return Ok(None),
+            }
+        }
+    };
+    let expected = tag_arches(hub, &tag)?;
+    let build = hub.call("getBuild", &[id.into()], &Map::new())?;
+    let tasks = match build["task_id"].as_u64() {
+        Some(task_id) => {
+            let children = hub.call("getTaskChildren", &[task_id.into()], &Map::new())?;
+            let arches = children
+                .as_array()
+                .map(|c| {
+                    c.iter()
+                        .filter(|t| t["method"] == "buildArch")
+                        .filter_map(|t| t["arch"].as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(arches)
+        }
+        None => None,
+    };
+    let headers = match srpm {
+        Some(srpm) => hub.call(
+            "getRPMHeaders",
+            &[],
+            &kwargs(&[
+                ("rpmID", srpm.into()),
+                (
+                    "headers",
+                    serde_json::json!(["excludearch", "exclusivearch"]),
+                ),
+            ]),
+        )?,
+        None => Value::Null,
+    };
+    Ok(Some(HubArches {
+        tag,
+        expected,
+        tasks,
+        exclude_arch: header_list(&headers["excludearch"]),
+        exclusive_arch: header_list(&headers["exclusivearch"]),
+    }))
+}
+
+/// Compare the arches built against those expected.  Arch names are
+/// compared by base arch, as tags may say `i686` where RPMs are `i386` or
+/// the other way around.
+fn report(info: &KojiBuildInfo, hub: HubArches) -> ArchReport {
+    let rpm_arches: BTreeSet<&str> = info
+        .rpms
+        .iter()
+        .filter(|(arch, rpms)| *arch != "src" && !rpms.is_empty())
+        .map(|(arch, _)| base_arch(arch))
+        .collect();
+    let noarch = rpm_arches.len() == 1 && rpm_arches.contains("noarch");
+    let tasks: Option<BTreeSet<&str>> = hub
+        .tasks
+        .as_ref()
+        .map(|t| t.iter().map(|a| base_arch(a)).collect());
+    let has = |list: &[String], base: &str| list.iter().any(|a| base_arch(a) == base);
+    let spec_excludes = |base: &str| {
+        has(&hub.exclude_arch, base)
+            || (!hub.exclusive_arch.is_empty() && !has(&hub.exclusive_arch, base))
+    };
+    let (mut built, mut missing, mut excluded) = (Vec::new(), Vec::new(), Vec::new());
+    for arch in &hub.expected {
+        let base = base_arch(arch);
+        if noarch || rpm_arches.contains(base) {
+            built.push(arch.clone());
+        } else if spec_excludes(base) || tasks.as_ref().map_or(false, |t| !t.contains(base)) {
+            excluded.push(arch.clone());
+        } else {
+            missing.push(arch.clone());
+        }
+    }
+    ArchReport {
+        nvr: info.nvr.clone(),
+        tag: hub.tag,
+        complete: missing.is_empty(),
+        expected: hub.expected,
+        built,
+        missing,
+        excluded,
+        exclude_arch: hub.exclude_arch,
+        exclusive_arch: hub.exclusive_arch,
+        noarch,
+    }
+}
+
+#[get("/buildinfo/{id}/arches")]
+async fn build_arches(
+    sources: Sources,
+    path: web::Path<(String,)>,
+    query: web::Query<ArchesQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let info = sources.build(&path.0, false).await?;
+    let tag = query.into_inner().tag;
+    let hub = sources.hub.read().unwrap().clone();
+    let id = info.id;
+    let srpm = info
+        .rpms
+        .get("src")
+        .and_then(|r| r.first())
+        .and_then(|f| f.strip_suffix(".rpm"))
+        .map(String::from);
+    let found = {
+        let _permit = sources.shedder.backend()?;
+        run_blocking(move || query_hub(&hub, id, srpm, tag)).await
+    };
+    let found = found
+        .map_err(|e| ErrorBadGateway(format!("{:#}", e)))?
+        .ok_or_else(|| ErrorBadRequest(format!("{} isn't tagged; pass ?tag=", info.nvr)))?;
+    if found.expected.is_empty() {
+        return Err(ErrorBadRequest(format!(
+            "No arches configured for {}",
+            found.tag
+        )));
+    }
+    Ok(HttpResponse::Ok().json(report(&info, found)))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(build_arches);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build(arches: &[&str]) -> KojiBuildInfo {
+        let mut info = KojiBuildInfo {
+            nvr: "foo-1-1.fc34".to_string(),
+            ..Default::default()
+        };
+        for arch in arches {
+            info.rpms
+                .insert(arch.to_string(), vec![format!("foo-1-1.fc34.{}.rpm", arch)]);
+        }
+        info
+    }
+
+    fn hub(tasks: Option<&[&str]>) -> HubArches {
+        HubArches {
+            tag: "f34".to_string(),
+            expected: ["x86_64", "aarch64", "i686", "s390x"]
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            tasks: tasks.map(|t| t.iter().map(|a| a.to_string()).collect()),
+            exclude_arch: Vec::new(),
+            exclusive_arch: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let r = report(
+            &build(&["src", "x86_64", "i686"]),
+            hub(Some(&["x86_64", "i686", "aarch64"])),
+        );
+        assert_eq!(r.built, ["x86_64", "i686"]);
+        assert_eq!(r.missing, ["aarch64"]);
+        assert_eq!(r.excluded, ["s390x"]);
+        assert!(!r.complete);
+
+        // Without a task, nothing is known to be excluded
+        let r = report(&build(&["src", "x86_64"]), hub(None));
+        assert_eq!(r.missing, ["aarch64", "i686", "s390x"]);
+        assert!(r.excluded.is_empty());
+
+        let r = report(&build(&["src", "noarch"]), hub(Some(&["noarch"])));
+        assert!(r.noarch && r.complete);
+        assert_eq!(r.built.len(), 4);
+    }
+
+    #[test]
+    fn test_spec_arches() {
+        // Even without task information
+        let mut h = hub(None);
+        h.exclude_arch = vec!["s390x".to_string()];
+        let r = report(&build(&["src", "x86_64", "aarch64"]), h);
+        assert_eq!(r.excluded, ["s390x"]);
+        assert_eq!(r.missing, ["i686"]);
+        assert_eq!(r.exclude_arch, ["s390x"]);
+
+        // %{ix86} expands to several names for the same base arch
+        let mut h = hub(None);
+        h.exclusive_arch = header_list(&serde_json::json!(["x86_64", "i586", "i686"]));
+        let r = report(&build(&["src", "x86_64"]), h);
+        assert_eq!(r.excluded, ["aarch64", "s390x"]);
+        assert_eq!(r.missing, ["i686"]);
+
+        assert_eq!(header_list(&serde_json::json!("x86_64")), ["x86_64"]);
+        assert!(header_list(&Value::Null).is_empty());
+    }
+}
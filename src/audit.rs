@@ -0,0 +1,148 @@
+//! Append-only record of administrative actions.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+/// Number of records kept in memory for `/admin/audit`.
+const RECENT: usize = 1000;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct AuditConfig {
+    /// File to append records to, one JSON object per line.  Without it,
+    /// only recent records are kept, in memory.
+    pub(crate) path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Record {
+    /// RFC 3339 timestamp.
+    pub(crate) time: String,
+    /// Who acted: an API key or token user, TLS client, or `signal`.
+    pub(crate) actor: String,
+    pub(crate) client: Option<String>,
+    pub(crate) request_id: Option<String>,
+    pub(crate) action: &'static str,
+    pub(crate) params: serde_json::Value,
+    /// `ok`, or the error.
+    pub(crate) outcome: String,
+}
+
+pub(crate) struct AuditLog {
+    file: Option<Mutex<File>>,
+    recent: Mutex<VecDeque<Record>>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(config: &AuditConfig) -> Result<Self> {
+        let file = match config.path.as_ref() {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Opening audit log {}", path.display()))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            file,
+            recent: Default::default(),
+        })
+    }
+
+    /// Record an action and its outcome.  Failing to write the file is
+    /// logged rather than failing the action, which has already happened.
+    pub(crate) fn record<T>(
+        &self,
+        actor: String,
+        client: Option<String>,
+        request_id: Option<String>,
+        action: &'static str,
+        params: serde_json::Value,
+        outcome: &Result<T>,
+    ) {
+        let record = Record {
+            time: chrono::Utc::now().to_rfc3339(),
+            actor,
+            client,
+            request_id,
+            action,
+            params,
+            outcome: match outcome {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("{:#}", e),
+            },
+        };
+        tracing::info!(target: "audit", actor = %record.actor, action, outcome = %record.outcome);
+        if let Some(file) = self.file.as_ref() {
+            let line = serde_json::to_string(&record).expect("serialize audit record");
+            let mut file = file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                tracing::error!("Failed to write audit log: {}", e);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// Up to `limit` of the most recent records, newest first.
+    pub(crate) fn recent(&self, limit: usize) -> Vec<Record> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::new(&AuditConfig {
+            path: Some(path.clone()),
+        })?;
+        let ok: Result<()> = Ok(());
+        log.record(
+            "alice".into(),
+            None,
+            None,
+            "reload",
+            serde_json::json!({}),
+            &ok,
+        );
+        let failed: Result<()> = Err(anyhow::anyhow!("nope"));
+        log.record(
+            "bob".into(),
+            None,
+            None,
+            "reload",
+            serde_json::json!({}),
+            &failed,
+        );
+        let recent = log.recent(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].actor, "bob");
+        assert_eq!(recent[0].outcome, "nope");
+        let lines = std::fs::read_to_string(&path)?;
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines
+            .lines()
+            .next()
+            .unwrap()
+            .contains("\"actor\":\"alice\""));
+        Ok(())
+    }
+}
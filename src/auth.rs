@@ -0,0 +1,459 @@
+//! Optional authentication by API key or OIDC bearer token, and
+//! authorization of administrative actions by role.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error;
+use crate::oidc::{OidcConfig, Verifier};
+use crate::ratelimit::{Limit, RouteClass};
+use crate::request_id;
+
+/// Header carrying the client's key.
+const HEADER: &str = "x-api-key";
+
+/// An action guarded by authorization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Permission {
+    /// The public API; only checked if `require-key` is set.
+    Read,
+    /// Re-reading the configuration.
+    Reload,
+    /// Exporting and importing the cache.
+    Cache,
+    /// Reading the audit log.
+    Audit,
+    /// Reading usage statistics.
+    Stats,
+    /// Reading the effective configuration.
+    Config,
+    /// Registering and removing webhooks.
+    Webhooks,
+    /// Listing, disabling and deleting anyone's webhooks.
+    Subscriptions,
+}
+
+impl Permission {
+    const ALL: &'static [Permission] = &[
+        Permission::Read,
+        Permission::Reload,
+        Permission::Cache,
+        Permission::Audit,
+        Permission::Stats,
+        Permission::Config,
+        Permission::Webhooks,
+        Permission::Subscriptions,
+    ];
+
+    /// Administrative actions, which without any credentials configured are
+    /// only allowed on the `admin-bind` listeners.
+    fn is_admin(self) -> bool {
+        !matches!(self, Permission::Read | Permission::Webhooks)
+    }
+
+    /// Actions never allowed to anonymous clients.  Webhooks make the
+    /// server send requests, so need credentials even on their own.
+    fn needs_credentials(self) -> bool {
+        self == Permission::Webhooks
+    }
+}
+
+/// App data of the `admin-bind` listeners, which only operators can reach.
+pub(crate) struct AdminListener;
+
+/// Built-in role granting only the public API.
+const ROLE_READ: &str = "read";
+/// Built-in role granting everything.
+const ROLE_ADMIN: &str = "admin";
+
+fn default_role() -> String {
+    ROLE_READ.to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ApiKey {
+    /// Identifies the key holder in logs.
+    pub(crate) name: String,
+    /// Hex SHA-256 digest of the key; the key itself is never stored.
+    #[serde(serialize_with = "crate::config::redacted")]
+    pub(crate) sha256: String,
+    /// Built-in (`read` or `admin`) or configured role.
+    #[serde(default = "default_role", alias = "scope")]
+    pub(crate) role: String,
+    /// Replaces the configured rate limits for requests with this key.
+    #[serde(default)]
+    pub(crate) rate_limit: Option<Limit>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct AuthConfig {
+    /// Reject `/buildinfo` requests without a valid key or token.  Otherwise
+    /// credentials are only needed for `/admin`, and to get per-client
+    /// rate limits.
+    pub(crate) require_key: bool,
+    pub(crate) keys: Vec<ApiKey>,
+    /// TOML file with further `[[keys]]`, e.g. kept in a secret store.
+    pub(crate) keys_file: Option<PathBuf>,
+    /// Additional roles, mapping names to their permissions.
+    pub(crate) roles: HashMap<String, BTreeSet<Permission>>,
+    pub(crate) oidc: OidcConfig,
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+fn load_keys(path: &Path) -> Result<Vec<ApiKey>> {
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading keys file {}", path.display()))?;
+    let f: KeysFile =
+        toml::from_str(&s).with_context(|| format!("Parsing keys file {}", path.display()))?;
+    Ok(f.keys)
+}
+
+/// The authenticated client of a request.
+#[derive(Debug, Clone)]
+pub(crate) struct Principal {
+    pub(crate) name: String,
+    pub(crate) permissions: BTreeSet<Permission>,
+    pub(crate) rate_limit: Option<Limit>,
+}
+
+fn digest(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Role names to permissions, including the built-in roles.
+struct Roles(HashMap<String, BTreeSet<Permission>>);
+
+impl Roles {
+    fn new(config: &HashMap<String, BTreeSet<Permission>>) -> Self {
+        let mut roles = config.clone();
+        roles.insert(
+            ROLE_READ.to_string(),
+            Some(Permission::Read).into_iter().collect(),
+        );
+        roles.insert(
+            ROLE_ADMIN.to_string(),
+            Permission::ALL.iter().copied().collect(),
+        );
+        Self(roles)
+    }
+
+    fn get(&self, role: &str) -> Result<&BTreeSet<Permission>> {
+        self.0
+            .get(role)
+            .ok_or_else(|| anyhow::anyhow!("Unknown role {}", role))
+    }
+}
+
+pub(crate) struct Authenticator {
+    require_key: bool,
+    /// Keyed by digest.
+    keys: HashMap<String, Principal>,
+    oidc: Option<Arc<Verifier>>,
+    /// Permissions of token holders by group.
+    groups: HashMap<String, BTreeSet<Permission>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: &AuthConfig) -> Result<Self> {
+        let roles = Roles::new(&config.roles);
+        let mut keys = config.keys.clone();
+        if let Some(path) = config.keys_file.as_deref() {
+            keys.extend(load_keys(path)?);
+        }
+        let keys = keys
+            .into_iter()
+            .map(|k| {
+                let sha256 = k.sha256.to_ascii_lowercase();
+                if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    anyhow::bail!("Invalid sha256 for API key {}", k.name);
+                }
+                let permissions = roles
+                    .get(&k.role)
+                    .with_context(|| format!("API key {}", k.name))?
+                    .clone();
+                let principal = Principal {
+                    name: k.name,
+                    permissions,
+                    rate_limit: k.rate_limit,
+                };
+                Ok((sha256, principal))
+            })
+            .collect::<Result<_>>()?;
+        let group_roles = config
+            .oidc
+            .admin_groups
+            .iter()
+            .map(|g| (g, ROLE_ADMIN))
+            .chain(config.oidc.group_roles.iter().map(|(g, r)| (g, r.as_str())));
+        let mut groups: HashMap<String, BTreeSet<Permission>> = HashMap::new();
+        for (group, role) in group_roles {
+            let permissions = roles
+                .get(role)
+                .with_context(|| format!("OIDC group {}", group))?;
+            groups
+                .entry(group.clone())
+                .or_default()
+                .extend(permissions.iter().copied());
+        }
+        Ok(Self {
+            require_key: config.require_key,
+            keys,
+            oidc: Verifier::new(&config.oidc).map(Arc::new),
+            groups,
+        })
+    }
+
+    /// Whether any credentials are configured; if not, only the public API
+    /// is allowed, and admin routes on the `admin-bind` listeners.
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || self.oidc.is_some() || self.require_key
+    }
+
+    /// The bearer token verifier, if an OIDC issuer is configured.
+    pub(crate) fn oidc(&self) -> Option<Arc<Verifier>> {
+        self.oidc.clone()
+    }
+
+    /// Identify the client from an API key or bearer token.
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Option<Principal>, &'static str> {
+        if let Some(key) = req.headers().get(HEADER).and_then(|v| v.to_str().ok()) {
+            return match self.keys.get(&digest(key)) {
+                Some(p) => Ok(Some(p.clone())),
+                None => Err("Invalid API key"),
+            };
+        }
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let identity = match (token, self.oidc.as_ref()) {
+            (Some(token), Some(oidc)) => match oidc.verify(token.trim()) {
+                Ok(identity) => identity,
+                Err(e) => {
+                    tracing::info!("Rejected bearer token: {:#}", e);
+                    return Err("Invalid bearer token");
+                }
+            },
+            _ => return Ok(None),
+        };
+        // Any valid token grants read access
+        let mut permissions: BTreeSet<_> = Some(Permission::Read).into_iter().collect();
+        for group in identity.groups.iter() {
+            if let Some(p) = self.groups.get(group) {
+                permissions.extend(p.iter().copied());
+            }
+        }
+        Ok(Some(Principal {
+            name: format!("oidc:{}", identity.name),
+            permissions,
+            rate_limit: None,
+        }))
+    }
+
+    /// Identify the client, recording it on the request.  Requests with
+    /// invalid credentials are refused, as are anonymous `/admin` requests
+    /// and anonymous `/buildinfo` requests if `require-key` is set.
+    /// Handlers check specific permissions with `require()`.
+    pub(crate) fn check(
+        &self,
+        class: RouteClass,
+        req: &ServiceRequest,
+    ) -> Result<(), HttpResponse> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let deny = |status, msg: &str| {
+            let id = request_id::get(req.request());
+            HttpResponse::build(status).json(error::body(msg, id.as_ref()))
+        };
+        let principal = self
+            .authenticate(req)
+            .map_err(|msg| deny(StatusCode::UNAUTHORIZED, msg))?;
+        match principal {
+            Some(p) => {
+                req.extensions_mut().insert(p);
+                Ok(())
+            }
+            // Refuse anonymous admin requests early, before reading any body
+            None if class == RouteClass::Admin
+                || (class == RouteClass::Build && self.require_key) =>
+            {
+                Err(deny(StatusCode::UNAUTHORIZED, "Authentication required"))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The client authenticated by `Authenticator::check()`, if any.
+pub(crate) fn principal(req: &HttpRequest) -> Option<Principal> {
+    req.extensions().get::<Principal>().cloned()
+}
+
+/// Fail with 401 or 403 unless the client may perform `permission`.
+pub(crate) fn require(req: &HttpRequest, permission: Permission) -> actix_web::Result<()> {
+    let enabled = req
+        .app_data::<web::Data<Authenticator>>()
+        .map(|a| a.is_enabled())
+        .unwrap_or(false);
+    if !enabled {
+        let admin_listener = req.app_data::<web::Data<AdminListener>>().is_some();
+        if permission.needs_credentials() {
+            return Err(ErrorForbidden("Configure credentials to allow this"));
+        }
+        return if !permission.is_admin() || admin_listener {
+            Ok(())
+        } else {
+            Err(ErrorForbidden(
+                "Administrative routes need credentials or an admin-bind listener",
+            ))
+        };
+    }
+    match principal(req) {
+        None => Err(ErrorUnauthorized("Authentication required")),
+        Some(p) if p.permissions.contains(&permission) => Ok(()),
+        Some(p) => {
+            tracing::warn!(principal = %p.name, ?permission, "Permission denied");
+            Err(ErrorForbidden("Not permitted"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn authenticator(require_key: bool) -> Authenticator {
+        let key = |name: &str| ApiKey {
+            name: name.to_string(),
+            sha256: digest(name).to_ascii_uppercase(),
+            role: name.to_string(),
+            rate_limit: None,
+        };
+        let mut roles = HashMap::new();
+        roles.insert(
+            "operator".to_string(),
+            Some(Permission::Reload).into_iter().collect(),
+        );
+        Authenticator::new(&AuthConfig {
+            require_key,
+            keys: vec![key("read"), key("admin"), key("operator")],
+            keys_file: None,
+            roles,
+            oidc: Default::default(),
+        })
+        .unwrap()
+    }
+
+    /// Outcome of a request by a client with `key` to a route in `class`
+    /// whose handler requires `perm`.
+    fn status(
+        auth: Authenticator,
+        class: RouteClass,
+        key: Option<&str>,
+        perm: Option<Permission>,
+    ) -> StatusCode {
+        let mut req = TestRequest::default().app_data(web::Data::new(auth));
+        if let Some(key) = key {
+            req = req.insert_header((HEADER, key));
+        }
+        let req = req.to_srv_request();
+        let auth = req.app_data::<web::Data<Authenticator>>().unwrap().clone();
+        if let Err(res) = auth.check(class, &req) {
+            return res.status();
+        }
+        match perm.map(|p| require(req.request(), p)) {
+            Some(Err(e)) => e.as_response_error().status_code(),
+            _ => StatusCode::OK,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        use Permission::*;
+        use StatusCode as S;
+        let a = || authenticator(false);
+        assert_eq!(status(a(), RouteClass::Build, None, None), S::OK);
+        assert_eq!(
+            status(a(), RouteClass::Build, Some("wrong"), None),
+            S::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(a(), RouteClass::Admin, None, Some(Reload)),
+            S::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(a(), RouteClass::Admin, Some("read"), Some(Reload)),
+            S::FORBIDDEN
+        );
+        assert_eq!(
+            status(a(), RouteClass::Admin, Some("operator"), Some(Reload)),
+            S::OK
+        );
+        assert_eq!(
+            status(a(), RouteClass::Admin, Some("operator"), Some(Cache)),
+            S::FORBIDDEN
+        );
+        assert_eq!(
+            status(a(), RouteClass::Admin, Some("admin"), Some(Cache)),
+            S::OK
+        );
+
+        let a = || authenticator(true);
+        assert_eq!(status(a(), RouteClass::Build, None, None), S::UNAUTHORIZED);
+        assert_eq!(status(a(), RouteClass::Build, Some("read"), None), S::OK);
+    }
+
+    #[test]
+    fn test_unconfigured() {
+        let req = |admin_listener: bool| {
+            let mut req = TestRequest::default().app_data(web::Data::new(
+                Authenticator::new(&AuthConfig::default()).unwrap(),
+            ));
+            if admin_listener {
+                req = req.app_data(web::Data::new(AdminListener));
+            }
+            req.to_http_request()
+        };
+        assert!(require(&req(false), Permission::Read).is_ok());
+        let e = require(&req(false), Permission::Reload).unwrap_err();
+        assert_eq!(e.as_response_error().status_code(), StatusCode::FORBIDDEN);
+        assert!(require(&req(false), Permission::Cache).is_err());
+        assert!(require(&req(true), Permission::Reload).is_ok());
+        assert!(require(&req(true), Permission::Cache).is_ok());
+        assert!(require(&req(false), Permission::Webhooks).is_err());
+    }
+
+    #[test]
+    fn test_unknown_role() {
+        let config = AuthConfig {
+            keys: vec![ApiKey {
+                name: "k".to_string(),
+                sha256: digest("k"),
+                role: "nonexistent".to_string(),
+                rate_limit: None,
+            }],
+            ..Default::default()
+        };
+        assert!(Authenticator::new(&config).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! `GET /buildinfo/{id}/bundle`: a build's RPMs as one archive, assembled
+//! while it streams.
+
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse};
+use futures::stream::{self, LocalBoxStream, Stream};
+use futures::StreamExt;
+use serde_derive::Deserialize;
+
+use crate::deleted;
+use crate::download::Downloader;
+use crate::koji::KojiBuildInfo;
+use crate::watch::Sources;
+
+/// Tar's block size; headers and data are padded to it.
+const BLOCK: usize = 512;
+/// RPMs are already compressed, so favor speed.
+const ZSTD_LEVEL: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    TarZst,
+}
+
+impl Format {
+    fn parse(s: &str) -> actix_web::Result<Self> {
+        match s {
+            "tar" => Ok(Self::Tar),
+            "tar.zst" => Ok(Self::TarZst),
+            _ => Err(ErrorBadRequest(format!(
+                "Unsupported format {}; expected tar or tar.zst",
+                s
+            ))),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Tar => "application/x-tar",
+            Self::TarZst => "application/zstd",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::TarZst => "tar.zst",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BundleQuery {
+    /// Comma-separated architectures; all of them if unset.
+    arch: Option<String>,
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    "tar".to_string()
+}
+
+/// The `(arch, filename)` of each RPM to bundle.
+fn select(info: &KojiBuildInfo, arches: Option<&str>) -> actix_web::Result<Vec<(String, String)>> {
+    let arches: Vec<&str> = match arches {
+        Some(a) => a
+            .split(',')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .collect(),
+        None => info.rpms.keys().map(|a| a.as_str()).collect(),
+    };
+    let mut files = Vec::new();
+    for arch in arches {
+        let rpms = info
+            .rpms
+            .get(arch)
+            .ok_or_else(|| ErrorNotFound(format!("No {} RPMs in {}", arch, info.nvr)))?;
+        files.extend(rpms.iter().map(|f| (arch.to_string(), f.clone())));
+    }
+    Ok(files)
+}
+
+/// The ustar header for a file of `len` bytes at `path`.
+fn tar_header(path: &str, len: u64, mtime: u64) -> io::Result<web::Bytes> {
+    let mut h = tar::Header::new_ustar();
+    h.set_path(path)?;
+    h.set_size(len);
+    h.set_mode(0o644);
+    h.set_mtime(mtime);
+    h.set_entry_type(tar::EntryType::Regular);
+    h.set_cksum();
+    Ok(web::Bytes::copy_from_slice(h.as_bytes()))
+}
+
+/// Zeroes to pad `len` bytes out to a whole block.
+fn tar_padding(len: u64) -> web::Bytes {
+    let rem = (len % BLOCK as u64) as usize;
+    let n = if rem == 0 { 0 } else { BLOCK - rem };
+    web::Bytes::from(vec![0; n])
+}
+
+/// Pass `body` through, failing if it isn't exactly `len` bytes, which
+/// would corrupt the rest of the archive.
+fn exactly(
+    body: LocalBoxStream<'static, io::Result<web::Bytes>>,
+    len: u64,
+) -> impl Stream<Item = io::Result<web::Bytes>> {
+    stream::unfold(
+        (body, 0u64, false),
+        move |(mut body, seen, done)| async move {
+            if done {
+                return None;
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    let seen = seen + chunk.len() as u64;
+                    if seen > len {
+                        let e =
+                            io::Error::new(io::ErrorKind::InvalidData, "File longer than expected");
+                        return Some((Err(e), (body, seen, true)));
+                    }
+                    Some((Ok(chunk), (body, seen, false)))
+                }
+                Some(Err(e)) => Some((Err(e), (body, seen, true))),
+                None if seen < len => {
+                    let e =
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "File shorter than expected");
+                    Some((Err(e), (body, seen, true)))
+                }
+                None => None,
+            }
+        },
+    )
+}
+
+/// Compress `body` with zstd as it streams.
+fn zstd_compress(
+    body: LocalBoxStream<'static, io::Result<web::Bytes>>,
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+) -> impl Stream<Item = io::Result<web::Bytes>> {
+    stream::unfold(
+        (body, Some(encoder)),
+        |(mut body, mut encoder)| async move {
+            loop {
+                let enc = encoder.as_mut()?;
+                match body.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Err(e) = enc.write_all(&chunk) {
+                            return Some((Err(e), (body, None)));
+                        }
+                        let out = std::mem::take(enc.get_mut());
+                        if !out.is_empty() {
+                            return Some((Ok(out.into()), (body, encoder)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (body, None))),
+                    None => {
+                        let enc = encoder.take()?;
+                        return Some((enc.finish().map(web::Bytes::from), (body, None)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Stream the selected RPMs of a build as a tar archive, fetching them
+/// through the download proxy one at a time.  Needs downloads enabled.
+#[get("/buildinfo/{id}/bundle")]
+async fn bundle(
+    downloader: web::Data<Downloader>,
+    sources: Sources,
+    path: web::Path<(String,)>,
+    query: web::Query<BundleQuery>,
+) -> actix_web::Result<HttpResponse> {
+    if !downloader.enabled() {
+        return Err(ErrorNotFound("Downloads are disabled"));
+    }
+    let format = Format::parse(&query.format)?;
+    let info = Rc::new(sources.build(&path.0, false).await?);
+    deleted::check(&info)?;
+    let files = select(&info, query.arch.as_deref())?;
+    let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+    let nvr = info.nvr.clone();
+    let entries = stream::iter(files)
+        .then(move |(arch, filename)| {
+            let (downloader, sources, info) = (downloader.clone(), sources.clone(), info.clone());
+            async move {
+                let (len, body) = downloader
+                    .fetch(&sources, &info, &arch, &filename)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let path = format!("{}/{}/{}", info.nvr, arch, filename);
+                let header = tar_header(&path, len, mtime)?;
+                Ok::<_, io::Error>(
+                    stream::once(async move { Ok(header) })
+                        .chain(exactly(body, len))
+                        .chain(stream::once(async move { Ok(tar_padding(len)) })),
+                )
+            }
+        })
+        .map(|entry| match entry {
+            Ok(s) => s.boxed_local(),
+            Err(e) => stream::once(async move { Err(e) }).boxed_local(),
+        })
+        .flatten();
+    // Two empty blocks end the archive
+    let trailer = stream::once(async { Ok(web::Bytes::from(vec![0; 2 * BLOCK])) });
+    let tarball = entries.chain(trailer).boxed_local();
+    let body = match format {
+        Format::Tar => tarball,
+        Format::TarZst => {
+            let encoder = zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                .map_err(ErrorInternalServerError)?;
+            zstd_compress(tarball, encoder).boxed_local()
+        }
+    };
+    let filename = format!("{}.{}", nvr, format.extension());
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .streaming(body))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(bundle);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tar() {
+        let data: &'static [u8] = b"not really an rpm";
+        let len = data.len() as u64;
+        let mut archive = Vec::new();
+        archive
+            .extend_from_slice(&tar_header("foo-1-1/noarch/foo-1-1.noarch.rpm", len, 0).unwrap());
+        archive.extend_from_slice(data);
+        archive.extend_from_slice(&tar_padding(len));
+        archive.extend_from_slice(&[0; 2 * BLOCK]);
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let mut ar = tar::Archive::new(&archive[..]);
+        let mut entries = ar.entries().unwrap();
+        let mut e = entries.next().unwrap().unwrap();
+        assert_eq!(
+            e.path().unwrap().to_str(),
+            Some("foo-1-1/noarch/foo-1-1.noarch.rpm")
+        );
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut e, &mut contents).unwrap();
+        assert_eq!(contents, data);
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut info = KojiBuildInfo {
+            nvr: "foo-1-1".to_string(),
+            ..Default::default()
+        };
+        info.rpms
+            .insert("src".to_string(), vec!["foo-1-1.src.rpm".to_string()]);
+        info.rpms
+            .insert("x86_64".to_string(), vec!["foo-1-1.x86_64.rpm".to_string()]);
+        assert_eq!(select(&info, None).unwrap().len(), 2);
+        assert_eq!(
+            select(&info, Some("x86_64")).unwrap(),
+            vec![("x86_64".to_string(), "foo-1-1.x86_64.rpm".to_string())]
+        );
+        assert!(select(&info, Some("x86_64,aarch64")).is_err());
+    }
+}
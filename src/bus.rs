@@ -0,0 +1,199 @@
+//! Publishing each build the service resolves for the first time to NATS or
+//! AMQP, so indexers and mirrors can follow what's actually being used.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Mutex, RwLock};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+
+use crate::config::BusConfig;
+use crate::koji::KojiBuildInfo;
+
+/// Messages waiting to be published; more are dropped.
+const QUEUE: usize = 1000;
+
+lazy_static! {
+    static ref PUBLISHER: RwLock<Option<Publisher>> = RwLock::new(None);
+}
+
+/// What's published, as JSON.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Message<'a> {
+    event: &'static str,
+    time: String,
+    build: &'a KojiBuildInfo,
+}
+
+/// Build ids already published, forgetting the oldest beyond a capacity.
+struct Seen {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl Seen {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Note `id`, returning whether it's new.
+    fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+struct Publisher {
+    tx: SyncSender<Vec<u8>>,
+    seen: Mutex<Seen>,
+}
+
+/// A connection to the bus.
+enum Connection {
+    Nats(nats::Connection),
+    Amqp(amiquip::Connection, amiquip::Channel),
+}
+
+impl Connection {
+    fn open(url: &str) -> Result<Self> {
+        if url.starts_with("nats://") {
+            let conn = nats::connect(url).context("Connecting to NATS")?;
+            Ok(Self::Nats(conn))
+        } else if url.starts_with("amqp://") {
+            let mut conn = amiquip::Connection::insecure_open(url)
+                .map_err(|e| anyhow!("Connecting to AMQP broker: {}", e))?;
+            let channel = conn
+                .open_channel(None)
+                .map_err(|e| anyhow!("Opening AMQP channel: {}", e))?;
+            Ok(Self::Amqp(conn, channel))
+        } else {
+            bail!("Expected a nats:// or amqp:// bus URL")
+        }
+    }
+
+    fn publish(&self, config: &BusConfig, body: &[u8]) -> Result<()> {
+        match self {
+            Self::Nats(conn) => conn.publish(&config.subject, body)?,
+            Self::Amqp(_, channel) => channel
+                .basic_publish(
+                    config.exchange.as_str(),
+                    amiquip::Publish::with_properties(
+                        body,
+                        config.subject.as_str(),
+                        amiquip::AmqpProperties::default()
+                            .with_content_type("application/json".to_string()),
+                    ),
+                )
+                .map_err(|e| anyhow!("{}", e))?,
+        }
+        Ok(())
+    }
+}
+
+/// Publish queued messages, reconnecting after failures.
+fn run(config: BusConfig, mut conn: Option<Connection>, rx: Receiver<Vec<u8>>) {
+    let url = config.url.clone().unwrap_or_default();
+    for body in rx {
+        // One reconnection per message, so a dead bus doesn't stall the queue
+        for attempt in 0..2 {
+            let c = match conn.take() {
+                Some(c) => c,
+                None => match Connection::open(&url) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        tracing::warn!("Failed to connect to message bus: {:#}", e);
+                        break;
+                    }
+                },
+            };
+            match c.publish(&config, &body) {
+                Ok(()) => {
+                    conn = Some(c);
+                    break;
+                }
+                Err(e) if attempt == 0 => {
+                    tracing::debug!("Reconnecting to message bus: {:#}", e);
+                }
+                Err(e) => tracing::warn!("Failed to publish to message bus: {:#}", e),
+            }
+        }
+    }
+}
+
+/// Connect to the bus in `config`, if any, and start publishing.
+pub(crate) fn start(config: &BusConfig) -> Result<()> {
+    let url = match config.url.as_ref() {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+    // Connect now, so a bad URL fails startup
+    let conn = Connection::open(url)?;
+    let (tx, rx) = sync_channel(QUEUE);
+    *PUBLISHER.write().unwrap() = Some(Publisher {
+        tx,
+        seen: Mutex::new(Seen::new(config.seen_capacity)),
+    });
+    tracing::info!(subject = %config.subject, "Publishing resolved builds");
+    let config = config.clone();
+    std::thread::spawn(move || run(config, Some(conn), rx));
+    Ok(())
+}
+
+/// Publish `info` if this is the first time it's been resolved.  Builds
+/// still in progress wait until they finish.
+pub(crate) fn resolved(info: &KojiBuildInfo) {
+    let publisher = PUBLISHER.read().unwrap();
+    let publisher = match publisher.as_ref() {
+        Some(p) => p,
+        None => return,
+    };
+    if info.is_in_progress() || !publisher.seen.lock().unwrap().insert(info.id) {
+        return;
+    }
+    let message = Message {
+        event: "resolved",
+        time: chrono::Utc::now().to_rfc3339(),
+        build: info,
+    };
+    let body = serde_json::to_vec(&message).expect("serializing message");
+    match publisher.tx.try_send(body) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!(nvr = %info.nvr, "Message bus queue full, dropping")
+        }
+        Err(TrySendError::Disconnected(_)) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seen() {
+        let mut seen = Seen::new(2);
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(3));
+        assert!(!seen.insert(3));
+        // The oldest was forgotten
+        assert!(seen.insert(1));
+    }
+}
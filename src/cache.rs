@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{CacheClass, CacheTtls};
+use crate::koji::{self, KojiBuildInfo};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+
+struct Entry {
+    body: String,
+    class: CacheClass,
+    inserted: Instant,
+    /// Number of times this entry has been served.
+    hits: u64,
+}
+
+impl Entry {
+    /// Approximate memory cost; dominated by the serialized body.
+    fn size(&self) -> usize {
+        self.body.len() + std::mem::size_of::<Self>()
+    }
+}
+
+/// The entry map along with the running total of its size.
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    bytes: usize,
+}
+
+impl std::ops::Deref for Entries {
+    type Target = HashMap<String, Entry>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl Entries {
+    fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.map.get_mut(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let e = self.map.remove(key)?;
+        self.bytes -= e.size() + key.len();
+        Some(e)
+    }
+
+    /// Insert an entry, evicting others as needed to stay under `max_bytes`.
+    /// Entries larger than the whole budget are not stored.
+    fn insert(&mut self, key: String, e: Entry, max_bytes: usize) {
+        self.remove(&key);
+        let size = e.size() + key.len();
+        if size > max_bytes {
+            return;
+        }
+        if self.bytes + size > max_bytes {
+            self.evict(self.bytes + size - max_bytes);
+        }
+        self.bytes += size;
+        self.map.insert(key, e);
+    }
+
+    /// Free at least `needed` bytes.  Victims are chosen by hits per byte so
+    /// a single huge, rarely used build goes before many small popular ones.
+    fn evict(&mut self, needed: usize) {
+        let mut candidates: Vec<_> = self
+            .map
+            .iter()
+            .map(|(k, e)| {
+                let size = e.size() + k.len();
+                ((e.hits + 1) as f64 / size as f64, e.inserted, k.clone())
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.1.cmp(&b.1))
+        });
+        let target = self.bytes.saturating_sub(needed);
+        for (_, _, k) in candidates {
+            if self.bytes <= target {
+                break;
+            }
+            self.remove(&k);
+        }
+    }
+}
+
+/// In-memory cache of serialized JSON responses, keyed by request identifier.
+pub(crate) struct Cache {
+    ttls: RwLock<CacheTtls>,
+    /// Memory budget for all entries.
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub(crate) fn new(ttls: CacheTtls, max_bytes: usize) -> Self {
+        Self {
+            ttls: RwLock::new(ttls),
+            max_bytes,
+            entries: Default::default(),
+        }
+    }
+
+    fn ttl(&self, class: CacheClass) -> Duration {
+        self.ttls.read().unwrap().get(class)
+    }
+
+    /// Replace the TTLs; existing entries are judged by the new values.
+    pub(crate) fn set_ttls(&self, ttls: CacheTtls) {
+        *self.ttls.write().unwrap() = ttls;
+    }
+
+    /// Total approximate size of all entries in bytes.
+    pub(crate) fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let expired = match entries.get(key) {
+            Some(e) => e.inserted.elapsed() >= self.ttl(e.class),
+            None => {
+                #[cfg(feature = "metrics")]
+                metrics::CACHE_MISSES.inc();
+                return None;
+            }
+        };
+        if expired {
+            #[cfg(feature = "metrics")]
+            metrics::CACHE_MISSES.inc();
+            // Expired entries are kept around for revalidation
+            if !entries[key].class.can_revalidate() {
+                entries.remove(key);
+            }
+            return None;
+        }
+        #[cfg(feature = "metrics")]
+        metrics::CACHE_HITS.inc();
+        entries.get_mut(key).map(|e| {
+            e.hits += 1;
+            e.body.clone()
+        })
+    }
+
+    /// Return an expired in-progress build, which may be revalidated and
+    /// then renewed via `touch()` rather than fetched again.
+    pub(crate) fn get_stale(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|e| e.class.can_revalidate())
+            .map(|e| e.body.clone())
+    }
+
+    /// Restart the TTL of an entry confirmed to still be accurate.
+    pub(crate) fn touch(&self, key: &str) {
+        if let Some(e) = self.entries.lock().unwrap().get_mut(key) {
+            e.inserted = Instant::now();
+            e.hits += 1;
+        }
+    }
+
+    /// Keys and classes of the `n` most requested mutable entries which
+    /// expire within `within`, most popular first.
+    pub(crate) fn hot_expiring(&self, n: usize, within: Duration) -> Vec<(String, CacheClass)> {
+        let entries = self.entries.lock().unwrap();
+        let mut hot: Vec<_> = entries
+            .iter()
+            .filter(|(_, e)| e.class.is_mutable() && e.hits > 0)
+            .filter(|(_, e)| {
+                let ttl = self.ttl(e.class);
+                let elapsed = e.inserted.elapsed();
+                elapsed < ttl && ttl - elapsed <= within
+            })
+            .map(|(k, e)| (e.hits, k.clone(), e.class))
+            .collect();
+        hot.sort_by(|a, b| b.0.cmp(&a.0));
+        hot.into_iter().take(n).map(|(_, k, c)| (k, c)).collect()
+    }
+
+    /// Whether `key` has an unexpired entry; unlike `get()` this doesn't
+    /// count as a use of the entry.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .map(|e| e.inserted.elapsed() < self.ttl(e.class))
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Serialize and cache a build under its NVR, recording its id mapping.
+    pub(crate) fn store_build(
+        &self,
+        nvrs: &NvrMap,
+        info: &KojiBuildInfo,
+    ) -> serde_json::Result<String> {
+        let body = serde_json::to_string(info)?;
+        nvrs.insert(&info.nvr, info.id);
+        self.insert(&info.nvr, CacheClass::of(info), body.clone());
+        Ok(body)
+    }
+
+    pub(crate) fn insert(&self, key: &str, class: CacheClass, body: String) {
+        let mut entries = self.entries.lock().unwrap();
+        // Keep popularity across refreshes so hot entries stay hot
+        let hits = entries.get(key).map(|e| e.hits).unwrap_or_default();
+        let e = Entry {
+            body,
+            class,
+            inserted: Instant::now(),
+            hits,
+        };
+        entries.insert(key.to_string(), e, self.max_bytes);
+    }
+}
+
+/// Portable form of a cache entry, used to seed other instances.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ExportedEntry {
+    key: String,
+    class: CacheClass,
+    /// Seconds since the entry was originally inserted.
+    age: u64,
+    body: String,
+}
+
+impl Cache {
+    /// Snapshot all unexpired entries.
+    pub(crate) fn export(&self) -> Vec<ExportedEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, e)| e.inserted.elapsed() < self.ttl(e.class))
+            .map(|(k, e)| ExportedEntry {
+                key: k.clone(),
+                class: e.class,
+                age: e.inserted.elapsed().as_secs(),
+                body: e.body.clone(),
+            })
+            .collect()
+    }
+
+    /// Load entries produced by `export()`, preserving their age so they
+    /// expire as they would have on the originating instance, and
+    /// populating `nvrs`.  Only builds keyed by their own NVR or id, in the
+    /// class their state calls for, are loaded.  Returns their number.
+    pub(crate) fn import(&self, nvrs: &NvrMap, exported: Vec<ExportedEntry>) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut n = 0;
+        for e in exported {
+            let inserted = match now.checked_sub(Duration::from_secs(e.age)) {
+                Some(t) => t,
+                None => continue,
+            };
+            if inserted.elapsed() >= self.ttl(e.class) {
+                continue;
+            }
+            let info = match serde_json::from_str::<KojiBuildInfo>(&e.body) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+            if (e.key != info.nvr && e.key != info.id.to_string())
+                || e.class != CacheClass::of(&info)
+            {
+                continue;
+            }
+            nvrs.insert(&info.nvr, info.id);
+            let entry = Entry {
+                body: e.body,
+                class: e.class,
+                inserted,
+                hits: 0,
+            };
+            entries.insert(e.key, entry, self.max_bytes);
+            n += 1;
+        }
+        n
+    }
+}
+
+#[derive(Default)]
+struct Mappings {
+    by_nvr: HashMap<String, u64>,
+    by_id: HashMap<u64, String>,
+}
+
+/// Small cache of NVR <-> build id resolutions.  These never change once
+/// a build exists, so entries don't expire; the map is simply reset when full.
+pub(crate) struct NvrMap {
+    capacity: usize,
+    inner: Mutex<Mappings>,
+}
+
+impl NvrMap {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Default::default(),
+        }
+    }
+
+    pub(crate) fn insert(&self, nvr: &str, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.by_nvr.len() >= self.capacity && !inner.by_nvr.contains_key(nvr) {
+            inner.by_nvr.clear();
+            inner.by_id.clear();
+        }
+        inner.by_nvr.insert(nvr.to_string(), id);
+        inner.by_id.insert(id, nvr.to_string());
+    }
+
+    pub(crate) fn nvr(&self, id: u64) -> Option<String> {
+        self.inner.lock().unwrap().by_id.get(&id).cloned()
+    }
+
+    /// Map a user-supplied build identifier (NVR, module NSVC or numeric
+    /// id) to the canonical NVR if known.
+    pub(crate) fn canonicalize(&self, buildid: &str) -> String {
+        buildid
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| self.nvr(id))
+            .unwrap_or_else(|| koji::koji_buildid(buildid).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ttls() -> CacheTtls {
+        CacheTtls::default()
+    }
+
+    #[test]
+    fn test_size_accounting() {
+        let c = Cache::new(ttls(), 1 << 20);
+        c.insert("a", CacheClass::Build, "x".repeat(100));
+        let one = c.bytes();
+        assert!(one >= 101);
+        c.insert("a", CacheClass::Build, "x".repeat(100));
+        assert_eq!(c.bytes(), one);
+        c.insert("b", CacheClass::Build, "x".repeat(100));
+        assert_eq!(c.bytes(), one * 2);
+    }
+
+    #[test]
+    fn test_evicts_large_unpopular() {
+        let small = 1000;
+        let c = Cache::new(ttls(), 10 * small);
+        c.insert("small", CacheClass::Build, "x".repeat(small / 2));
+        for _ in 0..10 {
+            assert!(c.get("small").is_some());
+        }
+        c.insert("big", CacheClass::Build, "x".repeat(small * 6));
+        c.insert("big2", CacheClass::Build, "x".repeat(small * 6));
+        assert!(c.get("small").is_some());
+        assert!(c.get("big").is_none());
+        assert!(c.get("big2").is_some());
+        assert!(c.bytes() <= 10 * small);
+    }
+
+    #[test]
+    fn test_oversized_not_stored() {
+        let c = Cache::new(ttls(), 100);
+        c.insert("huge", CacheClass::Build, "x".repeat(1000));
+        assert!(c.get("huge").is_none());
+        assert_eq!(c.bytes(), 0);
+    }
+
+    #[test]
+    fn test_hot_expiring() {
+        let c = Cache::new(ttls(), 1 << 20);
+        c.insert("bash-5.1.8-9.el9", CacheClass::Build, "{}".to_string());
+        c.insert(
+            "bash-5.1.8-10.el9",
+            CacheClass::BuildInProgress,
+            "{}".to_string(),
+        );
+        c.insert(
+            "tag/f34-build/inheritance",
+            CacheClass::Tag,
+            "{}".to_string(),
+        );
+        c.insert(
+            "tag/f34-build/external-repos",
+            CacheClass::Tag,
+            "{}".to_string(),
+        );
+        for _ in 0..2 {
+            c.get("tag/f34-build/inheritance");
+        }
+        c.get("bash-5.1.8-10.el9");
+        c.get("bash-5.1.8-9.el9");
+        let within = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            c.hot_expiring(10, within),
+            [
+                ("tag/f34-build/inheritance".to_string(), CacheClass::Tag),
+                ("bash-5.1.8-10.el9".to_string(), CacheClass::BuildInProgress),
+            ]
+        );
+        assert_eq!(c.hot_expiring(1, within).len(), 1);
+        assert!(c.hot_expiring(10, Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_import_validates() {
+        let info = KojiBuildInfo {
+            nvr: "bash-5.1.8-9.el9".to_string(),
+            id: 42,
+            state: "COMPLETE".to_string(),
+            ..Default::default()
+        };
+        let body = serde_json::to_string(&info).unwrap();
+        let entry = |key: &str, class, body: &str| ExportedEntry {
+            key: key.to_string(),
+            class,
+            age: 0,
+            body: body.to_string(),
+        };
+        let c = Cache::new(ttls(), 1 << 20);
+        let nvrs = NvrMap::new(10);
+        let exported = vec![
+            entry("bash-5.1.8-9.el9", CacheClass::Build, &body),
+            entry("42", CacheClass::Build, &body),
+            // Another build's key, the wrong class, or not a build at all
+            entry("openssl-3.0.7-6.el9", CacheClass::Build, &body),
+            entry("bash-5.1.8-9.el9", CacheClass::BuildInProgress, &body),
+            entry("tag/f34-build/inheritance", CacheClass::Tag, "{}"),
+        ];
+        assert_eq!(c.import(&nvrs, exported), 2);
+        assert!(c.get("openssl-3.0.7-6.el9").is_none());
+        assert_eq!(nvrs.nvr(42).as_deref(), Some("bash-5.1.8-9.el9"));
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let nvrs = NvrMap::new(10);
+        nvrs.insert("bash-5.1.8-9.el9", 42);
+        assert_eq!(nvrs.canonicalize("42"), "bash-5.1.8-9.el9");
+        assert_eq!(nvrs.canonicalize("43"), "43");
+        assert_eq!(
+            nvrs.canonicalize("nodejs:14:3420210101120000:abcdef12"),
+            "nodejs-14-3420210101120000.abcdef12"
+        );
+    }
+}
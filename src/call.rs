@@ -0,0 +1,92 @@
+//! `POST /call/{method}`: hub methods the operator allows, called with the
+//! request's arguments and answered with koji's result as JSON.  An escape
+//! hatch for anything without its own endpoint yet.
+
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{post, web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::koji::{self, Backend};
+use crate::server::run_blocking;
+use crate::watch::Sources;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct CallConfig {
+    /// Hub methods `/call` may use, e.g. `getBuildTarget`; disabled if
+    /// empty.  Nothing stops listing methods with side effects, so only
+    /// list read-only ones.
+    pub(crate) allowed_methods: Vec<String>,
+}
+
+/// The request body; both parts are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CallArgs {
+    args: Vec<Value>,
+    kwargs: Map<String, Value>,
+}
+
+/// Parse a request body, which may be empty for a call without arguments.
+fn parse_args(body: &[u8]) -> actix_web::Result<CallArgs> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(CallArgs::default());
+    }
+    serde_json::from_slice(body).map_err(|e| ErrorBadRequest(format!("Invalid arguments: {}", e)))
+}
+
+#[post("/call/{method}")]
+async fn call(
+    config: web::Data<CallConfig>,
+    sources: Sources,
+    path: web::Path<(String,)>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    if config.allowed_methods.is_empty() {
+        return Err(ErrorNotFound("Hub calls are disabled"));
+    }
+    let method = path.into_inner().0;
+    if !config.allowed_methods.contains(&method) {
+        return Err(ErrorForbidden(format!(
+            "{} is not an allowed method",
+            method
+        )));
+    }
+    let CallArgs { args, kwargs } = parse_args(&body)?;
+    koji::validate_call(&method, &args, &kwargs).map_err(ErrorBadRequest)?;
+    let hub = sources.hub.read().unwrap().clone();
+    let _permit = sources.shedder.backend()?;
+    let result = run_blocking({
+        let method = method.clone();
+        move || hub.call(&method, &args, &kwargs)
+    })
+    .await;
+    match result {
+        Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => {
+            tracing::error!(%method, "Hub call failed: {}", e);
+            Err(ErrorInternalServerError(e))
+        }
+    }
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(call);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let a = parse_args(b"").unwrap();
+        assert!(a.args.is_empty() && a.kwargs.is_empty());
+        let a = parse_args(br#"{"args": ["f34"], "kwargs": {"strict": true}}"#).unwrap();
+        assert_eq!(a.args, vec![Value::from("f34")]);
+        assert_eq!(a.kwargs["strict"], Value::from(true));
+        assert!(parse_args(br#"{"argz": []}"#).is_err());
+        assert!(parse_args(b"[1]").is_err());
+    }
+}
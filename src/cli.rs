@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::access_log::AccessLogFormat;
+use crate::config::{BindAddress, LogFormat};
+
+fn validate_url(s: &str) -> Result<(), String> {
+    if s.starts_with("https://") || s.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(format!("Expected an http:// or https:// URL, not {}", s))
+    }
+}
+
+fn validate_log_level(s: &str) -> Result<(), String> {
+    tracing_subscriber::EnvFilter::try_new(s)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Proxy service offering a modern JSON API for reading Koji build metadata.
+#[derive(Debug, Parser)]
+#[clap(version)]
+pub(crate) struct Opt {
+    /// Address to listen on, e.g. `::`, `127.0.0.1:8080` or
+    /// `unix:/path/to/socket`; may be repeated [default: ::]
+    #[clap(long)]
+    pub(crate) bind: Vec<BindAddress>,
+
+    /// Serve /admin routes only on this address; may be repeated
+    #[clap(long)]
+    pub(crate) admin_bind: Vec<BindAddress>,
+
+    /// Port for --bind addresses without one [default: 8080]
+    #[clap(long)]
+    pub(crate) port: Option<u16>,
+
+    /// Mount all routes under this path prefix, e.g. /koji-api
+    #[clap(long)]
+    pub(crate) base_path: Option<String>,
+
+    /// PEM certificate chain; enables HTTPS together with --tls-key
+    #[clap(long, parse(from_os_str))]
+    pub(crate) tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[clap(long, parse(from_os_str))]
+    pub(crate) tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle; require client certificates signed by it
+    #[clap(long, parse(from_os_str))]
+    pub(crate) tls_client_ca: Option<PathBuf>,
+
+    /// Koji hub XML-RPC URL; defaults to the koji client configuration
+    #[clap(long, global = true, validator = validate_url)]
+    pub(crate) hub: Option<String>,
+
+    /// Base URL for build artifacts [default: https://kojipkgs.fedoraproject.org]
+    #[clap(long, global = true, validator = validate_url)]
+    pub(crate) topurl: Option<String>,
+
+    /// Path to a configuration file; overrides $KOJI_API_CONFIG
+    #[clap(long, global = true, parse(from_os_str))]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Log level (error, warn, info, debug, trace) or filter directives [default: info]
+    #[clap(long, validator = validate_log_level)]
+    pub(crate) log_level: Option<String>,
+
+    /// Log output format: text or json [default: text]
+    #[clap(long)]
+    pub(crate) log_format: Option<LogFormat>,
+
+    /// Per-request log lines: off, structured or combined [default: off]
+    #[clap(long)]
+    pub(crate) access_log: Option<AccessLogFormat>,
+
+    /// Run a one-shot command instead of serving
+    #[clap(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Resolve against the hub directly and print the result, without HTTP
+    #[clap(subcommand)]
+    Query(Query),
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Query {
+    /// Look up a build by NVR or numeric id, as `/buildinfo` would
+    Buildinfo {
+        buildid: String,
+
+        /// Print the JSON served by `/buildinfo` rather than a summary
+        #[clap(long)]
+        json: bool,
+    },
+}
@@ -0,0 +1,134 @@
+//! A client for the HTTP API.
+
+use anyhow::{bail, Result};
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+
+use crate::koji::{validate_buildid, KojiBuildInfo};
+
+/// Header carrying an API key, for instances which require one.
+const API_KEY_HEADER: &str = "x-api-key";
+/// Header naming which of the server's hubs to ask.
+const HUB_HEADER: &str = "x-koji-hub";
+
+/// An error body from the server.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(rename = "request-id")]
+    request_id: Option<String>,
+}
+
+/// Talks to a koji-sane-json-api instance.
+#[derive(Clone, Debug)]
+pub struct KojiSaneClient {
+    base: String,
+    http: reqwest::Client,
+    api_key: Option<String>,
+    hub: Option<String>,
+}
+
+impl KojiSaneClient {
+    /// `base_url` includes any base path, e.g. `https://example.com/koji-api`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base: base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            api_key: None,
+            hub: None,
+        }
+    }
+
+    /// Send an API key with each request.
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    /// Ask about builds on one of the server's further hubs (see its
+    /// `/hubs`), rather than the main one.
+    pub fn with_hub(mut self, hub: &str) -> Self {
+        self.hub = Some(hub.to_string());
+        self
+    }
+
+    fn build_url(&self, buildid: &str, refresh: bool) -> Result<String> {
+        validate_buildid(buildid)?;
+        let mut url = format!("{}/buildinfo/{}", self.base, buildid);
+        if refresh {
+            url.push_str("?refresh=true");
+        }
+        Ok(url)
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let mut req = self.http.request(method, url);
+        if let Some(key) = self.api_key.as_deref() {
+            req = req.header(API_KEY_HEADER, key);
+        }
+        if let Some(hub) = self.hub.as_deref() {
+            req = req.header(HUB_HEADER, hub);
+        }
+        req
+    }
+
+    async fn get_build(&self, buildid: &str, refresh: bool) -> Result<KojiBuildInfo> {
+        let url = self.build_url(buildid, refresh)?;
+        let res = self.request(reqwest::Method::GET, &url).send().await?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res.json().await?);
+        }
+        match res.json::<ErrorBody>().await {
+            Ok(e) => bail!(
+                "{}: {} (request id {})",
+                status,
+                e.error,
+                e.request_id.as_deref().unwrap_or("unknown")
+            ),
+            Err(_) => bail!("{}", status),
+        }
+    }
+
+    /// Look up a build by NVR or numeric id.
+    pub async fn build_info(&self, buildid: &str) -> Result<KojiBuildInfo> {
+        self.get_build(buildid, false).await
+    }
+
+    /// Like `build_info()`, but bypassing the server's cache, e.g. after a
+    /// build was re-signed.
+    pub async fn build_info_fresh(&self, buildid: &str) -> Result<KojiBuildInfo> {
+        self.get_build(buildid, true).await
+    }
+
+    /// Whether a build exists, without transferring it.
+    pub async fn build_exists(&self, buildid: &str) -> Result<bool> {
+        let url = self.build_url(buildid, false)?;
+        let res = self.request(reqwest::Method::HEAD, &url).send().await?;
+        match res.status() {
+            s if s.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            s => bail!("{}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_url() -> Result<()> {
+        let c = KojiSaneClient::new("https://example.com/koji-api/");
+        assert_eq!(
+            c.build_url("rpm-ostree-2020.10-1.fc34", false)?,
+            "https://example.com/koji-api/buildinfo/rpm-ostree-2020.10-1.fc34"
+        );
+        assert_eq!(
+            c.build_url("1657648", true)?,
+            "https://example.com/koji-api/buildinfo/1657648?refresh=true"
+        );
+        assert!(c.build_url("../admin/reload", false).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,551 @@
+//! `POST /compare/deep`: which files differ between the RPMs of two builds,
+//! as rpmdiff reports them.  Each RPM's headers record its files' modes,
+//! sizes, digests, link targets and owners, so only those are fetched
+//! rather than whole payloads.  That is still slow for large builds, so
+//! comparisons run as jobs: the POST answers 202 with the job, which
+//! `GET /compare/deep/{id}` reports on until it is done.
+//!
+//! Changes to sonames and symbol versions the RPMs provide are reported
+//! too, as libraries' ABI changes; removals break programs linked against
+//! them.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::deleted;
+use crate::download::Downloader;
+use crate::koji::KojiBuildInfo;
+use crate::repo::read_headers;
+use crate::rpm::{Dependency, FileDetails, Package};
+use crate::watch::Sources;
+
+/// Pairs of RPMs one job may compare.
+const MAX_PAIRS: usize = 100;
+/// RPMs whose headers are read at once.
+const CONCURRENCY: usize = 4;
+/// Jobs kept for reporting on, the oldest forgotten first.
+const KEPT: usize = 64;
+
+lazy_static! {
+    static ref JOBS: Mutex<Jobs> = Mutex::new(Jobs::default());
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DeepRequest {
+    from: String,
+    to: String,
+    /// Package names to compare; all those in both builds if empty.
+    #[serde(default)]
+    rpms: Vec<String>,
+    /// Only compare RPMs of this arch; all arches both builds have if unset.
+    arch: Option<String>,
+}
+
+/// An RPM of the same name and arch in both builds.
+#[derive(Debug, PartialEq)]
+struct Pair {
+    arch: String,
+    from: String,
+    to: String,
+}
+
+/// The RPMs to compare, and those selected which only one build has.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Selection {
+    #[serde(skip)]
+    pairs: Vec<Pair>,
+    /// RPMs only `to` has.
+    added_rpms: Vec<String>,
+    /// RPMs only `from` has.
+    removed_rpms: Vec<String>,
+}
+
+/// Pair up the RPMs of `from` and `to` by arch and package name.
+fn select(
+    from: &KojiBuildInfo,
+    to: &KojiBuildInfo,
+    arch: Option<&str>,
+    names: &[String],
+) -> Selection {
+    let wanted = |info: &KojiBuildInfo, a: &str, f: &str| {
+        arch.map_or(true, |arch| arch == a)
+            && (names.is_empty() || names.iter().any(|n| n == info.rpm_name(f)))
+    };
+    let by_name = |info: &KojiBuildInfo| -> BTreeMap<(String, String), String> {
+        info.rpms
+            .iter()
+            .flat_map(|(a, files)| files.iter().map(move |f| (a, f)))
+            .filter(|(a, f)| wanted(info, a, f))
+            .map(|(a, f)| ((a.clone(), info.rpm_name(f).to_string()), f.clone()))
+            .collect()
+    };
+    let (mut old, new) = (by_name(from), by_name(to));
+    let mut s = Selection::default();
+    for ((arch, name), file) in new {
+        match old.remove(&(arch.clone(), name)) {
+            Some(from) => s.pairs.push(Pair {
+                arch,
+                from,
+                to: file,
+            }),
+            None => s.added_rpms.push(file),
+        }
+    }
+    s.removed_rpms = old.into_iter().map(|(_, f)| f).collect();
+    s
+}
+
+/// How a file present in both RPMs differs.
+#[derive(Debug, PartialEq, Serialize)]
+struct Change {
+    /// `mode`, `size`, `digest`, `linkto`, `user` or `group`.
+    field: &'static str,
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct ChangedFile {
+    path: String,
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RpmDiff {
+    arch: String,
+    from: String,
+    to: String,
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<ChangedFile>,
+    /// Files whose type and permission bits differ, also among `changed`.
+    mode_changed: Vec<String>,
+    abi: Abi,
+}
+
+/// Changes to what an RPM offers dynamically linked programs, from its
+/// automatic provides: `libfoo.so.1()(64bit)` for a soname and
+/// `libfoo.so.1(FOO_1.2)(64bit)` for a symbol version.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Abi {
+    added_sonames: Vec<String>,
+    removed_sonames: Vec<String>,
+    added_symbol_versions: Vec<String>,
+    removed_symbol_versions: Vec<String>,
+    /// Something was removed, e.g. by a soname bump.
+    breaking: bool,
+}
+
+/// Whether a provide is of a soname (`false`) or symbol version (`true`),
+/// if either.
+fn soname_provide(name: &str) -> Option<bool> {
+    let name = name.strip_suffix("(64bit)").unwrap_or(name);
+    let (soname, version) = match name.split_once('(') {
+        Some((soname, rest)) => (soname, rest.strip_suffix(')')?),
+        None => (name, ""),
+    };
+    let so = soname.find(".so")?;
+    // `libfoo.so` or `libfoo.so.1`, not e.g. `libfoo.sock`
+    if !matches!(soname[so + 3..].chars().next(), None | Some('.')) {
+        return None;
+    }
+    Some(!version.is_empty())
+}
+
+/// Compare two RPMs' soname and symbol version provides.
+fn diff_abi(from: &[Dependency], to: &[Dependency]) -> Abi {
+    let provides = |deps: &[Dependency], versions: bool| -> BTreeSet<String> {
+        deps.iter()
+            .filter(|d| soname_provide(&d.name) == Some(versions))
+            .map(|d| d.name.clone())
+            .collect()
+    };
+    let diff = |versions: bool| -> (Vec<String>, Vec<String>) {
+        let (old, new) = (provides(from, versions), provides(to, versions));
+        (
+            new.difference(&old).cloned().collect(),
+            old.difference(&new).cloned().collect(),
+        )
+    };
+    let (added_sonames, removed_sonames) = diff(false);
+    let (added_symbol_versions, removed_symbol_versions) = diff(true);
+    Abi {
+        breaking: !removed_sonames.is_empty() || !removed_symbol_versions.is_empty(),
+        added_sonames,
+        removed_sonames,
+        added_symbol_versions,
+        removed_symbol_versions,
+    }
+}
+
+/// Compare two RPMs.
+fn diff(pair: Pair, from: &Package, to: &Package) -> RpmDiff {
+    RpmDiff {
+        abi: diff_abi(&from.provides, &to.provides),
+        ..diff_files(pair, &from.file_details, &to.file_details)
+    }
+}
+
+/// Compare two RPMs' files by path.
+fn diff_files(pair: Pair, from: &[FileDetails], to: &[FileDetails]) -> RpmDiff {
+    let mut old: BTreeMap<&str, &FileDetails> = from.iter().map(|f| (f.path.as_str(), f)).collect();
+    let mut d = RpmDiff {
+        arch: pair.arch,
+        from: pair.from,
+        to: pair.to,
+        ..Default::default()
+    };
+    let mut new: Vec<&FileDetails> = to.iter().collect();
+    new.sort_by(|a, b| a.path.cmp(&b.path));
+    for f in new {
+        let o = match old.remove(f.path.as_str()) {
+            Some(o) => o,
+            None => {
+                d.added.push(f.path.clone());
+                continue;
+            }
+        };
+        let mut changes = Vec::new();
+        let mut check = |field, from: String, to: String| {
+            if from != to {
+                changes.push(Change { field, from, to });
+            }
+        };
+        check("mode", format!("{:o}", o.mode), format!("{:o}", f.mode));
+        check("size", o.size.to_string(), f.size.to_string());
+        check("digest", o.digest.clone(), f.digest.clone());
+        check("linkto", o.linkto.clone(), f.linkto.clone());
+        check("user", o.user.clone(), f.user.clone());
+        check("group", o.group.clone(), f.group.clone());
+        if changes.is_empty() {
+            continue;
+        }
+        if changes[0].field == "mode" {
+            d.mode_changed.push(f.path.clone());
+        }
+        d.changed.push(ChangedFile {
+            path: f.path.clone(),
+            changes,
+        });
+    }
+    d.removed = old.into_iter().map(|(p, _)| p.to_string()).collect();
+    d
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Job {
+    id: String,
+    status: Status,
+    from: String,
+    to: String,
+    #[serde(flatten)]
+    selection: Selection,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpms: Option<Vec<RpmDiff>>,
+    /// Once done, the RPMs whose ABI changes are breaking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abi_breaking: Option<Vec<String>>,
+}
+
+/// Recent jobs by id, oldest first.
+#[derive(Default)]
+struct Jobs {
+    jobs: HashMap<String, Job>,
+    order: VecDeque<String>,
+}
+
+impl Jobs {
+    fn insert(&mut self, job: Job) {
+        let id = job.id.clone();
+        if self.jobs.insert(id.clone(), job).is_none() {
+            self.order.push_back(id);
+        }
+        if self.order.len() > KEPT {
+            if let Some(old) = self.order.pop_front() {
+                self.jobs.remove(&old);
+            }
+        }
+    }
+
+    /// Record how a job ended, unless it has been forgotten.
+    fn finish(&mut self, id: &str, result: actix_web::Result<Vec<RpmDiff>>) {
+        if let Some(job) = self.jobs.get_mut(id) {
+            match result {
+                Ok(rpms) => {
+                    job.status = Status::Done;
+                    let breaking = rpms.iter().filter(|d| d.abi.breaking);
+                    job.abi_breaking = Some(breaking.map(|d| d.to.clone()).collect());
+                    job.rpms = Some(rpms);
+                }
+                Err(e) => {
+                    job.status = Status::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// A build whose RPMs can be read.
+async fn lookup(
+    downloader: &Downloader,
+    sources: &Sources,
+    buildid: &str,
+) -> actix_web::Result<KojiBuildInfo> {
+    if !downloader.enabled() {
+        return Err(ErrorNotFound("Downloads are disabled"));
+    }
+    let info = sources.build(buildid, false).await?;
+    deleted::check(&info)?;
+    if info.state != "COMPLETE" {
+        return Err(ErrorConflict(format!(
+            "{} is {}; only completed builds can be compared",
+            info.nvr, info.state
+        )));
+    }
+    Ok(info)
+}
+
+/// Read and compare each pair's headers.
+async fn run(
+    downloader: web::Data<Downloader>,
+    from: KojiBuildInfo,
+    to: KojiBuildInfo,
+    pairs: Vec<Pair>,
+) -> actix_web::Result<Vec<RpmDiff>> {
+    let (downloader, from, to) = (&downloader, &from, &to);
+    stream::iter(pairs)
+        .map(|pair| async move {
+            let (old, _) = read_headers(downloader, from, &pair.arch, &pair.from).await?;
+            let (new, _) = read_headers(downloader, to, &pair.arch, &pair.to).await?;
+            Ok(diff(pair, &old, &new))
+        })
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// Start comparing, answering with the job to poll.
+#[post("/compare/deep")]
+async fn deep(
+    req: HttpRequest,
+    sources: Sources,
+    downloader: web::Data<Downloader>,
+    body: web::Json<DeepRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let body = body.into_inner();
+    let from = lookup(&downloader, &sources, &body.from).await?;
+    let to = lookup(&downloader, &sources, &body.to).await?;
+    let mut selection = select(&from, &to, body.arch.as_deref(), &body.rpms);
+    if selection.pairs.is_empty() {
+        return Err(ErrorBadRequest(format!(
+            "{} and {} have no RPMs in common to compare",
+            from.nvr, to.nvr
+        )));
+    }
+    if selection.pairs.len() > MAX_PAIRS {
+        return Err(ErrorBadRequest(format!(
+            "{} RPMs would be compared; select at most {} with rpms or arch",
+            selection.pairs.len(),
+            MAX_PAIRS
+        )));
+    }
+    let pairs = std::mem::take(&mut selection.pairs);
+    let job = Job {
+        id: uuid::Uuid::new_v4().to_simple().to_string(),
+        status: Status::Running,
+        from: from.nvr.clone(),
+        to: to.nvr.clone(),
+        selection,
+        error: None,
+        rpms: None,
+        abi_breaking: None,
+    };
+    tracing::info!(id = %job.id, from = %job.from, to = %job.to, rpms = pairs.len(), "Comparing");
+    let id = job.id.clone();
+    let response = HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("{}/{}", req.path(), id)))
+        .json(&job);
+    JOBS.lock().unwrap().insert(job);
+    actix_web::rt::spawn(async move {
+        let result = run(downloader, from, to, pairs).await;
+        JOBS.lock().unwrap().finish(&id, result);
+    });
+    Ok(response)
+}
+
+#[get("/compare/deep/{id}")]
+async fn deep_job(path: web::Path<(String,)>) -> actix_web::Result<HttpResponse> {
+    let jobs = JOBS.lock().unwrap();
+    let job = jobs
+        .jobs
+        .get(&path.0)
+        .ok_or_else(|| ErrorNotFound("No such comparison"))?;
+    Ok(HttpResponse::Ok().json(job))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(deep).service(deep_job);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build(nvr: &str, rpms: &[(&str, &str)]) -> KojiBuildInfo {
+        let mut info = KojiBuildInfo {
+            nvr: nvr.to_string(),
+            ..Default::default()
+        };
+        for (arch, f) in rpms {
+            info.rpms
+                .entry(arch.to_string())
+                .or_default()
+                .push(f.to_string());
+        }
+        info
+    }
+
+    #[test]
+    fn test_select() {
+        let from = build(
+            "foo-1-1",
+            &[
+                ("src", "foo-1-1.src.rpm"),
+                ("x86_64", "foo-1-1.x86_64.rpm"),
+                ("x86_64", "foo-old-1-1.x86_64.rpm"),
+            ],
+        );
+        let to = build(
+            "foo-1-2",
+            &[
+                ("src", "foo-1-2.src.rpm"),
+                ("x86_64", "foo-1-2.x86_64.rpm"),
+                ("x86_64", "foo-new-1-2.x86_64.rpm"),
+            ],
+        );
+        let s = select(&from, &to, None, &[]);
+        assert_eq!(s.pairs.len(), 2);
+        assert_eq!(s.added_rpms, ["foo-new-1-2.x86_64.rpm"]);
+        assert_eq!(s.removed_rpms, ["foo-old-1-1.x86_64.rpm"]);
+
+        let s = select(&from, &to, Some("x86_64"), &["foo".to_string()]);
+        assert_eq!(
+            s.pairs,
+            [Pair {
+                arch: "x86_64".into(),
+                from: "foo-1-1.x86_64.rpm".into(),
+                to: "foo-1-2.x86_64.rpm".into(),
+            }]
+        );
+        assert!(s.added_rpms.is_empty() && s.removed_rpms.is_empty());
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let file = |path: &str, mode, digest: &str| FileDetails {
+            path: path.into(),
+            mode,
+            size: 10,
+            digest: digest.into(),
+            user: "root".into(),
+            group: "root".into(),
+            ..Default::default()
+        };
+        let from = [
+            file("/usr/bin/foo", 0o100755, "aa"),
+            file("/etc/foo.conf", 0o100644, "bb"),
+            file("/usr/share/foo/old", 0o100644, "cc"),
+        ];
+        let to = [
+            file("/usr/bin/foo", 0o100755, "ab"),
+            file("/etc/foo.conf", 0o100600, "bb"),
+            file("/usr/share/foo/new", 0o100644, "cc"),
+        ];
+        let pair = Pair {
+            arch: "x86_64".into(),
+            from: "foo-1-1.x86_64.rpm".into(),
+            to: "foo-1-2.x86_64.rpm".into(),
+        };
+        let d = diff_files(pair, &from, &to);
+        assert_eq!(d.added, ["/usr/share/foo/new"]);
+        assert_eq!(d.removed, ["/usr/share/foo/old"]);
+        assert_eq!(d.mode_changed, ["/etc/foo.conf"]);
+        let paths: Vec<&str> = d.changed.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, ["/etc/foo.conf", "/usr/bin/foo"]);
+        assert_eq!(
+            d.changed[0].changes,
+            [Change {
+                field: "mode",
+                from: "100644".into(),
+                to: "100600".into(),
+            }]
+        );
+        assert_eq!(d.changed[1].changes[0].field, "digest");
+    }
+
+    #[test]
+    fn test_diff_abi() {
+        assert_eq!(soname_provide("libfoo.so.1()(64bit)"), Some(false));
+        assert_eq!(soname_provide("libfoo.so.1"), Some(false));
+        assert_eq!(soname_provide("libfoo.so.1(FOO_1.2)(64bit)"), Some(true));
+        assert_eq!(soname_provide("libfoo.sock"), None);
+        assert_eq!(soname_provide("foo(x86-64)"), None);
+        assert_eq!(soname_provide("foo"), None);
+
+        let deps = |names: &[&str]| -> Vec<Dependency> {
+            names
+                .iter()
+                .map(|n| Dependency {
+                    name: n.to_string(),
+                    flags: None,
+                    evr: String::new(),
+                    pre: false,
+                })
+                .collect()
+        };
+        let from = deps(&["foo", "libfoo.so.1()(64bit)", "libfoo.so.1(FOO_1.0)(64bit)"]);
+        let to = deps(&[
+            "foo",
+            "libfoo.so.2()(64bit)",
+            "libfoo.so.2(FOO_1.0)(64bit)",
+            "libfoo.so.2(FOO_2.0)(64bit)",
+        ]);
+        let abi = diff_abi(&from, &to);
+        assert_eq!(abi.added_sonames, ["libfoo.so.2()(64bit)"]);
+        assert_eq!(abi.removed_sonames, ["libfoo.so.1()(64bit)"]);
+        assert_eq!(abi.added_symbol_versions.len(), 2);
+        assert_eq!(abi.removed_symbol_versions, ["libfoo.so.1(FOO_1.0)(64bit)"]);
+        assert!(abi.breaking);
+
+        // Only additions, as for a compatible update
+        let abi = diff_abi(
+            &from,
+            &[from.clone(), deps(&["libfoo.so.1(FOO_1.1)(64bit)"])].concat(),
+        );
+        assert_eq!(abi.added_symbol_versions, ["libfoo.so.1(FOO_1.1)(64bit)"]);
+        assert!(!abi.breaking);
+    }
+}
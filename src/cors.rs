@@ -0,0 +1,43 @@
+//! Cross-origin requests from browser-based dashboards.
+
+use actix_cors::Cors;
+use actix_web::middleware::Condition;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct CorsConfig {
+    /// Origins such as `https://dashboard.example.com`, or `*` for any;
+    /// CORS is disabled when empty.
+    pub(crate) allowed_origins: Vec<String>,
+    pub(crate) allowed_methods: Vec<String>,
+    /// Seconds browsers may cache a preflight response.
+    pub(crate) max_age: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+            max_age: 3600,
+        }
+    }
+}
+
+/// The CORS middleware, a no-op unless origins are configured.
+pub(crate) fn middleware(config: &CorsConfig) -> Condition<Cors> {
+    let mut cors = Cors::default()
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allow_any_header()
+        .expose_headers(vec!["x-request-id"])
+        .max_age(config.max_age);
+    for origin in config.allowed_origins.iter() {
+        cors = if origin == "*" {
+            cors.allow_any_origin()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    Condition::new(!config.allowed_origins.is_empty(), cors)
+}
@@ -0,0 +1,123 @@
+//! Deleted builds: answered with 410 Gone and what is known of the
+//! deletion, rather than RPM URLs koji has purged.
+
+#[cfg(feature = "download-proxy")]
+use actix_web::error::ErrorGone;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::TimeZone;
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+
+#[cfg(feature = "download-proxy")]
+use crate::download::Downloader;
+use crate::koji::{Backend, KojiBuildInfo};
+use crate::request_id;
+use crate::server::run_blocking;
+use crate::watch::Sources;
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Gone {
+    error: String,
+    request_id: Option<String>,
+    nvr: String,
+    id: u64,
+    /// When the build was last untagged.  Koji doesn't record when builds
+    /// are deleted, but only untagged ones can be, so this is the latest
+    /// known time before the deletion.
+    untagged_at: Option<String>,
+    /// Whether kojipkgs still serves the build's RPMs, which it usually
+    /// stops doing once the deletion is processed; unknown without the
+    /// `download-proxy` feature.
+    rpms_available: Option<bool>,
+}
+
+/// Refuse to serve files of a deleted build.
+#[cfg(feature = "download-proxy")]
+pub(crate) fn check(info: &KojiBuildInfo) -> actix_web::Result<()> {
+    if info.is_deleted() {
+        return Err(ErrorGone(format!("{} was deleted", info.nvr)));
+    }
+    Ok(())
+}
+
+/// The latest time in `queryHistory` output at which the build was
+/// removed from a tag.
+fn last_untagged(history: &Value) -> Option<String> {
+    let ts = history["tag_listing"]
+        .as_array()?
+        .iter()
+        .filter_map(|e| e["revoke_ts"].as_f64())
+        .fold(None, |max: Option<f64>, t| {
+            Some(max.map_or(t, |m| m.max(t)))
+        })?;
+    let t = chrono::Utc.timestamp_opt(ts as i64, 0).single()?;
+    Some(t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+async fn untagged_at(sources: &Sources, id: u64) -> Option<String> {
+    let hub = sources.hub.read().unwrap().clone();
+    let _permit = sources.shedder.backend().ok()?;
+    let mut kwargs = Map::new();
+    kwargs.insert("build".into(), id.into());
+    kwargs.insert("table".into(), "tag_listing".into());
+    match run_blocking(move || hub.call("queryHistory", &[], &kwargs)).await {
+        Ok(history) => last_untagged(&history),
+        Err(e) => {
+            tracing::warn!(id, "Failed to get tag history: {}", e);
+            None
+        }
+    }
+}
+
+/// Whether kojipkgs still serves a deleted build's RPMs, if it has any.
+#[cfg(feature = "download-proxy")]
+pub(crate) async fn rpms_available(downloader: &Downloader, info: &KojiBuildInfo) -> Option<bool> {
+    // The source RPM if there is one, as every build has it
+    let (arch, filename) = info
+        .rpms
+        .get("src")
+        .and_then(|r| r.first().map(|f| ("src", f)))
+        .or_else(|| {
+            info.rpms
+                .iter()
+                .find_map(|(arch, r)| r.first().map(|f| (arch.as_str(), f)))
+        })?;
+    Some(downloader.exists(info, arch, filename).await)
+}
+
+/// The 410 response for a deleted build.
+pub(crate) async fn gone(
+    req: &HttpRequest,
+    sources: &Sources,
+    info: &KojiBuildInfo,
+    rpms_available: Option<bool>,
+) -> HttpResponse {
+    HttpResponse::Gone().json(Gone {
+        error: format!("{} was deleted", info.nvr),
+        request_id: request_id::get(req).map(|id| id.0),
+        nvr: info.nvr.clone(),
+        id: info.id,
+        untagged_at: untagged_at(sources, info.id).await,
+        rpms_available,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_last_untagged() {
+        let history = serde_json::json!({"tag_listing": [
+            {"tag.name": "f34", "revoke_ts": 1607714000.5},
+            {"tag.name": "f34-updates", "revoke_ts": 1609459200.0},
+            {"tag.name": "trashcan", "revoke_ts": null},
+        ]});
+        assert_eq!(
+            last_untagged(&history).as_deref(),
+            Some("2021-01-01T00:00:00Z")
+        );
+        assert_eq!(last_untagged(&serde_json::json!({"tag_listing": []})), None);
+    }
+}
@@ -0,0 +1,512 @@
+//! `GET /download/{buildid}/{arch}/{filename}`: a build's RPMs streamed from
+//! kojipkgs through this server, for clients which can reach nothing else.
+//! Ranges are honored, so interrupted downloads can resume, and whole
+//! files are checked against the SHA-256 koji recorded.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use actix_files::NamedFile;
+use actix_web::error::{ErrorBadGateway, ErrorNotFound};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use futures::stream::{LocalBoxStream, Stream};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::config::DownloadConfig;
+use crate::deleted;
+use crate::koji::{self, Backend, KojiBuildInfo};
+use crate::server::run_blocking;
+use crate::watch::Sources;
+
+const RPM_CONTENT_TYPE: &str = "application/x-rpm";
+/// Size of reads from locally cached RPMs.
+const READ_CHUNK: usize = 64 * 1024;
+/// Passed on to kojipkgs, for ranges and resuming.
+const REQUEST_HEADERS: &[&str] = &["range", "if-range"];
+/// Passed back from kojipkgs.
+const RESPONSE_HEADERS: &[&str] = &["accept-ranges", "content-range", "etag", "last-modified"];
+
+/// Fetches RPMs, through the local cache if there is one.
+pub(crate) struct Downloader {
+    enabled: bool,
+    verify: bool,
+    client: reqwest::Client,
+    cache_dir: Option<PathBuf>,
+}
+
+impl Downloader {
+    /// `ca_bundle` is the hub's, trusted for kojipkgs too.
+    pub(crate) fn new(config: &DownloadConfig, ca_bundle: Option<&Path>) -> Result<Self> {
+        let mut client =
+            reqwest::Client::builder().connect_timeout(Duration::from_secs(config.connect_timeout));
+        if let Some(path) = ca_bundle {
+            let f = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+            let certs = rustls_pemfile::certs(&mut io::BufReader::new(f))
+                .with_context(|| format!("Reading {}", path.display()))?;
+            for der in certs {
+                client = client.add_root_certificate(reqwest::Certificate::from_der(&der)?);
+            }
+        }
+        let client = client.build().context("Creating HTTP client")?;
+        Ok(Self {
+            enabled: config.enabled,
+            verify: config.verify,
+            client,
+            cache_dir: config.cache_dir.clone(),
+        })
+    }
+
+    /// Where an RPM is kept locally; only completed builds' RPMs are, as
+    /// they won't change.
+    fn cache_path(&self, info: &KojiBuildInfo, arch: &str, filename: &str) -> Option<PathBuf> {
+        if info.state != "COMPLETE" {
+            return None;
+        }
+        let dir = self.cache_dir.as_ref()?;
+        Some(dir.join(&info.nvr).join(arch).join(filename))
+    }
+
+    /// Whether kojipkgs has an RPM of `info`, per a `HEAD` request.
+    pub(crate) async fn exists(&self, info: &KojiBuildInfo, arch: &str, filename: &str) -> bool {
+        let url = rpm_url(info, arch, filename);
+        match self.client.head(&url).send().await {
+            Ok(res) => res.status().is_success(),
+            Err(e) => {
+                tracing::debug!(%url, "Failed to check for RPM: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Whether `/download` and bundles are served.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// A whole RPM and its size, from the local copy or kojipkgs, checked
+    /// and cached as for `/download`.
+    pub(crate) async fn fetch(
+        &self,
+        sources: &Sources,
+        info: &KojiBuildInfo,
+        arch: &str,
+        filename: &str,
+    ) -> actix_web::Result<(u64, LocalBoxStream<'static, io::Result<web::Bytes>>)> {
+        let cache_path = self.cache_path(info, arch, filename);
+        if let Some(path) = cache_path.as_ref().filter(|p| p.exists()) {
+            let f = File::open(path)?;
+            return Ok((f.metadata()?.len(), read_file(f)));
+        }
+        let sha256 = if self.verify {
+            rpm_sha256(sources, filename).await?
+        } else {
+            None
+        };
+        let url = rpm_url(info, arch, filename);
+        let upstream = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| ErrorBadGateway(format!("Fetching {}: {}", url, e)))?;
+        if upstream.status().as_u16() != 200 {
+            return Err(ErrorBadGateway(format!(
+                "Fetching {}: kojipkgs answered {}",
+                url,
+                upstream.status()
+            )));
+        }
+        let len = upstream
+            .content_length()
+            .ok_or_else(|| ErrorBadGateway(format!("Fetching {}: no length", url)))?;
+        let body = upstream_body(upstream);
+        let body = match sha256 {
+            Some(sha256) => verified(body, url, sha256).boxed_local(),
+            None => body,
+        };
+        let writer = cache_path.map(|path| cache_writer(path, len));
+        Ok((len, tee(body, writer)))
+    }
+
+    /// The first `len` bytes of an RPM (all of it, if shorter) and its
+    /// whole size, without downloading the rest.
+    pub(crate) async fn head(
+        &self,
+        info: &KojiBuildInfo,
+        arch: &str,
+        filename: &str,
+        len: u64,
+    ) -> actix_web::Result<(web::Bytes, u64)> {
+        let cache_path = self.cache_path(info, arch, filename);
+        if let Some(path) = cache_path.filter(|p| p.exists()) {
+            return Ok(web::block(move || {
+                let f = File::open(&path)?;
+                let size = f.metadata()?.len();
+                let mut buf = Vec::new();
+                f.take(len).read_to_end(&mut buf)?;
+                Ok::<_, io::Error>((buf.into(), size))
+            })
+            .await??);
+        }
+        let url = rpm_url(info, arch, filename);
+        let upstream = self
+            .client
+            .get(&url)
+            .header("range", format!("bytes=0-{}", len.saturating_sub(1)))
+            .send()
+            .await
+            .map_err(|e| ErrorBadGateway(format!("Fetching {}: {}", url, e)))?;
+        let size = match upstream.status().as_u16() {
+            // `bytes 0-N/SIZE`
+            206 => upstream
+                .headers()
+                .get("content-range")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('/').next())
+                .and_then(|v| v.parse().ok()),
+            200 => upstream.content_length(),
+            404 => return Err(ErrorNotFound(format!("{} is not on kojipkgs", filename))),
+            s => {
+                return Err(ErrorBadGateway(format!(
+                    "Fetching {}: kojipkgs answered {}",
+                    url, s
+                )))
+            }
+        }
+        .ok_or_else(|| ErrorBadGateway(format!("Fetching {}: no length", url)))?;
+        // Servers ignoring the range send everything; stop reading early
+        let mut body = upstream_body(upstream);
+        let mut buf = Vec::new();
+        while (buf.len() as u64) < len {
+            match body.next().await {
+                Some(chunk) => buf.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        buf.truncate(len as usize);
+        Ok((buf.into(), size))
+    }
+}
+
+/// Read a file as a stream, on the blocking thread pool.
+fn read_file(f: File) -> LocalBoxStream<'static, io::Result<web::Bytes>> {
+    futures::stream::unfold(Some(f), |f| async move {
+        let mut f = f?;
+        let r = web::block(move || {
+            let mut buf = vec![0; READ_CHUNK];
+            let n = f.read(&mut buf)?;
+            buf.truncate(n);
+            Ok::<_, io::Error>((f, buf))
+        })
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+        match r.and_then(|r| r) {
+            Ok((_, buf)) if buf.is_empty() => None,
+            Ok((f, buf)) => Some((Ok(buf.into()), Some(f))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed_local()
+}
+
+fn upstream_body(upstream: reqwest::Response) -> LocalBoxStream<'static, io::Result<web::Bytes>> {
+    upstream
+        .bytes_stream()
+        .map(|c| c.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        .boxed_local()
+}
+
+/// Pass `body` through, copying it to the cache via `writer` if set.
+/// Applied after checking, so a corrupt file is never complete there.
+fn tee(
+    body: LocalBoxStream<'static, io::Result<web::Bytes>>,
+    writer: Option<Sender<web::Bytes>>,
+) -> LocalBoxStream<'static, io::Result<web::Bytes>> {
+    match writer {
+        Some(w) => body
+            .map(move |chunk| {
+                if let Ok(b) = chunk.as_ref() {
+                    let _ = w.send(b.clone());
+                }
+                chunk
+            })
+            .boxed_local(),
+        None => body,
+    }
+}
+
+/// The SHA-256 koji recorded for an RPM; a failure to find out is logged,
+/// and the download goes unchecked.
+pub(crate) async fn rpm_sha256(
+    sources: &Sources,
+    filename: &str,
+) -> actix_web::Result<Option<String>> {
+    let hub = sources.hub.read().unwrap().clone();
+    let _permit = sources.shedder.backend()?;
+    let name = filename.to_string();
+    match run_blocking(move || hub.rpm_sha256(&name)).await {
+        Ok(sha256) => Ok(sha256),
+        Err(e) => {
+            tracing::warn!(%filename, "Failed to get RPM checksum: {:#}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Pass `body` through, failing at its end if it doesn't match `sha256`.
+/// The last chunk is held back until then, so a client never receives all
+/// of a corrupt file.
+fn verified(
+    body: LocalBoxStream<'static, io::Result<web::Bytes>>,
+    url: String,
+    sha256: String,
+) -> impl Stream<Item = io::Result<web::Bytes>> {
+    let state = (body, Sha256::new(), None::<web::Bytes>, false);
+    futures::stream::unfold(state, move |(mut body, mut hasher, mut held, done)| {
+        let url = url.clone();
+        let sha256 = sha256.clone();
+        async move {
+            if done {
+                return None;
+            }
+            loop {
+                match body.next().await {
+                    Some(Ok(chunk)) => {
+                        hasher.update(&chunk);
+                        if let Some(prev) = held.replace(chunk) {
+                            return Some((Ok(prev), (body, hasher, held, false)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (body, hasher, held, true))),
+                    None => {
+                        let actual = hex::encode(hasher.finalize_reset());
+                        if actual != sha256 {
+                            tracing::error!(%url, expected = %sha256, %actual, "Checksum mismatch");
+                            let e = io::Error::new(io::ErrorKind::InvalidData, "Checksum mismatch");
+                            return Some((Err(e), (body, hasher, None, true)));
+                        }
+                        let last = held.take()?;
+                        return Some((Ok(last), (body, hasher, None, true)));
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Write a download to `path` as it streams, keeping it only if all
+/// `expected` bytes arrive.
+fn write_cached(path: &Path, expected: u64, rx: Receiver<web::Bytes>) -> Result<()> {
+    let dir = path.parent().expect("cache path has a parent");
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4().to_simple()));
+    let mut written = 0;
+    let r = (|| {
+        let mut f = File::create(&tmp)?;
+        for chunk in rx {
+            f.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        f.sync_all()
+    })();
+    if r.is_ok() && written == expected {
+        std::fs::rename(&tmp, path).with_context(|| format!("Renaming {}", tmp.display()))?;
+        tracing::debug!(path = %path.display(), "Cached download");
+    } else {
+        let _ = std::fs::remove_file(&tmp);
+        r.with_context(|| format!("Writing {}", tmp.display()))?;
+    }
+    Ok(())
+}
+
+/// Start writing a download to the cache in the background.
+fn cache_writer(path: PathBuf, expected: u64) -> Sender<web::Bytes> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        if let Err(e) = write_cached(&path, expected, rx) {
+            tracing::warn!("Failed to cache download: {:#}", e);
+        }
+    });
+    tx
+}
+
+#[get("/download/{buildid}/{arch}/{filename}")]
+async fn download(
+    req: HttpRequest,
+    downloader: web::Data<Downloader>,
+    sources: Sources,
+    path: web::Path<(String, String, String)>,
+) -> actix_web::Result<HttpResponse> {
+    if !downloader.enabled() {
+        return Err(ErrorNotFound("Downloads are disabled"));
+    }
+    let (buildid, arch, filename) = path.into_inner();
+    let info = sources.build(&buildid, false).await?;
+    deleted::check(&info)?;
+    // Only what koji lists, so this can't fetch arbitrary URLs
+    if !info
+        .rpms
+        .get(&arch)
+        .map_or(false, |r| r.contains(&filename))
+    {
+        return Err(ErrorNotFound(format!(
+            "No {}/{} in {}",
+            arch, filename, info.nvr
+        )));
+    }
+    serve(&req, &downloader, &sources, &info, &arch, &filename).await
+}
+
+/// Where kojipkgs has an RPM of `info`.
+fn rpm_url(info: &KojiBuildInfo, arch: &str, filename: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        info.kojipkgs_url_prefix,
+        koji::encode_path_segment(arch),
+        koji::encode_path_segment(filename)
+    )
+}
+
+/// Respond with an RPM koji lists in `info`, from the local copy or
+/// kojipkgs, honoring ranges.
+pub(crate) async fn serve(
+    req: &HttpRequest,
+    downloader: &Downloader,
+    sources: &Sources,
+    info: &KojiBuildInfo,
+    arch: &str,
+    filename: &str,
+) -> actix_web::Result<HttpResponse> {
+    let cache_path = downloader.cache_path(info, arch, filename);
+    if let Some(path) = cache_path.as_ref().filter(|p| p.exists()) {
+        let f = NamedFile::open_async(path).await?;
+        return Ok(f
+            .set_content_type(RPM_CONTENT_TYPE.parse().unwrap())
+            .into_response(req));
+    }
+
+    // Only whole files can be checked
+    let sha256 = if downloader.verify && !req.headers().contains_key("range") {
+        rpm_sha256(sources, filename).await?
+    } else {
+        None
+    };
+    let url = rpm_url(info, arch, filename);
+    let mut upstream = downloader.client.get(&url);
+    for name in REQUEST_HEADERS {
+        if let Some(v) = req.headers().get(*name) {
+            upstream = upstream.header(*name, v.as_bytes());
+        }
+    }
+    let upstream = upstream
+        .send()
+        .await
+        .map_err(|e| ErrorBadGateway(format!("Fetching {}: {}", url, e)))?;
+    let status = match upstream.status().as_u16() {
+        200 => StatusCode::OK,
+        206 => StatusCode::PARTIAL_CONTENT,
+        416 => StatusCode::RANGE_NOT_SATISFIABLE,
+        404 => return Err(ErrorNotFound(format!("{} is not on kojipkgs", filename))),
+        s => {
+            return Err(ErrorBadGateway(format!(
+                "Fetching {}: kojipkgs answered {}",
+                url, s
+            )))
+        }
+    };
+    let len = upstream.content_length();
+    // Only whole files are cached
+    let writer = match (cache_path, len) {
+        (Some(path), Some(len)) if status == StatusCode::OK => Some(cache_writer(path, len)),
+        _ => None,
+    };
+    let mut resp = HttpResponse::build(status);
+    for name in RESPONSE_HEADERS {
+        if let Some(v) = upstream.headers().get(*name) {
+            if let Ok(v) = HeaderValue::from_bytes(v.as_bytes()) {
+                resp.insert_header((HeaderName::from_static(*name), v));
+            }
+        }
+    }
+    let body = upstream_body(upstream);
+    let body = match sha256 {
+        Some(sha256) if status == StatusCode::OK => verified(body, url, sha256).boxed_local(),
+        _ => body,
+    };
+    let body = tee(body, writer);
+    if status != StatusCode::RANGE_NOT_SATISFIABLE {
+        resp.content_type(RPM_CONTENT_TYPE);
+    }
+    if let Some(len) = len {
+        resp.no_chunking(len);
+    }
+    Ok(resp.streaming(body))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(download);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunks(parts: &[&'static [u8]]) -> LocalBoxStream<'static, io::Result<web::Bytes>> {
+        let parts: Vec<_> = parts
+            .iter()
+            .map(|p| Ok(web::Bytes::from_static(p)))
+            .collect();
+        futures::stream::iter(parts).boxed_local()
+    }
+
+    #[test]
+    fn test_verified() {
+        // sha256("hello world")
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let body = verified(chunks(&[b"hello ", b"world"]), String::new(), sha256.into());
+        let out: Vec<_> = futures::executor::block_on(body.collect());
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|c| c.is_ok()));
+
+        // The last chunk is withheld on a mismatch
+        let body = verified(chunks(&[b"hello ", b"w0rld"]), String::new(), sha256.into());
+        let out: Vec<_> = futures::executor::block_on(body.collect());
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].as_ref().unwrap(), &b"hello "[..]);
+        assert_eq!(
+            out[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_write_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo-1-1/x86_64/foo-1-1.x86_64.rpm");
+        let (tx, rx) = channel();
+        tx.send(web::Bytes::from_static(b"hello ")).unwrap();
+        tx.send(web::Bytes::from_static(b"world")).unwrap();
+        drop(tx);
+        write_cached(&path, 11, rx).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+
+        // A truncated download isn't kept
+        let path = dir
+            .path()
+            .join("foo-1-1/x86_64/foo-debuginfo-1-1.x86_64.rpm");
+        let (tx, rx) = channel();
+        tx.send(web::Bytes::from_static(b"hello")).unwrap();
+        drop(tx);
+        write_cached(&path, 11, rx).unwrap();
+        assert!(!path.exists());
+        let left = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(left, 1);
+    }
+}
@@ -0,0 +1,43 @@
+//! JSON error responses.
+
+use actix_web::body::BoxBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+
+use crate::request_id::RequestId;
+
+/// The body of an error response.
+pub(crate) fn body(message: &str, request_id: Option<&RequestId>) -> serde_json::Value {
+    serde_json::json!({
+        "error": message,
+        "request-id": request_id.map(|id| &id.0),
+    })
+}
+
+/// Replace the plain text body of an error response (e.g. from actix
+/// extractors, or a handler returning `Err`) with a JSON one.
+pub(crate) fn to_json(res: ServiceResponse, request_id: &RequestId) -> ServiceResponse {
+    let status = res.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return res;
+    }
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json"))
+        .unwrap_or(false);
+    if is_json {
+        return res;
+    }
+    let message = match res.response().error() {
+        Some(e) => e.to_string(),
+        None => status.canonical_reason().unwrap_or("Error").to_string(),
+    };
+    let body = body(&message, Some(request_id)).to_string();
+    res.map_body(|head, _| {
+        head.headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        BoxBody::new(body)
+    })
+}
@@ -0,0 +1,183 @@
+//! `GET /buildinfo/{id}/gating`: whether a build passed gating, as
+//! Greenwave decides from ResultsDB's test results.
+
+use std::time::Duration;
+
+use actix_web::error::{ErrorBadGateway, ErrorBadRequest, ErrorNotFound};
+use actix_web::{get, web, HttpResponse};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::koji;
+use crate::watch::Sources;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct GatingConfig {
+    /// Greenwave's URL, e.g. `https://greenwave.fedoraproject.org`;
+    /// disabled if unset.
+    pub(crate) url: Option<String>,
+    /// The decision asked about, unless the request names another.
+    pub(crate) decision_context: String,
+    /// E.g. `fedora-34`; if unset, taken from a `.fcNN` release.
+    pub(crate) product_version: Option<String>,
+    /// Seconds to wait for Greenwave.
+    pub(crate) timeout: u64,
+}
+
+impl Default for GatingConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            decision_context: "bodhi_update_push_stable".to_string(),
+            product_version: None,
+            timeout: 30,
+        }
+    }
+}
+
+/// Asks Greenwave for decisions.
+pub(crate) struct Gating {
+    config: GatingConfig,
+    client: reqwest::Client,
+}
+
+impl Gating {
+    pub(crate) fn new(config: GatingConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()?;
+        Ok(Self { config, client })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct GatingQuery {
+    decision_context: Option<String>,
+    product_version: Option<String>,
+}
+
+/// What Greenwave answers; requirements are passed on as they are.
+#[derive(Deserialize)]
+struct Decision {
+    policies_satisfied: bool,
+    summary: String,
+    #[serde(default)]
+    satisfied_requirements: Vec<Value>,
+    #[serde(default)]
+    unsatisfied_requirements: Vec<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct GatingResponse {
+    nvr: String,
+    decision_context: String,
+    product_version: String,
+    passed: bool,
+    summary: String,
+    satisfied_requirements: Vec<Value>,
+    unsatisfied_requirements: Vec<Value>,
+}
+
+/// The product version of a Fedora build, from its release's dist tag.
+fn product_version(nvr: &str) -> Option<String> {
+    let (_, _, release) = koji::split_nvr(nvr).ok()?;
+    let release = release.split(',').next().unwrap_or_default();
+    release.split('.').rev().find_map(|part| {
+        let n = part.strip_prefix("fc")?;
+        (!n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())).then(|| format!("fedora-{}", n))
+    })
+}
+
+#[get("/buildinfo/{id}/gating")]
+async fn build_gating(
+    gating: web::Data<Gating>,
+    sources: Sources,
+    path: web::Path<(String,)>,
+    query: web::Query<GatingQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let url = match gating.config.url.as_deref() {
+        Some(url) => url.trim_end_matches('/'),
+        None => return Err(ErrorNotFound("Gating is disabled")),
+    };
+    let info = sources.build(&path.0, false).await?;
+    let query = query.into_inner();
+    let decision_context = query
+        .decision_context
+        .unwrap_or_else(|| gating.config.decision_context.clone());
+    let product_version = query
+        .product_version
+        .or_else(|| gating.config.product_version.clone())
+        .or_else(|| product_version(&info.nvr))
+        .ok_or_else(|| {
+            ErrorBadRequest(format!(
+                "No product version known for {}; pass ?product-version=",
+                info.nvr
+            ))
+        })?;
+    let res = gating
+        .client
+        .post(format!("{}/api/v1.0/decision", url))
+        .json(&serde_json::json!({
+            "decision_context": decision_context,
+            "product_version": product_version,
+            "subject": [{"type": "koji_build", "item": info.nvr}],
+        }))
+        .send()
+        .await
+        .map_err(|e| ErrorBadGateway(format!("Querying Greenwave: {}", e)))?;
+    let status = res.status();
+    if !status.is_success() {
+        let message = res
+            .json::<Value>()
+            .await
+            .ok()
+            .and_then(|v| v["message"].as_str().map(String::from))
+            .unwrap_or_else(|| status.to_string());
+        // Notably "Found no applicable policies"
+        return Err(if status == reqwest::StatusCode::NOT_FOUND {
+            ErrorNotFound(message)
+        } else {
+            ErrorBadGateway(format!("Greenwave: {}", message))
+        });
+    }
+    let decision: Decision = res
+        .json()
+        .await
+        .map_err(|e| ErrorBadGateway(format!("Parsing Greenwave's decision: {}", e)))?;
+    Ok(HttpResponse::Ok().json(GatingResponse {
+        nvr: info.nvr,
+        decision_context,
+        product_version,
+        passed: decision.policies_satisfied,
+        summary: decision.summary,
+        satisfied_requirements: decision.satisfied_requirements,
+        unsatisfied_requirements: decision.unsatisfied_requirements,
+    }))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(build_gating);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_product_version() {
+        assert_eq!(
+            product_version("rpm-ostree-2020.10-1.fc34").as_deref(),
+            Some("fedora-34")
+        );
+        assert_eq!(
+            product_version("kernel-5.14.0-1.fc35.1").as_deref(),
+            Some("fedora-35")
+        );
+        assert_eq!(product_version("bash-5.1.8-9.el9"), None);
+        assert_eq!(product_version("foo-1-1.fcx"), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! The JSON API's operations over gRPC, on a port of their own; see
+//! `proto/koji_sane_json_api.proto`.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use actix_web::http::StatusCode;
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+use crate::koji::KojiBuildInfo;
+use crate::watch::Sources;
+
+mod proto {
+    tonic::include_proto!("koji_sane_json_api.v1");
+}
+
+use proto::koji_sane_json_api_server::{KojiSaneJsonApi, KojiSaneJsonApiServer};
+
+impl From<KojiBuildInfo> for proto::BuildInfo {
+    fn from(info: KojiBuildInfo) -> Self {
+        Self {
+            nvr: info.nvr,
+            id: info.id,
+            state: info.state,
+            kojipkgs_url_prefix: info.kojipkgs_url_prefix,
+            rpms: info
+                .rpms
+                .into_iter()
+                .map(|(arch, rpms)| (arch, proto::RpmList { rpms }))
+                .collect(),
+            draft: info.draft,
+            nevras: info
+                .nevras
+                .into_iter()
+                .map(|(filename, n)| {
+                    let n = proto::Nevra {
+                        name: n.name,
+                        epoch: n.epoch,
+                        version: n.version,
+                        release: n.release,
+                        arch: n.arch,
+                    };
+                    (filename, n)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Map an error from the HTTP side onto the closest gRPC status.
+fn to_status(e: actix_web::Error) -> Status {
+    let msg = e.to_string();
+    match e.as_response_error().status_code() {
+        StatusCode::NOT_FOUND => Status::not_found(msg),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(msg),
+        s if s.is_client_error() => Status::invalid_argument(msg),
+        _ => Status::internal(msg),
+    }
+}
+
+/// Shares the hub, cache and backend limits with the HTTP server.
+#[derive(Clone)]
+struct Service(Sources);
+
+impl Service {
+    async fn get(&self, buildid: &str, refresh: bool) -> Result<proto::BuildInfo, Status> {
+        let info = self.0.build(buildid, refresh).await.map_err(to_status)?;
+        Ok(info.into())
+    }
+}
+
+#[tonic::async_trait]
+impl KojiSaneJsonApi for Service {
+    async fn get_build(
+        &self,
+        req: Request<proto::GetBuildRequest>,
+    ) -> Result<Response<proto::BuildInfo>, Status> {
+        let req = req.into_inner();
+        Ok(Response::new(self.get(&req.buildid, req.refresh).await?))
+    }
+
+    type GetBuildsStream = Pin<Box<dyn Stream<Item = Result<proto::BuildInfo, Status>> + Send>>;
+
+    async fn get_builds(
+        &self,
+        req: Request<proto::GetBuildsRequest>,
+    ) -> Result<Response<Self::GetBuildsStream>, Status> {
+        let req = req.into_inner();
+        let svc = self.clone();
+        let refresh = req.refresh;
+        let builds = futures::stream::iter(req.buildids).then(move |buildid| {
+            let svc = svc.clone();
+            async move { svc.get(&buildid, refresh).await }
+        });
+        Ok(Response::new(Box::pin(builds)))
+    }
+}
+
+/// Listen on `bind` and serve gRPC in the background.
+pub(crate) fn spawn(bind: SocketAddr, sources: Sources) -> Result<()> {
+    // Bind now, so a bad address fails startup rather than being logged
+    let incoming = TcpIncoming::new(bind, true, None).map_err(|e| anyhow::anyhow!(e))?;
+    let svc = Service(sources);
+    tracing::info!(%bind, "Serving gRPC");
+    actix_web::rt::spawn(async move {
+        let server = tonic::transport::Server::builder()
+            .add_service(KojiSaneJsonApiServer::new(svc))
+            .serve_with_incoming(incoming);
+        if let Err(e) = server.await {
+            tracing::error!("gRPC server failed: {}", e);
+        }
+    });
+    Ok(())
+}
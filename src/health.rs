@@ -0,0 +1,290 @@
+//! Health checks covering the service's dependencies, and the liveness and
+//! readiness probes used by e.g. Kubernetes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::{get, web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::cache::Cache;
+use crate::koji::{Backend, Hub};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct HealthConfig {
+    /// Seconds the hub may take to answer before it's considered down.
+    pub(crate) backend_timeout: u64,
+    /// Seconds between the background hub checks behind `/readyz`.
+    pub(crate) check_interval: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            backend_timeout: 5,
+            check_interval: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Ok,
+    Fail,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct HubCheck {
+    status: Status,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CacheCheck {
+    status: Status,
+    entries: usize,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    hub: HubCheck,
+    cache: CacheCheck,
+}
+
+#[derive(Serialize)]
+struct DeepHealth {
+    status: Status,
+    checks: Checks,
+}
+
+/// Ask the hub for its API version, within the configured time limit.
+async fn check_hub(hub: Hub, timeout: Duration) -> HubCheck {
+    let start = Instant::now();
+    let res = actix_web::rt::time::timeout(
+        timeout,
+        crate::server::run_blocking(move || hub.api_version()),
+    )
+    .await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let (api_version, error) = match res {
+        Ok(Ok(v)) => (Some(v), None),
+        Ok(Err(e)) => (None, Some(e.to_string())),
+        Err(_) => (
+            None,
+            Some(format!("No answer within {}s", timeout.as_secs())),
+        ),
+    };
+    HubCheck {
+        status: if error.is_none() {
+            Status::Ok
+        } else {
+            Status::Fail
+        },
+        latency_ms,
+        api_version,
+        error,
+    }
+}
+
+/// Verify the hub is reachable and report on each dependency; 503 if any
+/// has failed.
+#[get("/health/deep")]
+async fn deep(
+    config: web::Data<HealthConfig>,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+) -> HttpResponse {
+    let hub = hub.read().unwrap().clone();
+    let hub = check_hub(hub, Duration::from_secs(config.backend_timeout)).await;
+    let cache = CacheCheck {
+        status: Status::Ok,
+        entries: cache.len(),
+        bytes: cache.bytes(),
+    };
+    let status = if hub.status == Status::Ok && cache.status == Status::Ok {
+        Status::Ok
+    } else {
+        Status::Fail
+    };
+    let body = DeepHealth {
+        status,
+        checks: Checks { hub, cache },
+    };
+    match status {
+        Status::Ok => HttpResponse::Ok().json(body),
+        Status::Fail => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
+
+/// What `/readyz` reports on, kept up to date in the background so probes
+/// answer immediately rather than waiting on koji.
+pub(crate) struct Readiness {
+    hub: Mutex<Option<HubCheck>>,
+    /// When the hub check in progress began, if it's taking a while.
+    checking_since: Mutex<Option<Instant>>,
+    cache_warm: AtomicBool,
+    /// When we were asked to shut down.
+    shutdown: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Readiness {
+    /// With prefetching configured, the cache isn't ready until the first
+    /// pass completes.
+    pub(crate) fn new(prefetching: bool) -> Self {
+        Self {
+            hub: Mutex::new(None),
+            checking_since: Mutex::new(None),
+            cache_warm: AtomicBool::new(!prefetching),
+            shutdown: Default::default(),
+        }
+    }
+
+    pub(crate) fn hub_api_version(&self) -> Option<u32> {
+        self.hub
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|c| c.api_version)
+    }
+
+    pub(crate) fn set_cache_warm(&self) {
+        self.cache_warm.store(true, Ordering::Relaxed);
+    }
+
+    /// Note when a termination signal arrives.  actix has its own
+    /// handlers, which still run and stop the server.
+    pub(crate) fn watch_shutdown(&self) -> std::io::Result<()> {
+        let mut signals = Signals::new(&[SIGTERM, SIGINT])?;
+        let shutdown = self.shutdown.clone();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                shutdown.lock().unwrap().get_or_insert_with(Instant::now);
+            }
+        });
+        Ok(())
+    }
+
+    pub(crate) fn shutdown_started(&self) -> Option<Instant> {
+        *self.shutdown.lock().unwrap()
+    }
+
+    /// Reasons we aren't ready, if any.
+    fn problems(&self, timeout: Duration) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.hub.lock().unwrap().as_ref() {
+            None => problems.push("hub: not yet checked".to_string()),
+            Some(c) if c.status == Status::Fail => problems.push(format!(
+                "hub: {}",
+                c.error.as_deref().unwrap_or("unreachable")
+            )),
+            Some(_) => {}
+        }
+        if let Some(since) = *self.checking_since.lock().unwrap() {
+            if since.elapsed() > timeout {
+                problems.push(format!("hub: no answer within {}s", timeout.as_secs()));
+            }
+        }
+        if !self.cache_warm.load(Ordering::Relaxed) {
+            problems.push("cache: prefetch in progress".to_string());
+        }
+        if self.shutdown_started().is_some() {
+            problems.push("draining".to_string());
+        }
+        problems
+    }
+}
+
+/// Check the hub every `check_interval` seconds for `/readyz`.
+pub(crate) fn spawn_monitor(
+    config: &HealthConfig,
+    hub: web::Data<RwLock<Hub>>,
+    readiness: web::Data<Readiness>,
+) {
+    let interval = Duration::from_secs(config.check_interval.max(1));
+    let timeout = Duration::from_secs(config.backend_timeout);
+    actix_web::rt::spawn(async move {
+        let mut ticks = actix_web::rt::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            *readiness.checking_since.lock().unwrap() = Some(Instant::now());
+            let h = hub.read().unwrap().clone();
+            let check = check_hub(h, timeout).await;
+            if let Some(e) = check.error.as_ref() {
+                tracing::warn!("Hub check failed: {}", e);
+            }
+            *readiness.hub.lock().unwrap() = Some(check);
+            *readiness.checking_since.lock().unwrap() = None;
+        }
+    });
+}
+
+/// The original liveness check, like `/livez`.
+#[get("/health")]
+async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Liveness: the process is up and serving requests.
+#[get("/livez")]
+async fn livez() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Readiness: the hub answered its last check, the cache is warm and we
+/// aren't shutting down.  Otherwise 503, listing what's wrong.
+#[get("/readyz")]
+async fn readyz(config: web::Data<HealthConfig>, readiness: web::Data<Readiness>) -> HttpResponse {
+    let problems = readiness.problems(Duration::from_secs(config.backend_timeout));
+    if problems.is_empty() {
+        HttpResponse::Ok().json(serde_json::json!({ "status": Status::Ok }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": Status::Fail,
+            "problems": problems,
+        }))
+    }
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(liveness)
+        .service(deep)
+        .service(livez)
+        .service(readyz);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let timeout = Duration::from_secs(5);
+        let r = Readiness::new(true);
+        assert_eq!(r.problems(timeout).len(), 2);
+        *r.hub.lock().unwrap() = Some(HubCheck {
+            status: Status::Ok,
+            latency_ms: 100,
+            api_version: Some(1),
+            error: None,
+        });
+        r.set_cache_warm();
+        assert!(r.problems(timeout).is_empty());
+        *r.checking_since.lock().unwrap() = Some(Instant::now() - Duration::from_secs(6));
+        assert_eq!(r.problems(timeout), vec!["hub: no answer within 5s"]);
+        *r.checking_since.lock().unwrap() = None;
+        *r.shutdown.lock().unwrap() = Some(Instant::now());
+        assert_eq!(r.problems(timeout), vec!["draining"]);
+    }
+}
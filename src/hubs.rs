@@ -0,0 +1,190 @@
+//! Hubs besides the main one, from `[hubs.NAME]`.  Each is served under
+//! `/hubs/{name}` with its own cache, since build ids and NVRs only mean
+//! something within one hub; `GET /hubs` lists them all.  Requests may
+//! instead name a hub in `X-Koji-Hub`, and are routed as if made under
+//! `/hubs/{name}`.
+
+use std::sync::RwLock;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderMap, HeaderValue, VARY};
+use actix_web::http::Uri;
+use actix_web::{get, web, HttpResponse};
+use serde_derive::Serialize;
+
+use crate::cache::{Cache, NvrMap};
+use crate::config::Config;
+use crate::error;
+use crate::koji::Hub;
+use crate::request_id::RequestId;
+
+/// What the main hub is called in listings.
+pub(crate) const DEFAULT_HUB: &str = "default";
+
+/// Names the hub a request is about, instead of the path.
+const HEADER: &str = "x-koji-hub";
+
+/// Paths served for each hub, relative to the base path.
+const HUB_ROUTES: &[&str] = &["/buildinfo/", "/download/", "/call/", "/tag/", "/compare/"];
+
+/// Where a request for `path` goes for `hub`, if `path` is one of a hub's
+/// routes.
+fn rewrite(prefix: &str, path: &str, hub: &str) -> Option<String> {
+    let rest = path.strip_prefix(prefix)?;
+    if !HUB_ROUTES.iter().any(|r| rest.starts_with(r)) {
+        return None;
+    }
+    Some(format!("{}/hubs/{}{}", prefix, hub, rest))
+}
+
+/// A further hub and its own state.
+struct ExtraHub {
+    name: String,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+}
+
+pub(crate) struct Hubs {
+    extra: Vec<ExtraHub>,
+}
+
+impl Hubs {
+    pub(crate) fn new(config: &Config) -> Self {
+        let extra = config
+            .hubs
+            .iter()
+            .map(|(name, hub)| ExtraHub {
+                name: name.clone(),
+                hub: web::Data::new(RwLock::new(hub.clone())),
+                cache: web::Data::new(Cache::new(config.cache.ttl, config.cache.max_bytes)),
+                nvrs: web::Data::new(NvrMap::new(config.cache.mapping_capacity)),
+            })
+            .collect();
+        Self { extra }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(DEFAULT_HUB).chain(self.extra.iter().map(|h| h.name.as_str()))
+    }
+
+    /// Route a request naming one of the extra hubs in `X-Koji-Hub` to that
+    /// hub, as if it were made under `/hubs/{name}`; `prefix` is the base
+    /// path routes are mounted under.  Errors name an unknown hub.
+    pub(crate) fn select(&self, prefix: &str, req: &mut ServiceRequest) -> Result<(), String> {
+        let name = match req.headers().get(HEADER) {
+            Some(v) => v.to_str().unwrap_or_default().trim().to_string(),
+            None => return Ok(()),
+        };
+        if name == DEFAULT_HUB {
+            return Ok(());
+        }
+        if !self.extra.iter().any(|h| h.name == name) {
+            return Err(format!(
+                "Unknown hub {:?}; expected one of {}",
+                name,
+                self.names().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        let uri = req.uri();
+        let path = match rewrite(prefix, uri.path(), &name) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let path = match uri.query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path.parse().map_err(|e| format!("{}", e))?);
+        let uri = Uri::from_parts(parts).map_err(|e| format!("{}", e))?;
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+        Ok(())
+    }
+
+    /// Whether responses to `path` depend on `X-Koji-Hub`, so caches must
+    /// keep them apart.
+    pub(crate) fn varies(&self, prefix: &str, path: &str) -> bool {
+        !self.extra.is_empty() && rewrite(prefix, path, DEFAULT_HUB).is_some()
+    }
+
+    /// Mount `routes` under `/hubs/{name}` for each extra hub, with that
+    /// hub's state in place of the main one's.
+    pub(crate) fn mount(&self, cfg: &mut web::ServiceConfig, routes: fn(&mut web::ServiceConfig)) {
+        for h in &self.extra {
+            cfg.service(
+                web::scope(&format!("/hubs/{}", h.name))
+                    .app_data(h.hub.clone())
+                    .app_data(h.cache.clone())
+                    .app_data(h.nvrs.clone())
+                    .configure(routes),
+            );
+        }
+    }
+}
+
+/// Add `Vary: X-Koji-Hub` to a response.
+pub(crate) fn set_vary(headers: &mut HeaderMap) {
+    headers.append(VARY, HeaderValue::from_static(HEADER));
+}
+
+/// The response for a request naming an unknown hub.
+pub(crate) fn unknown_hub(message: &str, id: &RequestId) -> HttpResponse {
+    HttpResponse::BadRequest().json(error::body(message, Some(id)))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct HubInfo {
+    name: String,
+    preset: Option<String>,
+    server: Option<String>,
+    profile: Option<String>,
+    topurl: String,
+    tags: Vec<String>,
+}
+
+impl HubInfo {
+    fn new(name: &str, hub: &RwLock<Hub>) -> Self {
+        let hub = hub.read().unwrap().clone();
+        Self {
+            name: name.to_string(),
+            preset: hub.preset,
+            server: hub.server,
+            profile: hub.profile,
+            topurl: hub.topurl,
+            tags: hub.tags,
+        }
+    }
+}
+
+#[get("/hubs")]
+async fn list(hub: web::Data<RwLock<Hub>>, hubs: web::Data<Hubs>) -> HttpResponse {
+    let mut r = vec![HubInfo::new(DEFAULT_HUB, &hub)];
+    r.extend(hubs.extra.iter().map(|h| HubInfo::new(&h.name, &h.hub)));
+    HttpResponse::Ok().json(r)
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(
+            rewrite("", "/buildinfo/foo-1-1", "cbs").as_deref(),
+            Some("/hubs/cbs/buildinfo/foo-1-1")
+        );
+        assert_eq!(
+            rewrite("/koji", "/koji/download/foo-1-1/src/foo-1-1.src.rpm", "cbs").as_deref(),
+            Some("/koji/hubs/cbs/download/foo-1-1/src/foo-1-1.src.rpm")
+        );
+        assert_eq!(rewrite("", "/health", "cbs"), None);
+        assert_eq!(rewrite("", "/hubs/cbs/buildinfo/foo-1-1", "cbs"), None);
+    }
+}
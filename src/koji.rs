@@ -1,63 +1,564 @@
-use std::collections::BTreeMap;
-use std::io::Write as IoWrite;
-use std::path::Path;
-use std::process::Command;
-
-use anyhow::{bail, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
+//! Resolving koji builds.  The types and [`Backend`] trait are always
+//! available; with the `cli-backend` feature, [`Hub`] implements it by
+//! running the koji CLI, and otherwise fails every query.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
 use serde_derive::{Deserialize, Serialize};
 
-const KOJIPKGS_URL: &str = "https://kojipkgs.fedoraproject.org/packages";
+#[cfg(feature = "cli-backend")]
+mod cli;
+#[cfg(feature = "fuzzing")]
+pub use cli::fuzz_buildinfo;
+#[cfg(feature = "cli-backend")]
+pub use cli::{drain, set_call_hook, set_call_timeout, CallHook};
+#[cfg(not(feature = "cli-backend"))]
+mod none;
+#[cfg(not(feature = "cli-backend"))]
+pub use none::{drain, set_call_hook, set_call_timeout, CallHook};
+
+pub const DEFAULT_TOPURL: &str = "https://kojipkgs.fedoraproject.org";
+
+/// A koji instance to query.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Hub {
+    /// A name from [`PRESETS`], filling in the server, topurl and tags
+    /// unless they're set explicitly.
+    pub preset: Option<String>,
+    /// Koji client configuration profile, passed as `koji --profile`.
+    pub profile: Option<String>,
+    /// XML-RPC URL passed as `koji --server`; the profile's hub is used if unset.
+    pub server: Option<String>,
+    /// Base URL for downloading build artifacts.
+    pub topurl: String,
+    /// PEM CA bundle to trust for the hub and topurl, for deployments
+    /// with an internal CA such as Brew.
+    pub ca_bundle: Option<PathBuf>,
+    /// Authenticate even read-only calls this way, e.g. `kerberos` or
+    /// `ssl`, for hubs which don't allow anonymous access.
+    pub authtype: Option<String>,
+    /// Kerberos principal and keytab to log in with; the ticket cache is
+    /// used if unset.
+    pub principal: Option<String>,
+    pub keytab: Option<PathBuf>,
+    /// Tags commonly worth following on this hub, for clients to discover.
+    pub tags: Vec<String>,
+}
+
+/// A well-known koji deployment.
+pub struct Preset {
+    pub name: &'static str,
+    pub server: &'static str,
+    pub topurl: &'static str,
+    pub tags: &'static [&'static str],
+}
+
+/// Hubs which can be configured by name alone.
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "fedora",
+        server: "https://koji.fedoraproject.org/kojihub",
+        topurl: DEFAULT_TOPURL,
+        tags: &["f44-updates", "f43-updates"],
+    },
+    Preset {
+        name: "epel",
+        server: "https://koji.fedoraproject.org/kojihub",
+        topurl: DEFAULT_TOPURL,
+        tags: &["epel9", "epel8"],
+    },
+    Preset {
+        name: "centos-stream",
+        server: "https://kojihub.stream.centos.org/kojihub",
+        topurl: "https://kojihub.stream.centos.org/kojifiles",
+        tags: &["c10s-gate", "c9s-gate"],
+    },
+    Preset {
+        name: "cbs",
+        server: "https://cbs.centos.org/kojihub",
+        topurl: "https://cbs.centos.org/kojifiles",
+        tags: &[],
+    },
+];
 
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            preset: None,
+            profile: None,
+            server: None,
+            topurl: DEFAULT_TOPURL.to_string(),
+            ca_bundle: None,
+            authtype: None,
+            principal: None,
+            keytab: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Hub {
+    /// Fill in what isn't set explicitly from the hub's preset, if any.
+    pub fn apply_preset(&mut self) -> Result<()> {
+        let name = match self.preset.as_deref() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let preset = PRESETS.iter().find(|p| p.name == name).ok_or_else(|| {
+            let names: Vec<_> = PRESETS.iter().map(|p| p.name).collect();
+            anyhow!(
+                "Unknown hub preset {}; expected one of {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+        if self.server.is_none() && self.profile.is_none() {
+            self.server = Some(preset.server.to_string());
+        }
+        if self.topurl == DEFAULT_TOPURL {
+            self.topurl = preset.topurl.to_string();
+        }
+        if self.tags.is_empty() {
+            self.tags = preset.tags.iter().map(|t| t.to_string()).collect();
+        }
+        Ok(())
+    }
+}
+
+/// A build, as served by `/buildinfo`.
 #[derive(Default, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct KojiBuildInfo {
-    nvr: String,
-    id: u64,
-    kojipkgs_url_prefix: String,
-    rpms: BTreeMap<String, Vec<String>>,
+pub struct KojiBuildInfo {
+    pub nvr: String,
+    pub id: u64,
+    /// E.g. `COMPLETE` or `BUILDING`.
+    pub state: String,
+    /// URL of the build's directory, under which RPMs are at `{arch}/{name}`.
+    pub kojipkgs_url_prefix: String,
+    /// RPM file names by architecture.
+    pub rpms: BTreeMap<String, Vec<String>>,
+    /// Each RPM's NEVRA by file name, which needn't match the build's NVR.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub nevras: BTreeMap<String, RpmNevra>,
+    /// A draft build, which may yet be promoted under its plain NVR.
+    #[serde(default)]
+    pub draft: bool,
+    /// When the build started, in UTC ISO 8601 and seconds since the
+    /// epoch; see [`TimeFormat`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_ts: Option<i64>,
+    /// When the build finished; unset while it's building.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_ts: Option<i64>,
+    /// For `FAILED` builds, which tasks failed and where their logs are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+    /// For builds imported by a content generator, which one and what it
+    /// produced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_generator: Option<ContentGenerator>,
+    /// For maven builds, their coordinates and artifacts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maven: Option<Maven>,
+    /// For Windows builds, their platform and archives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub win: Option<WinBuild>,
+}
+
+/// An RPM's own name, epoch, version, release and arch, as the hub records
+/// them.  Subpackages may differ from their build, e.g. with an epoch of
+/// their own or a version bumped for the binary package alone.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RpmNevra {
+    pub name: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+}
+
+impl RpmNevra {
+    /// The file name koji stores the RPM under.
+    pub fn filename(&self) -> String {
+        format!(
+            "{}-{}-{}.{}.rpm",
+            self.name, self.version, self.release, self.arch
+        )
+    }
+}
+
+/// Why a build failed, as far as its tasks tell.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Failure {
+    /// The build's top-level `build` task.
+    pub task_id: u64,
+    /// Its subtasks which failed, usually `buildArch` ones.
+    pub tasks: Vec<FailedTask>,
+}
+
+/// A failed koji task.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailedTask {
+    pub id: u64,
+    /// E.g. `buildArch` or `buildSRPMFromSCM`.
+    pub method: String,
+    pub arch: String,
+    /// URLs of the task's logs, e.g. `build.log` and `root.log`.
+    pub logs: Vec<String>,
+    /// When the task was created, started and finished, as for builds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_ts: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_ts: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_ts: Option<i64>,
+}
+
+/// How times are given: koji's are recorded both as UTC ISO 8601 strings
+/// (`*-time`) and in whole seconds since the epoch (`*-ts`), and either
+/// may be dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimeFormat {
+    Both,
+    Iso8601,
+    Epoch,
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        TimeFormat::Both
+    }
+}
+
+impl std::str::FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "both" => Ok(TimeFormat::Both),
+            "iso8601" => Ok(TimeFormat::Iso8601),
+            "epoch" => Ok(TimeFormat::Epoch),
+            _ => bail!("Unknown time format {}; expected both, iso8601 or epoch", s),
+        }
+    }
+}
+
+impl TimeFormat {
+    /// Drop whichever of a time's forms isn't wanted.
+    fn apply(self, time: &mut Option<String>, ts: &mut Option<i64>) {
+        match self {
+            TimeFormat::Both => {}
+            TimeFormat::Iso8601 => *ts = None,
+            TimeFormat::Epoch => *time = None,
+        }
+    }
+}
+
+/// A build imported by a content generator such as osbuild or MBS, rather
+/// than built from a koji task.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContentGenerator {
+    /// E.g. `osbuild` or `module-build-service`.
+    pub name: String,
+    /// What it recorded about the build, as the hub's `extra`.
+    pub metadata: serde_json::Value,
+    /// Its outputs other than RPMs, e.g. images or module metadata.
+    pub outputs: Vec<CgOutput>,
+}
+
+/// A non-RPM file of a build; koji calls these archives.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CgOutput {
+    pub filename: String,
+    /// The archive type, e.g. `qcow2` or `yaml`.
+    #[serde(rename = "type")]
+    pub archive_type: String,
+    /// The build type it belongs to, e.g. `image` or `module`.
+    pub btype: String,
+    pub size: u64,
+    pub checksum: Option<String>,
+    /// E.g. `sha256`.
+    pub checksum_type: Option<String>,
+    pub url: String,
+}
+
+/// A maven build's group/artifact/version coordinates and the artifacts
+/// it produced.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Maven {
+    pub group_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    pub artifacts: Vec<MavenArtifact>,
 }
 
-// This likely isn't right, need to use something more like hy_split_nevra() maybe or reimplement in Rust
-fn split_nvr(pkg: &str) -> Result<(&str, &str, &str)> {
-    let idx = pkg
-        .rfind('-')
-        .ok_or_else(|| anyhow::anyhow!("Invalid buildid, missing a '-'"))?;
-    let (pkgver, rest) = pkg.split_at(idx);
-    let rest = rest.strip_prefix("-").expect("-");
-    let idx = pkgver
-        .rfind('-')
-        .ok_or_else(|| anyhow::anyhow!("Invalid buildid, missing pkgver '-'"))?;
-    let (pkgname, version) = pkgver.split_at(idx);
-    let version = version.strip_prefix("-").expect("-");
-    if pkgname.is_empty() {
-        anyhow::bail!("Invalid buildid with empty name");
+/// A jar, pom or other file of a maven build, with coordinates of its own
+/// as builds may produce several artifacts.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MavenArtifact {
+    pub filename: String,
+    pub group_id: String,
+    pub artifact_id: String,
+    pub version: String,
+    /// E.g. `jar` or `pom`.
+    #[serde(rename = "type")]
+    pub archive_type: String,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub checksum_type: Option<String>,
+    pub url: String,
+}
+
+/// A Windows build, from koji's `winbuild` task.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WinBuild {
+    /// The VM platform it was built on, e.g. `w2k8r2 x64`.
+    pub platform: String,
+    pub archives: Vec<WinArchive>,
+}
+
+/// A zip, msi or other file of a Windows build.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WinArchive {
+    pub filename: String,
+    /// Its directory within the build's outputs; may be empty.
+    pub relpath: String,
+    /// E.g. `zip` or `msi`.
+    #[serde(rename = "type")]
+    pub archive_type: String,
+    /// Platforms it is for, e.g. `w2k8r2`.
+    pub platforms: Vec<String>,
+    /// Build flags it was made with, e.g. `debug`.
+    pub flags: Vec<String>,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub checksum_type: Option<String>,
+    pub url: String,
+}
+
+/// A package's name, epoch, version, release and architecture, as split
+/// from `N-V-R`, `N-E:V-R` or an RPM's `N-[E:]V-R.A`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nevra<'a> {
+    pub name: &'a str,
+    pub epoch: Option<u32>,
+    pub version: &'a str,
+    pub release: &'a str,
+    pub arch: Option<&'a str>,
+}
+
+/// What koji appends to a draft build's release, before its build id.
+const DRAFT_SUFFIX: &str = ",draft_";
+
+/// The build id in a draft build's release, e.g. `1.fc40,draft_2401133`.
+pub fn draft_id(release: &str) -> Option<u64> {
+    let (_, id) = release.rsplit_once(DRAFT_SUFFIX)?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+/// Whether `s` is a valid version or release: rpm allows neither `-` nor
+/// `:`, and we don't allow punctuation beyond `._+~^` in them either.
+/// Letters and digits needn't be ASCII.
+fn is_version(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || "._+~^".contains(c))
+}
+
+impl<'a> Nevra<'a> {
+    /// Split `N-V-R` or `N-E:V-R`.  Only the last two `-` separate fields,
+    /// as names may contain them but versions and releases may not.
+    pub fn parse_nevr(s: &'a str) -> Result<Self> {
+        let mut parts = s.rsplitn(3, '-');
+        let release = parts.next().unwrap_or_default();
+        let (version, name) = match (parts.next(), parts.next()) {
+            (Some(v), Some(n)) => (v, n),
+            _ => bail!("Invalid buildid {}, expected NAME-VERSION-RELEASE", s),
+        };
+        let (epoch, version) = match version.split_once(':') {
+            Some((e, v)) => {
+                let e = e
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid epoch {:?} in {}", e, s))?;
+                (Some(e), v)
+            }
+            None => (None, version),
+        };
+        if name.is_empty() {
+            bail!("Invalid buildid {} with empty name", s);
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|&c| !(c.is_alphanumeric() || "-._+".contains(c)))
+        {
+            bail!("Invalid character {:?} in name of {}", c, s);
+        }
+        if !is_version(version) {
+            bail!("Invalid version {:?} in {}", version, s);
+        }
+        // Draft builds' releases carry their build id
+        let plain_release = match release.rsplit_once(DRAFT_SUFFIX) {
+            Some((r, _)) if draft_id(release).is_some() => r,
+            Some(_) => bail!("Invalid draft release {:?} in {}", release, s),
+            None => release,
+        };
+        if !is_version(plain_release) {
+            bail!("Invalid release {:?} in {}", release, s);
+        }
+        Ok(Self {
+            name,
+            epoch,
+            version,
+            release,
+            arch: None,
+        })
+    }
+
+    /// Split `N-[E:]V-R.A`, e.g. an RPM's file name without `.rpm`.
+    pub fn parse_nevra(s: &'a str) -> Result<Self> {
+        let (nevr, arch) = s
+            .rsplit_once('.')
+            .ok_or_else(|| anyhow!("Invalid NEVRA {}, missing an architecture", s))?;
+        if arch.is_empty() || !arch.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid architecture {:?} in {}", arch, s);
+        }
+        Ok(Self {
+            arch: Some(arch),
+            ..Self::parse_nevr(nevr)?
+        })
+    }
+}
+
+impl std::fmt::Display for Nevra<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-", self.name)?;
+        if let Some(epoch) = self.epoch {
+            write!(f, "{}:", epoch)?;
+        }
+        write!(f, "{}-{}", self.version, self.release)?;
+        if let Some(arch) = self.arch {
+            write!(f, ".{}", arch)?;
+        }
+        Ok(())
     }
-    if version.is_empty() {
-        anyhow::bail!("Invalid buildid with empty version");
+}
+
+/// Split an NVR into its name, version and release; see
+/// [`Nevra::parse_nevr`] for one which may have an epoch.
+pub fn split_nvr(pkg: &str) -> Result<(&str, &str, &str)> {
+    let n = Nevra::parse_nevr(pkg)?;
+    if n.epoch.is_some() {
+        bail!("Invalid buildid {}, NVRs have no epoch", pkg);
     }
-    if rest.is_empty() {
-        anyhow::bail!("Invalid buildid with empty release");
+    Ok((n.name, n.version, n.release))
+}
+
+/// A module build's `name:stream:version:context`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nsvc<'a> {
+    pub name: &'a str,
+    pub stream: &'a str,
+    pub version: &'a str,
+    pub context: &'a str,
+}
+
+impl<'a> Nsvc<'a> {
+    pub fn parse(s: &'a str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (name, stream, version, context) = match parts[..] {
+            [n, s, v, c] => (n, s, v, c),
+            _ => bail!("Invalid module {}, expected NAME:STREAM:VERSION:CONTEXT", s),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || "-._+".contains(c))
+        {
+            bail!("Invalid name {:?} in module {}", name, s);
+        }
+        if stream.is_empty()
+            || !stream
+                .chars()
+                .all(|c| c.is_alphanumeric() || "-._+~".contains(c))
+        {
+            bail!("Invalid stream {:?} in module {}", stream, s);
+        }
+        if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+            bail!("Invalid version {:?} in module {}", version, s);
+        }
+        if context.is_empty() || !context.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("Invalid context {:?} in module {}", context, s);
+        }
+        Ok(Self {
+            name,
+            stream,
+            version,
+            context,
+        })
+    }
+
+    /// The NVR koji knows the module build by, `-` in the stream becoming
+    /// `_` as MBS imports it.
+    pub fn nvr(&self) -> String {
+        format!(
+            "{}-{}-{}.{}",
+            self.name,
+            self.stream.replace('-', "_"),
+            self.version,
+            self.context
+        )
     }
-    Ok((pkgname, version, rest))
 }
 
-fn get_kojipkgs_url_prefix(buildid: &str) -> Result<String> {
-    let (name, version, release) = split_nvr(buildid)?;
-    Ok(format!("{}/{}/{}/{}", KOJIPKGS_URL, name, version, release))
+/// The NVR to ask koji for: a module's (see [`Nsvc`]), or `buildid` as is.
+pub fn koji_buildid(buildid: &str) -> std::borrow::Cow<'_, str> {
+    match Nsvc::parse(buildid) {
+        Ok(m) => m.nvr().into(),
+        Err(_) => buildid.into(),
+    }
 }
 
-pub(crate) fn validate_buildid(s: &str) -> Result<()> {
-    // None of this supports non-ASCII
-    if let Some(c) = s.chars().find(|c| !c.is_ascii()) {
-        bail!("Invalid non-ASCII character {} in buildid", c);
+/// Reject build ids (NVRs, module NSVCs or numeric ids) which could be
+/// mistaken for options or paths.
+pub fn validate_buildid(s: &str) -> Result<()> {
+    // Koji allows non-ASCII letters, but nothing invisible or path-like
+    if let Some(c) = s
+        .chars()
+        .find(|&c| c.is_control() || c.is_whitespace() || c == '/' || c == '\\')
+    {
+        bail!("Invalid character {:?} in buildid", c);
     }
     // Validating the first character is alphanumeric shuts down potential
     // special characters like `-` and `.` etc.
     match s.chars().next() {
         Some(c) => {
-            if !c.is_ascii_alphanumeric() {
+            if !c.is_alphanumeric() {
                 bail!("Invalid alphanumeric character {} in buildid", c);
             }
         }
@@ -65,72 +566,506 @@ pub(crate) fn validate_buildid(s: &str) -> Result<()> {
             bail!("Invalid empty buildid");
         }
     }
+    // Only module identifiers have colons
+    if s.contains(':') {
+        Nsvc::parse(s)?;
+    }
+    Ok(())
+}
+
+/// Percent-encode `s` for use as one segment of a URL path, e.g. of a
+/// kojipkgs URL; characters a path segment allows are kept as they are.
+pub fn encode_path_segment(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => r.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => r.push(b as char),
+            _ => r.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    r
+}
+
+/// Reject hub calls which can't be passed on unambiguously: method names
+/// other than identifiers, keyword names other than identifiers, and
+/// positional arguments containing `=`, which koji would take for keywords.
+pub fn validate_call(
+    method: &str,
+    args: &[serde_json::Value],
+    kwargs: &serde_json::Map<String, serde_json::Value>,
+) -> Result<()> {
+    fn is_identifier(s: &str) -> bool {
+        s.chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    if !method.split('.').all(is_identifier) {
+        bail!("Invalid method name {}", method);
+    }
+    if let Some(k) = kwargs.keys().find(|k| !is_identifier(k)) {
+        bail!("Invalid keyword argument name {}", k);
+    }
+    if args.iter().any(|a| a.to_string().contains('=')) {
+        bail!("Positional arguments can't contain '='; pass them by keyword");
+    }
     Ok(())
 }
 
-lazy_static! {
-    static ref BUILDRE: Regex = Regex::new(r#"^BUILD: +([^ ]+) +\[(\d+)\]"#).unwrap();
-}
-
-fn scrape_koji_cli(output: &str) -> Result<KojiBuildInfo> {
-    let mut r: KojiBuildInfo = Default::default();
-    // Convenience so the client doesn't have to hardcode this
-    let mut in_rpms = false;
-    for line in output.lines() {
-        if in_rpms {
-            let p = Path::new(line.split_whitespace().next().expect("split"));
-            let name = p
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("Missing RPM name"))?;
-            let name = name
-                .to_str()
-                .ok_or_else(|| anyhow::anyhow!("Invalid RPM name"))?;
-            let arch = p
-                .parent()
-                .map(|p| p.file_name())
-                .flatten()
-                .ok_or_else(|| anyhow::anyhow!("Missing RPM arch"))?;
-            let arch = arch
-                .to_str()
-                .ok_or_else(|| anyhow::anyhow!("Invalid RPM arch"))?;
-
-            let v = r.rpms.entry(arch.to_string()).or_default();
-            v.push(name.to_string());
-        } else if let Some(m) = BUILDRE.captures(line) {
-            r.nvr = m[1].to_string();
-            r.id = str::parse(&m[2]).expect("parse u64");
-        } else if line.starts_with("RPMs:") {
-            in_rpms = true;
-        }
-    }
-    if r.nvr.is_empty() {
-        bail!("Failed to find BUILD");
-    }
-    if !in_rpms {
-        bail!("Failed to find RPMs");
-    }
-    r.kojipkgs_url_prefix = get_kojipkgs_url_prefix(&r.nvr)?;
-    Ok(r)
-}
-
-pub(crate) fn get_koji_build(buildid: &str) -> Result<KojiBuildInfo> {
-    validate_buildid(buildid)?;
-    let c = Command::new("koji")
-        .arg("buildinfo")
-        .arg(buildid)
-        .output()?;
-    if !c.status.success() {
-        let _ = std::io::stderr().write_all(&c.stderr);
-        anyhow::bail!("koji failed");
-    }
-    scrape_koji_cli(std::str::from_utf8(&c.stdout)?)
+impl KojiBuildInfo {
+    /// Whether the build is still running, so its RPM list may yet grow.
+    pub fn is_in_progress(&self) -> bool {
+        self.state == "BUILDING"
+    }
+
+    /// Whether the build was deleted, so its files are (or will soon be)
+    /// gone from kojipkgs.
+    pub fn is_deleted(&self) -> bool {
+        self.state == "DELETED"
+    }
+
+    /// Merge the RPM lists of architectures with the same [`base_arch`],
+    /// e.g. `i686` into `i386`.
+    pub fn normalize_arches(&mut self) {
+        let rpms = std::mem::take(&mut self.rpms);
+        for (arch, mut names) in rpms {
+            let v = self.rpms.entry(base_arch(&arch).to_string()).or_default();
+            v.append(&mut names);
+            v.sort();
+        }
+    }
+
+    /// The number of RPMs, of all architectures.
+    pub fn rpm_count(&self) -> usize {
+        self.rpms.values().map(Vec::len).sum()
+    }
+
+    /// Keep only the page of `limit` RPMs (or all) from the `offset`th,
+    /// counting by architecture then listing order, and their NEVRAs.
+    /// Returns whether RPMs after the page were dropped.
+    pub fn page_rpms(&mut self, offset: usize, limit: Option<usize>) -> bool {
+        let total = self.rpm_count();
+        let end = limit.map_or(total, |l| offset.saturating_add(l).min(total));
+        let mut i = 0;
+        for names in self.rpms.values_mut() {
+            names.retain(|_| {
+                i += 1;
+                (offset..end).contains(&(i - 1))
+            });
+        }
+        self.rpms.retain(|_, names| !names.is_empty());
+        let kept: HashSet<&String> = self.rpms.values().flatten().collect();
+        self.nevras.retain(|name, _| kept.contains(name));
+        end < total
+    }
+
+    /// The package name of one of the build's RPMs, from its NEVRA if
+    /// known, else its file name (or the file name itself, if unparseable).
+    pub fn rpm_name<'a>(&'a self, filename: &'a str) -> &'a str {
+        match self.nevras.get(filename) {
+            Some(n) => n.name.as_str(),
+            None => filename
+                .strip_suffix(".rpm")
+                .and_then(|f| Nevra::parse_nevra(f).ok())
+                .map_or(filename, |n| n.name),
+        }
+    }
+
+    /// Keep only the forms of the build's and its tasks' times `format`
+    /// asks for.
+    pub fn format_times(&mut self, format: TimeFormat) {
+        format.apply(&mut self.creation_time, &mut self.creation_ts);
+        format.apply(&mut self.completion_time, &mut self.completion_ts);
+        for t in self.failure.iter_mut().flat_map(|f| f.tasks.iter_mut()) {
+            format.apply(&mut t.create_time, &mut t.create_ts);
+            format.apply(&mut t.start_time, &mut t.start_ts);
+            format.apply(&mut t.completion_time, &mut t.completion_ts);
+        }
+    }
+
+    /// The RPMs of each architecture grouped by variant: their name without
+    /// the build's as a prefix, e.g. `core`, `modules` or `debug-devel` for
+    /// the kernel.  The build's own package is under its name, as are
+    /// those not named after it.
+    pub fn variants(&self) -> BTreeMap<String, BTreeMap<String, Vec<String>>> {
+        let build = split_nvr(&self.nvr).map(|(n, _, _)| n).unwrap_or_default();
+        let variant = |filename: &str| -> String {
+            let name = self.rpm_name(filename);
+            name.strip_prefix(build)
+                .and_then(|v| v.strip_prefix('-'))
+                .filter(|v| !v.is_empty())
+                .unwrap_or(name)
+                .to_string()
+        };
+        self.rpms
+            .iter()
+            .map(|(arch, names)| {
+                let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for name in names {
+                    groups.entry(variant(name)).or_default().push(name.clone());
+                }
+                (arch.clone(), groups)
+            })
+            .collect()
+    }
+}
+
+/// The base architecture (as in dnf's `$basearch`) of a koji arch
+/// directory, e.g. `armhfp` for `armv7hl`; others are their own.
+pub fn base_arch(arch: &str) -> &str {
+    match arch {
+        "i386" | "i486" | "i586" | "i686" | "athlon" | "geode" => "i386",
+        "armv7hl" | "armv7hnl" | "armv7l" => "armhfp",
+        "ppc64p7" => "ppc64",
+        a => a,
+    }
+}
+
+/// Whether a task in `state` has finished, one way or another.
+pub fn is_task_finished(state: &str) -> bool {
+    matches!(state, "CLOSED" | "CANCELED" | "FAILED")
+}
+
+/// Why the hub couldn't answer, so callers can tell a build that doesn't
+/// exist from a failure worth retrying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HubError {
+    /// The hub knows no such build.
+    NoSuchBuild(String),
+    /// The hub couldn't be reached, or didn't answer in time.
+    Unavailable(String),
+    /// The hub answered with a fault, e.g. failed authentication.
+    Fault(String),
+}
+
+impl fmt::Display for HubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchBuild(id) => write!(f, "No such build: {}", id),
+            Self::Unavailable(msg) => write!(f, "Koji hub unavailable: {}", msg),
+            Self::Fault(msg) => write!(f, "Koji hub fault: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for HubError {}
+
+/// A source of build metadata.
+pub trait Backend {
+    /// Return the hub's API version, verifying that it is reachable.
+    fn api_version(&self) -> Result<u32>;
+
+    /// Look up a build by NVR or numeric id.  Fails with
+    /// [`HubError::NoSuchBuild`] for unknown builds.
+    fn get_koji_build(&self, buildid: &str) -> Result<KojiBuildInfo>;
+
+    /// Cheaply check whether a cached in-progress build is still accurate.
+    fn is_unchanged(&self, cached: &KojiBuildInfo) -> Result<bool>;
+
+    /// Return the NVRs of the `count` builds most recently tagged into `tag`.
+    fn list_recently_tagged(&self, tag: &str, count: usize) -> Result<Vec<String>>;
+
+    /// Return a task's state, e.g. `OPEN` or `CLOSED`.
+    fn task_state(&self, task_id: u64) -> Result<String>;
+
+    /// Return the SHA-256 koji recorded for an unsigned RPM, by file name,
+    /// if it has one.
+    fn rpm_sha256(&self, filename: &str) -> Result<Option<String>>;
+
+    /// Call a hub method with JSON arguments (see [`validate_call`]),
+    /// returning its result.
+    fn call(
+        &self,
+        method: &str,
+        args: &[serde_json::Value],
+        kwargs: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value>;
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    const KOJI_OUTPUT: &str = include_str!("example-koji-output.txt");
+    /// Real Fedora NVRs, including the awkward ones.
+    #[test]
+    fn test_split_nvr() -> Result<()> {
+        for (nvr, expected) in [
+            (
+                "rpm-ostree-2020.10-1.fc34",
+                ("rpm-ostree", "2020.10", "1.fc34"),
+            ),
+            (
+                "NetworkManager-1.26.4-1.fc33",
+                ("NetworkManager", "1.26.4", "1.fc33"),
+            ),
+            (
+                "kernel-5.14.0-0.rc7.54.fc36",
+                ("kernel", "5.14.0", "0.rc7.54.fc36"),
+            ),
+            (
+                "libsigc++20-2.10.7-2.fc34",
+                ("libsigc++20", "2.10.7", "2.fc34"),
+            ),
+            ("python3.9-3.9.7-1.fc34", ("python3.9", "3.9.7", "1.fc34")),
+            (
+                "perl-Module-Build-Tiny-0.039-15.fc34",
+                ("perl-Module-Build-Tiny", "0.039", "15.fc34"),
+            ),
+            (
+                "golang-github-google-go-cmp-0.5.6-3.fc35",
+                ("golang-github-google-go-cmp", "0.5.6", "3.fc35"),
+            ),
+            ("firefox-93.0~b5-1.fc35", ("firefox", "93.0~b5", "1.fc35")),
+            ("mesa-21.3.0~rc1-1.fc36", ("mesa", "21.3.0~rc1", "1.fc36")),
+            (
+                "neovim-0.7.0^20220301git-1.fc37",
+                ("neovim", "0.7.0^20220301git", "1.fc37"),
+            ),
+            ("tzdata-2021e-1.fc35", ("tzdata", "2021e", "1.fc35")),
+            ("bash-5.1.8-9.el9", ("bash", "5.1.8", "9.el9")),
+            (
+                "kernel-4.18.0-348.el8_5.2",
+                ("kernel", "4.18.0", "348.el8_5.2"),
+            ),
+            (
+                "bash-5.2.26-3.fc41,draft_2401133",
+                ("bash", "5.2.26", "3.fc41,draft_2401133"),
+            ),
+        ] {
+            assert_eq!(split_nvr(nvr)?, expected, "{}", nvr);
+        }
+        for nvr in [
+            "",
+            "foo",
+            "foo-1",
+            "-1-1",
+            "foo--1",
+            "foo-1-",
+            "foo-1:2-1",
+            "foo-1-1/../bar",
+            "foo bar-1-1",
+            "foo-1.0-1.fc34 ",
+            "foo-1.0-1.fc34,draft_",
+            "foo-1.0-1.fc34,draft_x",
+            "foo-1.0-1,fc34",
+        ] {
+            assert!(split_nvr(nvr).is_err(), "{}", nvr);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_arches() {
+        let mut info = KojiBuildInfo::default();
+        for (arch, name) in [
+            ("i686", "foo-1-1.i686.rpm"),
+            ("i386", "foo-data-1-1.i386.rpm"),
+            ("armv7hl", "foo-1-1.armv7hl.rpm"),
+            ("x86_64", "foo-1-1.x86_64.rpm"),
+        ] {
+            info.rpms
+                .entry(arch.to_string())
+                .or_default()
+                .push(name.to_string());
+        }
+        info.normalize_arches();
+        assert_eq!(
+            info.rpms.keys().collect::<Vec<_>>(),
+            ["armhfp", "i386", "x86_64"]
+        );
+        assert_eq!(
+            info.rpms["i386"],
+            ["foo-1-1.i686.rpm", "foo-data-1-1.i386.rpm"]
+        );
+    }
+
+    #[test]
+    fn test_format_times() {
+        let info = || KojiBuildInfo {
+            creation_time: Some("2020-12-11T19:01:02Z".to_string()),
+            creation_ts: Some(1607713262),
+            failure: Some(Failure {
+                task_id: 1,
+                tasks: vec![FailedTask {
+                    start_time: Some("2020-12-11T19:01:03Z".to_string()),
+                    start_ts: Some(1607713263),
+                    ..Default::default()
+                }],
+            }),
+            ..Default::default()
+        };
+        let mut i = info();
+        i.format_times(TimeFormat::Both);
+        assert!(i.creation_time.is_some() && i.creation_ts.is_some());
+        let mut i = info();
+        i.format_times(TimeFormat::Iso8601);
+        assert_eq!(i.creation_ts, None);
+        assert_eq!(i.failure.unwrap().tasks[0].start_ts, None);
+        let mut i = info();
+        i.format_times(TimeFormat::Epoch);
+        assert_eq!(i.creation_time, None);
+        assert_eq!(i.failure.unwrap().tasks[0].start_ts, Some(1607713263));
+        assert!("rfc822".parse::<TimeFormat>().is_err());
+    }
+
+    #[test]
+    fn test_variants() {
+        let mut info = KojiBuildInfo {
+            nvr: "kernel-6.8.5-301.fc40".to_string(),
+            ..Default::default()
+        };
+        for (arch, name) in [
+            ("src", "kernel-6.8.5-301.fc40.src.rpm"),
+            ("x86_64", "kernel-6.8.5-301.fc40.x86_64.rpm"),
+            ("x86_64", "kernel-core-6.8.5-301.fc40.x86_64.rpm"),
+            ("x86_64", "kernel-modules-6.8.5-301.fc40.x86_64.rpm"),
+            ("x86_64", "kernel-debug-core-6.8.5-301.fc40.x86_64.rpm"),
+            ("x86_64", "kernel-debug-devel-6.8.5-301.fc40.x86_64.rpm"),
+            ("x86_64", "bpftool-7.3.0-301.fc40.x86_64.rpm"),
+        ] {
+            info.rpms
+                .entry(arch.to_string())
+                .or_default()
+                .push(name.to_string());
+        }
+        let v = info.variants();
+        assert_eq!(v["src"]["kernel"], ["kernel-6.8.5-301.fc40.src.rpm"]);
+        let x86: Vec<&str> = v["x86_64"].keys().map(|k| k.as_str()).collect();
+        assert_eq!(
+            x86,
+            [
+                "bpftool",
+                "core",
+                "debug-core",
+                "debug-devel",
+                "kernel",
+                "modules"
+            ]
+        );
+        assert_eq!(
+            v["x86_64"]["debug-core"],
+            ["kernel-debug-core-6.8.5-301.fc40.x86_64.rpm"]
+        );
+    }
+
+    #[test]
+    fn test_page_rpms() {
+        let page = |offset, limit| {
+            let mut info = KojiBuildInfo::default();
+            for (arch, name) in [
+                ("src", "foo-1-1.src.rpm"),
+                ("x86_64", "foo-1-1.x86_64.rpm"),
+                ("x86_64", "foo-libs-1-1.x86_64.rpm"),
+                ("noarch", "foo-doc-1-1.noarch.rpm"),
+            ] {
+                info.rpms
+                    .entry(arch.to_string())
+                    .or_default()
+                    .push(name.to_string());
+            }
+            let truncated = info.page_rpms(offset, limit);
+            let names: Vec<String> = info.rpms.into_iter().flat_map(|(_, v)| v).collect();
+            (names, truncated)
+        };
+        // Arches in order: noarch, src, x86_64
+        assert_eq!(
+            page(1, Some(2)),
+            (
+                vec!["foo-1-1.src.rpm".into(), "foo-1-1.x86_64.rpm".into()],
+                true
+            )
+        );
+        assert_eq!(
+            page(3, Some(2)),
+            (vec!["foo-libs-1-1.x86_64.rpm".into()], false)
+        );
+        assert_eq!(page(0, None).0.len(), 4);
+        assert_eq!(page(9, Some(1)), (vec![], false));
+        assert_eq!(page(0, Some(0)), (vec![], true));
+    }
+
+    #[test]
+    fn test_encode_path_segment() {
+        assert_eq!(encode_path_segment("gtk+"), "gtk+");
+        assert_eq!(encode_path_segment("3.fc41,draft_1"), "3.fc41,draft_1");
+        assert_eq!(encode_path_segment("0.7.0^1git"), "0.7.0%5E1git");
+        assert_eq!(encode_path_segment("päckage"), "p%C3%A4ckage");
+        assert_eq!(encode_path_segment("a/b%c"), "a%2Fb%25c");
+    }
+
+    #[test]
+    fn test_nsvc() -> Result<()> {
+        let m = Nsvc::parse("container-tools:rhel8:8050020211004152025:b4937e53")?;
+        assert_eq!(
+            (m.name, m.stream, m.version, m.context),
+            (
+                "container-tools",
+                "rhel8",
+                "8050020211004152025",
+                "b4937e53"
+            )
+        );
+        assert_eq!(
+            m.nvr(),
+            "container-tools-rhel8-8050020211004152025.b4937e53"
+        );
+        let m = Nsvc::parse("perl-DBI:1.641-fixed:3320200224104410:e1e8a8d3")?;
+        assert_eq!(m.nvr(), "perl-DBI-1.641_fixed-3320200224104410.e1e8a8d3");
+        assert_eq!(
+            koji_buildid("nodejs:14:3420210101120000:abcdef12"),
+            "nodejs-14-3420210101120000.abcdef12"
+        );
+        assert_eq!(koji_buildid("bash-5.1.8-9.el9"), "bash-5.1.8-9.el9");
+        for s in [
+            "nodejs:14",
+            "nodejs:14:3420210101120000",
+            "nodejs:14:3420210101120000:abcdef12:x86_64",
+            ":14:3420210101120000:abcdef12",
+            "nodejs:14:3420210101120000:",
+            "nodejs:1/4:3420210101120000:abcdef12",
+        ] {
+            assert!(Nsvc::parse(s).is_err(), "{}", s);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_draft_id() {
+        assert_eq!(draft_id("3.fc41,draft_2401133"), Some(2401133));
+        assert_eq!(draft_id("3.fc41"), None);
+        assert_eq!(draft_id("3.fc41,draft_"), None);
+    }
+
+    #[test]
+    fn test_nevra() -> Result<()> {
+        let n = Nevra::parse_nevr("NetworkManager-1:1.32.10-2.fc35")?;
+        assert_eq!(
+            (n.name, n.epoch, n.version, n.release),
+            ("NetworkManager", Some(1), "1.32.10", "2.fc35")
+        );
+        assert_eq!(n.to_string(), "NetworkManager-1:1.32.10-2.fc35");
+        let n = Nevra::parse_nevra("vim-common-2:8.2.3404-1.fc35.x86_64")?;
+        assert_eq!(
+            n,
+            Nevra {
+                name: "vim-common",
+                epoch: Some(2),
+                version: "8.2.3404",
+                release: "1.fc35",
+                arch: Some("x86_64"),
+            }
+        );
+        assert_eq!(n.to_string(), "vim-common-2:8.2.3404-1.fc35.x86_64");
+        let n = Nevra::parse_nevra("glibc-langpack-en-2.34-8.fc35.x86_64")?;
+        assert_eq!((n.name, n.epoch), ("glibc-langpack-en", None));
+        assert!(Nevra::parse_nevr("bind-x:9.16.21-1.fc34").is_err());
+        assert!(Nevra::parse_nevr("bind-:9.16.21-1.fc34").is_err());
+        assert!(Nevra::parse_nevra("bash-5.1.8-2").is_err());
+        assert!(Nevra::parse_nevra("bash-5.1.8-2.fc35.").is_err());
+        Ok(())
+    }
 
     #[test]
     fn test_validate_buildid() -> Result<()> {
@@ -139,26 +1074,61 @@ mod test {
         assert!(validate_buildid("").is_err());
         assert!(validate_buildid("-foo").is_err());
         assert!(validate_buildid("../bar.rpm").is_err());
+        validate_buildid("nodejs:14:3420210101120000:abcdef12")?;
+        validate_buildid("python-päckage-1.0-1.fc34")?;
+        assert!(validate_buildid("foo-1-1/../bar").is_err());
+        assert!(validate_buildid("foo-1-1\nbar").is_err());
+        assert!(validate_buildid("ümlaut-1-1").is_ok());
+        assert!(validate_buildid("…-1-1").is_err());
+        assert!(validate_buildid("nodejs:14:latest:abcdef12").is_err());
+        assert!(validate_buildid("bind-32:9.16.21-1.fc34").is_err());
         Ok(())
     }
 
     #[test]
-    fn test_buildre() {
-        let s = "BUILD: rpm-ostree-2020.10-1.fc34 [1657648]";
-        assert!(BUILDRE.captures(s).is_some());
-    }
+    fn test_apply_preset() -> Result<()> {
+        let mut hub = Hub {
+            preset: Some("centos-stream".into()),
+            ..Default::default()
+        };
+        hub.apply_preset()?;
+        assert_eq!(
+            hub.server.as_deref(),
+            Some("https://kojihub.stream.centos.org/kojihub")
+        );
+        assert_eq!(hub.topurl, "https://kojihub.stream.centos.org/kojifiles");
+        assert!(!hub.tags.is_empty());
 
-    #[test]
-    fn test_scrape_koji_cli() -> Result<()> {
-        let r = scrape_koji_cli(KOJI_OUTPUT)?;
-        assert_eq!(r.nvr, "rpm-ostree-2020.10-1.fc34");
-        assert_eq!(r.id, 1657648);
-        assert_eq!(r.rpms.len(), 7);
-        assert_eq!(r.rpms["src"][0], "rpm-ostree-2020.10-1.fc34.src.rpm");
+        // Explicit settings win
+        let mut hub = Hub {
+            preset: Some("cbs".into()),
+            server: Some("https://cbs.example.com/kojihub".into()),
+            ..Default::default()
+        };
+        hub.apply_preset()?;
         assert_eq!(
-            r.rpms["x86_64"][2],
-            "rpm-ostree-libs-debuginfo-2020.10-1.fc34.x86_64.rpm"
+            hub.server.as_deref(),
+            Some("https://cbs.example.com/kojihub")
         );
+        assert_eq!(hub.topurl, "https://cbs.centos.org/kojifiles");
+
+        hub.preset = Some("nope".into());
+        assert!(hub.apply_preset().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_call() -> Result<()> {
+        let kwargs = serde_json::Map::new();
+        validate_call("getBuildTarget", &["f34".into()], &kwargs)?;
+        validate_call("host.getID", &[], &kwargs)?;
+        assert!(validate_call("--help", &[], &kwargs).is_err());
+        assert!(validate_call("getBuild", &["a=b".into()], &kwargs).is_err());
+        let mut kwargs = serde_json::Map::new();
+        kwargs.insert("strict".into(), true.into());
+        validate_call("getBuild", &[1.into()], &kwargs)?;
+        kwargs.insert("not-a-name".into(), true.into());
+        assert!(validate_call("getBuild", &[], &kwargs).is_err());
         Ok(())
     }
 }
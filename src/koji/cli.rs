@@ -0,0 +1,921 @@
+//! The koji CLI backend: queries the hub by running `koji`, which must be
+//! installed.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write as IoWrite};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::TimeZone;
+use lazy_static::lazy_static;
+use serde_derive::Deserialize;
+
+mod scrape;
+
+use super::{
+    encode_path_segment, koji_buildid, validate_buildid, validate_call, Backend, CgOutput,
+    ContentGenerator, FailedTask, Failure, Hub, HubError, KojiBuildInfo, MavenArtifact, RpmNevra,
+    WinArchive,
+};
+
+/// Seconds before a koji call is killed; 0 for no limit.
+static CALL_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// How often to check whether a koji call has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long killed koji processes get to be reaped at shutdown.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Called after each koji call with its subcommand or hub method, start
+/// time and whether it succeeded, e.g. to record metrics.
+pub type CallHook = fn(call: &str, start: Instant, ok: bool);
+
+lazy_static! {
+    /// The number of koji processes running, so shutdown can wait for them.
+    static ref RUNNING: (Mutex<usize>, Condvar) = Default::default();
+    static ref CALL_HOOK: RwLock<Option<CallHook>> = RwLock::new(None);
+}
+
+/// Install a hook to observe koji calls.
+pub fn set_call_hook(hook: CallHook) {
+    *CALL_HOOK.write().unwrap() = Some(hook);
+}
+
+/// Set at shutdown: running koji processes are killed and no more started.
+static KILL: AtomicBool = AtomicBool::new(false);
+
+/// Counts a koji process in `RUNNING` while alive.
+struct Running;
+
+impl Running {
+    fn new() -> Self {
+        *RUNNING.0.lock().unwrap() += 1;
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *RUNNING.0.lock().unwrap() -= 1;
+        RUNNING.1.notify_all();
+    }
+}
+
+/// Wait until `deadline` for running koji processes to exit, then kill any
+/// left, so none outlive us or are cut off by a `SIGKILL` mid-parse.
+pub fn drain(deadline: Instant) {
+    let (lock, exited) = &*RUNNING;
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let (n, _) = exited
+        .wait_timeout_while(lock.lock().unwrap(), timeout, |n| *n > 0)
+        .unwrap();
+    KILL.store(true, Ordering::SeqCst);
+    if *n > 0 {
+        tracing::warn!("Killing {} koji process(es) still running", *n);
+        // They are killed and reaped by the threads which started them
+        let (n, _) = exited
+            .wait_timeout_while(n, KILL_GRACE, |n| *n > 0)
+            .unwrap();
+        if *n > 0 {
+            tracing::error!("{} koji process(es) did not exit", *n);
+        }
+    }
+}
+
+/// Set the limit on koji calls; it applies to calls started afterwards.
+pub fn set_call_timeout(timeout: Option<Duration>) {
+    CALL_TIMEOUT.store(timeout.map_or(0, |t| t.as_secs()), Ordering::Relaxed);
+}
+
+fn call_timeout() -> Option<Duration> {
+    match CALL_TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Like `Command::output()`, but kill the process if it runs too long or
+/// we're shutting down.
+fn output(c: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+    if KILL.load(Ordering::SeqCst) {
+        return Err(HubError::Unavailable("shutting down".into()).into());
+    }
+    let _running = Running::new();
+    let mut child = c
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Drain the pipes as we go so the child can't block writing to them
+    fn drain(mut r: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = r.read_to_end(&mut buf);
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take().expect("stdout"));
+    let stderr = drain(child.stderr.take().expect("stderr"));
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let timed_out = deadline.map_or(false, |d| Instant::now() >= d);
+        if timed_out || KILL.load(Ordering::SeqCst) {
+            let _ = child.kill();
+            let _ = child.wait();
+            let why = match timeout {
+                Some(t) if timed_out => format!("koji call timed out after {}s", t.as_secs()),
+                _ => "koji call killed at shutdown".to_string(),
+            };
+            return Err(HubError::Unavailable(why).into());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().expect("stdout reader"),
+        stderr: stderr.join().expect("stderr reader"),
+    })
+}
+
+/// What python-requests and koji print when the hub can't be reached or
+/// is overloaded, as opposed to answering with a fault.
+const UNAVAILABLE: &[&str] = &[
+    "ConnectionError",
+    "Connection refused",
+    "Max retries exceeded",
+    "Name or service not known",
+    "Temporary failure in name resolution",
+    "timed out",
+    "ServerOffline",
+    "502 Server Error",
+    "503 Server Error",
+    "504 Server Error",
+];
+
+/// Classify a failed koji call by its stderr.
+fn classify(stderr: &str) -> HubError {
+    if let Some(id) = stderr
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("No such build: "))
+    {
+        return HubError::NoSuchBuild(id.trim().to_string());
+    }
+    // Tracebacks end with the exception, which is the useful part
+    let last = stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("koji failed");
+    let last = match last.char_indices().nth(200) {
+        Some((i, _)) => &last[..i],
+        None => last,
+    };
+    if UNAVAILABLE.iter().any(|m| stderr.contains(m)) {
+        HubError::Unavailable(last.to_string())
+    } else {
+        HubError::Fault(last.to_string())
+    }
+}
+
+/// Parse `koji buildinfo` output as a lookup does, for `cargo fuzz`.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn fuzz_buildinfo(output: &str) {
+    let _ = scrape::buildinfo(output);
+    let _ = scrape::task(output);
+    let _ = scrape::maven(output);
+    let _ = scrape::win(output);
+    let _ = classify(output);
+}
+
+/// Each RPM's NEVRA by file name, from `listRPMs` output.
+fn nevras(output: &str) -> Result<BTreeMap<String, RpmNevra>> {
+    let rpms: Vec<RpmNevra> = serde_json::from_str(output)?;
+    Ok(rpms.into_iter().map(|r| (r.filename(), r)).collect())
+}
+
+/// The subset of `getBuild` output saying where a build's files are.  Older
+/// CLIs don't show the volume, and archived builds may be on another one.
+#[derive(Deserialize)]
+struct BuildLocation {
+    name: String,
+    version: String,
+    release: String,
+    #[serde(default)]
+    volume_name: Option<String>,
+}
+
+/// The content generator fields of `getBuild` output, unset for builds from
+/// koji tasks.
+#[derive(Deserialize)]
+struct CgBuild {
+    #[serde(default)]
+    cg_name: Option<String>,
+    #[serde(default)]
+    extra: Option<serde_json::Value>,
+}
+
+/// An entry of `listArchives` output.
+#[derive(Deserialize)]
+struct Archive {
+    filename: String,
+    type_name: String,
+    btype: String,
+    size: u64,
+    checksum: Option<String>,
+    checksum_type: Option<usize>,
+    /// Only listed for `type=maven`.
+    #[serde(default)]
+    group_id: String,
+    #[serde(default)]
+    artifact_id: String,
+    #[serde(default)]
+    version: String,
+    /// Only listed for `type=win`; `platforms` and `flags` space-separated.
+    #[serde(default)]
+    relpath: Option<String>,
+    #[serde(default)]
+    platforms: Option<String>,
+    #[serde(default)]
+    flags: Option<String>,
+}
+
+/// koji's `CHECKSUM_TYPES`, by number.
+const CHECKSUM_TYPES: &[&str] = &["md5", "sha1", "sha256"];
+
+fn checksum_type(t: Option<usize>) -> Option<String> {
+    t.and_then(|t| CHECKSUM_TYPES.get(t)).map(|t| t.to_string())
+}
+
+/// Where koji's `pathinfo.typedir()` puts archives of a build type, under
+/// the build's directory.
+fn typedir(btype: &str) -> String {
+    match btype {
+        "maven" | "win" => btype.to_string(),
+        "image" => "images".to_string(),
+        _ => format!("files/{}", encode_path_segment(btype)),
+    }
+}
+
+/// A build's outputs from `listArchives` output, with URLs under its
+/// `prefix`.
+fn cg_outputs(prefix: &str, output: &str) -> Result<Vec<CgOutput>> {
+    let archives: Vec<Archive> = serde_json::from_str(output)?;
+    Ok(archives
+        .into_iter()
+        .map(|a| CgOutput {
+            url: format!(
+                "{}/{}/{}",
+                prefix,
+                typedir(&a.btype),
+                encode_path_segment(&a.filename)
+            ),
+            checksum_type: checksum_type(a.checksum_type),
+            filename: a.filename,
+            archive_type: a.type_name,
+            btype: a.btype,
+            size: a.size,
+            checksum: a.checksum,
+        })
+        .collect())
+}
+
+/// A maven build's artifacts from `listArchives type=maven` output, with
+/// URLs as koji's `pathinfo.mavenfile()` lays them out under the build's
+/// `prefix`: `maven/{group/id}/{artifact}/{version}/{filename}`.
+fn maven_artifacts(prefix: &str, output: &str) -> Result<Vec<MavenArtifact>> {
+    let archives: Vec<Archive> = serde_json::from_str(output)?;
+    Ok(archives
+        .into_iter()
+        .map(|a| {
+            let group: Vec<String> = a.group_id.split('.').map(encode_path_segment).collect();
+            MavenArtifact {
+                url: format!(
+                    "{}/maven/{}/{}/{}/{}",
+                    prefix,
+                    group.join("/"),
+                    encode_path_segment(&a.artifact_id),
+                    encode_path_segment(&a.version),
+                    encode_path_segment(&a.filename)
+                ),
+                checksum_type: checksum_type(a.checksum_type),
+                filename: a.filename,
+                group_id: a.group_id,
+                artifact_id: a.artifact_id,
+                version: a.version,
+                archive_type: a.type_name,
+                size: a.size,
+                checksum: a.checksum,
+            }
+        })
+        .collect())
+}
+
+/// A Windows build's archives from `listArchives type=win` output, with
+/// URLs as koji's `pathinfo.winfile()` lays them out under the build's
+/// `prefix`: `win/{relpath}/{filename}`.
+fn win_archives(prefix: &str, output: &str) -> Result<Vec<WinArchive>> {
+    let archives: Vec<Archive> = serde_json::from_str(output)?;
+    let words = |s: Option<String>| -> Vec<String> {
+        s.unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect()
+    };
+    Ok(archives
+        .into_iter()
+        .map(|a| {
+            let relpath = a.relpath.unwrap_or_default();
+            let mut url = format!("{}/win", prefix);
+            for segment in relpath.split('/').filter(|s| !s.is_empty()) {
+                url.push('/');
+                url.push_str(&encode_path_segment(segment));
+            }
+            url.push('/');
+            url.push_str(&encode_path_segment(&a.filename));
+            WinArchive {
+                url,
+                checksum_type: checksum_type(a.checksum_type),
+                filename: a.filename,
+                relpath,
+                archive_type: a.type_name,
+                platforms: words(a.platforms),
+                flags: words(a.flags),
+                size: a.size,
+                checksum: a.checksum,
+            }
+        })
+        .collect())
+}
+
+/// The build's directory as koji's `pathinfo.build()` lays it out: under
+/// `packages/{name}/{version}/{release}`, prefixed by `vol/{volume}` for
+/// volumes other than `DEFAULT` (e.g. `vol/fedora_koji_archive00`).
+fn get_kojipkgs_url_prefix(topurl: &str, build: &BuildLocation) -> Result<String> {
+    let topurl = topurl.trim_end_matches('/');
+    let root = match build.volume_name.as_deref() {
+        Some(v) if v != "DEFAULT" => {
+            validate_buildid(v)?;
+            format!("{}/vol/{}", topurl, encode_path_segment(v))
+        }
+        _ => topurl.to_string(),
+    };
+    Ok(format!(
+        "{}/packages/{}/{}/{}",
+        root,
+        encode_path_segment(&build.name),
+        encode_path_segment(&build.version),
+        encode_path_segment(&build.release)
+    ))
+}
+
+#[derive(Deserialize)]
+struct TaggedBuild {
+    nvr: String,
+    create_event: u64,
+}
+
+/// The subset of `getBuild` output needed to tell whether a build changed.
+#[derive(Deserialize)]
+struct BuildState {
+    state: u32,
+    completion_time: Option<String>,
+}
+
+/// Names for koji's numeric build states, as shown by `koji buildinfo`.
+const BUILD_STATES: &[&str] = &["BUILDING", "COMPLETE", "DELETED", "FAILED", "CANCELED"];
+
+/// Names for koji's numeric task states, as shown by `koji taskinfo`.
+const TASK_STATES: &[&str] = &["FREE", "OPEN", "CLOSED", "CANCELED", "ASSIGNED", "FAILED"];
+
+/// The subset of `getTaskInfo` output we use.
+#[derive(Deserialize)]
+struct TaskInfo {
+    state: u32,
+}
+
+/// The subset of `getTaskChildren` output we use.
+#[derive(Deserialize)]
+struct ChildTask {
+    id: u64,
+    method: String,
+    arch: String,
+    state: u32,
+    create_ts: Option<f64>,
+    start_ts: Option<f64>,
+    completion_ts: Option<f64>,
+}
+
+/// The times of `getBuild` output.
+#[derive(Deserialize)]
+struct BuildTimes {
+    creation_ts: Option<f64>,
+    completion_ts: Option<f64>,
+}
+
+/// A koji timestamp, a float of seconds since the epoch, as a UTC ISO 8601
+/// string and whole seconds.
+fn koji_time(ts: Option<f64>) -> (Option<String>, Option<i64>) {
+    let secs = match ts {
+        Some(ts) if ts.is_finite() => ts.floor() as i64,
+        _ => return (None, None),
+    };
+    let time = chrono::Utc
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    (time, Some(secs))
+}
+
+/// Index of `FAILED` in [`TASK_STATES`].
+const TASK_FAILED: u32 = 5;
+
+/// Where kojipkgs serves a task's output, e.g. its logs.
+fn task_output_url(topurl: &str, task_id: u64, filename: &str) -> String {
+    format!(
+        "{}/work/tasks/{}/{}/{}",
+        topurl.trim_end_matches('/'),
+        task_id % 10000,
+        task_id,
+        encode_path_segment(filename)
+    )
+}
+
+/// The unsigned RPM's SHA-256 from `getRPMChecksums` output, which maps
+/// signing keys (empty for unsigned) to checksums by type.
+fn unsigned_sha256(output: &str) -> Result<Option<String>> {
+    let mut sums: HashMap<String, HashMap<String, String>> = serde_json::from_str(output)?;
+    Ok(sums.remove("").and_then(|mut s| s.remove("sha256")))
+}
+
+impl Hub {
+    /// The failed subtasks of a build's task, with their logs.
+    fn failure(&self, task_id: u64) -> Result<Failure> {
+        let id = task_id.to_string();
+        let out = self.run_koji(&["call", "--json-output", "getTaskChildren", &id])?;
+        let children: Vec<ChildTask> = serde_json::from_str(&out)?;
+        let tasks = children
+            .into_iter()
+            .filter(|t| t.state == TASK_FAILED)
+            .map(|t| {
+                let id = t.id.to_string();
+                let out = self.run_koji(&["call", "--json-output", "listTaskOutput", &id])?;
+                let files: Vec<String> = serde_json::from_str(&out)?;
+                let logs = files
+                    .iter()
+                    .filter(|f| f.ends_with(".log"))
+                    .map(|f| task_output_url(&self.topurl, t.id, f))
+                    .collect();
+                let (create_time, create_ts) = koji_time(t.create_ts);
+                let (start_time, start_ts) = koji_time(t.start_ts);
+                let (completion_time, completion_ts) = koji_time(t.completion_ts);
+                Ok(FailedTask {
+                    id: t.id,
+                    method: t.method,
+                    arch: t.arch,
+                    logs,
+                    create_time,
+                    create_ts,
+                    start_time,
+                    start_ts,
+                    completion_time,
+                    completion_ts,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Failure { task_id, tasks })
+    }
+
+    fn run_koji(&self, args: &[&str]) -> Result<String> {
+        let mut c = Command::new("koji");
+        if let Some(profile) = self.profile.as_deref() {
+            c.arg(format!("--profile={}", profile));
+        }
+        if let Some(server) = self.server.as_deref() {
+            c.arg(format!("--server={}", server));
+        }
+        c.arg(format!("--topurl={}", self.topurl));
+        if let Some(ca) = self.ca_bundle.as_deref() {
+            // Used by python-requests, which koji talks to the hub with
+            c.env("REQUESTS_CA_BUNDLE", ca);
+        }
+        if let Some(authtype) = self.authtype.as_deref() {
+            c.arg(format!("--authtype={}", authtype));
+            c.arg("--force-auth");
+        }
+        if let Some(principal) = self.principal.as_deref() {
+            c.arg(format!("--principal={}", principal));
+        }
+        if let Some(keytab) = self.keytab.as_deref() {
+            c.arg(format!("--keytab={}", keytab.display()));
+        }
+        // For `koji call`, label by the hub method rather than "call"
+        let call = match args {
+            ["call", rest @ ..] => rest
+                .iter()
+                .find(|a| !a.starts_with("--"))
+                .copied()
+                .unwrap_or("call"),
+            [cmd, ..] => *cmd,
+            [] => "",
+        };
+        let _span = tracing::info_span!("koji", call).entered();
+        let start = Instant::now();
+        let c = output(c.args(args), call_timeout());
+        if let Some(hook) = *CALL_HOOK.read().unwrap() {
+            hook(
+                call,
+                start,
+                c.as_ref().map(|c| c.status.success()).unwrap_or(false),
+            );
+        }
+        tracing::debug!(
+            call,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "koji call finished"
+        );
+        let c = c?;
+        if !c.status.success() {
+            let _ = std::io::stderr().write_all(&c.stderr);
+            return Err(classify(&String::from_utf8_lossy(&c.stderr)).into());
+        }
+        Ok(String::from_utf8(c.stdout)?)
+    }
+}
+
+/// Queries the hub with the koji CLI, which must be installed.
+impl Backend for Hub {
+    fn api_version(&self) -> Result<u32> {
+        let out = self.run_koji(&["call", "--json-output", "getAPIVersion"])?;
+        Ok(serde_json::from_str(&out)?)
+    }
+
+    fn get_koji_build(&self, buildid: &str) -> Result<KojiBuildInfo> {
+        validate_buildid(buildid)?;
+        let buildid = koji_buildid(buildid);
+        let out = self.run_koji(&["buildinfo", &buildid])?;
+        // Older CLIs say so on stdout, and succeed
+        if out.trim_start().starts_with("No such build: ") {
+            return Err(classify(&out).into());
+        }
+        let mut r = scrape::buildinfo(&out)?;
+        // Ask where the build is rather than assuming from its NVR
+        let id = r.id.to_string();
+        let build = self.run_koji(&["call", "--json-output", "getBuild", &id])?;
+        let location: Option<BuildLocation> = serde_json::from_str(&build)?;
+        let location = location.ok_or_else(|| HubError::NoSuchBuild(r.nvr.clone()))?;
+        r.kojipkgs_url_prefix = get_kojipkgs_url_prefix(&self.topurl, &location)?;
+        let build_id = format!("buildID={}", id);
+        let times: BuildTimes = serde_json::from_str(&build)?;
+        let (creation_time, creation_ts) = koji_time(times.creation_ts);
+        let (completion_time, completion_ts) = koji_time(times.completion_ts);
+        r.creation_time = creation_time;
+        r.creation_ts = creation_ts;
+        r.completion_time = completion_time;
+        r.completion_ts = completion_ts;
+        let cg: CgBuild = serde_json::from_str(&build)?;
+        if let Some(name) = cg.cg_name {
+            let out = self.run_koji(&["call", "--json-output", "listArchives", &build_id])?;
+            r.content_generator = Some(ContentGenerator {
+                name,
+                metadata: cg.extra.unwrap_or_default(),
+                outputs: cg_outputs(&r.kojipkgs_url_prefix, &out)?,
+            });
+        }
+        let rpms = self.run_koji(&["call", "--json-output", "listRPMs", &build_id])?;
+        r.nevras = nevras(&rpms)?;
+        if let Some(mut maven) = scrape::maven(&out) {
+            let out = self.run_koji(&[
+                "call",
+                "--json-output",
+                "listArchives",
+                &build_id,
+                "type=maven",
+            ])?;
+            maven.artifacts = maven_artifacts(&r.kojipkgs_url_prefix, &out)?;
+            r.maven = Some(maven);
+        }
+        if let Some(mut win) = scrape::win(&out) {
+            let out = self.run_koji(&[
+                "call",
+                "--json-output",
+                "listArchives",
+                &build_id,
+                "type=win",
+            ])?;
+            win.archives = win_archives(&r.kojipkgs_url_prefix, &out)?;
+            r.win = Some(win);
+        }
+        if r.state == "FAILED" {
+            if let Some(task_id) = scrape::task(&out) {
+                // The build itself was found, so don't fail over the details
+                match self.failure(task_id) {
+                    Ok(f) => r.failure = Some(f),
+                    Err(e) => tracing::warn!(task_id, "Failed to get failed tasks: {}", e),
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    /// Fetches only the build's state, without re-listing its RPMs.
+    fn is_unchanged(&self, cached: &KojiBuildInfo) -> Result<bool> {
+        let id = cached.id.to_string();
+        let b: BuildState =
+            serde_json::from_str(&self.run_koji(&["call", "--json-output", "getBuild", &id])?)?;
+        let state = BUILD_STATES.get(b.state as usize).copied();
+        Ok(state == Some(cached.state.as_str()) && b.completion_time.is_none())
+    }
+
+    fn list_recently_tagged(&self, tag: &str, count: usize) -> Result<Vec<String>> {
+        let out = self.run_koji(&["call", "--json-output", "listTagged", tag])?;
+        let mut builds: Vec<TaggedBuild> = serde_json::from_str(&out)?;
+        builds.sort_by(|a, b| b.create_event.cmp(&a.create_event));
+        Ok(builds.into_iter().take(count).map(|b| b.nvr).collect())
+    }
+
+    fn task_state(&self, task_id: u64) -> Result<String> {
+        let id = task_id.to_string();
+        let out = self.run_koji(&["call", "--json-output", "getTaskInfo", &id])?;
+        let t: Option<TaskInfo> = serde_json::from_str(&out)?;
+        let t = t.ok_or_else(|| anyhow::anyhow!("No such task {}", task_id))?;
+        TASK_STATES
+            .get(t.state as usize)
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow::anyhow!("Unknown state {} of task {}", t.state, task_id))
+    }
+
+    /// Uses `getRPMChecksums`, which older hubs lack.
+    fn rpm_sha256(&self, filename: &str) -> Result<Option<String>> {
+        let nvra = filename
+            .strip_suffix(".rpm")
+            .ok_or_else(|| anyhow::anyhow!("Not an RPM: {}", filename))?;
+        validate_buildid(nvra)?;
+        let out = self.run_koji(&[
+            "call",
+            "--json-output",
+            "getRPMChecksums",
+            nvra,
+            "checksum_types=['sha256']",
+        ])?;
+        unsigned_sha256(&out)
+    }
+
+    /// Uses `koji call --json-input`, so each argument is passed as JSON.
+    fn call(
+        &self,
+        method: &str,
+        args: &[serde_json::Value],
+        kwargs: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        validate_call(method, args, kwargs)?;
+        let mut argv: Vec<String> = ["call", "--json-output", "--json-input", method, "--"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        argv.extend(args.iter().map(|a| a.to_string()));
+        argv.extend(kwargs.iter().map(|(k, v)| format!("{}={}", k, v)));
+        let argv: Vec<&str> = argv.iter().map(|s| s.as_str()).collect();
+        Ok(serde_json::from_str(&self.run_koji(&argv)?)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::koji::DEFAULT_TOPURL;
+
+    const KOJI_OUTPUT: &str = include_str!("../example-koji-output.txt");
+
+    #[test]
+    fn test_scrape_koji_cli() -> Result<()> {
+        let r = scrape::buildinfo(KOJI_OUTPUT)?;
+        assert_eq!(r.nvr, "rpm-ostree-2020.10-1.fc34");
+        assert_eq!(r.id, 1657648);
+        assert_eq!(r.state, "COMPLETE");
+        assert!(!r.is_in_progress());
+        assert_eq!(r.rpms.len(), 7);
+        assert_eq!(r.rpms["src"][0], "rpm-ostree-2020.10-1.fc34.src.rpm");
+        assert_eq!(
+            r.rpms["x86_64"][2],
+            "rpm-ostree-libs-debuginfo-2020.10-1.fc34.x86_64.rpm"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_kojipkgs_url_prefix() -> Result<()> {
+        let location = |build: serde_json::Value| -> Result<BuildLocation> {
+            Ok(serde_json::from_value(build)?)
+        };
+        let b = location(serde_json::json!({
+            "name": "rpm-ostree", "version": "2020.10", "release": "1.fc34",
+            "volume_name": "DEFAULT", "state": 1,
+        }))?;
+        assert_eq!(
+            get_kojipkgs_url_prefix(DEFAULT_TOPURL, &b)?,
+            "https://kojipkgs.fedoraproject.org/packages/rpm-ostree/2020.10/1.fc34"
+        );
+        let b = location(serde_json::json!({
+            "name": "kernel", "version": "2.6.29.4", "release": "167.fc11",
+            "volume_name": "fedora_koji_archive00",
+        }))?;
+        assert_eq!(
+            get_kojipkgs_url_prefix(DEFAULT_TOPURL, &b)?,
+            "https://kojipkgs.fedoraproject.org/vol/fedora_koji_archive00/packages/kernel/2.6.29.4/167.fc11"
+        );
+        let b = location(serde_json::json!({
+            "name": "rpm-ostree", "version": "2020.10", "release": "1.el8", "volume_name": "rhel-8",
+        }))?;
+        assert_eq!(
+            get_kojipkgs_url_prefix("https://download.example.com/brewroot/", &b)?,
+            "https://download.example.com/brewroot/vol/rhel-8/packages/rpm-ostree/2020.10/1.el8"
+        );
+        // Hubs too old to report volumes
+        let b = location(serde_json::json!({
+            "name": "python-päckage", "version": "1.0^1", "release": "1.fc34",
+        }))?;
+        assert_eq!(
+            get_kojipkgs_url_prefix(DEFAULT_TOPURL, &b)?,
+            "https://kojipkgs.fedoraproject.org/packages/python-p%C3%A4ckage/1.0%5E1/1.fc34"
+        );
+        let b = location(serde_json::json!({
+            "name": "bash", "version": "5.2.26", "release": "3.fc41,draft_2401133",
+        }))?;
+        assert_eq!(
+            get_kojipkgs_url_prefix(DEFAULT_TOPURL, &b)?,
+            "https://kojipkgs.fedoraproject.org/packages/bash/5.2.26/3.fc41,draft_2401133"
+        );
+        let b = location(serde_json::json!({
+            "name": "foo", "version": "1", "release": "1", "volume_name": "../etc",
+        }))?;
+        assert!(get_kojipkgs_url_prefix(DEFAULT_TOPURL, &b).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nevras() -> Result<()> {
+        // A subpackage with an epoch and version of its own, as e.g. texlive has
+        let out = r#"[
+            {"id": 1, "name": "texlive", "version": "2021", "release": "55.fc35",
+             "epoch": 9, "arch": "src", "build_id": 7},
+            {"id": 2, "name": "texlive-a2ping", "version": "svn52964", "release": "55.fc35",
+             "epoch": 9, "arch": "noarch", "build_id": 7},
+            {"id": 3, "name": "texlive-base", "version": "20210325", "release": "55.fc35",
+             "epoch": null, "arch": "x86_64", "build_id": 7}
+        ]"#;
+        let n = nevras(out)?;
+        assert_eq!(n.len(), 3);
+        let a2ping = &n["texlive-a2ping-svn52964-55.fc35.noarch.rpm"];
+        assert_eq!(a2ping.epoch, Some(9));
+        assert_eq!(a2ping.version, "svn52964");
+        assert_eq!(n["texlive-base-20210325-55.fc35.x86_64.rpm"].epoch, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cg_outputs() -> Result<()> {
+        let prefix = "https://kojipkgs.fedoraproject.org/packages/Fedora-Cloud/40/1";
+        let out = r#"[
+            {"id": 1, "filename": "Fedora-Cloud-Base-40-1.x86_64.qcow2", "type_name": "qcow2",
+             "btype": "image", "size": 403701760, "checksum": "abc", "checksum_type": 2},
+            {"id": 2, "filename": "modulemd.txt", "type_name": "txt",
+             "btype": "module", "size": 12, "checksum": null, "checksum_type": null}
+        ]"#;
+        let o = cg_outputs(prefix, out)?;
+        assert_eq!(o.len(), 2);
+        assert_eq!(
+            o[0].url,
+            format!("{}/images/Fedora-Cloud-Base-40-1.x86_64.qcow2", prefix)
+        );
+        assert_eq!(o[0].archive_type, "qcow2");
+        assert_eq!(o[0].checksum_type.as_deref(), Some("sha256"));
+        assert_eq!(o[1].url, format!("{}/files/module/modulemd.txt", prefix));
+        assert_eq!(o[1].checksum_type, None);
+        assert!(cg_outputs(prefix, "[]")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_maven_artifacts() -> Result<()> {
+        let prefix = "https://koji.example.com/packages/commons-io/2.11.0/1";
+        let out = r#"[
+            {"id": 1, "filename": "commons-io-2.11.0.jar", "type_name": "jar",
+             "btype": "maven", "size": 327135, "checksum": "abc", "checksum_type": 0,
+             "group_id": "org.apache.commons", "artifact_id": "commons-io", "version": "2.11.0"},
+            {"id": 2, "filename": "commons-io-2.11.0.pom", "type_name": "pom",
+             "btype": "maven", "size": 2048, "checksum": "def", "checksum_type": 0,
+             "group_id": "org.apache.commons", "artifact_id": "commons-io", "version": "2.11.0"}
+        ]"#;
+        let a = maven_artifacts(prefix, out)?;
+        assert_eq!(a.len(), 2);
+        assert_eq!(
+            a[0].url,
+            format!(
+                "{}/maven/org/apache/commons/commons-io/2.11.0/commons-io-2.11.0.jar",
+                prefix
+            )
+        );
+        assert_eq!(a[0].group_id, "org.apache.commons");
+        assert_eq!(a[1].archive_type, "pom");
+        assert_eq!(a[1].checksum_type.as_deref(), Some("md5"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_win_archives() -> Result<()> {
+        let prefix = "https://koji.example.com/packages/qpid-cpp-win/1.0/1";
+        let out = r#"[
+            {"id": 1, "filename": "qpid.zip", "type_name": "zip", "btype": "win",
+             "size": 1024, "checksum": "abc", "checksum_type": 2,
+             "relpath": "bin/x64", "platforms": "w2k8r2 w2k12", "flags": "debug"},
+            {"id": 2, "filename": "qpid setup.msi", "type_name": "msi", "btype": "win",
+             "size": 2048, "checksum": "def", "checksum_type": 2,
+             "relpath": "", "platforms": "w2k8r2", "flags": null}
+        ]"#;
+        let a = win_archives(prefix, out)?;
+        assert_eq!(a.len(), 2);
+        assert_eq!(a[0].url, format!("{}/win/bin/x64/qpid.zip", prefix));
+        assert_eq!(a[0].platforms, ["w2k8r2", "w2k12"]);
+        assert_eq!(a[0].flags, ["debug"]);
+        assert_eq!(a[1].url, format!("{}/win/qpid%20setup.msi", prefix));
+        assert!(a[1].flags.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_koji_time() {
+        assert_eq!(
+            koji_time(Some(1607715075.52)),
+            (Some("2020-12-11T19:31:15Z".to_string()), Some(1607715075))
+        );
+        assert_eq!(koji_time(None), (None, None));
+        assert_eq!(koji_time(Some(f64::NAN)), (None, None));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify("No such build: foo-1-1\n"),
+            HubError::NoSuchBuild("foo-1-1".into())
+        );
+        let e = classify(
+            "Traceback (most recent call last):\n  ...\nrequests.exceptions.ConnectionError: \
+             HTTPSConnectionPool(host='koji.example.com', port=443): Max retries exceeded\n",
+        );
+        assert!(matches!(e, HubError::Unavailable(m) if m.starts_with("requests.exceptions")));
+        assert_eq!(
+            classify("2021-01-01 00:00:00 ERROR: koji.AuthError: unable to obtain a session\n"),
+            HubError::Fault(
+                "2021-01-01 00:00:00 ERROR: koji.AuthError: unable to obtain a session".into()
+            )
+        );
+        assert_eq!(classify(""), HubError::Fault("koji failed".into()));
+    }
+
+    #[test]
+    fn test_task_output_url() {
+        assert_eq!(
+            task_output_url("https://kojipkgs.fedoraproject.org/", 57269519, "build.log"),
+            "https://kojipkgs.fedoraproject.org/work/tasks/9519/57269519/build.log"
+        );
+    }
+
+    #[test]
+    fn test_unsigned_sha256() -> Result<()> {
+        let out = r#"{"": {"sha256": "abc123"}, "eb10b464": {"sha256": "def456"}}"#;
+        assert_eq!(unsigned_sha256(out)?.as_deref(), Some("abc123"));
+        assert_eq!(
+            unsigned_sha256(r#"{"eb10b464": {"sha256": "def456"}}"#)?,
+            None
+        );
+        assert_eq!(unsigned_sha256("{}")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_timeout() -> Result<()> {
+        let out = output(
+            Command::new("echo").arg("hi"),
+            Some(Duration::from_secs(10)),
+        )?;
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"hi\n");
+        let start = Instant::now();
+        assert!(output(
+            Command::new("sleep").arg("10"),
+            Some(Duration::from_millis(100))
+        )
+        .is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
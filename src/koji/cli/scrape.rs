@@ -0,0 +1,368 @@
+//! Parsing `koji buildinfo` output, whose shape has shifted between koji
+//! releases.  Each shape we know is a [`Dialect`]; anything else is
+//! rejected as unsupported rather than half-parsed.  Nothing here may
+//! panic, whatever the output; `fuzz/` exercises that.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::koji::{draft_id, split_nvr, KojiBuildInfo, Maven, WinBuild};
+
+lazy_static! {
+    static ref BUILDRE: Regex = Regex::new(r#"^BUILD: +([^ ]+) +\[(\d+)\]"#).unwrap();
+}
+
+/// The shapes of `koji buildinfo` output we understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Dialect {
+    /// RPMs listed by path alone, as older CLIs print them.
+    Plain,
+    /// Each RPM's path followed by `Signatures: ...`.
+    Signed,
+    /// With a `Draft:` line, from CLIs which know about draft builds; RPMs
+    /// may or may not list signatures.
+    Draft,
+}
+
+impl Dialect {
+    /// Work out which dialect `output` is in.
+    pub(super) fn detect(output: &str) -> Result<Self, Unsupported> {
+        let first = output
+            .lines()
+            .enumerate()
+            .find(|(_, l)| !l.trim().is_empty());
+        match first {
+            Some((_, l)) if BUILDRE.is_match(l) => {}
+            Some((n, l)) => return Err(Unsupported::at("expected a BUILD line first", n, l)),
+            None => return Err(Unsupported::new("no output")),
+        }
+        if !output.lines().any(|l| l.starts_with("State: ")) {
+            return Err(Unsupported::new("no State line"));
+        }
+        if output.lines().any(|l| l.starts_with("Draft: ")) {
+            return Ok(Self::Draft);
+        }
+        if rpm_lines(output).any(|(_, l)| l.contains("Signatures:")) {
+            Ok(Self::Signed)
+        } else {
+            Ok(Self::Plain)
+        }
+    }
+}
+
+/// Output we don't understand, with the offending line in full so that it
+/// can be reported as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Unsupported {
+    why: &'static str,
+    /// The 1-based number and text of the line to blame, if any.
+    line: Option<(usize, String)>,
+}
+
+impl Unsupported {
+    fn new(why: &'static str) -> Self {
+        Self { why, line: None }
+    }
+
+    /// Blame the line at 0-based index `n`.
+    fn at(why: &'static str, n: usize, line: &str) -> Self {
+        Self {
+            why,
+            line: Some((n + 1, line.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported koji CLI output: {}", self.why)?;
+        if let Some((n, line)) = &self.line {
+            write!(f, " at line {}: {:?}", n, line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Lines of the `RPMs:` section with their indices, ending at a blank line
+/// or the next section's heading.
+fn rpm_lines(output: &str) -> impl Iterator<Item = (usize, &str)> {
+    output
+        .lines()
+        .enumerate()
+        .skip_while(|(_, l)| !l.starts_with("RPMs:"))
+        .skip(1)
+        .take_while(|(_, l)| !l.trim().is_empty() && !(l.ends_with(':') && !l.starts_with('/')))
+}
+
+/// An RPM's `(arch, filename)` from line `n`, in the `RPMs:` section.
+fn parse_rpm(dialect: Dialect, n: usize, line: &str) -> Result<(String, String), Unsupported> {
+    let path = match dialect {
+        Dialect::Plain => line.trim(),
+        Dialect::Signed | Dialect::Draft => {
+            line.split("Signatures:").next().unwrap_or_default().trim()
+        }
+    };
+    if !path.starts_with('/') || !path.ends_with(".rpm") || path.contains(char::is_whitespace) {
+        return Err(Unsupported::at("expected an RPM path", n, line));
+    }
+    let p = Path::new(path);
+    let name = p.file_name().and_then(|n| n.to_str());
+    let arch = p
+        .parent()
+        .and_then(|p| p.file_name())
+        .and_then(|a| a.to_str());
+    match (arch, name) {
+        (Some(arch), Some(name)) => Ok((arch.to_string(), name.to_string())),
+        _ => Err(Unsupported::at("expected ARCH/NAME.rpm", n, line)),
+    }
+}
+
+/// Parse `koji buildinfo` output in any supported dialect.
+pub(super) fn buildinfo(output: &str) -> Result<KojiBuildInfo> {
+    let dialect = Dialect::detect(output)?;
+    tracing::trace!(?dialect, "Parsing koji buildinfo");
+    let mut r: KojiBuildInfo = Default::default();
+    for (n, line) in output.lines().enumerate() {
+        if let Some(m) = BUILDRE.captures(line) {
+            r.nvr = m[1].to_string();
+            r.id = m[2]
+                .parse()
+                .map_err(|_| Unsupported::at("build id out of range", n, line))?;
+        } else if let Some(state) = line.strip_prefix("State: ") {
+            r.state = state.trim().to_string();
+        } else if let Some(draft) = line.strip_prefix("Draft: ") {
+            r.draft = matches!(draft.trim(), "YES" | "True" | "true");
+        }
+    }
+    // In case the CLI doesn't say, drafts' releases do
+    if let Ok((_, _, release)) = split_nvr(&r.nvr) {
+        r.draft |= draft_id(release).is_some();
+    }
+    for (n, line) in rpm_lines(output) {
+        let (arch, name) = parse_rpm(dialect, n, line)?;
+        r.rpms.entry(arch).or_default().push(name);
+    }
+    Ok(r)
+}
+
+/// The build's task, from e.g. `Task: 57269515 build (rawhide, ...)`;
+/// imported builds have none.
+pub(super) fn task(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|l| l.strip_prefix("Task: "))
+        .and_then(|t| t.split_whitespace().next())
+        .and_then(|id| id.parse().ok())
+}
+
+/// A maven build's coordinates, from its `Maven groupId:`, `Maven
+/// artifactId:` and `Maven version:` lines; without artifacts yet.
+pub(super) fn maven(output: &str) -> Option<Maven> {
+    let field = |prefix: &str| {
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix(prefix))
+            .map(|v| v.trim().to_string())
+    };
+    Some(Maven {
+        group_id: field("Maven groupId: ")?,
+        artifact_id: field("Maven artifactId: ")?,
+        version: field("Maven version: ")?,
+        artifacts: Vec::new(),
+    })
+}
+
+/// A Windows build's `Windows build platform:`; without archives yet.
+pub(super) fn win(output: &str) -> Option<WinBuild> {
+    output
+        .lines()
+        .find_map(|l| l.strip_prefix("Windows build platform: "))
+        .map(|p| WinBuild {
+            platform: p.trim().to_string(),
+            archives: Vec::new(),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIGNED: &str = include_str!("../../example-koji-output.txt");
+
+    const PLAIN: &str = "\
+BUILD: rpm-ostree-2020.10-1.fc34 [1657648]
+State: COMPLETE
+Built by: walters
+Volume: DEFAULT
+Task: 57269515 build (rawhide, /rpms/rpm-ostree.git:89773b3c)
+Finished: Fri, 11 Dec 2020 19:31:15 UTC
+Tags: f34
+RPMs:
+/mnt/koji/packages/rpm-ostree/2020.10/1.fc34/src/rpm-ostree-2020.10-1.fc34.src.rpm
+/mnt/koji/packages/rpm-ostree/2020.10/1.fc34/x86_64/rpm-ostree-2020.10-1.fc34.x86_64.rpm
+";
+
+    const DRAFT: &str = "\
+BUILD: bash-5.2.26-3.fc41,draft_2401133 [2401133]
+Draft: YES
+State: COMPLETE
+Built by: svashisht
+Volume: DEFAULT
+Task: 115478203 build (rawhide, /rpms/bash.git:0c6e2bd0)
+Finished: Mon, 04 Mar 2024 10:12:44 UTC
+Tags:
+RPMs:
+/mnt/koji/packages/bash/5.2.26/3.fc41,draft_2401133/src/bash-5.2.26-3.fc41.src.rpm\tSignatures: none
+/mnt/koji/packages/bash/5.2.26/3.fc41,draft_2401133/x86_64/bash-5.2.26-3.fc41.x86_64.rpm\tSignatures: none
+Image archives:
+/mnt/koji/packages/bash/5.2.26/3.fc41,draft_2401133/images/bash.log
+";
+
+    #[test]
+    fn test_buildre() {
+        let s = "BUILD: rpm-ostree-2020.10-1.fc34 [1657648]";
+        assert!(BUILDRE.captures(s).is_some());
+    }
+
+    #[test]
+    fn test_dialects() -> Result<()> {
+        assert_eq!(Dialect::detect(PLAIN)?, Dialect::Plain);
+        assert_eq!(Dialect::detect(SIGNED)?, Dialect::Signed);
+        assert_eq!(Dialect::detect(DRAFT)?, Dialect::Draft);
+
+        let plain = buildinfo(PLAIN)?;
+        let signed = buildinfo(SIGNED)?;
+        assert_eq!(plain.nvr, signed.nvr);
+        assert_eq!(plain.rpms["src"], signed.rpms["src"]);
+        assert_eq!(plain.rpms["x86_64"].len(), 1);
+
+        let draft = buildinfo(DRAFT)?;
+        assert_eq!(draft.nvr, "bash-5.2.26-3.fc41,draft_2401133");
+        assert!(draft.draft);
+        assert!(!signed.draft);
+        assert_eq!(draft.rpms.len(), 2);
+        assert_eq!(draft.rpms["x86_64"], ["bash-5.2.26-3.fc41.x86_64.rpm"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for output in [
+            "",
+            "Build rpm-ostree-2020.10-1.fc34 (1657648)\nState: COMPLETE\n",
+            "BUILD: rpm-ostree-2020.10-1.fc34 [1657648]\nStatus: COMPLETE\n",
+            "BUILD: foo-1-1 [1]\nState: COMPLETE\nRPMs:\nfoo-1-1.x86_64.rpm\n",
+        ] {
+            let e = buildinfo(output).err().expect("unsupported").to_string();
+            assert!(e.starts_with("Unsupported koji CLI output"), "{}", e);
+        }
+        // The offending line is reported in full
+        let long = format!("/{}.rpm", "x".repeat(200));
+        let output = format!("BUILD: foo-1-1 [1]\nState: COMPLETE\nRPMs:\n{}\n", long);
+        let e = buildinfo(&output).err().expect("unsupported");
+        assert_eq!(
+            e.downcast_ref::<Unsupported>(),
+            Some(&Unsupported::at("expected ARCH/NAME.rpm", 3, &long))
+        );
+        assert!(e.to_string().ends_with(&format!("at line 4: {:?}", long)));
+    }
+
+    /// Every truncation of real output, and lines mangled in every way the
+    /// parser looks at, must give an error or a result rather than panic.
+    #[test]
+    fn test_no_panic() {
+        for output in [PLAIN, SIGNED, DRAFT] {
+            for (i, _) in output.char_indices() {
+                let _ = buildinfo(&output[..i]);
+            }
+            let lines: Vec<&str> = output.lines().collect();
+            for skip in 0..lines.len() {
+                let mut mangled = lines.clone();
+                mangled.remove(skip);
+                let _ = buildinfo(&mangled.join("\n"));
+            }
+        }
+        for junk in [
+            "BUILD: x [99999999999999999999999]\nState: \n",
+            "BUILD: \u{e9}-\u{e9}-\u{e9} [1]\nState: COMPLETE\nDraft: \nRPMs:\n/.rpm\n",
+            "BUILD: a-1-1 [1]\nState: COMPLETE\nRPMs:\n/a.rpm Signatures:\n//.rpm\n",
+            "\n\n\nBUILD: - [0]\r\nState: \r\nRPMs:\r\n\r\n",
+        ] {
+            let _ = buildinfo(junk);
+            let _ = task(junk);
+            let _ = maven(junk);
+            let _ = win(junk);
+        }
+    }
+
+    #[test]
+    fn test_maven() -> Result<()> {
+        let output = "\
+BUILD: commons-io-2.11.0-1 [1828000]
+State: COMPLETE
+Built by: jdoe
+Volume: DEFAULT
+Task: 40000001 maven (java-build, git://example.com/commons-io#abc)
+Finished: Tue, 01 Feb 2022 10:00:00 UTC
+Maven groupId: commons-io
+Maven artifactId: commons-io
+Maven version: 2.11.0
+Tags: java-candidate
+Maven archives:
+/mnt/koji/packages/commons-io/2.11.0/1/maven/commons-io/commons-io/2.11.0/commons-io-2.11.0.jar
+";
+        let r = buildinfo(output)?;
+        assert!(r.rpms.is_empty());
+        let m = maven(output).unwrap();
+        assert_eq!(m.group_id, "commons-io");
+        assert_eq!(m.artifact_id, "commons-io");
+        assert_eq!(m.version, "2.11.0");
+        assert!(maven(PLAIN).is_none());
+        assert!(win(output).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_win() -> Result<()> {
+        let output = "\
+BUILD: qpid-cpp-win-1.0-1 [1500000]
+State: COMPLETE
+Built by: jdoe
+Volume: DEFAULT
+Task: 30000001 winbuild (win-build, git://example.com/qpid-cpp#abc)
+Finished: Tue, 01 Feb 2022 10:00:00 UTC
+Windows build platform: w2k8r2 x64
+Tags: win-candidate
+Windows archives:
+/mnt/koji/packages/qpid-cpp-win/1.0/1/win/bin/qpid.zip
+";
+        let r = buildinfo(output)?;
+        assert!(r.rpms.is_empty());
+        assert_eq!(win(output).unwrap().platform, "w2k8r2 x64");
+        assert!(win(PLAIN).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_rpms() -> Result<()> {
+        let r = buildinfo("BUILD: foo-1-1 [1]\nState: FAILED\nTask: 2 build\n")?;
+        assert_eq!(r.state, "FAILED");
+        assert!(r.rpms.is_empty());
+        assert_eq!(
+            task("BUILD: foo-1-1 [1]\nState: FAILED\nTask: 2 build\n"),
+            Some(2)
+        );
+        assert_eq!(task(PLAIN), Some(57269515));
+        assert_eq!(
+            task("BUILD: foo-1-1 [1]\nState: COMPLETE\nTask: none\n"),
+            None
+        );
+        Ok(())
+    }
+}
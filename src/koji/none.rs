@@ -0,0 +1,53 @@
+//! Stand-ins for builds without the `cli-backend` feature: [`Hub`] fails
+//! every query, so a server built this way serves only what it has cached,
+//! imported or been told by webhooks.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use super::{Backend, Hub, HubError, KojiBuildInfo};
+
+pub type CallHook = fn(call: &str, start: Instant, ok: bool);
+
+pub fn set_call_hook(_hook: CallHook) {}
+
+pub fn set_call_timeout(_timeout: Option<Duration>) {}
+
+/// Nothing to wait for, with no koji processes.
+pub fn drain(_deadline: Instant) {}
+
+fn unavailable<T>() -> Result<T> {
+    Err(HubError::Unavailable("no koji backend is compiled in".to_string()).into())
+}
+
+impl Backend for Hub {
+    fn api_version(&self) -> Result<u32> {
+        unavailable()
+    }
+
+    fn get_koji_build(&self, _buildid: &str) -> Result<KojiBuildInfo> {
+        unavailable()
+    }
+
+    fn is_unchanged(&self, _cached: &KojiBuildInfo) -> Result<bool> {
+        unavailable()
+    }
+
+    fn list_recently_tagged(&self, _tag: &str, _count: usize) -> Result<Vec<String>> {
+        unavailable()
+    }
+
+    fn task_state(&self, _task_id: u64) -> Result<String> {
+        unavailable()
+    }
+
+    fn rpm_sha256(&self, _filename: &str) -> Result<Option<String>> {
+        unavailable()
+    }
+
+    fn call(&self, _method: &str, _args: &[Value], _kwargs: &Map<String, Value>) -> Result<Value> {
+        unavailable()
+    }
+}
@@ -0,0 +1,119 @@
+//! Koji build metadata as sane JSON.
+//!
+//! The [`koji`] module resolves builds, and is usable on its own; the
+//! `server` module serves the same over HTTP, either as the
+//! `koji-sane-json-api` binary or mounted in another actix-web application
+//! via `configure`.  With the `client` feature, `client` talks to a
+//! running instance.
+//!
+//! The `server`, `cli-backend`, `metrics`, `reporting`, `webhooks` and
+//! `download-proxy` features are on by default; library users may want
+//! `default-features = false`.
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod koji;
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "server")]
+mod about;
+#[cfg(feature = "server")]
+mod access_log;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod arches;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "download-proxy")]
+mod bundle;
+#[cfg(feature = "bus")]
+mod bus;
+#[cfg(feature = "server")]
+mod cache;
+#[cfg(feature = "server")]
+mod call;
+#[cfg(feature = "server")]
+mod cli;
+#[cfg(feature = "download-proxy")]
+mod compare;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod cors;
+#[cfg(feature = "server")]
+mod deleted;
+#[cfg(feature = "download-proxy")]
+mod download;
+#[cfg(feature = "server")]
+mod error;
+#[cfg(feature = "server")]
+mod export;
+#[cfg(feature = "server")]
+mod gating;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(feature = "server")]
+mod hubs;
+#[cfg(feature = "download-proxy")]
+mod licenses;
+#[cfg(feature = "server")]
+mod listen;
+#[cfg(feature = "server")]
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "server")]
+mod ndjson;
+#[cfg(feature = "server")]
+mod oidc;
+#[cfg(feature = "server")]
+mod prefetch;
+#[cfg(feature = "server")]
+mod proxy;
+#[cfg(feature = "server")]
+mod query;
+#[cfg(feature = "server")]
+mod ratelimit;
+#[cfg(feature = "server")]
+mod recover;
+#[cfg(feature = "server")]
+mod reload;
+#[cfg(feature = "download-proxy")]
+mod repo;
+#[cfg(feature = "reporting")]
+mod report;
+#[cfg(feature = "server")]
+mod request_id;
+#[cfg(feature = "download-proxy")]
+mod rpm;
+#[cfg(feature = "server")]
+mod schema;
+#[cfg(feature = "server")]
+mod shed;
+#[cfg(feature = "server")]
+mod systemd;
+#[cfg(feature = "server")]
+mod tag;
+#[cfg(feature = "telemetry")]
+mod telemetry;
+#[cfg(feature = "server")]
+mod timeout;
+#[cfg(feature = "server")]
+mod tls;
+#[cfg(feature = "server")]
+mod usage;
+#[cfg(feature = "server")]
+mod vulnerabilities;
+#[cfg(feature = "server")]
+mod watch;
+#[cfg(feature = "webhooks")]
+mod webhooks;
+
+#[cfg(feature = "server")]
+pub use server::configure;
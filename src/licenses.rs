@@ -0,0 +1,137 @@
+//! `GET /buildinfo/{id}/licenses`: the `License` tags of all of a build's
+//! RPMs, for compliance tooling working per build rather than per package.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use actix_web::error::ErrorNotFound;
+use actix_web::{get, web, HttpResponse};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde_derive::Serialize;
+
+use crate::deleted;
+use crate::download::Downloader;
+use crate::repo::read_headers;
+use crate::watch::Sources;
+
+/// RPMs whose headers are read at once.
+const CONCURRENCY: usize = 4;
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Licenses {
+    nvr: String,
+    /// Each distinct `License` tag.
+    expressions: Vec<String>,
+    /// The licenses they combine, each `WITH` its exception if any.
+    licenses: Vec<String>,
+    /// Each RPM's `License` tag, by file name.
+    rpms: BTreeMap<String, String>,
+}
+
+/// The licenses an expression such as `(MIT OR Apache-2.0) AND BSD-3-Clause`
+/// combines, also splitting Fedora's older `and`/`or` style.
+fn split_expression(expr: &str) -> Vec<String> {
+    let mut r = Vec::new();
+    let mut term: Vec<&str> = Vec::new();
+    let spaced = expr.replace('(', " ( ").replace(')', " ) ");
+    for token in spaced.split_whitespace() {
+        match token {
+            "AND" | "OR" | "and" | "or" | "(" | ")" => {
+                if !term.is_empty() {
+                    r.push(term.join(" "));
+                    term.clear();
+                }
+            }
+            t => term.push(t),
+        }
+    }
+    if !term.is_empty() {
+        r.push(term.join(" "));
+    }
+    r
+}
+
+fn summarize(nvr: &str, rpms: BTreeMap<String, String>) -> Licenses {
+    let expressions: BTreeSet<&String> = rpms.values().filter(|l| !l.is_empty()).collect();
+    let licenses: BTreeSet<String> = expressions
+        .iter()
+        .flat_map(|e| split_expression(e))
+        .collect();
+    Licenses {
+        nvr: nvr.to_string(),
+        expressions: expressions.into_iter().cloned().collect(),
+        licenses: licenses.into_iter().collect(),
+        rpms,
+    }
+}
+
+#[get("/buildinfo/{id}/licenses")]
+async fn build_licenses(
+    sources: Sources,
+    downloader: web::Data<Downloader>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    if !downloader.enabled() {
+        return Err(ErrorNotFound("Downloads are disabled"));
+    }
+    let info = sources.build(&path.0, false).await?;
+    deleted::check(&info)?;
+    let files: Vec<(&str, &str)> = info
+        .rpms
+        .iter()
+        .flat_map(|(a, files)| files.iter().map(move |f| (a.as_str(), f.as_str())))
+        .collect();
+    let (downloader, info) = (&downloader, &info);
+    let rpms: BTreeMap<String, String> = stream::iter(files)
+        .map(|(arch, filename)| async move {
+            let (pkg, _) = read_headers(downloader, info, arch, filename).await?;
+            Ok::<_, actix_web::Error>((filename.to_string(), pkg.license))
+        })
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+    Ok(HttpResponse::Ok().json(summarize(&info.nvr, rpms)))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(build_licenses);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_expression() {
+        assert_eq!(
+            split_expression("(MIT OR Apache-2.0) AND BSD-3-Clause"),
+            ["MIT", "Apache-2.0", "BSD-3-Clause"]
+        );
+        assert_eq!(
+            split_expression("GPL-2.0-only WITH Classpath-exception-2.0"),
+            ["GPL-2.0-only WITH Classpath-exception-2.0"]
+        );
+        assert_eq!(
+            split_expression("GPLv2+ and LGPLv2+"),
+            ["GPLv2+", "LGPLv2+"]
+        );
+        assert!(split_expression("").is_empty());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let rpms: BTreeMap<String, String> = [
+            ("foo-1-1.src.rpm", "MIT AND GPL-2.0-or-later"),
+            ("foo-1-1.x86_64.rpm", "MIT AND GPL-2.0-or-later"),
+            ("foo-libs-1-1.x86_64.rpm", "MIT"),
+            ("foo-doc-1-1.noarch.rpm", ""),
+        ]
+        .iter()
+        .map(|(f, l)| (f.to_string(), l.to_string()))
+        .collect();
+        let s = summarize("foo-1-1", rpms);
+        assert_eq!(s.expressions, ["MIT", "MIT AND GPL-2.0-or-later"]);
+        assert_eq!(s.licenses, ["GPL-2.0-or-later", "MIT"]);
+        assert_eq!(s.rpms.len(), 4);
+        assert_eq!(s.rpms["foo-libs-1-1.x86_64.rpm"], "MIT");
+    }
+}
@@ -0,0 +1,62 @@
+//! Opening listening sockets.
+
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+
+use crate::config::BindAddress;
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+fn open_tcp(addr: std::net::SocketAddr) -> Result<TcpListener> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    sock.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        // Make `::` accept IPv4 too regardless of the net.ipv6.bindv6only sysctl
+        sock.set_only_v6(false)?;
+    }
+    sock.bind(&addr.into())?;
+    sock.listen(1024)?;
+    Ok(sock.into())
+}
+
+fn open_unix(path: &std::path::Path) -> Result<UnixListener> {
+    // Clean up after a previous instance
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Open a listening socket for `addr`, using `default_port` for TCP
+/// addresses which don't specify one.
+pub(crate) fn open(addr: &BindAddress, default_port: u16) -> Result<Listener> {
+    let r = match addr {
+        BindAddress::Tcp(..) => {
+            open_tcp(addr.socket_addr(default_port).expect("tcp")).map(Listener::Tcp)
+        }
+        BindAddress::Unix(path) => open_unix(path).map(Listener::Unix),
+    };
+    r.with_context(|| format!("Binding {}", addr))
+}
+
+/// Sockets passed by systemd socket activation, if any.
+pub(crate) fn from_systemd() -> Result<Vec<Listener>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let mut r = Vec::new();
+    for i in 0..fds.len() {
+        if let Ok(Some(l)) = fds.take_tcp_listener(i) {
+            r.push(Listener::Tcp(l));
+        } else if let Some(l) = fds.take_unix_listener(i)? {
+            r.push(Listener::Unix(l));
+        }
+    }
+    Ok(r)
+}
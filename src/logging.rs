@@ -0,0 +1,69 @@
+//! Logging via `tracing`, in text or JSON.
+
+use anyhow::{Context, Result};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::config::{LogFormat, ServerConfig};
+
+/// The OTLP tracer spans are exported with, if any.
+#[cfg(feature = "telemetry")]
+pub(crate) type Tracer = opentelemetry::sdk::trace::Tracer;
+/// Without the `telemetry` feature there is never a tracer.
+#[cfg(not(feature = "telemetry"))]
+pub(crate) type Tracer = std::convert::Infallible;
+
+/// Allows changing the log filter at runtime.
+pub(crate) struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogHandle {
+    /// Replace the log filter, unless `RUST_LOG` is set.
+    pub(crate) fn set_level(&self, level: &str) -> Result<()> {
+        if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+            return Ok(());
+        }
+        let filter = EnvFilter::try_new(level).context("Invalid log level")?;
+        self.0.reload(filter).context("Reloading log filter")?;
+        Ok(())
+    }
+}
+
+/// Install the global subscriber.  `RUST_LOG` takes precedence over the
+/// configured level.  Records from crates using `log` are forwarded too.
+/// With `report`, errors are also sent to Sentry and other events recorded
+/// as breadcrumbs.
+pub(crate) fn init(
+    config: &ServerConfig,
+    tracer: Option<Tracer>,
+    report: bool,
+) -> Result<LogHandle> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(f) => f,
+        Err(_) => EnvFilter::try_new(&config.log_level).context("Invalid log level")?,
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let json = config.log_format == LogFormat::Json;
+    let subscriber = tracing_subscriber::registry().with(filter);
+    #[cfg(feature = "telemetry")]
+    let subscriber = subscriber.with(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t)));
+    #[cfg(not(feature = "telemetry"))]
+    let _ = tracer;
+    #[cfg(feature = "reporting")]
+    let subscriber = subscriber.with(if report {
+        Some(sentry_tracing::layer())
+    } else {
+        None
+    });
+    #[cfg(not(feature = "reporting"))]
+    let _ = report;
+    let subscriber = subscriber
+        .with(if json {
+            Some(fmt::layer().json())
+        } else {
+            None
+        })
+        .with(if json { None } else { Some(fmt::layer()) });
+    tracing::subscriber::set_global_default(subscriber)?;
+    tracing_log::LogTracer::init()?;
+    Ok(LogHandle(handle))
+}
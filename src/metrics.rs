@@ -0,0 +1,119 @@
+//! Prometheus metrics, served at `/metrics`.
+
+use std::time::Instant;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::{get, web, HttpResponse};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::cache::Cache;
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "koji_api_requests_total",
+        "HTTP requests by route and status code",
+        &["route", "status"]
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "koji_api_request_duration_seconds",
+        "HTTP request latency by route",
+        &["route"]
+    )
+    .unwrap();
+    static ref IN_FLIGHT: IntGauge = register_int_gauge!(
+        "koji_api_requests_in_flight",
+        "HTTP requests currently being served"
+    )
+    .unwrap();
+    static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "koji_api_errors_total",
+        "Errors by class: client (4xx), server (5xx) or backend (failed koji calls)",
+        &["class"]
+    )
+    .unwrap();
+    static ref BACKEND_DURATION: HistogramVec = register_histogram_vec!(
+        "koji_api_backend_duration_seconds",
+        "Duration of koji calls by command",
+        &["call"]
+    )
+    .unwrap();
+    pub(crate) static ref CACHE_HITS: IntCounter = register_int_counter!(
+        "koji_api_cache_hits_total",
+        "Cache lookups served from cache"
+    )
+    .unwrap();
+    pub(crate) static ref CACHE_MISSES: IntCounter =
+        register_int_counter!("koji_api_cache_misses_total", "Cache lookups which missed").unwrap();
+    static ref CACHE_BYTES: IntGauge = register_int_gauge!(
+        "koji_api_cache_bytes",
+        "Approximate size of cached responses"
+    )
+    .unwrap();
+    static ref CACHE_ENTRIES: IntGauge =
+        register_int_gauge!("koji_api_cache_entries", "Number of cached responses").unwrap();
+}
+
+/// Called by the request middleware before handling a request.
+pub(crate) fn request_started() -> Instant {
+    IN_FLIGHT.inc();
+    Instant::now()
+}
+
+/// Called by the request middleware with the outcome of a request.
+pub(crate) fn request_finished<B>(
+    start: Instant,
+    res: &Result<ServiceResponse<B>, actix_web::Error>,
+) {
+    IN_FLIGHT.dec();
+    let (route, status) = match res {
+        Ok(r) => (
+            r.request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string()),
+            r.status(),
+        ),
+        Err(e) => ("unmatched".to_string(), e.as_response_error().status_code()),
+    };
+    REQUESTS.with_label_values(&[&route, status.as_str()]).inc();
+    REQUEST_DURATION
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    if status.is_client_error() {
+        ERRORS.with_label_values(&["client"]).inc();
+    } else if status.is_server_error() {
+        ERRORS.with_label_values(&["server"]).inc();
+    }
+}
+
+/// Record a koji call; `call` is the subcommand or hub method.
+pub(crate) fn backend_call(call: &str, start: Instant, ok: bool) {
+    BACKEND_DURATION
+        .with_label_values(&[call])
+        .observe(start.elapsed().as_secs_f64());
+    if !ok {
+        ERRORS.with_label_values(&["backend"]).inc();
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(cache: web::Data<Cache>) -> HttpResponse {
+    CACHE_BYTES.set(cache.bytes() as i64);
+    CACHE_ENTRIES.set(cache.len() as i64);
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf)
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
@@ -0,0 +1,115 @@
+//! `/buildinfo/{id}?format=ndjson`: a build as newline-delimited JSON, its
+//! RPMs a line each, streamed as they are serialized.  Builds such as
+//! texlive list thousands of RPMs; this way neither side needs the whole
+//! listing as one document.
+
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::koji::{KojiBuildInfo, RpmNevra};
+use crate::schema;
+
+const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// A line per RPM after the first.
+#[derive(Serialize)]
+struct RpmLine {
+    arch: String,
+    filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nevra: Option<RpmNevra>,
+}
+
+/// The build without its RPMs but with `extra` fields (e.g. of paging),
+/// then each RPM; serialized only as the iterator is advanced.
+fn lines(
+    mut info: KojiBuildInfo,
+    extra: Option<Value>,
+) -> impl Iterator<Item = serde_json::Result<String>> {
+    let rpms = std::mem::take(&mut info.rpms);
+    let mut nevras = std::mem::take(&mut info.nevras);
+    let first = serde_json::to_value(&info).map(|mut v| {
+        if let Some(o) = v.as_object_mut() {
+            o.remove("rpms");
+            o.insert("schema-version".to_string(), schema::DEFAULT.into());
+            if let Some(Value::Object(extra)) = extra {
+                o.extend(extra);
+            }
+        }
+        v.to_string()
+    });
+    let rpms = rpms.into_iter().flat_map(|(arch, files)| {
+        files
+            .into_iter()
+            .map(move |filename| (arch.clone(), filename))
+    });
+    std::iter::once(first).chain(rpms.map(move |(arch, filename)| {
+        let nevra = nevras.remove(&filename);
+        serde_json::to_string(&RpmLine {
+            arch,
+            filename,
+            nevra,
+        })
+    }))
+}
+
+pub(crate) fn response(info: KojiBuildInfo, extra: Option<Value>) -> HttpResponse {
+    let body = futures::stream::iter(lines(info, extra).map(|line| {
+        line.map(|mut l| {
+            l.push('\n');
+            Bytes::from(l)
+        })
+        .map_err(actix_web::error::ErrorInternalServerError)
+    }));
+    HttpResponse::Ok()
+        .content_type(CONTENT_TYPE)
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() -> anyhow::Result<()> {
+        let mut info = KojiBuildInfo {
+            nvr: "foo-1-1".to_string(),
+            id: 1,
+            state: "COMPLETE".to_string(),
+            ..Default::default()
+        };
+        info.rpms.insert(
+            "x86_64".to_string(),
+            vec![
+                "foo-1-1.x86_64.rpm".to_string(),
+                "foo-libs-1-1.x86_64.rpm".to_string(),
+            ],
+        );
+        info.rpms
+            .insert("src".to_string(), vec!["foo-1-1.src.rpm".to_string()]);
+        let nevra = RpmNevra {
+            name: "foo-libs".to_string(),
+            epoch: Some(1),
+            version: "1".to_string(),
+            release: "1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        info.nevras.insert(nevra.filename(), nevra);
+
+        let extra = serde_json::json!({"truncated": false});
+        let lines = lines(info, Some(extra)).collect::<serde_json::Result<Vec<_>>>()?;
+        assert_eq!(lines.len(), 4);
+        let first: serde_json::Value = serde_json::from_str(&lines[0])?;
+        assert_eq!(first["nvr"], "foo-1-1");
+        assert_eq!(first["truncated"], false);
+        assert_eq!(first["schema-version"], 1);
+        assert!(first.get("rpms").is_none() && first.get("nevras").is_none());
+        assert_eq!(lines[1], r#"{"arch":"src","filename":"foo-1-1.src.rpm"}"#);
+        let libs: serde_json::Value = serde_json::from_str(&lines[3])?;
+        assert_eq!(libs["filename"], "foo-libs-1-1.x86_64.rpm");
+        assert_eq!(libs["nevra"]["epoch"], 1);
+        Ok(())
+    }
+}
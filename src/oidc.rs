@@ -0,0 +1,170 @@
+//! Bearer token (JWT) authentication against an OpenID Connect issuer.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_derive::{Deserialize, Serialize};
+
+/// How often the issuer's signing keys are re-fetched, as they rotate.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Signature algorithms accepted; all need the issuer's public RSA keys.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct OidcConfig {
+    /// Issuer URL, e.g. `https://id.fedoraproject.org/openidc`; bearer
+    /// tokens are not accepted if unset.
+    pub(crate) issuer: Option<String>,
+    /// Required `aud` claim, i.e. the client id of this service.
+    pub(crate) audience: Option<String>,
+    /// Claim holding the user's groups.
+    pub(crate) groups_claim: String,
+    /// Members of these groups get the admin role.
+    pub(crate) admin_groups: Vec<String>,
+    /// Roles of members of other groups.
+    pub(crate) group_roles: HashMap<String, String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            audience: None,
+            groups_claim: "groups".to_string(),
+            admin_groups: Vec::new(),
+            group_roles: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// The user a token was issued to.
+pub(crate) struct Identity {
+    pub(crate) name: String,
+    pub(crate) groups: Vec<String>,
+}
+
+pub(crate) struct Verifier {
+    config: OidcConfig,
+    issuer: String,
+    /// Signing keys by key id.
+    keys: RwLock<HashMap<String, DecodingKey<'static>>>,
+}
+
+impl Verifier {
+    pub(crate) fn new(config: &OidcConfig) -> Option<Self> {
+        let issuer = config.issuer.clone()?;
+        Some(Self {
+            config: config.clone(),
+            issuer,
+            keys: Default::default(),
+        })
+    }
+
+    fn fetch_keys(&self) -> Result<HashMap<String, DecodingKey<'static>>> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = ureq::get(&url)
+            .call()
+            .with_context(|| format!("Fetching {}", url))?
+            .into_json()?;
+        let jwks: Jwks = ureq::get(&discovery.jwks_uri)
+            .call()
+            .with_context(|| format!("Fetching {}", discovery.jwks_uri))?
+            .into_json()?;
+        let mut keys = HashMap::new();
+        for k in jwks.keys {
+            if let (Some(kid), "RSA", Some(n), Some(e)) = (k.kid, k.kty.as_str(), k.n, k.e) {
+                keys.insert(kid, DecodingKey::from_rsa_components(&n, &e).into_static());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Re-fetch the issuer's keys.
+    pub(crate) fn refresh(&self) -> Result<()> {
+        let keys = self.fetch_keys()?;
+        tracing::debug!("Loaded {} signing keys from {}", keys.len(), self.issuer);
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Start a thread keeping the signing keys current.
+    pub(crate) fn spawn_refresh(self: Arc<Self>) {
+        std::thread::spawn(move || loop {
+            if let Err(e) = self.refresh() {
+                tracing::warn!("Failed to fetch OIDC signing keys: {:#}", e);
+            }
+            std::thread::sleep(REFRESH_INTERVAL);
+        });
+    }
+
+    /// Validate a token, returning who it identifies.
+    pub(crate) fn verify(&self, token: &str) -> Result<Identity> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let kid = header.kid.ok_or_else(|| anyhow!("Token has no key id"))?;
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .get(&kid)
+            .ok_or_else(|| anyhow!("Unknown signing key {}", kid))?;
+        let mut validation = Validation {
+            algorithms: ALGORITHMS.to_vec(),
+            iss: Some(self.issuer.clone()),
+            ..Default::default()
+        };
+        if let Some(aud) = self.config.audience.as_deref() {
+            validation.set_audience(&[aud]);
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, key, &validation)?.claims;
+        let name = ["preferred_username", "sub"]
+            .iter()
+            .find_map(|c| claims.get(*c).and_then(|v| v.as_str()))
+            .ok_or_else(|| anyhow!("Token has no subject"))?;
+        let groups = claims
+            .get(&self.config.groups_claim)
+            .and_then(|v| v.as_array())
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|g| g.as_str())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Identity {
+            name: name.to_string(),
+            groups,
+        })
+    }
+}
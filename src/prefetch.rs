@@ -0,0 +1,83 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web;
+
+use crate::cache::{Cache, NvrMap};
+use crate::config::{CacheClass, PrefetchConfig, RefreshConfig};
+use crate::health::Readiness;
+use crate::koji::{Backend, Hub};
+use crate::tag;
+
+fn prefetch_tag(
+    hub: &Hub,
+    cache: &Cache,
+    nvrs: &NvrMap,
+    tag: &str,
+    count: usize,
+) -> anyhow::Result<()> {
+    for nvr in hub.list_recently_tagged(tag, count)? {
+        if cache.contains(&nvr) {
+            continue;
+        }
+        let info = hub.get_koji_build(&nvr)?;
+        cache.store_build(nvrs, &info)?;
+    }
+    Ok(())
+}
+
+/// Start a thread which keeps the most recently tagged builds of the
+/// configured tags warm in the cache, marking the cache ready after the
+/// first pass.  Does nothing if no tags are configured.
+pub(crate) fn spawn(
+    config: PrefetchConfig,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+    readiness: web::Data<Readiness>,
+) {
+    if config.tags.is_empty() {
+        return;
+    }
+    std::thread::spawn(move || loop {
+        let hub = hub.read().unwrap().clone();
+        for tag in config.tags.iter() {
+            if let Err(e) = prefetch_tag(&hub, &cache, &nvrs, tag, config.count) {
+                tracing::warn!(%tag, "Failed to prefetch tag: {:#}", e);
+            }
+        }
+        readiness.set_cache_warm();
+        std::thread::sleep(Duration::from_secs(config.interval));
+    });
+}
+
+/// Start a thread which re-fetches the most popular mutable cache entries,
+/// builds and tag listings alike, before they expire, so clients don't all
+/// see a slow miss at the TTL boundary.
+pub(crate) fn spawn_refresh(
+    config: RefreshConfig,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+) {
+    if config.count == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(config.interval);
+    std::thread::spawn(move || loop {
+        let hub = hub.read().unwrap().clone();
+        for (key, class) in cache.hot_expiring(config.count, interval) {
+            let r = match class {
+                CacheClass::Tag => tag::refresh(&hub, &cache, &key),
+                _ => hub.get_koji_build(&key).and_then(|info| {
+                    cache.store_build(&nvrs, &info)?;
+                    Ok(())
+                }),
+            };
+            if let Err(e) = r {
+                tracing::warn!(%key, "Failed to refresh: {:#}", e);
+            }
+        }
+        std::thread::sleep(interval);
+    });
+}
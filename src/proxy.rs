@@ -0,0 +1,169 @@
+//! Determining the real client address behind trusted reverse proxies.
+
+use std::net::IpAddr;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+
+/// Networks whose `Forwarded` / `X-Forwarded-For` headers we believe.
+#[derive(Debug, Default)]
+pub(crate) struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parse a list of addresses or CIDR networks, e.g. `127.0.0.1` or `10.0.0.0/8`.
+    pub(crate) fn new(specs: &[String]) -> Result<Self> {
+        let nets = specs
+            .iter()
+            .map(|s| {
+                s.parse::<IpNet>()
+                    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("Invalid trusted proxy {}", s))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(nets))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|n| n.contains(&ip))
+    }
+
+    /// The client address for this request.  Connections over a Unix socket
+    /// can only come from a local proxy, so are always trusted.
+    pub(crate) fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        self.resolve(req.peer_addr().map(|a| a.ip()), req.headers())
+    }
+
+    /// Walk the forwarding chain from the nearest hop outwards, stopping at
+    /// the first address not belonging to a trusted proxy.
+    fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        if let Some(peer) = peer {
+            if !self.is_trusted(peer) {
+                return Some(peer);
+            }
+        }
+        let mut client = peer;
+        for hop in forwarded_chain(headers).into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // Obfuscated or unparseable; can't look further
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Parse an address from a `for=` parameter or `X-Forwarded-For` element,
+/// which may be quoted and carry a port.
+fn parse_node(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Some(rest) = s.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    // IPv4 with port
+    s.rsplitn(2, ':').nth(1)?.parse().ok()
+}
+
+/// The client addresses recorded by proxies, in order from the original
+/// client to the nearest proxy.  `Forwarded` (RFC 7239) takes precedence
+/// over `X-Forwarded-For`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(actix_web::http::header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|elt| {
+                elt.split(';')
+                    .filter_map(|p| {
+                        let (k, v) = p.trim().split_at(p.trim().find('=')?);
+                        Some((k, &v[1..]))
+                    })
+                    .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, v)| parse_node(v))
+            })
+            .collect();
+    }
+    values(actix_web::http::header::HeaderName::from_static(
+        "x-forwarded-for",
+    ))
+    .iter()
+    .map(|v| parse_node(v))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(h: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut m = HeaderMap::new();
+        for (k, v) in h {
+            m.append(HeaderName::from_static(k), HeaderValue::from_static(v));
+        }
+        m
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(
+            parse_node("192.0.2.43"),
+            Some("192.0.2.43".parse().unwrap())
+        );
+        assert_eq!(
+            parse_node("192.0.2.43:4711"),
+            Some("192.0.2.43".parse().unwrap())
+        );
+        assert_eq!(
+            parse_node("\"[2001:db8:cafe::17]:4711\""),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+        assert_eq!(
+            parse_node("2001:db8::1"),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let t = TrustedProxies::new(&["10.0.0.0/8".into(), "127.0.0.1".into()])?;
+        let proxy = Some("10.1.2.3".parse()?);
+        let h = headers(&[("x-forwarded-for", "203.0.113.7, 10.9.9.9")]);
+        assert_eq!(t.resolve(proxy, &h), Some("203.0.113.7".parse()?));
+        // Untrusted peers can't spoof their address
+        let h = headers(&[("x-forwarded-for", "203.0.113.7")]);
+        let peer = Some("198.51.100.1".parse()?);
+        assert_eq!(t.resolve(peer, &h), peer);
+        // A client-supplied prefix beyond an untrusted hop is ignored
+        let h = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7")]);
+        assert_eq!(t.resolve(proxy, &h), Some("203.0.113.7".parse()?));
+        let h = headers(&[
+            ("forwarded", "for=\"[2001:db8::1]:4711\";proto=https"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(t.resolve(proxy, &h), Some("2001:db8::1".parse()?));
+        // Unix socket peer
+        let h = headers(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(t.resolve(None, &h), Some("203.0.113.7".parse()?));
+        Ok(())
+    }
+}
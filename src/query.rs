@@ -0,0 +1,66 @@
+//! One-shot lookups from the command line, e.g.
+//! `koji-sane-json-api query buildinfo rpm-ostree-2020.10-1.fc34 --json`.
+
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::cli::Query;
+use crate::koji::{Backend, Hub, KojiBuildInfo};
+
+/// A short human-readable description of a build.
+fn summary(info: &KojiBuildInfo) -> String {
+    let mut s = format!(
+        "{} [{}]\nState: {}\nURL: {}\n",
+        info.nvr, info.id, info.state, info.kojipkgs_url_prefix
+    );
+    for (arch, rpms) in info.rpms.iter() {
+        s.push_str(&format!("{}:\n", arch));
+        for rpm in rpms {
+            s.push_str(&format!("  {}\n", rpm));
+        }
+    }
+    s
+}
+
+/// Run `query` against `hub`, printing the result to stdout.
+pub(crate) fn run(query: &Query, hub: &Hub) -> Result<()> {
+    let out = match query {
+        Query::Buildinfo { buildid, json } => {
+            let info = hub.get_koji_build(buildid)?;
+            if *json {
+                format!("{}\n", serde_json::to_string_pretty(&info)?)
+            } else {
+                summary(&info)
+            }
+        }
+    };
+    std::io::stdout().write_all(out.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut info = KojiBuildInfo {
+            nvr: "foo-1.0-1.fc34".to_string(),
+            id: 42,
+            state: "COMPLETE".to_string(),
+            kojipkgs_url_prefix: "https://kojipkgs.example.com/packages/foo/1.0/1.fc34".to_string(),
+            ..Default::default()
+        };
+        info.rpms
+            .entry("src".to_string())
+            .or_default()
+            .push("foo-1.0-1.fc34.src.rpm".to_string());
+        assert_eq!(
+            summary(&info),
+            "foo-1.0-1.fc34 [42]\nState: COMPLETE\n\
+             URL: https://kojipkgs.example.com/packages/foo/1.0/1.fc34\n\
+             src:\n  foo-1.0-1.fc34.src.rpm\n"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Per-client token bucket rate limiting.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::error;
+use crate::proxy::TrustedProxies;
+use crate::request_id::RequestId;
+use crate::tls::{self, ClientIdentity};
+
+/// Beyond this many tracked clients, idle buckets are dropped.
+const MAX_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct Limit {
+    /// Sustained requests per second per client; 0 means unlimited.
+    pub(crate) rate: f64,
+    /// Requests a client may make in a burst above the rate.
+    pub(crate) burst: u32,
+}
+
+/// Limits per class of route.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct RateLimitConfig {
+    /// `/buildinfo`, `/tag` and others which may cost koji calls.
+    pub(crate) build: Limit,
+    pub(crate) admin: Limit,
+    /// Everything else, e.g. `/health` and `/metrics`.
+    pub(crate) other: Limit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RouteClass {
+    Build,
+    Admin,
+    Other,
+}
+
+impl RouteClass {
+    /// Classify a request path relative to the base path.
+    fn of(path: &str) -> Self {
+        // Routes under /hubs/{name} are those of the main hub
+        let path = path
+            .strip_prefix("/hubs/")
+            .and_then(|p| p.find('/').map(|i| &p[i..]))
+            .unwrap_or(path);
+        if path.starts_with("/buildinfo/")
+            || path.starts_with("/download/")
+            || path.starts_with("/call/")
+            || path.starts_with("/tag/")
+            || path.starts_with("/compare/")
+        {
+            RouteClass::Build
+        } else if path.starts_with("/admin/") {
+            RouteClass::Admin
+        } else {
+            RouteClass::Other
+        }
+    }
+
+    /// Classify a request; `prefix` is the base path routes are mounted under.
+    pub(crate) fn of_request(prefix: &str, req: &ServiceRequest) -> Self {
+        Self::of(
+            req.path()
+                .strip_prefix(prefix)
+                .unwrap_or_else(|| req.path()),
+        )
+    }
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            updated: now,
+        }
+    }
+
+    /// Take a token, or return how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let burst = self.limit.burst.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.rate,
+            ))
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.limit.rate >= self.limit.burst.max(1) as f64
+    }
+}
+
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(RouteClass, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    fn limit(&self, class: RouteClass) -> Limit {
+        match class {
+            RouteClass::Build => self.config.build,
+            RouteClass::Admin => self.config.admin,
+            RouteClass::Other => self.config.other,
+        }
+    }
+
+    /// Count a request by `client`, returning how long it should wait if it
+    /// is over the limit.
+    fn check_at(
+        &self,
+        class: RouteClass,
+        limit: Limit,
+        client: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if limit.rate <= 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            buckets.retain(|_, b| !b.is_full(now));
+        }
+        buckets
+            .entry((class, client.to_string()))
+            .or_insert_with(|| Bucket::new(limit, now))
+            .take(now)
+    }
+
+    /// Check a request.  Clients with an API key are identified by it and
+    /// may have their own limit; others by their TLS client certificate if
+    /// they presented one, else by address.
+    pub(crate) fn check(&self, class: RouteClass, req: &ServiceRequest) -> Result<(), Duration> {
+        let (client, limit) = match auth::principal(req.request()) {
+            Some(p) => (
+                format!("key:{}", p.name),
+                p.rate_limit.unwrap_or_else(|| self.limit(class)),
+            ),
+            None => match tls::client_identity(req.request()) {
+                Some(ClientIdentity(name)) => (format!("cert:{}", name), self.limit(class)),
+                None => (
+                    req.app_data::<web::Data<TrustedProxies>>()
+                        .and_then(|p| p.client_ip(req.request()))
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_default(),
+                    self.limit(class),
+                ),
+            },
+        };
+        self.check_at(class, limit, &client, Instant::now())
+    }
+}
+
+/// The response for a client over its limit.
+pub(crate) fn too_many_requests(retry_after: Duration, id: &RequestId) -> HttpResponse {
+    let secs = retry_after.as_secs_f64().ceil() as u64;
+    tracing::debug!(retry_after = secs, "Rate limited");
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, secs.max(1).to_string()))
+        .json(error::body("Rate limit exceeded", Some(id)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            build: Limit {
+                rate: 2.0,
+                burst: 3,
+            },
+            ..Default::default()
+        });
+        let check = |class, client, now| limiter.check_at(class, limiter.limit(class), client, now);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(check(RouteClass::Build, "a", now).is_ok());
+        }
+        let wait = check(RouteClass::Build, "a", now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // Other clients and unlimited classes are unaffected
+        assert!(check(RouteClass::Build, "b", now).is_ok());
+        assert!(check(RouteClass::Other, "a", now).is_ok());
+        let later = now + Duration::from_millis(500);
+        assert!(check(RouteClass::Build, "a", later).is_ok());
+        assert!(check(RouteClass::Build, "a", later).is_err());
+    }
+
+    #[test]
+    fn test_route_class() {
+        assert_eq!(RouteClass::of("/buildinfo/foo-1-1"), RouteClass::Build);
+        assert_eq!(
+            RouteClass::of("/download/foo-1-1/src/foo-1-1.src.rpm"),
+            RouteClass::Build
+        );
+        assert_eq!(RouteClass::of("/call/getBuildTarget"), RouteClass::Build);
+        assert_eq!(
+            RouteClass::of("/tag/f34-build/external-repos"),
+            RouteClass::Build
+        );
+        assert_eq!(RouteClass::of("/compare/deep"), RouteClass::Build);
+        assert_eq!(RouteClass::of("/admin/reload"), RouteClass::Admin);
+        assert_eq!(RouteClass::of("/health"), RouteClass::Other);
+        assert_eq!(
+            RouteClass::of("/hubs/centos-stream/buildinfo/foo-1-1"),
+            RouteClass::Build
+        );
+        assert_eq!(RouteClass::of("/hubs"), RouteClass::Other);
+    }
+}
@@ -0,0 +1,58 @@
+//! Turning panics into error responses rather than dropped connections.
+
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::{HttpRequest, HttpResponse};
+use futures::FutureExt;
+
+use crate::error;
+use crate::request_id::RequestId;
+
+/// The message a panic was raised with, if it was a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown cause"
+    }
+}
+
+/// Handle `req`, responding 500 if the handler panics.  The panic itself
+/// has already been printed (and reported) by the panic hook.
+pub(crate) async fn catch_panic<F>(
+    req: HttpRequest,
+    id: &RequestId,
+    fut: F,
+) -> actix_web::Result<ServiceResponse>
+where
+    F: Future<Output = actix_web::Result<ServiceResponse>>,
+{
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(res) => res,
+        Err(payload) => {
+            tracing::error!("Handler panicked: {}", panic_message(&*payload));
+            Ok(ServiceResponse::new(
+                req,
+                HttpResponse::InternalServerError().json(error::body("Internal error", Some(id))),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("bad {}", "rpm")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "bad rpm");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "unknown cause");
+    }
+}
@@ -0,0 +1,98 @@
+//! Reloading configuration at runtime without restarting.
+//!
+//! Only some settings can change on the fly: the log level, cache TTLs,
+//! the hub, the koji call timeout and the contents of the TLS certificate
+//! files.  Others (e.g. the
+//! listening address) require a restart.
+
+use std::sync::{Arc, RwLock};
+
+use actix_web::web;
+use anyhow::Result;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::audit::AuditLog;
+use crate::cache::Cache;
+use crate::cli::Opt;
+use crate::config::Config;
+use crate::koji::{self, Hub};
+use crate::logging::LogHandle;
+use crate::tls::CertResolver;
+
+pub(crate) struct Reloader {
+    opt: Opt,
+    log: LogHandle,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    tls: Option<Arc<CertResolver>>,
+    /// The configuration in use, for `/admin/config`.
+    effective: RwLock<serde_json::Value>,
+}
+
+impl Reloader {
+    pub(crate) fn new(
+        opt: Opt,
+        log: LogHandle,
+        hub: web::Data<RwLock<Hub>>,
+        cache: web::Data<Cache>,
+        tls: Option<Arc<CertResolver>>,
+        effective: serde_json::Value,
+    ) -> Self {
+        Self {
+            opt,
+            log,
+            hub,
+            cache,
+            tls,
+            effective: RwLock::new(effective),
+        }
+    }
+
+    /// The configuration in effect: as at startup, with any reloaded
+    /// settings updated.
+    pub(crate) fn effective_config(&self) -> serde_json::Value {
+        self.effective.read().unwrap().clone()
+    }
+
+    /// Re-read configuration from all sources and apply reloadable settings.
+    /// In-flight requests keep using the settings they started with.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let config = Config::new(&self.opt)?;
+        if let Some(tls) = self.tls.as_ref() {
+            tls.reload()?;
+        }
+        self.log.set_level(&config.server.log_level)?;
+        self.cache.set_ttls(config.cache.ttl);
+        koji::set_call_timeout(config.backend.call_timeout());
+        {
+            let mut effective = self.effective.write().unwrap();
+            effective["server"]["log-level"] = config.server.log_level.clone().into();
+            effective["cache"]["ttl"] = serde_json::to_value(&config.cache.ttl)?;
+            effective["hub"] = serde_json::to_value(&config.hub)?;
+            effective["backend"]["call-timeout"] = config.backend.call_timeout.into();
+        }
+        *self.hub.write().unwrap() = config.hub;
+        tracing::info!("Reloaded configuration");
+        Ok(())
+    }
+}
+
+/// Reload configuration whenever we receive SIGHUP.
+pub(crate) fn spawn_sighup_handler(
+    reloader: web::Data<Reloader>,
+    audit: web::Data<AuditLog>,
+) -> std::io::Result<()> {
+    let mut signals = Signals::new(&[SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let result = reloader.reload();
+            if let Err(e) = result.as_ref() {
+                tracing::error!("Failed to reload configuration: {:#}", e);
+            }
+            let params = serde_json::json!({ "signal": "SIGHUP" });
+            audit.record("signal".into(), None, None, "reload", params, &result);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,566 @@
+//! `GET /buildinfo/{id}/repo/{arch}/...`: a completed build's RPMs as a dnf
+//! repository, so that e.g.
+//! `dnf --repofrompath=foo,https://.../buildinfo/foo-1-1/repo/x86_64 install foo`
+//! installs exactly that build.  The metadata is generated from the RPMs'
+//! headers on first use, and packages are served as by `/download`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+
+use actix_web::error::{ErrorBadGateway, ErrorConflict, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::deleted;
+use crate::download::{self, Downloader};
+use crate::koji::KojiBuildInfo;
+use crate::rpm::{self, Dependency, FileKind, Package};
+use crate::watch::Sources;
+
+/// Enough of most RPMs to cover their headers in one request.
+const HEAD_GUESS: u64 = 256 * 1024;
+/// Packages described at once.
+const CONCURRENCY: usize = 4;
+/// Generated repositories kept in memory.
+const KEPT: usize = 64;
+
+lazy_static! {
+    static ref GENERATED: Mutex<Generated> = Mutex::new(Generated::default());
+}
+
+/// Recently generated repositories by build id and arch, oldest first.
+#[derive(Default)]
+struct Generated {
+    repos: HashMap<(u64, String), Arc<Repodata>>,
+    order: VecDeque<(u64, String)>,
+}
+
+impl Generated {
+    fn get(&self, id: u64, arch: &str) -> Option<Arc<Repodata>> {
+        self.repos.get(&(id, arch.to_string())).cloned()
+    }
+
+    fn insert(&mut self, id: u64, arch: &str, repo: Arc<Repodata>) {
+        let key = (id, arch.to_string());
+        if self.repos.insert(key.clone(), repo).is_none() {
+            self.order.push_back(key);
+        }
+        if self.order.len() > KEPT {
+            if let Some(old) = self.order.pop_front() {
+                self.repos.remove(&old);
+            }
+        }
+    }
+}
+
+/// One RPM in the repository.
+struct Entry {
+    filename: String,
+    pkg: Package,
+    size: u64,
+    sha256: String,
+}
+
+/// The files under `repodata/`.
+struct Repodata {
+    repomd: String,
+    /// Compressed metadata by filename.
+    files: HashMap<&'static str, web::Bytes>,
+}
+
+/// The koji arches of RPMs in the repository for `arch`: those built for
+/// it, plus noarch ones that install there.
+fn arches<'a>(info: &'a KojiBuildInfo, arch: &'a str) -> Vec<&'a str> {
+    let mut r = vec![arch];
+    if arch != "src" && arch != "noarch" {
+        r.push("noarch");
+    }
+    r.retain(|a| info.rpms.contains_key(*a));
+    r
+}
+
+/// Escape text for XML, dropping characters it can't contain at all.
+fn escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&apos;"),
+            '\t' | '\n' | '\r' => r.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+/// Split `[epoch:]version[-release]`.
+fn split_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, vr) = match evr.split_once(':') {
+        Some((e, vr)) => (e, vr),
+        None => ("0", evr),
+    };
+    match vr.rsplit_once('-') {
+        Some((v, r)) => (epoch, v, Some(r)),
+        None => (epoch, vr, None),
+    }
+}
+
+/// Files listed in primary.xml as well as filelists.xml, as createrepo
+/// does, so common file dependencies resolve without the latter.
+fn is_primary_file(path: &str) -> bool {
+    path.starts_with("/etc/") || path.contains("bin/") || path == "/usr/lib/sendmail"
+}
+
+fn write_files<'a>(out: &mut String, files: impl Iterator<Item = &'a (String, FileKind)>) {
+    for (path, kind) in files {
+        let ty = match kind {
+            FileKind::File => "",
+            FileKind::Dir => " type=\"dir\"",
+            FileKind::Ghost => " type=\"ghost\"",
+        };
+        let _ = writeln!(out, "    <file{}>{}</file>", ty, escape(path));
+    }
+}
+
+fn write_dependencies(out: &mut String, kind: &str, deps: &[Dependency]) {
+    let deps: Vec<_> = deps
+        .iter()
+        .filter(|d| !d.name.starts_with("rpmlib("))
+        .collect();
+    if deps.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "    <rpm:{}>", kind);
+    for d in deps {
+        let _ = write!(out, "      <rpm:entry name=\"{}\"", escape(&d.name));
+        if let Some(flags) = d.flags {
+            let (epoch, ver, rel) = split_evr(&d.evr);
+            let _ = write!(
+                out,
+                " flags=\"{}\" epoch=\"{}\" ver=\"{}\"",
+                flags,
+                escape(epoch),
+                escape(ver)
+            );
+            if let Some(rel) = rel {
+                let _ = write!(out, " rel=\"{}\"", escape(rel));
+            }
+        }
+        if d.pre && kind == "requires" {
+            out.push_str(" pre=\"1\"");
+        }
+        out.push_str("/>\n");
+    }
+    let _ = writeln!(out, "    </rpm:{}>", kind);
+}
+
+fn primary_xml(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <metadata xmlns=\"http://linux.duke.edu/metadata/common\" \
+         xmlns:rpm=\"http://linux.duke.edu/metadata/rpm\" packages=\"{}\">",
+        entries.len()
+    );
+    for e in entries {
+        let p = &e.pkg;
+        let _ = writeln!(out, "<package type=\"rpm\">");
+        let _ = writeln!(out, "  <name>{}</name>", escape(&p.name));
+        let _ = writeln!(out, "  <arch>{}</arch>", escape(&p.arch));
+        let _ = writeln!(
+            out,
+            "  <version epoch=\"{}\" ver=\"{}\" rel=\"{}\"/>",
+            p.epoch,
+            escape(&p.version),
+            escape(&p.release)
+        );
+        let _ = writeln!(
+            out,
+            "  <checksum type=\"sha256\" pkgid=\"YES\">{}</checksum>",
+            e.sha256
+        );
+        let _ = writeln!(out, "  <summary>{}</summary>", escape(&p.summary));
+        let _ = writeln!(
+            out,
+            "  <description>{}</description>",
+            escape(&p.description)
+        );
+        let _ = writeln!(out, "  <packager>{}</packager>", escape(&p.packager));
+        let _ = writeln!(out, "  <url>{}</url>", escape(&p.url));
+        let _ = writeln!(out, "  <time file=\"{0}\" build=\"{0}\"/>", p.build_time);
+        let _ = writeln!(
+            out,
+            "  <size package=\"{}\" installed=\"{}\" archive=\"{}\"/>",
+            e.size, p.installed_size, p.archive_size
+        );
+        let _ = writeln!(out, "  <location href=\"{}\"/>", escape(&e.filename));
+        let _ = writeln!(out, "  <format>");
+        let _ = writeln!(out, "    <rpm:license>{}</rpm:license>", escape(&p.license));
+        let _ = writeln!(out, "    <rpm:vendor>{}</rpm:vendor>", escape(&p.vendor));
+        let _ = writeln!(out, "    <rpm:group>{}</rpm:group>", escape(&p.group));
+        let _ = writeln!(
+            out,
+            "    <rpm:buildhost>{}</rpm:buildhost>",
+            escape(&p.buildhost)
+        );
+        let _ = writeln!(
+            out,
+            "    <rpm:sourcerpm>{}</rpm:sourcerpm>",
+            escape(&p.sourcerpm)
+        );
+        let _ = writeln!(
+            out,
+            "    <rpm:header-range start=\"{}\" end=\"{}\"/>",
+            p.header_range.0, p.header_range.1
+        );
+        write_dependencies(&mut out, "provides", &p.provides);
+        write_dependencies(&mut out, "requires", &p.requires);
+        write_dependencies(&mut out, "conflicts", &p.conflicts);
+        write_dependencies(&mut out, "obsoletes", &p.obsoletes);
+        write_files(&mut out, p.files.iter().filter(|f| is_primary_file(&f.0)));
+        let _ = writeln!(out, "  </format>\n</package>");
+    }
+    out.push_str("</metadata>\n");
+    out
+}
+
+fn filelists_xml(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <filelists xmlns=\"http://linux.duke.edu/metadata/filelists\" packages=\"{}\">",
+        entries.len()
+    );
+    for e in entries {
+        let p = &e.pkg;
+        let _ = writeln!(
+            out,
+            "<package pkgid=\"{}\" name=\"{}\" arch=\"{}\">",
+            e.sha256,
+            escape(&p.name),
+            escape(&p.arch)
+        );
+        let _ = writeln!(
+            out,
+            "  <version epoch=\"{}\" ver=\"{}\" rel=\"{}\"/>",
+            p.epoch,
+            escape(&p.version),
+            escape(&p.release)
+        );
+        write_files(&mut out, p.files.iter());
+        out.push_str("</package>\n");
+    }
+    out.push_str("</filelists>\n");
+    out
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+/// Compress each `(type, xml)` and describe them all in repomd.xml.
+/// `timestamp` is fixed per build, so the result is too.
+fn repodata(metadata: &[(&'static str, String)], timestamp: u64) -> std::io::Result<Repodata> {
+    let mut repomd = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <repomd xmlns=\"http://linux.duke.edu/metadata/repo\" \
+         xmlns:rpm=\"http://linux.duke.edu/metadata/rpm\">\n  \
+         <revision>{}</revision>\n",
+        timestamp
+    );
+    let mut files = HashMap::new();
+    for (ty, xml) in metadata {
+        let gz = gzip(xml.as_bytes())?;
+        let name = match *ty {
+            "primary" => "primary.xml.gz",
+            "filelists" => "filelists.xml.gz",
+            _ => unreachable!("unknown metadata type {}", ty),
+        };
+        let _ = write!(
+            repomd,
+            "  <data type=\"{}\">\n    \
+             <checksum type=\"sha256\">{}</checksum>\n    \
+             <open-checksum type=\"sha256\">{}</open-checksum>\n    \
+             <location href=\"repodata/{}\"/>\n    \
+             <timestamp>{}</timestamp>\n    \
+             <size>{}</size>\n    \
+             <open-size>{}</open-size>\n  \
+             </data>\n",
+            ty,
+            hex::encode(Sha256::digest(&gz)),
+            hex::encode(Sha256::digest(xml.as_bytes())),
+            name,
+            timestamp,
+            gz.len(),
+            xml.len()
+        );
+        files.insert(name, gz.into());
+    }
+    repomd.push_str("</repomd>\n");
+    Ok(Repodata { repomd, files })
+}
+
+/// An RPM's SHA-256: what koji recorded, or else computed by fetching it.
+async fn sha256(
+    downloader: &Downloader,
+    sources: &Sources,
+    info: &KojiBuildInfo,
+    arch: &str,
+    filename: &str,
+) -> actix_web::Result<String> {
+    if let Some(sha256) = download::rpm_sha256(sources, filename).await? {
+        return Ok(sha256);
+    }
+    let (_, mut body) = downloader.fetch(sources, info, arch, filename).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.next().await {
+        hasher.update(&chunk.map_err(ErrorBadGateway)?);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Parse an RPM's headers, reading no more of it than they take; returns
+/// them with the RPM's whole size.
+pub(crate) async fn read_headers(
+    downloader: &Downloader,
+    info: &KojiBuildInfo,
+    arch: &str,
+    filename: &str,
+) -> actix_web::Result<(Package, u64)> {
+    let invalid = |e: anyhow::Error| ErrorBadGateway(format!("Reading {}: {}", filename, e));
+    let mut want = HEAD_GUESS;
+    let (head, size) = loop {
+        let (head, size) = downloader.head(info, arch, filename, want).await?;
+        match rpm::needed(&head).map_err(invalid)? {
+            // Headers this big are rare, but happen with many files
+            Some(n) if n as u64 > want && n as u64 <= size => want = n as u64,
+            _ => break (head, size),
+        }
+    };
+    let mut pkg = rpm::parse(&head).map_err(invalid)?;
+    // Source RPMs' headers carry the arch they were built on
+    if arch == "src" {
+        pkg.arch = "src".to_string();
+    }
+    Ok((pkg, size))
+}
+
+/// Read enough of an RPM to describe it.
+async fn describe(
+    downloader: &Downloader,
+    sources: &Sources,
+    info: &KojiBuildInfo,
+    arch: &str,
+    filename: &str,
+) -> actix_web::Result<Entry> {
+    let (pkg, size) = read_headers(downloader, info, arch, filename).await?;
+    let sha256 = sha256(downloader, sources, info, arch, filename).await?;
+    Ok(Entry {
+        filename: filename.to_string(),
+        pkg,
+        size,
+        sha256,
+    })
+}
+
+/// Generate the metadata for a build's repository.
+async fn generate(
+    downloader: &Downloader,
+    sources: &Sources,
+    info: &KojiBuildInfo,
+    arches: &[&str],
+) -> actix_web::Result<Repodata> {
+    let files: Vec<(&str, &str)> = arches
+        .iter()
+        .flat_map(|a| info.rpms[*a].iter().map(move |f| (*a, f.as_str())))
+        .collect();
+    tracing::debug!(nvr = %info.nvr, rpms = files.len(), "Generating repodata");
+    let entries: Vec<Entry> = stream::iter(files)
+        .map(|(arch, filename)| describe(downloader, sources, info, arch, filename))
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+    let timestamp = entries.iter().map(|e| e.pkg.build_time).max().unwrap_or(0);
+    let metadata = [
+        ("primary", primary_xml(&entries)),
+        ("filelists", filelists_xml(&entries)),
+    ];
+    repodata(&metadata, timestamp).map_err(ErrorInternalServerError)
+}
+
+/// A build's repository for `arch`, generated if it isn't already.
+async fn repository(
+    downloader: &Downloader,
+    sources: &Sources,
+    buildid: &str,
+    arch: &str,
+) -> actix_web::Result<Arc<Repodata>> {
+    let info = lookup(downloader, sources, buildid).await?;
+    if let Some(repo) = GENERATED.lock().unwrap().get(info.id, arch) {
+        return Ok(repo);
+    }
+    let arches = arches(&info, arch);
+    if arches.is_empty() {
+        return Err(ErrorNotFound(format!("No {} RPMs in {}", arch, info.nvr)));
+    }
+    let repo = Arc::new(generate(downloader, sources, &info, &arches).await?);
+    GENERATED
+        .lock()
+        .unwrap()
+        .insert(info.id, arch, repo.clone());
+    Ok(repo)
+}
+
+/// The build behind a repository; only completed builds have one, as
+/// metadata for anything else could go stale.
+async fn lookup(
+    downloader: &Downloader,
+    sources: &Sources,
+    buildid: &str,
+) -> actix_web::Result<KojiBuildInfo> {
+    if !downloader.enabled() {
+        return Err(ErrorNotFound("Downloads are disabled"));
+    }
+    let info = sources.build(buildid, false).await?;
+    deleted::check(&info)?;
+    if info.state != "COMPLETE" {
+        return Err(ErrorConflict(format!(
+            "{} is {}; only completed builds have repositories",
+            info.nvr, info.state
+        )));
+    }
+    Ok(info)
+}
+
+#[get("/buildinfo/{id}/repo/{arch}/repodata/{file}")]
+async fn repodata_file(
+    downloader: web::Data<Downloader>,
+    sources: Sources,
+    path: web::Path<(String, String, String)>,
+) -> actix_web::Result<HttpResponse> {
+    let (buildid, arch, file) = path.into_inner();
+    if file != "repomd.xml" && !file.ends_with(".xml.gz") {
+        return Err(ErrorNotFound(format!("No repodata/{}", file)));
+    }
+    let repo = repository(&downloader, &sources, &buildid, &arch).await?;
+    if file == "repomd.xml" {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/xml")
+            .body(repo.repomd.clone()));
+    }
+    match repo.files.get(file.as_str()) {
+        Some(data) => Ok(HttpResponse::Ok()
+            .content_type("application/gzip")
+            .body(data.clone())),
+        None => Err(ErrorNotFound(format!("No repodata/{}", file))),
+    }
+}
+
+/// The packages themselves, where repodata locates them.
+#[get("/buildinfo/{id}/repo/{arch}/{filename}")]
+async fn package(
+    req: HttpRequest,
+    downloader: web::Data<Downloader>,
+    sources: Sources,
+    path: web::Path<(String, String, String)>,
+) -> actix_web::Result<HttpResponse> {
+    let (buildid, arch, filename) = path.into_inner();
+    let info = lookup(&downloader, &sources, &buildid).await?;
+    let rpm_arch = arches(&info, &arch)
+        .into_iter()
+        .find(|a| info.rpms[*a].contains(&filename))
+        .ok_or_else(|| ErrorNotFound(format!("No {} in {}", filename, info.nvr)))?;
+    download::serve(&req, &downloader, &sources, &info, rpm_arch, &filename).await
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(repodata_file).service(package);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry() -> Entry {
+        let b = crate::rpm::test::example_rpm();
+        Entry {
+            filename: "foo-1.0-1.fc34.x86_64.rpm".to_string(),
+            size: b.len() as u64 + 1000,
+            pkg: rpm::parse(&b).unwrap(),
+            sha256: "ab".repeat(32),
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("a<b> & \"c\"\u{1}"),
+            "a&lt;b&gt; &amp; &quot;c&quot;"
+        );
+        assert_eq!(split_evr("1:2.0-3"), ("1", "2.0", Some("3")));
+        assert_eq!(split_evr("2.0"), ("0", "2.0", None));
+    }
+
+    #[test]
+    fn test_arches() {
+        let mut info = KojiBuildInfo::default();
+        for a in &["src", "x86_64", "noarch"] {
+            info.rpms.insert(a.to_string(), Vec::new());
+        }
+        assert_eq!(arches(&info, "x86_64"), vec!["x86_64", "noarch"]);
+        assert_eq!(arches(&info, "src"), vec!["src"]);
+        // A noarch-only build still installs anywhere
+        assert_eq!(arches(&info, "aarch64"), vec!["noarch"]);
+        assert!(arches(&KojiBuildInfo::default(), "x86_64").is_empty());
+    }
+
+    #[test]
+    fn test_primary() {
+        let xml = primary_xml(&[entry()]);
+        assert!(xml.contains("packages=\"1\""));
+        assert!(xml.contains("<version epoch=\"0\" ver=\"1.0\" rel=\"1.fc34\"/>"));
+        assert!(xml.contains("<location href=\"foo-1.0-1.fc34.x86_64.rpm\"/>"));
+        assert!(xml.contains(
+            "<rpm:entry name=\"foo\" flags=\"EQ\" epoch=\"0\" ver=\"1.0\" rel=\"1.fc34\"/>"
+        ));
+        assert!(xml.contains("<rpm:entry name=\"/bin/sh\" pre=\"1\"/>"));
+        assert!(!xml.contains("rpmlib("));
+        // Only some files are in primary.xml
+        assert!(xml.contains("<file>/usr/bin/foo</file>"));
+        assert!(xml.contains("<file type=\"ghost\">/etc/foo.conf</file>"));
+        assert!(!xml.contains("/usr/share/foo"));
+        let xml = filelists_xml(&[entry()]);
+        assert!(xml.contains("<file type=\"dir\">/usr/share/foo</file>"));
+    }
+
+    #[test]
+    fn test_repodata() {
+        let metadata = [("primary", primary_xml(&[entry()]))];
+        let repo = repodata(&metadata, 1_600_000_000).unwrap();
+        let gz = &repo.files["primary.xml.gz"];
+        let mut xml = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&gz[..]), &mut xml)
+            .unwrap();
+        assert_eq!(xml, metadata[0].1);
+        assert!(repo
+            .repomd
+            .contains("<location href=\"repodata/primary.xml.gz\"/>"));
+        assert!(repo.repomd.contains(&format!(
+            "<checksum type=\"sha256\">{}</checksum>",
+            hex::encode(Sha256::digest(&gz[..]))
+        )));
+        assert!(repo.repomd.contains("<revision>1600000000</revision>"));
+    }
+}
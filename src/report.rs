@@ -0,0 +1,19 @@
+//! Error reporting to Sentry.
+
+use crate::config::ReportConfig;
+
+/// Start the Sentry client if configured.  Panics are reported, as are
+/// `ERROR` log events (e.g. failed koji calls) via the layer added by
+/// `logging::init()`.  The guard flushes pending events when dropped.
+pub(crate) fn init(config: &ReportConfig) -> Option<sentry::ClientInitGuard> {
+    let dsn = config.sentry_dsn.as_deref()?;
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: config.environment.clone().map(Into::into),
+            ..Default::default()
+        },
+    ));
+    Some(guard)
+}
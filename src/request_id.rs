@@ -0,0 +1,72 @@
+//! Per-request identifiers, for correlating logs with user reports.
+
+use std::fmt::Display;
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+
+const HEADER: &str = "x-request-id";
+
+/// Longest incoming `X-Request-Id` we accept rather than replace.
+const MAX_LEN: usize = 128;
+
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId(pub(crate) String);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Whether a client-supplied id is safe to log and echo back.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Take the id from the request's `X-Request-Id` header, or generate one,
+/// and attach it to the request.
+pub(crate) fn assign(req: &ServiceRequest) -> RequestId {
+    let id = req
+        .headers()
+        .get(HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid(v))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let id = RequestId(id);
+    req.extensions_mut().insert(id.clone());
+    id
+}
+
+/// The id assigned to this request by `assign()`.
+pub(crate) fn get(req: &HttpRequest) -> Option<RequestId> {
+    req.extensions().get::<RequestId>().cloned()
+}
+
+/// Echo the id in the response headers.
+pub(crate) fn set_header<B>(res: &mut ServiceResponse<B>, id: &RequestId) {
+    if let Ok(v) = HeaderValue::from_str(&id.0) {
+        res.headers_mut().insert(HeaderName::from_static(HEADER), v);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("4c3f5e1a-7b7e-4d0f-9a53-8f1e0b2a6c11"));
+        assert!(is_valid("bodhi.push:1234"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid("x\r\nSet-Cookie: y"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+    }
+}
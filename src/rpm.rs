@@ -0,0 +1,559 @@
+//! Just enough of the RPM file format to describe a package in repodata,
+//! and to compare packages' files.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+const LEAD_SIZE: usize = 96;
+const LEAD_MAGIC: [u8; 4] = [0xed, 0xab, 0xee, 0xdb];
+const HEADER_MAGIC: [u8; 3] = [0x8e, 0xad, 0xe8];
+/// The magic, reserved bytes and counts before a header's index.
+const HEADER_INTRO: usize = 16;
+const INDEX_ENTRY: usize = 16;
+
+// Header tags
+const NAME: u32 = 1000;
+const VERSION: u32 = 1001;
+const RELEASE: u32 = 1002;
+const EPOCH: u32 = 1003;
+const SUMMARY: u32 = 1004;
+const DESCRIPTION: u32 = 1005;
+const BUILDTIME: u32 = 1006;
+const BUILDHOST: u32 = 1007;
+const SIZE: u32 = 1009;
+const VENDOR: u32 = 1011;
+const LICENSE: u32 = 1014;
+const PACKAGER: u32 = 1015;
+const GROUP: u32 = 1016;
+const URL: u32 = 1020;
+const ARCH: u32 = 1022;
+const FILESIZES: u32 = 1028;
+const FILEMODES: u32 = 1030;
+const FILEDIGESTS: u32 = 1035;
+const FILELINKTOS: u32 = 1036;
+const FILEFLAGS: u32 = 1037;
+const FILEUSERNAME: u32 = 1039;
+const FILEGROUPNAME: u32 = 1040;
+const SOURCERPM: u32 = 1044;
+const PROVIDENAME: u32 = 1047;
+const REQUIREFLAGS: u32 = 1048;
+const REQUIRENAME: u32 = 1049;
+const REQUIREVERSION: u32 = 1050;
+const CONFLICTFLAGS: u32 = 1053;
+const CONFLICTNAME: u32 = 1054;
+const CONFLICTVERSION: u32 = 1055;
+const OBSOLETENAME: u32 = 1090;
+const PROVIDEFLAGS: u32 = 1112;
+const PROVIDEVERSION: u32 = 1113;
+const OBSOLETEFLAGS: u32 = 1114;
+const OBSOLETEVERSION: u32 = 1115;
+const DIRINDEXES: u32 = 1116;
+const BASENAMES: u32 = 1117;
+const DIRNAMES: u32 = 1118;
+const LONGFILESIZES: u32 = 5008;
+const LONGSIZE: u32 = 5009;
+// Signature header tags
+const SIG_PAYLOADSIZE: u32 = 1007;
+
+// Header data types
+const TYPE_INT16: u32 = 3;
+const TYPE_INT32: u32 = 4;
+const TYPE_INT64: u32 = 5;
+const TYPE_STRING: u32 = 6;
+const TYPE_STRING_ARRAY: u32 = 8;
+const TYPE_I18NSTRING: u32 = 9;
+
+// Dependency flags
+const SENSE_LESS: u32 = 0x02;
+const SENSE_GREATER: u32 = 0x04;
+const SENSE_EQUAL: u32 = 0x08;
+/// Any of the scriptlet or pre-requisite bits.
+const SENSE_PREREQ: u32 = 0x40 | 0x200 | 0x400 | 0x800 | 0x1000;
+
+const FILE_GHOST: u32 = 0x40;
+const MODE_DIR: u32 = 0o040000;
+const MODE_TYPE: u32 = 0o170000;
+
+/// A dependency, as in `Requires: foo >= 1.0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Dependency {
+    pub(crate) name: String,
+    /// `EQ`, `LT`, `LE`, `GT` or `GE`, if versioned.
+    pub(crate) flags: Option<&'static str>,
+    /// `[epoch:]version[-release]`.
+    pub(crate) evr: String,
+    /// Needed before installing, e.g. by scriptlets.
+    pub(crate) pre: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileKind {
+    File,
+    Dir,
+    Ghost,
+}
+
+/// What an RPM records of one of its files, e.g. to tell whether it changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FileDetails {
+    pub(crate) path: String,
+    pub(crate) mode: u32,
+    pub(crate) size: u64,
+    /// Hex digest of regular files' contents; empty for others.
+    pub(crate) digest: String,
+    /// Symlinks' targets; empty for others.
+    pub(crate) linkto: String,
+    pub(crate) user: String,
+    pub(crate) group: String,
+}
+
+/// What repodata needs from an RPM's headers.
+#[derive(Debug, Default)]
+pub(crate) struct Package {
+    pub(crate) name: String,
+    pub(crate) epoch: u32,
+    pub(crate) version: String,
+    pub(crate) release: String,
+    pub(crate) arch: String,
+    pub(crate) summary: String,
+    pub(crate) description: String,
+    pub(crate) url: String,
+    pub(crate) license: String,
+    pub(crate) vendor: String,
+    pub(crate) group: String,
+    pub(crate) buildhost: String,
+    pub(crate) packager: String,
+    pub(crate) sourcerpm: String,
+    pub(crate) build_time: u64,
+    pub(crate) installed_size: u64,
+    pub(crate) archive_size: u64,
+    /// Byte offsets of the main header, and of the payload after it.
+    pub(crate) header_range: (u64, u64),
+    pub(crate) provides: Vec<Dependency>,
+    pub(crate) requires: Vec<Dependency>,
+    pub(crate) conflicts: Vec<Dependency>,
+    pub(crate) obsoletes: Vec<Dependency>,
+    pub(crate) files: Vec<(String, FileKind)>,
+    /// The same files in more detail.
+    pub(crate) file_details: Vec<FileDetails>,
+}
+
+/// A parsed header: tag to type, count and data.
+struct Header<'a> {
+    entries: HashMap<u32, (u32, usize, &'a [u8])>,
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes(b[..4].try_into().unwrap())
+}
+
+/// The size of the header at the start of `b`, given at least its intro.
+fn header_size(b: &[u8]) -> Result<usize> {
+    if b.len() < HEADER_INTRO || b[..3] != HEADER_MAGIC {
+        bail!("Invalid RPM header magic");
+    }
+    let il = be32(&b[8..]) as usize;
+    let dl = be32(&b[12..]) as usize;
+    Ok(HEADER_INTRO + il * INDEX_ENTRY + dl)
+}
+
+/// Where the signature header ends and the main header begins.
+fn main_header_start(b: &[u8]) -> Result<usize> {
+    let sig = header_size(&b[LEAD_SIZE..])?;
+    // The signature header is padded to 8 bytes
+    Ok(LEAD_SIZE + (sig + 7) / 8 * 8)
+}
+
+/// How many leading bytes of an RPM are needed to parse it; `b` must be
+/// at least as long as a previous answer (starting with the lead and
+/// signature intro).  Returns `None` once `b` is long enough.
+pub(crate) fn needed(b: &[u8]) -> Result<Option<usize>> {
+    if b.len() < LEAD_SIZE + HEADER_INTRO {
+        return Ok(Some(LEAD_SIZE + HEADER_INTRO));
+    }
+    if b[..4] != LEAD_MAGIC {
+        bail!("Not an RPM");
+    }
+    let start = main_header_start(b)?;
+    if b.len() < start + HEADER_INTRO {
+        return Ok(Some(start + HEADER_INTRO));
+    }
+    let end = start + header_size(&b[start..])?;
+    Ok(if b.len() < end { Some(end) } else { None })
+}
+
+impl<'a> Header<'a> {
+    fn parse(b: &'a [u8]) -> Result<Self> {
+        let size = header_size(b)?;
+        if b.len() < size {
+            bail!("Truncated RPM header");
+        }
+        let il = be32(&b[8..]) as usize;
+        let store = &b[HEADER_INTRO + il * INDEX_ENTRY..size];
+        let mut entries = HashMap::new();
+        for i in 0..il {
+            let e = &b[HEADER_INTRO + i * INDEX_ENTRY..];
+            let (tag, ty, offset, count) = (
+                be32(e),
+                be32(&e[4..]),
+                be32(&e[8..]) as usize,
+                be32(&e[12..]) as usize,
+            );
+            if offset > store.len() {
+                bail!("Invalid offset for RPM header tag {}", tag);
+            }
+            entries.insert(tag, (ty, count, &store[offset..]));
+        }
+        Ok(Self { entries })
+    }
+
+    fn strings(&self, tag: u32) -> Vec<String> {
+        let (ty, count, data) = match self.entries.get(&tag) {
+            Some(e) => *e,
+            None => return Vec::new(),
+        };
+        let count = match ty {
+            TYPE_STRING => 1,
+            TYPE_STRING_ARRAY | TYPE_I18NSTRING => count,
+            _ => return Vec::new(),
+        };
+        data.split(|c| *c == 0)
+            .take(count)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect()
+    }
+
+    /// A string, or the first translation of an internationalized one.
+    fn string(&self, tag: u32) -> String {
+        self.strings(tag).into_iter().next().unwrap_or_default()
+    }
+
+    fn ints(&self, tag: u32) -> Vec<u64> {
+        let (ty, count, data) = match self.entries.get(&tag) {
+            Some(e) => *e,
+            None => return Vec::new(),
+        };
+        let width = match ty {
+            TYPE_INT16 => 2,
+            TYPE_INT32 => 4,
+            TYPE_INT64 => 8,
+            _ => return Vec::new(),
+        };
+        data.chunks_exact(width)
+            .take(count)
+            .map(|c| match width {
+                2 => u16::from_be_bytes(c.try_into().unwrap()) as u64,
+                4 => u32::from_be_bytes(c.try_into().unwrap()) as u64,
+                _ => u64::from_be_bytes(c.try_into().unwrap()),
+            })
+            .collect()
+    }
+
+    fn int(&self, tag: u32) -> Option<u64> {
+        self.ints(tag).into_iter().next()
+    }
+
+    fn dependencies(&self, names: u32, flags: u32, versions: u32) -> Vec<Dependency> {
+        let flags = self.ints(flags);
+        let versions = self.strings(versions);
+        self.strings(names)
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let f = flags.get(i).copied().unwrap_or(0) as u32;
+                let evr = versions.get(i).cloned().unwrap_or_default();
+                let cmp = f & (SENSE_LESS | SENSE_GREATER | SENSE_EQUAL);
+                let flags = match cmp {
+                    _ if evr.is_empty() => None,
+                    SENSE_EQUAL => Some("EQ"),
+                    SENSE_LESS => Some("LT"),
+                    x if x == SENSE_LESS | SENSE_EQUAL => Some("LE"),
+                    SENSE_GREATER => Some("GT"),
+                    x if x == SENSE_GREATER | SENSE_EQUAL => Some("GE"),
+                    _ => None,
+                };
+                Dependency {
+                    name,
+                    flags,
+                    evr,
+                    pre: f & SENSE_PREREQ != 0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parse the leading bytes of an RPM, which must cover its headers (see
+/// [`needed`]).
+pub(crate) fn parse(b: &[u8]) -> Result<Package> {
+    if needed(b)?.is_some() {
+        bail!("Truncated RPM");
+    }
+    let sig = Header::parse(&b[LEAD_SIZE..])?;
+    let start = main_header_start(b)?;
+    let h = Header::parse(&b[start..])?;
+    let end = start + header_size(&b[start..])?;
+
+    let dirs = h.strings(DIRNAMES);
+    let modes = h.ints(FILEMODES);
+    let fileflags = h.ints(FILEFLAGS);
+    let files = h
+        .strings(BASENAMES)
+        .into_iter()
+        .zip(h.ints(DIRINDEXES))
+        .enumerate()
+        .map(|(i, (base, dir))| {
+            let dir = dirs.get(dir as usize).map(|d| d.as_str()).unwrap_or("");
+            let mode = modes.get(i).copied().unwrap_or(0) as u32;
+            let kind = if fileflags.get(i).copied().unwrap_or(0) as u32 & FILE_GHOST != 0 {
+                FileKind::Ghost
+            } else if mode & MODE_TYPE == MODE_DIR {
+                FileKind::Dir
+            } else {
+                FileKind::File
+            };
+            (format!("{}{}", dir, base), kind)
+        })
+        .collect::<Vec<_>>();
+    let mut sizes = h.ints(LONGFILESIZES);
+    if sizes.is_empty() {
+        sizes = h.ints(FILESIZES);
+    }
+    let (digests, links) = (h.strings(FILEDIGESTS), h.strings(FILELINKTOS));
+    let (users, groups) = (h.strings(FILEUSERNAME), h.strings(FILEGROUPNAME));
+    let at = |v: &[String], i: usize| v.get(i).cloned().unwrap_or_default();
+    let file_details = files
+        .iter()
+        .enumerate()
+        .map(|(i, (path, _))| FileDetails {
+            path: path.clone(),
+            mode: modes.get(i).copied().unwrap_or(0) as u32,
+            size: sizes.get(i).copied().unwrap_or(0),
+            digest: at(&digests, i),
+            linkto: at(&links, i),
+            user: at(&users, i),
+            group: at(&groups, i),
+        })
+        .collect();
+
+    Ok(Package {
+        name: h.string(NAME),
+        epoch: h.int(EPOCH).unwrap_or(0) as u32,
+        version: h.string(VERSION),
+        release: h.string(RELEASE),
+        arch: h.string(ARCH),
+        summary: h.string(SUMMARY),
+        description: h.string(DESCRIPTION),
+        url: h.string(URL),
+        license: h.string(LICENSE),
+        vendor: h.string(VENDOR),
+        group: h.string(GROUP),
+        buildhost: h.string(BUILDHOST),
+        packager: h.string(PACKAGER),
+        sourcerpm: h.string(SOURCERPM),
+        build_time: h.int(BUILDTIME).unwrap_or(0),
+        installed_size: h.int(LONGSIZE).or_else(|| h.int(SIZE)).unwrap_or(0),
+        archive_size: sig.int(SIG_PAYLOADSIZE).unwrap_or(0),
+        header_range: (start as u64, end as u64),
+        provides: h.dependencies(PROVIDENAME, PROVIDEFLAGS, PROVIDEVERSION),
+        requires: h.dependencies(REQUIRENAME, REQUIREFLAGS, REQUIREVERSION),
+        conflicts: h.dependencies(CONFLICTNAME, CONFLICTFLAGS, CONFLICTVERSION),
+        obsoletes: h.dependencies(OBSOLETENAME, OBSOLETEFLAGS, OBSOLETEVERSION),
+        files,
+        file_details,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A header with the given `(tag, type, count, data)` entries.
+    fn header(entries: &[(u32, u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut store = Vec::new();
+        for (tag, ty, count, data) in entries {
+            // Integers are aligned to their size
+            let align = match *ty {
+                TYPE_INT16 => 2,
+                TYPE_INT32 => 4,
+                TYPE_INT64 => 8,
+                _ => 1,
+            };
+            while store.len() % align != 0 {
+                store.push(0);
+            }
+            for v in &[*tag, *ty, store.len() as u32, *count] {
+                index.extend_from_slice(&v.to_be_bytes());
+            }
+            store.extend_from_slice(data);
+        }
+        let mut h = vec![0x8e, 0xad, 0xe8, 0x01, 0, 0, 0, 0];
+        h.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        h.extend_from_slice(&(store.len() as u32).to_be_bytes());
+        h.extend(index);
+        h.extend(store);
+        h
+    }
+
+    fn s(v: &str) -> (u32, Vec<u8>) {
+        let mut d = v.as_bytes().to_vec();
+        d.push(0);
+        (1, d)
+    }
+
+    fn a(v: &[&str]) -> (u32, Vec<u8>) {
+        let mut d = Vec::new();
+        for v in v {
+            d.extend_from_slice(v.as_bytes());
+            d.push(0);
+        }
+        (v.len() as u32, d)
+    }
+
+    fn i32s(v: &[u32]) -> (u32, Vec<u8>) {
+        (
+            v.len() as u32,
+            v.iter().flat_map(|v| v.to_be_bytes()).collect(),
+        )
+    }
+
+    /// A minimal RPM's leading bytes, for `foo-1.0-1.fc34.x86_64`.
+    pub(crate) fn example_rpm() -> Vec<u8> {
+        let mut b = vec![0; LEAD_SIZE];
+        b[..4].copy_from_slice(&LEAD_MAGIC);
+        let (n, d) = i32s(&[12345]);
+        // 13 bytes of signature store, so it needs padding
+        let mut sig = header(&[(SIG_PAYLOADSIZE, TYPE_INT32, n, d), (999, 7, 9, vec![0; 9])]);
+        while sig.len() % 8 != 0 {
+            sig.push(0);
+        }
+        b.extend(sig);
+        let mut entries = Vec::new();
+        for (tag, v) in &[
+            (NAME, "foo"),
+            (VERSION, "1.0"),
+            (RELEASE, "1.fc34"),
+            (ARCH, "x86_64"),
+            (LICENSE, "MIT"),
+            (SOURCERPM, "foo-1.0-1.fc34.src.rpm"),
+        ] {
+            let (n, d) = s(v);
+            entries.push((*tag, TYPE_STRING, n, d));
+        }
+        let (n, d) = a(&["A foo"]);
+        entries.push((SUMMARY, TYPE_I18NSTRING, n, d));
+        let (n, d) = i32s(&[1_600_000_000]);
+        entries.push((BUILDTIME, TYPE_INT32, n, d));
+        let (n, d) = i32s(&[4096]);
+        entries.push((SIZE, TYPE_INT32, n, d));
+        let (n, d) = a(&["foo", "foo(x86-64)"]);
+        entries.push((PROVIDENAME, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = i32s(&[SENSE_EQUAL, SENSE_EQUAL]);
+        entries.push((PROVIDEFLAGS, TYPE_INT32, n, d));
+        let (n, d) = a(&["1.0-1.fc34", "1.0-1.fc34"]);
+        entries.push((PROVIDEVERSION, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = a(&[
+            "/bin/sh",
+            "libbar.so.1()(64bit)",
+            "rpmlib(CompressedFileNames)",
+        ]);
+        entries.push((REQUIRENAME, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = i32s(&[0x200, 0, SENSE_LESS | SENSE_EQUAL | 0x1000000]);
+        entries.push((REQUIREFLAGS, TYPE_INT32, n, d));
+        let (n, d) = a(&["", "", "3.0.4-1"]);
+        entries.push((REQUIREVERSION, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = a(&["foo", "foo.conf", "foo"]);
+        entries.push((BASENAMES, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = a(&["/usr/bin/", "/etc/", "/usr/share/"]);
+        entries.push((DIRNAMES, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = i32s(&[0, 1, 2]);
+        entries.push((DIRINDEXES, TYPE_INT32, n, d));
+        let modes: Vec<u8> = [0o100755u16, 0o100644, 0o040755]
+            .iter()
+            .flat_map(|m| m.to_be_bytes())
+            .collect();
+        entries.push((FILEMODES, TYPE_INT16, 3, modes));
+        let (n, d) = i32s(&[0, FILE_GHOST, 0]);
+        entries.push((FILEFLAGS, TYPE_INT32, n, d));
+        let (n, d) = i32s(&[1024, 0, 4096]);
+        entries.push((FILESIZES, TYPE_INT32, n, d));
+        let (n, d) = a(&["abc123", "", ""]);
+        entries.push((FILEDIGESTS, TYPE_STRING_ARRAY, n, d));
+        let (n, d) = a(&["root", "root", "root"]);
+        entries.push((FILEUSERNAME, TYPE_STRING_ARRAY, n, d));
+        b.extend(header(&entries));
+        b
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let b = example_rpm();
+        assert_eq!(needed(&b[..50])?, Some(LEAD_SIZE + HEADER_INTRO));
+        let start = main_header_start(&b)?;
+        assert_eq!(start % 8, 0);
+        assert_eq!(needed(&b[..start])?, Some(start + HEADER_INTRO));
+        assert_eq!(needed(&b[..start + HEADER_INTRO])?, Some(b.len()));
+        assert_eq!(needed(&b)?, None);
+        assert!(parse(&b[..b.len() - 1]).is_err());
+
+        let p = parse(&b)?;
+        assert_eq!(
+            (
+                p.name.as_str(),
+                p.epoch,
+                p.version.as_str(),
+                p.release.as_str()
+            ),
+            ("foo", 0, "1.0", "1.fc34")
+        );
+        assert_eq!(p.arch, "x86_64");
+        assert_eq!(p.summary, "A foo");
+        assert_eq!(p.build_time, 1_600_000_000);
+        assert_eq!(p.installed_size, 4096);
+        assert_eq!(p.archive_size, 12345);
+        assert_eq!(p.header_range, (start as u64, b.len() as u64));
+        assert_eq!(p.provides.len(), 2);
+        assert_eq!(p.provides[0].flags, Some("EQ"));
+        assert_eq!(
+            p.requires[0],
+            Dependency {
+                name: "/bin/sh".into(),
+                flags: None,
+                evr: "".into(),
+                pre: true,
+            }
+        );
+        assert!(!p.requires[1].pre);
+        assert_eq!(p.requires[2].flags, Some("LE"));
+        assert_eq!(
+            p.files,
+            vec![
+                ("/usr/bin/foo".to_string(), FileKind::File),
+                ("/etc/foo.conf".to_string(), FileKind::Ghost),
+                ("/usr/share/foo".to_string(), FileKind::Dir),
+            ]
+        );
+        assert_eq!(
+            p.file_details[0],
+            FileDetails {
+                path: "/usr/bin/foo".into(),
+                mode: 0o100755,
+                size: 1024,
+                digest: "abc123".into(),
+                linkto: "".into(),
+                user: "root".into(),
+                group: "".into(),
+            }
+        );
+        assert_eq!(p.file_details[2].size, 4096);
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_rpm() {
+        assert!(needed(&[0; 200]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Versions of `/buildinfo`'s JSON, chosen with `?schema=`, so that fields
+//! can be restructured without breaking parsers of an older shape.  Every
+//! response says which it is in `schema-version`.
+//!
+//! - 1 (the default): as cached, the source RPM listed under `rpms.src`.
+//! - 2: the source RPM split out as `srpm`, and the build's `epoch` added.
+
+use actix_web::error::ErrorBadRequest;
+use serde_json::Value;
+
+use crate::koji::KojiBuildInfo;
+
+pub(crate) const DEFAULT: u32 = 1;
+pub(crate) const LATEST: u32 = 2;
+
+/// Reject versions we don't know.
+pub(crate) fn check(version: u32) -> actix_web::Result<u32> {
+    if (1..=LATEST).contains(&version) {
+        Ok(version)
+    } else {
+        Err(ErrorBadRequest(format!(
+            "Unsupported schema {}; expected 1 to {}",
+            version, LATEST
+        )))
+    }
+}
+
+/// Label a cached body as version 1 without parsing it.
+pub(crate) fn v1(body: String) -> String {
+    match body.strip_prefix('{') {
+        Some("}") => format!("{{\"schema-version\":{}}}", DEFAULT),
+        Some(rest) => format!("{{\"schema-version\":{},{}", DEFAULT, rest),
+        None => body,
+    }
+}
+
+/// A build as JSON of `version`.
+pub(crate) fn render(info: &KojiBuildInfo, version: u32) -> serde_json::Result<Value> {
+    let mut v = serde_json::to_value(info)?;
+    let o = match v.as_object_mut() {
+        Some(o) => o,
+        None => return Ok(v),
+    };
+    o.insert("schema-version".to_string(), version.into());
+    if version >= 2 {
+        let srpm = info.rpms.get("src").and_then(|r| r.first());
+        if let Some(Value::Object(rpms)) = o.get_mut("rpms") {
+            rpms.remove("src");
+        }
+        let epoch = srpm.and_then(|f| info.nevras.get(f)).and_then(|n| n.epoch);
+        o.insert("srpm".to_string(), srpm.cloned().into());
+        o.insert("epoch".to_string(), epoch.into());
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::koji::RpmNevra;
+
+    fn info() -> KojiBuildInfo {
+        let mut info = KojiBuildInfo {
+            nvr: "foo-1-1".to_string(),
+            ..Default::default()
+        };
+        info.rpms
+            .insert("src".to_string(), vec!["foo-1-1.src.rpm".to_string()]);
+        info.rpms
+            .insert("x86_64".to_string(), vec!["foo-1-1.x86_64.rpm".to_string()]);
+        let nevra = RpmNevra {
+            name: "foo".to_string(),
+            epoch: Some(2),
+            version: "1".to_string(),
+            release: "1".to_string(),
+            arch: "src".to_string(),
+        };
+        info.nevras.insert(nevra.filename(), nevra);
+        info
+    }
+
+    #[test]
+    fn test_v1() -> anyhow::Result<()> {
+        let info = info();
+        let cached = serde_json::to_string(&info)?;
+        let v: Value = serde_json::from_str(&v1(cached))?;
+        assert_eq!(v, render(&info, 1)?);
+        assert_eq!(v["schema-version"], 1);
+        assert_eq!(v["rpms"]["src"][0], "foo-1-1.src.rpm");
+        assert_eq!(v1("{}".to_string()), r#"{"schema-version":1}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2() -> anyhow::Result<()> {
+        let v = render(&info(), 2)?;
+        assert_eq!(v["schema-version"], 2);
+        assert_eq!(v["srpm"], "foo-1-1.src.rpm");
+        assert_eq!(v["epoch"], 2);
+        assert!(v["rpms"].get("src").is_none());
+        assert_eq!(v["rpms"]["x86_64"][0], "foo-1-1.x86_64.rpm");
+
+        let v = render(&KojiBuildInfo::default(), 2)?;
+        assert!(v["srpm"].is_null() && v["epoch"].is_null());
+        assert!(check(3).is_err() && check(0).is_err());
+        Ok(())
+    }
+}
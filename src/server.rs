@@ -0,0 +1,876 @@
+//! The HTTP server, and its routes for embedding in other applications.
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Service;
+use actix_web::error::{
+    ErrorBadGateway, ErrorBadRequest, ErrorInternalServerError, ErrorNotFound,
+    ErrorServiceUnavailable,
+};
+use actix_web::http::header;
+use actix_web::Result;
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use clap::Parser;
+use futures::future::Either;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::access_log::CacheStatus;
+#[cfg(feature = "bus")]
+use crate::bus;
+use crate::cache::{Cache, NvrMap};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::koji::{self, Backend};
+use crate::listen::Listener;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::ratelimit::RouteClass;
+#[cfg(feature = "reporting")]
+use crate::report;
+#[cfg(feature = "telemetry")]
+use crate::telemetry;
+#[cfg(feature = "webhooks")]
+use crate::webhooks;
+use crate::{
+    about, access_log, admin, arches, audit, auth, call, cli, config, cors, deleted, error, export,
+    gating, health, hubs, listen, logging, ndjson, prefetch, proxy, query, ratelimit, recover,
+    reload, request_id, schema, shed, systemd, tag, timeout, tls, usage, vulnerabilities, watch,
+};
+#[cfg(feature = "download-proxy")]
+use crate::{bundle, compare, download, licenses, repo};
+
+/// Run blocking work (i.e. koji calls) on the worker's blocking thread
+/// pool, within the current tracing span.  A panic (e.g. from parsing
+/// unexpected koji output) becomes an error.
+pub(crate) async fn run_blocking<F, T>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    web::block(move || {
+        let _guard = span.enter();
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            Err(anyhow::anyhow!(
+                "Panicked: {}",
+                recover::panic_message(&*payload)
+            ))
+        })
+    })
+    .await?
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct BuildInfoConfig {
+    /// Answer `/buildinfo/{id}` for a numeric id with a permanent redirect
+    /// to `/buildinfo/{nvr}`, so caches only hold the NVR form.
+    pub(crate) canonical_redirect: bool,
+    /// Which forms of times to give.
+    pub(crate) timestamps: koji::TimeFormat,
+}
+
+/// Just the state of a build's JSON, without the cost of its RPM list.
+#[derive(Deserialize)]
+struct BuildState {
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct BuildInfoQuery {
+    /// Skip the cache and fetch fresh data from the hub.
+    #[serde(default)]
+    refresh: bool,
+    /// Merge architectures into their base one, e.g. `i686` into `i386`.
+    #[serde(default)]
+    normalize_arch: bool,
+    /// JSON unless set.
+    format: Option<Format>,
+    /// Return at most this many RPMs, for builds with very many.
+    rpm_limit: Option<usize>,
+    /// Skip this many RPMs, counting by architecture then listing order.
+    #[serde(default)]
+    rpm_offset: usize,
+    /// The shape of the JSON; see the `schema` module.
+    schema: Option<u32>,
+    /// List RPMs as `variants` rather than `rpms`.
+    group: Option<Group>,
+}
+
+/// How `/buildinfo` may group RPMs, beyond by architecture.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Group {
+    /// By subpackage within each architecture; see
+    /// [`koji::KojiBuildInfo::variants`].
+    Variant,
+}
+
+/// How `/buildinfo` responds.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    /// One JSON document, as cached.
+    Json,
+    /// Newline-delimited JSON, an RPM per line.
+    Ndjson,
+}
+
+/// Whether the client asked us to bypass cached data, either via
+/// `Cache-Control: no-cache` or `?refresh=true`.
+fn wants_refresh(req: &HttpRequest, query: &BuildInfoQuery) -> bool {
+    if query.refresh {
+        return true;
+    }
+    req.headers()
+        .get_all(header::CACHE_CONTROL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case("no-cache"))
+}
+
+/// An opaque validator for a response body.
+fn etag(body: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut h);
+    format!("\"{:016x}\"", h.finish())
+}
+
+/// Respond with a JSON body and its `ETag`, or 304 if the client already
+/// has it per `If-None-Match`.
+fn json_response(req: &HttpRequest, body: String) -> HttpResponse {
+    let etag = etag(&body);
+    let not_modified = req
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().trim_start_matches("W/"))
+        .any(|t| t == etag || t == "*");
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::ETAG, etag))
+        .body(body)
+}
+
+/// Resolve a canonical build id through the cache, revalidating or asking
+/// the hub as needed.  Returns the JSON body and where it came from.
+pub(crate) async fn lookup_build(
+    hub: &RwLock<koji::Hub>,
+    cache: &Cache,
+    nvrs: &NvrMap,
+    shedder: &shed::LoadShedder,
+    buildid: &str,
+    refresh: bool,
+) -> Result<(String, CacheStatus)> {
+    let hub = hub.read().unwrap().clone();
+    if !refresh {
+        if let Some(body) = cache.get(buildid) {
+            return Ok((body, CacheStatus::Hit));
+        }
+        if let Some(body) = cache.get_stale(buildid) {
+            let stale: koji::KojiBuildInfo = serde_json::from_str(&body)?;
+            let hub = hub.clone();
+            let _permit = shedder.backend()?;
+            match run_blocking(move || hub.is_unchanged(&stale)).await {
+                Ok(true) => {
+                    cache.touch(buildid);
+                    return Ok((body, CacheStatus::Revalidated));
+                }
+                Ok(false) => {}
+                Err(e) => tracing::warn!(%buildid, "Failed to revalidate: {}", e),
+            }
+        }
+    }
+    let info = {
+        let buildid = buildid.to_string();
+        let _permit = shedder.backend()?;
+        run_blocking(move || hub.get_koji_build(&buildid)).await
+    };
+    let info = info.map_err(|e| lookup_error(buildid, e))?;
+    let body = cache.store_build(nvrs, &info)?;
+    #[cfg(feature = "bus")]
+    bus::resolved(&info);
+    export::resolved(&info, &body);
+    Ok((body, CacheStatus::fetched(refresh)))
+}
+
+/// The response for a failed lookup: 404 for builds the hub doesn't know,
+/// 503 if it couldn't be reached and 502 for its other faults, so clients
+/// know what is worth retrying.
+fn lookup_error(buildid: &str, e: anyhow::Error) -> actix_web::Error {
+    match e.downcast_ref::<koji::HubError>() {
+        Some(koji::HubError::NoSuchBuild(_)) => {
+            tracing::debug!(%buildid, "No such build");
+            ErrorNotFound(format!("No such build: {}", buildid))
+        }
+        Some(e @ koji::HubError::Unavailable(_)) => {
+            tracing::warn!(%buildid, "Failed to get koji build: {}", e);
+            ErrorServiceUnavailable(e.to_string())
+        }
+        Some(e @ koji::HubError::Fault(_)) => {
+            tracing::error!(%buildid, "Failed to get koji build: {}", e);
+            ErrorBadGateway(e.to_string())
+        }
+        None => {
+            tracing::error!(%buildid, "Failed to get koji build: {:#}", e);
+            ErrorInternalServerError(e)
+        }
+    }
+}
+
+/// A numeric build id, if `buildid` is one.
+fn numeric_id(buildid: &str) -> Result<Option<u64>> {
+    if buildid.is_empty() || !buildid.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    buildid
+        .parse()
+        .map(Some)
+        .map_err(|_| ErrorBadRequest(format!("Build id {} is out of range", buildid)))
+}
+
+/// A permanent redirect from the request for a numeric id to its NVR,
+/// keeping the query.
+fn canonical_redirect(req: &HttpRequest, nvr: &str) -> HttpResponse {
+    let base = req.path().rsplit_once('/').map_or("", |(base, _)| base);
+    let mut location = format!("{}/{}", base, koji::encode_path_segment(nvr));
+    if !req.query_string().is_empty() {
+        location.push('?');
+        location.push_str(req.query_string());
+    }
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+/// Serves both GET and HEAD; for HEAD actix omits the body but keeps its
+/// `Content-Length`, and cached answers need no koji call.
+async fn buildinfo(
+    req: HttpRequest,
+    sources: watch::Sources,
+    #[cfg(feature = "download-proxy")] downloader: web::Data<download::Downloader>,
+    config: web::Data<BuildInfoConfig>,
+    path: web::Path<(String,)>,
+    query: web::Query<BuildInfoQuery>,
+) -> Result<HttpResponse> {
+    let requested = path.into_inner().0;
+    // Numeric ids are passed to the hub as they are; anything else must
+    // look like a build
+    let id = numeric_id(&requested)?;
+    if id.is_none() {
+        koji::validate_buildid(&requested).map_err(ErrorBadRequest)?;
+    }
+    let watch::Sources {
+        hub,
+        cache,
+        nvrs,
+        shedder,
+    } = &sources;
+    let buildid = nvrs.canonicalize(&requested);
+    if config.canonical_redirect && id.is_some() && buildid != requested {
+        return Ok(canonical_redirect(&req, &buildid));
+    }
+    tracing::Span::current().record("buildid", &buildid.as_str());
+    usage::set_build(&req, &buildid);
+    let refresh = wants_refresh(&req, &query);
+    // Until we know better, e.g. for the access log of a failed lookup
+    access_log::set_cache_status(&req, CacheStatus::fetched(refresh));
+    let (body, status) = lookup_build(hub, cache, nvrs, shedder, &buildid, refresh).await?;
+    access_log::set_cache_status(&req, status);
+    if let (true, Some(id)) = (config.canonical_redirect, id) {
+        if let Some(nvr) = nvrs.nvr(id) {
+            return Ok(canonical_redirect(&req, &nvr));
+        }
+    }
+    if serde_json::from_str::<BuildState>(&body)?.state == "DELETED" {
+        let info = serde_json::from_str(&body)?;
+        #[cfg(feature = "download-proxy")]
+        let rpms_available = deleted::rpms_available(&downloader, &info).await;
+        #[cfg(not(feature = "download-proxy"))]
+        let rpms_available = None;
+        return Ok(deleted::gone(&req, &sources, &info, rpms_available).await);
+    }
+    let ndjson = query.format == Some(Format::Ndjson);
+    let version = schema::check(query.schema.unwrap_or(schema::DEFAULT))?;
+    if ndjson && version != schema::DEFAULT {
+        return Err(ErrorBadRequest("?schema= only applies to JSON responses"));
+    }
+    let variants = query.group == Some(Group::Variant);
+    if ndjson && variants {
+        return Err(ErrorBadRequest("?group= only applies to JSON responses"));
+    }
+    let paged = query.rpm_limit.is_some() || query.rpm_offset > 0;
+    let times = config.timestamps;
+    if !(ndjson
+        || paged
+        || query.normalize_arch
+        || variants
+        || version != schema::DEFAULT
+        || times != koji::TimeFormat::Both)
+    {
+        return Ok(json_response(&req, schema::v1(body)));
+    }
+    let mut info: koji::KojiBuildInfo = serde_json::from_str(&body)?;
+    drop(body);
+    info.format_times(times);
+    if query.normalize_arch {
+        info.normalize_arches();
+    }
+    let page = paged.then(|| {
+        let total = info.rpm_count();
+        let truncated = info.page_rpms(query.rpm_offset, query.rpm_limit);
+        serde_json::json!({"truncated": truncated, "rpm-total": total})
+    });
+    if ndjson {
+        return Ok(ndjson::response(info, page));
+    }
+    let mut body = schema::render(&info, version)?;
+    if let Some(body) = body.as_object_mut() {
+        if let Some(serde_json::Value::Object(page)) = page {
+            body.extend(page);
+        }
+        if variants {
+            let mut groups = info.variants();
+            if version >= 2 {
+                // Split out as `srpm`
+                groups.remove("src");
+            }
+            body.remove("rpms");
+            body.insert("variants".to_string(), serde_json::to_value(groups)?);
+        }
+    }
+    Ok(json_response(&req, body.to_string()))
+}
+
+/// Routes about a hub's builds, also served for each of `[hubs]`.
+fn configure_hub_api(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/buildinfo/{id}")
+            .route(web::get().to(buildinfo))
+            .route(web::head().to(buildinfo)),
+    )
+    .configure(arches::configure)
+    .configure(call::configure)
+    .configure(gating::configure)
+    .configure(tag::configure)
+    .configure(vulnerabilities::configure);
+    #[cfg(feature = "download-proxy")]
+    cfg.configure(licenses::configure)
+        .configure(download::configure)
+        .configure(bundle::configure)
+        .configure(repo::configure)
+        .configure(compare::configure);
+}
+
+/// Routes of the public API.
+fn configure_api(cfg: &mut web::ServiceConfig) {
+    cfg.configure(configure_hub_api)
+        .configure(health::configure)
+        .configure(watch::configure)
+        .configure(hubs::configure)
+        .configure(about::configure);
+    #[cfg(feature = "webhooks")]
+    cfg.configure(webhooks::configure);
+}
+
+/// State for routes mounted by [`configure`], shared by all of the host
+/// application's workers.
+struct Embedded {
+    hub: web::Data<RwLock<koji::Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+    shedder: web::Data<shed::LoadShedder>,
+    health_config: web::Data<health::HealthConfig>,
+    watch_config: web::Data<watch::WatchConfig>,
+    #[cfg(feature = "webhooks")]
+    webhook_config: web::Data<config::WebhookConfig>,
+    #[cfg(feature = "webhooks")]
+    webhooks: web::Data<webhooks::Webhooks>,
+    call_config: web::Data<call::CallConfig>,
+    buildinfo_config: web::Data<BuildInfoConfig>,
+    gating: web::Data<gating::Gating>,
+    vulnerabilities: web::Data<vulnerabilities::Vulnerabilities>,
+    #[cfg(feature = "download-proxy")]
+    downloader: web::Data<download::Downloader>,
+    readiness: web::Data<health::Readiness>,
+    about: web::Data<about::About>,
+    hubs: web::Data<hubs::Hubs>,
+}
+
+impl Embedded {
+    fn new() -> anyhow::Result<Self> {
+        let config = config::Config::from_env()?;
+        koji::set_call_timeout(config.backend.call_timeout());
+        let hub = web::Data::new(RwLock::new(config.hub.clone()));
+        let readiness = web::Data::new(health::Readiness::new(false));
+        health::spawn_monitor(&config.health, hub.clone(), readiness.clone());
+        let cache = web::Data::new(Cache::new(config.cache.ttl, config.cache.max_bytes));
+        let nvrs = web::Data::new(NvrMap::new(config.cache.mapping_capacity));
+        #[cfg(feature = "webhooks")]
+        let webhooks = web::Data::new(webhooks::Webhooks::open(&config.webhooks)?);
+        #[cfg(feature = "webhooks")]
+        webhooks::spawn(
+            &config.webhooks,
+            hub.clone(),
+            cache.clone(),
+            nvrs.clone(),
+            webhooks.clone(),
+        );
+        Ok(Self {
+            hub,
+            cache,
+            nvrs,
+            shedder: web::Data::new(shed::LoadShedder::new(
+                config.server.max_requests,
+                config.backend.max_calls(config.server.max_backend_calls),
+            )),
+            about: web::Data::new(about::About::new(&config)),
+            hubs: web::Data::new(hubs::Hubs::new(&config)),
+            health_config: web::Data::new(config.health),
+            watch_config: web::Data::new(config.watch),
+            #[cfg(feature = "webhooks")]
+            webhook_config: web::Data::new(config.webhooks),
+            #[cfg(feature = "webhooks")]
+            webhooks,
+            call_config: web::Data::new(config.call),
+            buildinfo_config: web::Data::new(config.buildinfo),
+            gating: web::Data::new(gating::Gating::new(config.gating)?),
+            vulnerabilities: web::Data::new(vulnerabilities::Vulnerabilities::new(
+                config.vulnerabilities,
+            )?),
+            #[cfg(feature = "download-proxy")]
+            downloader: web::Data::new(download::Downloader::new(
+                &config.download,
+                config.hub.ca_bundle.as_deref(),
+            )?),
+            readiness,
+        })
+    }
+}
+
+lazy_static! {
+    static ref EMBEDDED: Embedded =
+        Embedded::new().expect("Failed to load koji-sane-json-api configuration");
+}
+
+/// Mount the public API (`/buildinfo`, `/health`, `/about` and so on) in
+/// another actix-web application, e.g.
+/// `App::new().service(web::scope("/koji").configure(koji_sane_json_api::configure))`.
+///
+/// Settings come from `KOJI_API_CONFIG` and `KOJI_API_*` as for the
+/// server; the hub, cache and limits are shared by every worker.  The
+/// server's middleware (authentication, rate limits, request IDs, JSON
+/// errors) is not applied; wrap the scope in the host's own instead.
+/// Panics if the configuration is invalid.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let state = &*EMBEDDED;
+    cfg.app_data(state.hub.clone())
+        .app_data(state.cache.clone())
+        .app_data(state.nvrs.clone())
+        .app_data(state.shedder.clone())
+        .app_data(state.health_config.clone())
+        .app_data(state.watch_config.clone())
+        .app_data(state.call_config.clone())
+        .app_data(state.buildinfo_config.clone())
+        .app_data(state.gating.clone())
+        .app_data(state.vulnerabilities.clone())
+        .app_data(state.readiness.clone())
+        .app_data(state.about.clone())
+        .app_data(state.hubs.clone());
+    #[cfg(feature = "webhooks")]
+    cfg.app_data(state.webhook_config.clone())
+        .app_data(state.webhooks.clone());
+    #[cfg(feature = "download-proxy")]
+    cfg.app_data(state.downloader.clone());
+    cfg.configure(configure_api);
+    state.hubs.mount(cfg, configure_hub_api);
+}
+
+/// Routes for operators, which may be served on a separate listener.
+/// `/admin/cache`, which can rewrite any build, is only served with an
+/// import limit.
+fn configure_admin(cfg: &mut web::ServiceConfig, cache_import: Option<usize>) {
+    admin::configure(cfg);
+    if let Some(max_bytes) = cache_import {
+        admin::configure_cache(cfg, max_bytes);
+    }
+    #[cfg(feature = "metrics")]
+    metrics::configure(cfg);
+}
+
+/// The span for an incoming request.
+fn request_span(method: &str, path: &str, id: &request_id::RequestId) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        request_id = %id,
+        http.method = method,
+        http.target = path,
+        buildid = tracing::field::Empty,
+    )
+}
+
+/// Run the server as configured by the command line, environment and
+/// config file, until it is shut down.
+pub async fn run() -> anyhow::Result<()> {
+    let opt = cli::Opt::parse();
+    let config = config::Config::new(&opt)?;
+    koji::set_call_timeout(config.backend.call_timeout());
+    if let Some(cli::Command::Query(q)) = opt.command.as_ref() {
+        return query::run(q, &config.hub);
+    }
+    let effective_config = serde_json::to_value(&config)?;
+    #[cfg(feature = "metrics")]
+    koji::set_call_hook(metrics::backend_call);
+    #[cfg(feature = "reporting")]
+    let sentry_guard = report::init(&config.report);
+    #[cfg(feature = "reporting")]
+    let reporting = sentry_guard.is_some();
+    #[cfg(not(feature = "reporting"))]
+    let reporting = match config.report.sentry_dsn {
+        Some(_) => anyhow::bail!("report.sentry-dsn is set, but error reporting is not built in"),
+        None => false,
+    };
+    #[cfg(feature = "telemetry")]
+    let tracer = telemetry::init(&config.tracing)?;
+    #[cfg(not(feature = "telemetry"))]
+    let tracer = match config.tracing.otlp_endpoint {
+        Some(_) => {
+            anyhow::bail!("tracing.otlp-endpoint is set, but tracing support is not built in")
+        }
+        None => None,
+    };
+    let log_handle = logging::init(&config.server, tracer, reporting)?;
+    #[cfg(not(feature = "cli-backend"))]
+    tracing::warn!("Built without a koji backend; only imported builds can be served");
+    let hub = web::Data::new(RwLock::new(config.hub.clone()));
+    let hubs = web::Data::new(hubs::Hubs::new(&config));
+    let nvrs = web::Data::new(NvrMap::new(config.cache.mapping_capacity));
+    let cache = web::Data::new(Cache::new(config.cache.ttl, config.cache.max_bytes));
+    let usage = web::Data::new(usage::Usage::new());
+    let about = web::Data::new(about::About::new(&config));
+    let readiness = web::Data::new(health::Readiness::new(!config.prefetch.tags.is_empty()));
+    readiness.watch_shutdown()?;
+    prefetch::spawn(
+        config.prefetch,
+        hub.clone(),
+        cache.clone(),
+        nvrs.clone(),
+        readiness.clone(),
+    );
+    prefetch::spawn_refresh(
+        config.cache.refresh,
+        hub.clone(),
+        cache.clone(),
+        nvrs.clone(),
+    );
+    let proxies = web::Data::new(proxy::TrustedProxies::new(&config.server.trusted_proxies)?);
+    health::spawn_monitor(&config.health, hub.clone(), readiness.clone());
+    let health_config = web::Data::new(config.health);
+    let watch_config = web::Data::new(config.watch);
+    #[cfg(feature = "webhooks")]
+    let webhooks = web::Data::new(webhooks::Webhooks::open(&config.webhooks)?);
+    #[cfg(feature = "webhooks")]
+    webhooks::spawn(
+        &config.webhooks,
+        hub.clone(),
+        cache.clone(),
+        nvrs.clone(),
+        webhooks.clone(),
+    );
+    #[cfg(feature = "webhooks")]
+    let webhook_config = web::Data::new(config.webhooks);
+    #[cfg(not(feature = "webhooks"))]
+    if config.webhooks.enabled {
+        anyhow::bail!("webhooks.enabled is set, but webhook support is not built in");
+    }
+    let call_config = web::Data::new(config.call);
+    let buildinfo_config = web::Data::new(config.buildinfo);
+    let gating = web::Data::new(gating::Gating::new(config.gating)?);
+    let vulnerabilities = web::Data::new(vulnerabilities::Vulnerabilities::new(
+        config.vulnerabilities,
+    )?);
+    #[cfg(feature = "download-proxy")]
+    let downloader = web::Data::new(download::Downloader::new(
+        &config.download,
+        config.hub.ca_bundle.as_deref(),
+    )?);
+    #[cfg(not(feature = "download-proxy"))]
+    if config.download.enabled {
+        anyhow::bail!("download.enabled is set, but download support is not built in");
+    }
+    let authenticator = web::Data::new(auth::Authenticator::new(&config.auth)?);
+    if let Some(oidc) = authenticator.oidc() {
+        oidc.spawn_refresh();
+    }
+    let limiter = web::Data::new(ratelimit::RateLimiter::new(config.rate_limit));
+    let shedder = web::Data::new(shed::LoadShedder::new(
+        config.server.max_requests,
+        config.backend.max_calls(config.server.max_backend_calls),
+    ));
+    if let Some(bind) = config.grpc.bind {
+        #[cfg(feature = "grpc")]
+        grpc::spawn(
+            bind,
+            watch::Sources {
+                hub: hub.clone(),
+                cache: cache.clone(),
+                nvrs: nvrs.clone(),
+                shedder: shedder.clone(),
+            },
+        )?;
+        #[cfg(not(feature = "grpc"))]
+        anyhow::bail!(
+            "grpc.bind is set to {}, but gRPC support is not built in",
+            bind
+        );
+    }
+    #[cfg(feature = "bus")]
+    bus::start(&config.bus)?;
+    #[cfg(not(feature = "bus"))]
+    if config.bus.url.is_some() {
+        anyhow::bail!("bus.url is set, but message bus support is not built in");
+    }
+    export::start(&config.export)?;
+    let tls = config.server.tls.server_config()?;
+    let reloader = web::Data::new(reload::Reloader::new(
+        opt,
+        log_handle,
+        hub.clone(),
+        cache.clone(),
+        tls.as_ref().map(|(resolver, _)| resolver.clone()),
+        effective_config,
+    ));
+    let audit_log = web::Data::new(audit::AuditLog::new(&config.audit)?);
+    reload::spawn_sighup_handler(reloader.clone(), audit_log.clone())?;
+    let tls = tls.map(|(_, c)| c);
+    let separate_admin = !config.server.admin_bind.is_empty();
+    // Without credentials, only a separate listener keeps others from
+    // rewriting the cache
+    let cache_import =
+        Some(config.cache.max_bytes).filter(|_| separate_admin || authenticator.is_enabled());
+    let prefix = config.server.route_prefix();
+    let access_log = config.server.access_log;
+    let request_timeout = config.server.request_timeout();
+    let cors = config.cors;
+    let http_workers = config::http_workers();
+    let max_connections = config.server.max_connections_per_worker(http_workers);
+    let blocking_threads = config.backend.threads_per_http_worker(http_workers);
+
+    // Both the public and admin servers share the same state
+    macro_rules! server {
+        ($configure:expr) => {{
+            let hub = hub.clone();
+            let cache = cache.clone();
+            let nvrs = nvrs.clone();
+            let reloader = reloader.clone();
+            let audit_log = audit_log.clone();
+            let health_config = health_config.clone();
+            let watch_config = watch_config.clone();
+            #[cfg(feature = "webhooks")]
+            let webhook_config = webhook_config.clone();
+            #[cfg(feature = "webhooks")]
+            let webhooks = webhooks.clone();
+            let call_config = call_config.clone();
+            let buildinfo_config = buildinfo_config.clone();
+            let gating = gating.clone();
+            let vulnerabilities = vulnerabilities.clone();
+            #[cfg(feature = "download-proxy")]
+            let downloader = downloader.clone();
+            let readiness = readiness.clone();
+            let about = about.clone();
+            let hubs = hubs.clone();
+            let usage = usage.clone();
+            let proxies = proxies.clone();
+            let authenticator = authenticator.clone();
+            let limiter = limiter.clone();
+            let shedder = shedder.clone();
+            let prefix = prefix.clone();
+            let cors = cors.clone();
+            let configure = $configure;
+            let server = HttpServer::new(move || {
+                let authn = authenticator.clone();
+                let limiter = limiter.clone();
+                let load = shedder.clone();
+                let class_prefix = prefix.clone();
+                let stats = usage.clone();
+                let selector = hubs.clone();
+                let app = App::new()
+                    .app_data(hub.clone())
+                    .app_data(cache.clone())
+                    .app_data(nvrs.clone())
+                    .app_data(reloader.clone())
+                    .app_data(audit_log.clone())
+                    .app_data(health_config.clone())
+                    .app_data(watch_config.clone())
+                    .app_data(call_config.clone())
+                    .app_data(buildinfo_config.clone())
+                    .app_data(gating.clone())
+                    .app_data(vulnerabilities.clone())
+                    .app_data(readiness.clone())
+                    .app_data(about.clone())
+                    .app_data(hubs.clone())
+                    .app_data(usage.clone())
+                    .app_data(proxies.clone())
+                    .app_data(shedder.clone())
+                    .app_data(authenticator.clone());
+                #[cfg(feature = "webhooks")]
+                let app = app
+                    .app_data(webhook_config.clone())
+                    .app_data(webhooks.clone());
+                #[cfg(feature = "download-proxy")]
+                let app = app.app_data(downloader.clone());
+                let app = app
+                    .wrap_fn(move |mut req, srv| {
+                        #[cfg(feature = "metrics")]
+                        let start = metrics::request_started();
+                        #[cfg(not(feature = "metrics"))]
+                        let start = Instant::now();
+                        let stats = stats.clone();
+                        let pending = access_log::Pending::new(access_log, &req);
+                        let id = request_id::assign(&req);
+                        let vary = selector.varies(&class_prefix, req.path());
+                        let selected = selector
+                            .select(&class_prefix, &mut req)
+                            .map_err(|e| hubs::unknown_hub(&e, &id));
+                        let span = request_span(req.method().as_str(), req.path(), &id);
+                        #[cfg(feature = "telemetry")]
+                        telemetry::continue_trace(&span, req.headers());
+                        let permit = load.admit();
+                        let http_req = req.request().clone();
+                        let class = RouteClass::of_request(&class_prefix, &req);
+                        let admitted = selected
+                            .and_then(|()| authn.check(class, &req))
+                            .and_then(|()| {
+                                limiter
+                                    .check(class, &req)
+                                    .map_err(|wait| ratelimit::too_many_requests(wait, &id))
+                            })
+                            .and_then(|()| {
+                                if permit.is_some() {
+                                    Ok(())
+                                } else {
+                                    Err(shed::overloaded(&id))
+                                }
+                            });
+                        let fut = match admitted {
+                            Ok(()) => Either::Left(srv.call(req)),
+                            Err(res) => Either::Right(futures::future::ok(req.into_response(res))),
+                        };
+                        async move {
+                            let _permit = permit;
+                            let fut = recover::catch_panic(http_req.clone(), &id, fut);
+                            let res = timeout::with_deadline(request_timeout, http_req, &id, fut)
+                                .await
+                                .map(|res| {
+                                    let mut res = error::to_json(res, &id);
+                                    request_id::set_header(&mut res, &id);
+                                    if vary {
+                                        hubs::set_vary(res.headers_mut());
+                                    }
+                                    res
+                                });
+                            #[cfg(feature = "metrics")]
+                            metrics::request_finished(start, &res);
+                            stats.record(start, &res);
+                            if let Some(pending) = pending {
+                                pending.finish(&res);
+                            }
+                            res
+                        }
+                        .instrument(span)
+                    })
+                    // gzip, deflate or brotli per Accept-Encoding; a build like
+                    // texlive shrinks about tenfold
+                    .wrap(middleware::Compress::default())
+                    .wrap(cors::middleware(&cors));
+                // Attaches request details to reported errors
+                #[cfg(feature = "reporting")]
+                let app = app.wrap(middleware::Condition::new(
+                    reporting,
+                    sentry_actix::Sentry::new(),
+                ));
+                app.service(web::scope(&prefix).configure(configure.clone()))
+            })
+            .on_connect(tls::on_connect)
+            .workers(http_workers)
+            .worker_max_blocking_threads(blocking_threads)
+            .keep_alive(config.server.keep_alive())
+            .client_request_timeout(Duration::from_secs(config.server.client_timeout))
+            // On SIGTERM, actix stops accepting connections and waits up to this
+            // long for in-flight requests (including their koji calls) to complete.
+            .shutdown_timeout(config.server.shutdown_timeout);
+            match max_connections {
+                Some(n) => server.max_connections(n),
+                None => server,
+            }
+        }};
+    }
+    macro_rules! listen_all {
+        ($server:expr, $listeners:expr) => {{
+            let mut server = $server;
+            for l in $listeners {
+                server = match l {
+                    Listener::Tcp(l) => match tls.clone() {
+                        Some(tls) => server.listen_rustls(l, tls)?,
+                        None => server.listen(l)?,
+                    },
+                    // TLS is only for TCP; a local proxy terminates it otherwise
+                    Listener::Unix(l) => server.listen_uds(l)?,
+                };
+            }
+            server
+        }};
+    }
+
+    // When socket activated by systemd, serve the passed sockets instead of binding
+    let mut listeners = listen::from_systemd()?;
+    if listeners.is_empty() {
+        for addr in config.server.bind.iter() {
+            listeners.push(listen::open(addr, config.server.port)?);
+        }
+    } else {
+        tracing::info!("Using {} socket(s) from systemd", listeners.len());
+    }
+    let extra_hubs = hubs.clone();
+    let public = server!(move |cfg: &mut web::ServiceConfig| {
+        configure_api(cfg);
+        extra_hubs.mount(cfg, configure_hub_api);
+        if !separate_admin {
+            configure_admin(cfg, cache_import);
+        }
+    });
+    let public = listen_all!(public, listeners).run();
+    systemd::spawn_notify(hub.clone());
+    if separate_admin {
+        let mut listeners = Vec::new();
+        for addr in config.server.admin_bind.iter() {
+            listeners.push(listen::open(addr, config.server.port)?);
+        }
+        let admin = server!(move |cfg: &mut web::ServiceConfig| {
+            cfg.app_data(web::Data::new(auth::AdminListener));
+            configure_admin(cfg, cache_import);
+        });
+        let admin = listen_all!(admin, listeners).run();
+        futures::future::try_join(public, admin).await?;
+    } else {
+        public.await?;
+    }
+    // Requests are done, but koji calls they gave up on (or prefetches) may
+    // still be running; give them what's left of the drain window
+    let started = readiness.shutdown_started().unwrap_or_else(Instant::now);
+    koji::drain(started + Duration::from_secs(config.server.shutdown_timeout));
+    #[cfg(feature = "telemetry")]
+    telemetry::shutdown();
+    tracing::info!("Shut down");
+    Ok(())
+}
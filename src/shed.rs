@@ -0,0 +1,90 @@
+//! Load shedding: fail fast with 503 rather than queue without bound.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::error::ErrorServiceUnavailable;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::HttpResponse;
+
+use crate::error;
+use crate::request_id::RequestId;
+
+/// Holds a slot until dropped.
+pub(crate) struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Take a slot from `counter` unless `max` (if non-zero) are already taken.
+fn acquire(counter: &Arc<AtomicUsize>, max: usize) -> Option<Permit> {
+    let n = counter.fetch_add(1, Ordering::SeqCst);
+    let permit = Permit(counter.clone());
+    if max > 0 && n >= max {
+        None
+    } else {
+        Some(permit)
+    }
+}
+
+/// Caps on concurrent requests and on backend calls running or waiting for
+/// a worker thread.
+pub(crate) struct LoadShedder {
+    max_requests: usize,
+    max_backend_calls: usize,
+    requests: Arc<AtomicUsize>,
+    backend_calls: Arc<AtomicUsize>,
+}
+
+impl LoadShedder {
+    /// Limits of 0 mean unlimited.
+    pub(crate) fn new(max_requests: usize, max_backend_calls: usize) -> Self {
+        Self {
+            max_requests,
+            max_backend_calls,
+            requests: Default::default(),
+            backend_calls: Default::default(),
+        }
+    }
+
+    /// Admit a request, or `None` if at capacity.
+    pub(crate) fn admit(&self) -> Option<Permit> {
+        acquire(&self.requests, self.max_requests)
+    }
+
+    /// Reserve a slot for a koji call, failing with 503 if at capacity.
+    pub(crate) fn backend(&self) -> actix_web::Result<Permit> {
+        acquire(&self.backend_calls, self.max_backend_calls).ok_or_else(|| {
+            tracing::warn!("Backend at capacity; shedding request");
+            ErrorServiceUnavailable("Too many pending koji calls")
+        })
+    }
+}
+
+/// The response for a request rejected by `admit()`.
+pub(crate) fn overloaded(id: &RequestId) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((RETRY_AFTER, "1"))
+        .json(error::body("Server overloaded", Some(id)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let s = LoadShedder::new(2, 0);
+        let a = s.admit().unwrap();
+        let _b = s.admit().unwrap();
+        assert!(s.admit().is_none());
+        drop(a);
+        assert!(s.admit().is_some());
+        // Unlimited
+        let permits: Vec<_> = (0..100).map(|_| s.backend().unwrap()).collect();
+        assert_eq!(s.backend_calls.load(Ordering::SeqCst), permits.len());
+    }
+}
@@ -0,0 +1,50 @@
+//! Integration with systemd's service notification protocol.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web;
+use sd_notify::NotifyState;
+
+use crate::koji::{Backend, Hub};
+
+/// How long to wait between failed backend checks at startup.
+const CHECK_RETRY: Duration = Duration::from_secs(5);
+
+/// Start a thread which tells systemd we're ready once the hub answers,
+/// then pings the watchdog if one is configured.  Without `NOTIFY_SOCKET`
+/// the notifications are no-ops.
+pub(crate) fn spawn_notify(hub: web::Data<RwLock<Hub>>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    std::thread::spawn(move || {
+        loop {
+            let h = hub.read().unwrap().clone();
+            match h.api_version() {
+                Ok(v) => {
+                    tracing::info!("Hub reachable, API version {}", v);
+                    break;
+                }
+                Err(e) => tracing::warn!("Waiting for hub: {}", e),
+            }
+            std::thread::sleep(CHECK_RETRY);
+        }
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+            tracing::error!("Failed to notify systemd: {}", e);
+            return;
+        }
+        let mut usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut usec) {
+            return;
+        }
+        // Ping at half the deadline, as recommended by sd_watchdog_enabled(3)
+        let interval = Duration::from_micros(usec) / 2;
+        loop {
+            std::thread::sleep(interval);
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                tracing::error!("Failed to ping watchdog: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,311 @@
+//! `/tag/{tag}/...`: how a tag's buildroots are made up, to understand
+//! what its builds could have pulled in.
+
+use actix_web::error::{ErrorBadGateway, ErrorBadRequest, ErrorNotFound};
+use actix_web::{get, web, HttpResponse};
+use anyhow::{anyhow, bail, Result};
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+
+use crate::cache::Cache;
+use crate::config::CacheClass;
+use crate::koji::{Backend, Hub};
+use crate::server::run_blocking;
+use crate::watch::Sources;
+
+/// Koji tag names: letters, digits and `-_.+`.
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+}
+
+/// Call `f` with the hub and `getTag`'s answer, once `tag` is known to
+/// exist.
+async fn with_tag<F, T>(sources: &Sources, tag: &str, f: F) -> actix_web::Result<T>
+where
+    F: FnOnce(&Hub, &Value) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    if !valid_tag(tag) {
+        return Err(ErrorBadRequest(format!("Invalid tag {:?}", tag)));
+    }
+    let hub = sources.hub.read().unwrap().clone();
+    let name = tag.to_string();
+    let tag = tag.to_string();
+    let _permit = sources.shedder.backend()?;
+    let r = run_blocking(move || {
+        let info = hub.call("getTag", &[tag.as_str().into()], &Map::new())?;
+        if info.is_null() {
+            return Ok(None);
+        }
+        f(&hub, &info).map(Some)
+    })
+    .await;
+    r.map_err(|e| ErrorBadGateway(format!("{:#}", e)))?
+        .ok_or_else(|| ErrorNotFound(format!("No such tag: {}", name)))
+}
+
+/// The cache key of `tag`'s `what` listing.  Unlike NVRs these contain
+/// slashes, and tags themselves never do.
+fn cache_key(tag: &str, what: &str) -> String {
+    format!("tag/{}/{}", tag, what)
+}
+
+/// The tag and listing of a key made by `cache_key()`.
+fn parse_cache_key(key: &str) -> Option<(&str, &str)> {
+    let (tag, what) = key.strip_prefix("tag/")?.split_once('/')?;
+    Some((tag, what))
+}
+
+/// Answer with `tag`'s `what` listing from the cache, else with the JSON
+/// `f` makes of it, cached for `cache.ttl.tag`.
+async fn cached(
+    sources: &Sources,
+    tag: &str,
+    what: &str,
+    f: Listing,
+) -> actix_web::Result<HttpResponse> {
+    let key = cache_key(tag, what);
+    let body = match sources.cache.get(&key) {
+        Some(body) => body,
+        None => {
+            let body = with_tag(sources, tag, f).await?.to_string();
+            sources.cache.insert(&key, CacheClass::Tag, body.clone());
+            body
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+/// Makes a listing's JSON from the hub and `getTag`'s answer.
+type Listing = fn(&Hub, &Value) -> Result<Value>;
+
+fn listing(what: &str) -> Option<Listing> {
+    match what {
+        "external-repos" => Some(external_repos_listing),
+        "inheritance" => Some(inheritance_listing),
+        _ => None,
+    }
+}
+
+/// Fetch the tag listing cached under `key` again and renew its entry,
+/// for `prefetch::spawn_refresh`.
+pub(crate) fn refresh(hub: &Hub, cache: &Cache, key: &str) -> Result<()> {
+    let (tag, what) = parse_cache_key(key).ok_or_else(|| anyhow!("Not a tag listing"))?;
+    let f = listing(what).ok_or_else(|| anyhow!("Unknown tag listing {}", what))?;
+    let info = hub.call("getTag", &[tag.into()], &Map::new())?;
+    if info.is_null() {
+        bail!("No such tag: {}", tag);
+    }
+    cache.insert(key, CacheClass::Tag, f(hub, &info)?.to_string());
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExternalRepo {
+    name: String,
+    url: String,
+    /// Lower priorities are searched first.
+    priority: i64,
+    /// How packages are merged with the tag's: `koji`, `simple` or `bare`.
+    merge_mode: Option<String>,
+    /// Arches the repo is used for; all of the tag's if empty.
+    arches: Vec<String>,
+    /// The tag the repo is configured on, which may be one inherited from.
+    tag: String,
+}
+
+/// Parse `getExternalRepoList` output.
+fn external_repos(list: &Value) -> Vec<ExternalRepo> {
+    let s = |v: &Value| v.as_str().unwrap_or_default().to_string();
+    list.as_array()
+        .map(|repos| {
+            repos
+                .iter()
+                .map(|r| ExternalRepo {
+                    name: s(&r["external_repo_name"]),
+                    url: s(&r["url"]),
+                    priority: r["priority"].as_i64().unwrap_or_default(),
+                    merge_mode: r["merge_mode"].as_str().map(String::from),
+                    arches: r["arches"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .map(String::from)
+                        .collect(),
+                    tag: s(&r["tag_name"]),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn external_repos_listing(hub: &Hub, info: &Value) -> Result<Value> {
+    // Unlike getTagExternalRepos, this includes those of parent tags
+    let list = hub.call("getExternalRepoList", &[info["id"].clone()], &Map::new())?;
+    Ok(serde_json::json!({
+        "tag": info["name"],
+        "external-repos": external_repos(&list),
+    }))
+}
+
+#[get("/tag/{tag}/external-repos")]
+async fn tag_external_repos(
+    sources: Sources,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    cached(&sources, &path.0, "external-repos", external_repos_listing).await
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Parent {
+    name: String,
+    id: u64,
+    /// Levels above the tag, 1 for its direct parents.
+    depth: u64,
+    /// The tag this one is a parent of.
+    parent_of: String,
+    /// Among the child's parents, lower priorities are searched first.
+    priority: i64,
+    /// Only inherited by the child, not by tags inheriting from it.
+    intransitive: bool,
+    /// How many further levels are inherited through this one; all if unset.
+    maxdepth: Option<u64>,
+    /// Only packages are inherited, not configuration such as arches.
+    noconfig: bool,
+    /// A regex of package names inherited; all if unset.
+    pkg_filter: Option<String>,
+}
+
+/// Parse `getFullInheritance` output for the tag `info`, naming each
+/// link's child.  Koji lists ancestors in the order it searches them.
+fn inheritance(info: &Value, full: &Value) -> Vec<Parent> {
+    let entries = full.as_array().map(Vec::as_slice).unwrap_or_default();
+    let name_of = |id: &Value| -> String {
+        if *id == info["id"] {
+            return info["name"].as_str().unwrap_or_default().to_string();
+        }
+        entries
+            .iter()
+            .find(|e| e["parent_id"] == *id)
+            .and_then(|e| e["name"].as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    entries
+        .iter()
+        .map(|e| Parent {
+            name: e["name"].as_str().unwrap_or_default().to_string(),
+            id: e["parent_id"].as_u64().unwrap_or_default(),
+            depth: e["currdepth"].as_u64().unwrap_or(1),
+            parent_of: name_of(&e["child_id"]),
+            priority: e["priority"].as_i64().unwrap_or_default(),
+            intransitive: e["intransitive"].as_bool().unwrap_or_default(),
+            maxdepth: e["maxdepth"].as_u64(),
+            noconfig: e["noconfig"].as_bool().unwrap_or_default(),
+            pkg_filter: e["pkg_filter"]
+                .as_str()
+                .filter(|f| !f.is_empty())
+                .map(String::from),
+        })
+        .collect()
+}
+
+fn inheritance_listing(hub: &Hub, info: &Value) -> Result<Value> {
+    let full = hub.call("getFullInheritance", &[info["id"].clone()], &Map::new())?;
+    Ok(serde_json::json!({
+        "tag": info["name"],
+        "inheritance": inheritance(info, &full),
+    }))
+}
+
+#[get("/tag/{tag}/inheritance")]
+async fn tag_inheritance(
+    sources: Sources,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    cached(&sources, &path.0, "inheritance", inheritance_listing).await
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(tag_external_repos).service(tag_inheritance);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_tag() {
+        assert!(valid_tag("f34-build"));
+        assert!(valid_tag("epel8-playground+side"));
+        assert!(!valid_tag(""));
+        assert!(!valid_tag("f34 build"));
+        assert!(!valid_tag("../f34"));
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = cache_key("f34-build", "inheritance");
+        assert_eq!(parse_cache_key(&key), Some(("f34-build", "inheritance")));
+        assert!(listing("inheritance").is_some());
+        assert!(listing("external-repos").is_some());
+        assert!(listing("builds").is_none());
+        assert_eq!(parse_cache_key("bash-5.1.8-9.el9"), None);
+        assert_eq!(parse_cache_key("tag/f34-build"), None);
+    }
+
+    #[test]
+    fn test_external_repos() {
+        let list = serde_json::json!([
+            {"external_repo_id": 1, "external_repo_name": "centos-stream-9-baseos",
+             "url": "https://mirror.stream.centos.org/9-stream/BaseOS/$arch/os/",
+             "priority": 5, "merge_mode": "bare", "arches": "x86_64 aarch64",
+             "tag_id": 2, "tag_name": "epel9-next-build"},
+            {"external_repo_id": 3, "external_repo_name": "rhel-9",
+             "url": "https://example.com/rhel9/$arch/", "priority": 10,
+             "merge_mode": null, "arches": null, "tag_name": "epel9-build"}
+        ]);
+        let repos = external_repos(&list);
+        assert_eq!(repos.len(), 2);
+        assert_eq!(repos[0].name, "centos-stream-9-baseos");
+        assert_eq!(repos[0].merge_mode.as_deref(), Some("bare"));
+        assert_eq!(repos[0].arches, ["x86_64", "aarch64"]);
+        assert_eq!(repos[1].tag, "epel9-build");
+        assert!(repos[1].arches.is_empty());
+        assert!(external_repos(&Value::Null).is_empty());
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let info = serde_json::json!({"id": 10, "name": "f34-build"});
+        let full = serde_json::json!([
+            {"parent_id": 11, "name": "f34-override", "child_id": 10, "currdepth": 1,
+             "priority": 0, "intransitive": false, "maxdepth": null, "noconfig": false,
+             "pkg_filter": ""},
+            {"parent_id": 12, "name": "f34-updates", "child_id": 11, "currdepth": 2,
+             "priority": 0, "intransitive": true, "maxdepth": 1, "noconfig": true,
+             "pkg_filter": "^kernel"},
+            {"parent_id": 13, "name": "f34", "child_id": 10, "currdepth": 1,
+             "priority": 5, "intransitive": false, "maxdepth": null, "noconfig": false,
+             "pkg_filter": ""}
+        ]);
+        let parents = inheritance(&info, &full);
+        let names: Vec<_> = parents.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["f34-override", "f34-updates", "f34"]);
+        assert_eq!(parents[0].parent_of, "f34-build");
+        assert_eq!(parents[1].parent_of, "f34-override");
+        assert_eq!(parents[1].depth, 2);
+        assert!(parents[1].intransitive);
+        assert_eq!(parents[1].maxdepth, Some(1));
+        assert_eq!(parents[1].pkg_filter.as_deref(), Some("^kernel"));
+        assert_eq!(parents[2].pkg_filter, None);
+        assert!(inheritance(&info, &Value::Null).is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+//! Distributed tracing, exported via OTLP.
+
+use actix_web::http::header::HeaderMap;
+use anyhow::Result;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace as sdktrace;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::TracingConfig;
+
+/// Set up the OTLP exporter if configured, returning the tracer to be
+/// installed into the subscriber by `logging::init()`.
+pub(crate) fn init(config: &TracingConfig) -> Result<Option<sdktrace::Tracer>> {
+    let endpoint = match config.otlp_endpoint.as_deref() {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            sdktrace::config().with_resource(opentelemetry::sdk::Resource::new(vec![
+                KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            ])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Flush pending spans on shutdown.
+pub(crate) fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Make `span`, an incoming request's, continue the caller's trace if it
+/// sent a `traceparent` header.
+pub(crate) fn continue_trace(span: &tracing::Span, headers: &HeaderMap) {
+    let parent =
+        opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
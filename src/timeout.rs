@@ -0,0 +1,49 @@
+//! End-to-end request deadlines.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServiceResponse;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+use crate::error;
+use crate::request_id::RequestId;
+
+/// When the request will be cut off, stored for handlers which wait.
+#[derive(Debug, Clone, Copy)]
+struct Deadline(Instant);
+
+/// The time by which `req` must be answered, if limited.
+pub(crate) fn deadline(req: &HttpRequest) -> Option<Instant> {
+    req.extensions().get::<Deadline>().map(|d| d.0)
+}
+
+/// Run the handling of `req`, responding 504 if it takes longer than
+/// `timeout`.  Koji calls already running on the thread pool are left to
+/// finish in the background.
+pub(crate) async fn with_deadline<F>(
+    timeout: Option<Duration>,
+    req: HttpRequest,
+    id: &RequestId,
+    fut: F,
+) -> actix_web::Result<ServiceResponse>
+where
+    F: Future<Output = actix_web::Result<ServiceResponse>>,
+{
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return fut.await,
+    };
+    req.extensions_mut()
+        .insert(Deadline(Instant::now() + timeout));
+    match actix_web::rt::time::timeout(timeout, fut).await {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!("Request timed out after {}s", timeout.as_secs());
+            Ok(ServiceResponse::new(
+                req,
+                HttpResponse::GatewayTimeout().json(error::body("Request timed out", Some(id))),
+            ))
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Serving HTTPS directly via rustls.
+
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Context, Result};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct TlsConfig {
+    /// PEM certificate chain; TLS is enabled when this and `key` are set.
+    pub(crate) cert: Option<PathBuf>,
+    /// PEM private key (PKCS#8 or RSA).
+    pub(crate) key: Option<PathBuf>,
+    /// PEM CA bundle; if set, clients must present a certificate signed by it.
+    pub(crate) client_ca: Option<PathBuf>,
+}
+
+/// Parse the DER items of one kind from a PEM file.
+fn read_pem(
+    path: &Path,
+    parse: fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>>,
+) -> Result<Vec<Vec<u8>>> {
+    let f = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    parse(&mut BufReader::new(f)).with_context(|| format!("Parsing {}", path.display()))
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let certs: Vec<_> = read_pem(cert, rustls_pemfile::certs)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert.display());
+    }
+    let mut keys = read_pem(key, rustls_pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(key, rustls_pemfile::rsa_private_keys)?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| anyhow!("No private key found in {}", key.display()))?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serves a certificate which can be re-read from disk, e.g. after renewal.
+pub(crate) struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub(crate) fn new(cert: &Path, key: &Path) -> Result<Self> {
+        Ok(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert, key)?)),
+        })
+    }
+
+    /// Re-read the certificate and key; new connections use them.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let k = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(k);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl TlsConfig {
+    /// Returns the resolver (for reloading) and server configuration if TLS is enabled.
+    pub(crate) fn server_config(&self) -> Result<Option<(Arc<CertResolver>, ServerConfig)>> {
+        let (cert, key) = match (self.cert.as_deref(), self.key.as_deref()) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => anyhow::bail!("Both a TLS certificate and key must be configured"),
+        };
+        let resolver = Arc::new(CertResolver::new(cert, key)?);
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_ca.as_deref() {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) =
+                    roots.add_parsable_certificates(&read_pem(ca, rustls_pemfile::certs)?);
+                if added == 0 {
+                    anyhow::bail!("No CA certificates found in {}", ca.display());
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_cert_resolver(resolver.clone());
+        Ok(Some((resolver, config)))
+    }
+}
+
+/// The subject common name of a verified TLS client certificate.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientIdentity(pub(crate) String);
+
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(String::from)
+}
+
+/// Connection callback recording the client certificate identity, if any,
+/// so it's available to every request on the connection.
+pub(crate) fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let stream = match conn.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(s) => s,
+        None => return,
+    };
+    let (sock, session) = stream.get_ref();
+    let cert = session.peer_certificates().and_then(|certs| certs.first());
+    if let Some(cert) = cert {
+        let name = common_name(&cert.0).unwrap_or_else(|| "<unknown>".to_string());
+        tracing::info!(
+            "TLS client {} connected from {}",
+            name,
+            sock.peer_addr().map(|a| a.to_string()).unwrap_or_default()
+        );
+        ext.insert(ClientIdentity(name));
+    }
+}
+
+/// The verified client certificate identity for this request's connection.
+pub(crate) fn client_identity(req: &HttpRequest) -> Option<ClientIdentity> {
+    req.conn_data::<ClientIdentity>().cloned()
+}
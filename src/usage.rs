@@ -0,0 +1,189 @@
+//! Usage statistics since startup, served at `/admin/stats`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest};
+use serde_derive::Serialize;
+
+use crate::access_log::CacheStatus;
+use crate::koji;
+
+/// Beyond this many distinct builds or packages, rarely requested ones are
+/// forgotten and new ones not counted.
+const MAX_TRACKED: usize = 10_000;
+
+/// The build a request was for, set by the handler.
+struct Build(String);
+
+/// Note which build a request was for, once its id has been canonicalized.
+pub(crate) fn set_build(req: &HttpRequest, buildid: &str) {
+    req.extensions_mut().insert(Build(buildid.to_string()));
+}
+
+fn count(counts: &mut HashMap<String, u64>, key: &str) {
+    if let Some(n) = counts.get_mut(key) {
+        *n += 1;
+        return;
+    }
+    if counts.len() >= MAX_TRACKED {
+        counts.retain(|_, n| *n > 1);
+        if counts.len() >= MAX_TRACKED {
+            return;
+        }
+    }
+    counts.insert(key.to_string(), 1);
+}
+
+/// The `n` most frequent keys, most frequent first.
+fn top(counts: &HashMap<String, u64>, n: usize) -> Vec<(String, u64)> {
+    let mut v: Vec<_> = counts.iter().map(|(k, n)| (k.clone(), *n)).collect();
+    v.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    v.truncate(n);
+    v
+}
+
+#[derive(Default)]
+struct Counts {
+    routes: HashMap<String, u64>,
+    builds: HashMap<String, u64>,
+    packages: HashMap<String, u64>,
+    hits: u64,
+    revalidated: u64,
+    misses: u64,
+    bypassed: u64,
+    /// Total time spent answering misses, to estimate what hits saved.
+    miss_time: Duration,
+}
+
+pub(crate) struct Usage {
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CacheSavings {
+    hits: u64,
+    revalidated: u64,
+    misses: u64,
+    bypassed: u64,
+    /// Koji calls avoided by answering from the cache.
+    backend_calls_saved: u64,
+    /// Hits times the mean time to answer a miss.
+    estimated_secs_saved: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Stats {
+    since_secs: u64,
+    routes: HashMap<String, u64>,
+    top_builds: Vec<(String, u64)>,
+    top_packages: Vec<(String, u64)>,
+    cache: CacheSavings,
+}
+
+impl Usage {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            counts: Default::default(),
+        }
+    }
+
+    /// Called by the request middleware with the outcome of a request.
+    pub(crate) fn record<B>(
+        &self,
+        start: Instant,
+        res: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        let res = match res {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let req = res.request();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let mut counts = self.counts.lock().unwrap();
+        count(&mut counts.routes, &route);
+        if !res.status().is_success() && res.status() != StatusCode::NOT_MODIFIED {
+            return;
+        }
+        let extensions = req.extensions();
+        if let Some(Build(buildid)) = extensions.get::<Build>() {
+            count(&mut counts.builds, buildid);
+            if let Ok((name, _, _)) = koji::split_nvr(buildid) {
+                count(&mut counts.packages, name);
+            }
+        }
+        match extensions.get::<CacheStatus>() {
+            Some(CacheStatus::Hit) => counts.hits += 1,
+            Some(CacheStatus::Revalidated) => counts.revalidated += 1,
+            Some(CacheStatus::Miss) => {
+                counts.misses += 1;
+                counts.miss_time += start.elapsed();
+            }
+            Some(CacheStatus::Bypass) => counts.bypassed += 1,
+            None => {}
+        }
+    }
+
+    /// Statistics with the `n` most requested builds and packages.
+    pub(crate) fn stats(&self, n: usize) -> Stats {
+        let counts = self.counts.lock().unwrap();
+        let mean_miss = if counts.misses > 0 {
+            counts.miss_time.as_secs_f64() / counts.misses as f64
+        } else {
+            0.0
+        };
+        Stats {
+            since_secs: self.started.elapsed().as_secs(),
+            routes: counts.routes.clone(),
+            top_builds: top(&counts.builds, n),
+            top_packages: top(&counts.packages, n),
+            cache: CacheSavings {
+                hits: counts.hits,
+                revalidated: counts.revalidated,
+                misses: counts.misses,
+                bypassed: counts.bypassed,
+                backend_calls_saved: counts.hits,
+                estimated_secs_saved: counts.hits as f64 * mean_miss,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_top() {
+        let mut counts = HashMap::new();
+        for k in &["a", "b", "b", "c", "c", "c"] {
+            count(&mut counts, k);
+        }
+        assert_eq!(
+            top(&counts, 2),
+            vec![("c".to_string(), 3), ("b".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut counts = HashMap::new();
+        for i in 0..MAX_TRACKED {
+            count(&mut counts, &i.to_string());
+        }
+        count(&mut counts, "0");
+        // Full, so the singletons are dropped to make room
+        count(&mut counts, "new");
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["0"], 2);
+    }
+}
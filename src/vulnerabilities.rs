@@ -0,0 +1,311 @@
+//! `GET /buildinfo/{id}/vulnerabilities`: known vulnerabilities of a
+//! build's package at its version, from OSV and optionally Red Hat's
+//! security data.
+
+use std::time::Duration;
+
+use actix_web::error::{ErrorBadGateway, ErrorNotFound};
+use actix_web::{get, web, HttpResponse};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::koji::{self, KojiBuildInfo};
+use crate::watch::Sources;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct VulnerabilitiesConfig {
+    /// OSV's API, e.g. `https://api.osv.dev`; not asked if unset.
+    pub(crate) osv_url: Option<String>,
+    /// The OSV ecosystem builds' packages are in.
+    pub(crate) ecosystem: String,
+    /// Red Hat's security data API, e.g.
+    /// `https://access.redhat.com/hydra/rest/securitydata`; not asked if
+    /// unset.
+    pub(crate) redhat_url: Option<String>,
+    /// Seconds to wait for each.
+    pub(crate) timeout: u64,
+}
+
+impl Default for VulnerabilitiesConfig {
+    fn default() -> Self {
+        Self {
+            osv_url: None,
+            ecosystem: "Red Hat".to_string(),
+            redhat_url: None,
+            timeout: 30,
+        }
+    }
+}
+
+impl VulnerabilitiesConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.osv_url.is_some() || self.redhat_url.is_some()
+    }
+}
+
+/// Asks OSV and Red Hat about vulnerabilities.
+pub(crate) struct Vulnerabilities {
+    config: VulnerabilitiesConfig,
+    client: reqwest::Client,
+}
+
+impl Vulnerabilities {
+    pub(crate) fn new(config: VulnerabilitiesConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()?;
+        Ok(Self { config, client })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Vulnerability {
+    /// E.g. `CVE-2023-0286` or `RHSA-2023:0946`.
+    id: String,
+    /// `osv` or `redhat`.
+    source: &'static str,
+    /// Other ids of the same vulnerability, e.g. advisories fixing it.
+    aliases: Vec<String>,
+    summary: String,
+    severity: Option<String>,
+    /// Versions or packages with the fix.
+    fixed_in: Vec<String>,
+    url: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct VulnerabilitiesResponse {
+    nvr: String,
+    name: String,
+    /// `[epoch:]version-release`, as asked about.
+    version: String,
+    vulnerabilities: Vec<Vulnerability>,
+}
+
+/// The build's package name and `[epoch:]version-release`, the epoch
+/// taken from its source RPM.
+fn name_evr(info: &KojiBuildInfo) -> Option<(String, String)> {
+    let (name, version, release) = koji::split_nvr(&info.nvr).ok()?;
+    // Drafts' releases are suffixed with their build id
+    let release = release.split(',').next().unwrap_or_default();
+    let epoch = info
+        .rpms
+        .get("src")
+        .and_then(|r| r.first())
+        .and_then(|f| info.nevras.get(f))
+        .and_then(|n| n.epoch);
+    let evr = match epoch {
+        Some(e) => format!("{}:{}-{}", e, version, release),
+        None => format!("{}-{}", version, release),
+    };
+    Some((name.to_string(), evr))
+}
+
+fn strings(v: &Value) -> Vec<String> {
+    v.as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|s| s.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parse OSV's `/v1/query` answer; the fixes are those of ranges of
+/// `name`'s package.
+fn osv_vulns(name: &str, answer: &Value) -> Vec<Vulnerability> {
+    let vulns = answer["vulns"].as_array().map(Vec::as_slice);
+    vulns
+        .unwrap_or_default()
+        .iter()
+        .map(|v| {
+            let id = v["id"].as_str().unwrap_or_default().to_string();
+            let fixed_in = v["affected"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .filter(|a| a["package"]["name"] == name)
+                .flat_map(|a| a["ranges"].as_array().cloned().unwrap_or_default())
+                .flat_map(|r| r["events"].as_array().cloned().unwrap_or_default())
+                .filter_map(|e| e["fixed"].as_str().map(String::from))
+                .collect();
+            let severity = v["database_specific"]["severity"]
+                .as_str()
+                .or_else(|| v["severity"][0]["score"].as_str())
+                .map(String::from);
+            Vulnerability {
+                url: Some(format!("https://osv.dev/vulnerability/{}", id)),
+                id,
+                source: "osv",
+                aliases: strings(&v["aliases"]),
+                summary: v["summary"]
+                    .as_str()
+                    .or_else(|| v["details"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                severity,
+                fixed_in,
+            }
+        })
+        .collect()
+}
+
+/// Parse Red Hat's `cve.json?package=` answer.  Its CVEs are all those
+/// ever affecting the package, whatever the version; the fixes are the
+/// package's builds in the advisories.
+fn redhat_cves(name: &str, answer: &Value) -> Vec<Vulnerability> {
+    let prefix = format!("{}-", name);
+    answer
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|c| Vulnerability {
+            id: c["CVE"].as_str().unwrap_or_default().to_string(),
+            source: "redhat",
+            aliases: strings(&c["advisories"]),
+            summary: c["bugzilla_description"]
+                .as_str()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+            severity: c["severity"].as_str().map(String::from),
+            fixed_in: strings(&c["affected_packages"])
+                .into_iter()
+                .filter(|p| p.starts_with(&prefix))
+                .collect(),
+            url: c["resource_url"].as_str().map(String::from),
+        })
+        .collect()
+}
+
+/// Fetch a JSON answer, failing for any other.
+async fn fetch(req: reqwest::RequestBuilder, what: &str) -> actix_web::Result<Value> {
+    let res = req
+        .send()
+        .await
+        .map_err(|e| ErrorBadGateway(format!("Querying {}: {}", what, e)))?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(ErrorBadGateway(format!("{} answered {}", what, status)));
+    }
+    res.json()
+        .await
+        .map_err(|e| ErrorBadGateway(format!("Parsing {}'s answer: {}", what, e)))
+}
+
+#[get("/buildinfo/{id}/vulnerabilities")]
+async fn vulnerabilities(
+    vulns: web::Data<Vulnerabilities>,
+    sources: Sources,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    let config = &vulns.config;
+    if !config.enabled() {
+        return Err(ErrorNotFound("Vulnerability lookup is disabled"));
+    }
+    let info = sources.build(&path.0, false).await?;
+    let (name, evr) =
+        name_evr(&info).ok_or_else(|| ErrorBadGateway(format!("Unparseable NVR {}", info.nvr)))?;
+    let mut found = Vec::new();
+    if let Some(url) = config.osv_url.as_deref() {
+        let req = vulns
+            .client
+            .post(format!("{}/v1/query", url.trim_end_matches('/')))
+            .json(&serde_json::json!({
+                "package": {"name": name, "ecosystem": config.ecosystem},
+                "version": evr,
+            }));
+        found.extend(osv_vulns(&name, &fetch(req, "OSV").await?));
+    }
+    if let Some(url) = config.redhat_url.as_deref() {
+        let req = vulns
+            .client
+            .get(format!("{}/cve.json", url.trim_end_matches('/')))
+            .query(&[("package", &name)]);
+        found.extend(redhat_cves(&name, &fetch(req, "Red Hat").await?));
+    }
+    Ok(HttpResponse::Ok().json(VulnerabilitiesResponse {
+        nvr: info.nvr,
+        name,
+        version: evr,
+        vulnerabilities: found,
+    }))
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(vulnerabilities);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::koji::RpmNevra;
+
+    #[test]
+    fn test_name_evr() {
+        let mut info = KojiBuildInfo {
+            nvr: "openssl-3.0.7-16.el9_2".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            name_evr(&info),
+            Some(("openssl".to_string(), "3.0.7-16.el9_2".to_string()))
+        );
+        let srpm = RpmNevra {
+            name: "openssl".to_string(),
+            epoch: Some(1),
+            version: "3.0.7".to_string(),
+            release: "16.el9_2".to_string(),
+            arch: "src".to_string(),
+        };
+        info.rpms.insert("src".to_string(), vec![srpm.filename()]);
+        info.nevras.insert(srpm.filename(), srpm);
+        assert_eq!(name_evr(&info).unwrap().1, "1:3.0.7-16.el9_2");
+    }
+
+    #[test]
+    fn test_osv_vulns() {
+        let answer = serde_json::json!({"vulns": [{
+            "id": "RHSA-2023:0946",
+            "summary": "openssl security update",
+            "aliases": ["CVE-2023-0286"],
+            "affected": [
+                {"package": {"ecosystem": "Red Hat", "name": "openssl"},
+                 "ranges": [{"type": "ECOSYSTEM",
+                             "events": [{"introduced": "0"}, {"fixed": "1:3.0.7-6.el9_2"}]}]},
+                {"package": {"ecosystem": "Red Hat", "name": "openssl-libs"},
+                 "ranges": [{"type": "ECOSYSTEM", "events": [{"fixed": "1:3.0.7-6.el9_2"}]}]}
+            ],
+            "database_specific": {"severity": "Important"}
+        }]});
+        let v = osv_vulns("openssl", &answer);
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].aliases, ["CVE-2023-0286"]);
+        assert_eq!(v[0].fixed_in, ["1:3.0.7-6.el9_2"]);
+        assert_eq!(v[0].severity.as_deref(), Some("Important"));
+        assert!(osv_vulns("openssl", &serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_redhat_cves() {
+        let answer = serde_json::json!([{
+            "CVE": "CVE-2023-0286",
+            "severity": "important",
+            "advisories": ["RHSA-2023:0946"],
+            "bugzilla_description": "openssl: X.400 address type confusion ",
+            "affected_packages": ["openssl-1:3.0.7-6.el9_2", "openssl-libs-1:3.0.7-6.el9_2"],
+            "resource_url": "https://access.redhat.com/hydra/rest/securitydata/cve/CVE-2023-0286.json"
+        }]);
+        let v = redhat_cves("openssl", &answer);
+        assert_eq!(v[0].id, "CVE-2023-0286");
+        assert_eq!(v[0].summary, "openssl: X.400 address type confusion");
+        // Not the subpackage's
+        assert_eq!(v[0].fixed_in.len(), 1);
+    }
+}
@@ -0,0 +1,455 @@
+//! Following builds and tasks until they finish: `/ws` pushes their state
+//! changes to WebSocket clients, `/buildinfo/{id}/events` streams a
+//! build's as server-sent events, and `/buildinfo/{id}/wait` long-polls.
+//!
+//! Clients send `{"subscribe": {"builds": ["rpm-ostree-2020.10-1.fc34"],
+//! "tasks": [12345]}}` (or `unsubscribe`), and get each target's current
+//! state, then an event whenever it changes, e.g.
+//! `{"build": "rpm-ostree-2020.10-1.fc34", "state": "COMPLETE", "info": {...}}`.
+//! Targets are dropped once finished or if looking them up fails.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::{get, web, FromRequest, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use futures::future::{ready, Either, Ready};
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cache::{Cache, NvrMap};
+use crate::koji::{self, Backend, Hub, KojiBuildInfo};
+use crate::server::{lookup_build, run_blocking};
+use crate::shed::LoadShedder;
+use crate::timeout;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct WatchConfig {
+    /// Seconds between checks of watched builds and tasks.
+    pub(crate) poll_interval: u64,
+    /// Targets one connection may watch at once.
+    pub(crate) max_subscriptions: usize,
+    /// Longest `timeout` accepted by `/buildinfo/{id}/wait`, in seconds.
+    pub(crate) max_wait: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: 30,
+            max_subscriptions: 100,
+            max_wait: 3600,
+        }
+    }
+}
+
+/// A build (by NVR or id, as the client gave it) or task.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Target {
+    Build(String),
+    Task(u64),
+}
+
+/// The state of a target, or why it couldn't be looked up.
+#[derive(Serialize)]
+pub(crate) struct Event {
+    #[serde(flatten)]
+    pub(crate) target: Target,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) state: Option<String>,
+    /// For builds, the same as `/buildinfo`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) info: Option<KojiBuildInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+impl Event {
+    /// Whether nothing more will happen to the target.
+    pub(crate) fn is_final(&self) -> bool {
+        match (&self.info, self.state.as_deref()) {
+            (Some(info), _) => !info.is_in_progress(),
+            (None, Some(state)) => koji::is_task_finished(state),
+            (None, None) => true,
+        }
+    }
+}
+
+/// The hub, cache and backend limits, for looking up what's watched.
+#[derive(Clone)]
+pub(crate) struct Sources {
+    pub(crate) hub: web::Data<RwLock<Hub>>,
+    pub(crate) cache: web::Data<Cache>,
+    pub(crate) nvrs: web::Data<NvrMap>,
+    pub(crate) shedder: web::Data<LoadShedder>,
+}
+
+/// Extracted from the application's data, like `web::Data`.
+impl FromRequest for Sources {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        fn data<T: 'static>(req: &HttpRequest) -> actix_web::Result<web::Data<T>> {
+            req.app_data::<web::Data<T>>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError("Missing application data"))
+        }
+        ready((|| {
+            Ok(Sources {
+                hub: data(req)?,
+                cache: data(req)?,
+                nvrs: data(req)?,
+                shedder: data(req)?,
+            })
+        })())
+    }
+}
+
+impl Sources {
+    /// Look up a build as `/buildinfo` does, through the cache.
+    pub(crate) async fn build(
+        &self,
+        buildid: &str,
+        refresh: bool,
+    ) -> actix_web::Result<KojiBuildInfo> {
+        let buildid = self.nvrs.canonicalize(buildid);
+        let (body, _) = lookup_build(
+            &self.hub,
+            &self.cache,
+            &self.nvrs,
+            &self.shedder,
+            &buildid,
+            refresh,
+        )
+        .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// The current state of `target`.
+    pub(crate) async fn poll(&self, target: &Target) -> Event {
+        let (state, info, error) = match target {
+            Target::Build(id) => match self.build(id, false).await {
+                Ok(info) => (Some(info.state.clone()), Some(info), None),
+                Err(e) => (None, None, Some(e.to_string())),
+            },
+            Target::Task(id) => {
+                let id = *id;
+                let hub = self.hub.read().unwrap().clone();
+                let state = match self.shedder.backend() {
+                    Ok(_permit) => run_blocking(move || hub.task_state(id))
+                        .await
+                        .map_err(|e| format!("{:#}", e)),
+                    Err(e) => Err(e.to_string()),
+                };
+                match state {
+                    Ok(state) => (Some(state), None, None),
+                    Err(e) => (None, None, Some(e)),
+                }
+            }
+        };
+        Event {
+            target: target.clone(),
+            state,
+            info,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Targets {
+    builds: Vec<String>,
+    tasks: Vec<u64>,
+}
+
+impl Targets {
+    fn into_iter(self) -> impl Iterator<Item = Target> {
+        self.builds
+            .into_iter()
+            .map(Target::Build)
+            .chain(self.tasks.into_iter().map(Target::Task))
+    }
+}
+
+/// A message from a client.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientMessage {
+    subscribe: Targets,
+    unsubscribe: Targets,
+}
+
+/// Watched targets and their last reported state.
+type Watched = BTreeMap<Target, Option<String>>;
+
+/// Send events for `targets` whose state changed, dropping those which are
+/// done.  Fails if the client has gone.
+async fn update(
+    session: &mut Session,
+    sources: &Sources,
+    watched: &mut Watched,
+    targets: Vec<Target>,
+) -> Result<(), actix_ws::Closed> {
+    for target in targets {
+        let event = sources.poll(&target).await;
+        let changed = watched
+            .get(&target)
+            .map_or(true, |last| *last != event.state);
+        if changed || event.error.is_some() {
+            let msg = serde_json::to_string(&event).expect("serializing event");
+            session.text(msg).await?;
+        }
+        if event.is_final() || event.error.is_some() {
+            watched.remove(&target);
+        } else {
+            watched.insert(target, event.state);
+        }
+    }
+    Ok(())
+}
+
+/// Handle a client's message, returning newly added targets.
+fn handle(text: &str, watched: &mut Watched, max: usize) -> Result<Vec<Target>, String> {
+    let msg: ClientMessage = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    for target in msg.unsubscribe.into_iter() {
+        watched.remove(&target);
+    }
+    let mut added: Vec<_> = msg
+        .subscribe
+        .into_iter()
+        .filter(|t| !watched.contains_key(t))
+        .collect();
+    added.sort();
+    added.dedup();
+    if watched.len() + added.len() > max {
+        return Err(format!("At most {} subscriptions per connection", max));
+    }
+    for target in added.iter() {
+        watched.insert(target.clone(), None);
+    }
+    Ok(added)
+}
+
+async fn session(
+    mut session: Session,
+    mut msgs: MessageStream,
+    sources: Sources,
+    config: web::Data<WatchConfig>,
+) -> Result<(), actix_ws::Closed> {
+    let mut watched = Watched::new();
+    let mut ticks = actix_web::rt::time::interval(Duration::from_secs(config.poll_interval.max(1)));
+    loop {
+        let msg = {
+            let tick = ticks.tick();
+            futures::pin_mut!(tick);
+            match futures::future::select(msgs.next(), tick).await {
+                Either::Left((msg, _)) => msg,
+                Either::Right(_) => {
+                    let targets = watched.keys().cloned().collect();
+                    update(&mut session, &sources, &mut watched, targets).await?;
+                    continue;
+                }
+            }
+        };
+        match msg {
+            Some(Ok(Message::Text(text))) => {
+                match handle(&text, &mut watched, config.max_subscriptions) {
+                    Ok(added) => update(&mut session, &sources, &mut watched, added).await?,
+                    Err(e) => {
+                        let msg = serde_json::json!({ "error": e }).to_string();
+                        session.text(msg).await?;
+                    }
+                }
+            }
+            Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await?,
+            Some(Ok(Message::Close(reason))) => return session.close(reason).await,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                tracing::debug!("WebSocket error: {}", e);
+                return session.close(None).await;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+#[get("/ws")]
+async fn ws(
+    req: HttpRequest,
+    body: web::Payload,
+    sources: Sources,
+    config: web::Data<WatchConfig>,
+) -> actix_web::Result<HttpResponse> {
+    let (res, ws_session, msgs) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(async move {
+        // Closed means the client went away; nothing more to do
+        let _ = session(ws_session, msgs, sources, config).await;
+    });
+    Ok(res)
+}
+
+/// Format `event` as a server-sent event.
+fn sse(event: &Event) -> web::Bytes {
+    let name = if event.error.is_some() {
+        "error"
+    } else {
+        "state"
+    };
+    let data = serde_json::to_string(event).expect("serializing event");
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Stream a build's state as server-sent events: now, on each change, and
+/// finally when it's done.  A comment is sent on each unchanged poll to
+/// keep the connection alive through proxies.
+#[get("/buildinfo/{id}/events")]
+async fn events(
+    path: web::Path<(String,)>,
+    sources: Sources,
+    config: web::Data<WatchConfig>,
+) -> HttpResponse {
+    let target = Target::Build(path.into_inner().0);
+    let interval = Duration::from_secs(config.poll_interval.max(1));
+    // The state is whether this is the first poll, and the last state sent
+    let stream = futures::stream::unfold(Some((true, None)), move |next| {
+        let sources = sources.clone();
+        let target = target.clone();
+        async move {
+            let (first, last) = next?;
+            if !first {
+                actix_web::rt::time::sleep(interval).await;
+            }
+            let event = sources.poll(&target).await;
+            let done = event.is_final() || event.error.is_some();
+            let chunk = if first || done || event.state != last {
+                sse(&event)
+            } else {
+                web::Bytes::from_static(b": unchanged\n\n")
+            };
+            let next = if done {
+                None
+            } else {
+                Some((false, event.state))
+            };
+            Some((Ok::<_, actix_web::Error>(chunk), next))
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Keep the compression middleware from buffering events
+        .insert_header((header::CONTENT_ENCODING, "identity"))
+        .streaming(stream)
+}
+
+/// How long before the request deadline `/wait` gives up, leaving time
+/// for its last lookup.
+const DEADLINE_MARGIN: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    /// Seconds to wait for the build to finish.
+    #[serde(default = "default_wait_timeout")]
+    timeout: u64,
+}
+
+fn default_wait_timeout() -> u64 {
+    300
+}
+
+/// Respond once the build is done, like `koji wait-task`: 200 with the
+/// final build, or 202 with its current state if `timeout` passes first.
+#[get("/buildinfo/{id}/wait")]
+async fn wait(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<WaitQuery>,
+    sources: Sources,
+    config: web::Data<WatchConfig>,
+) -> actix_web::Result<HttpResponse> {
+    let buildid = path.into_inner().0;
+    let mut end = Instant::now() + Duration::from_secs(query.timeout.min(config.max_wait));
+    if let Some(deadline) = timeout::deadline(&req) {
+        end = end.min(deadline.checked_sub(DEADLINE_MARGIN).unwrap_or(deadline));
+    }
+    let interval = Duration::from_secs(config.poll_interval.max(1));
+    loop {
+        let info = sources.build(&buildid, false).await?;
+        if !info.is_in_progress() {
+            return Ok(HttpResponse::Ok().json(info));
+        }
+        let now = Instant::now();
+        if now >= end {
+            return Ok(HttpResponse::Accepted().json(info));
+        }
+        actix_web::rt::time::sleep(interval.min(end - now)).await;
+    }
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(ws).service(events).service(wait);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handle() {
+        let mut watched = Watched::new();
+        let added = handle(
+            r#"{"subscribe": {"builds": ["foo-1-1"], "tasks": [42]}}"#,
+            &mut watched,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            added,
+            vec![Target::Build("foo-1-1".to_string()), Target::Task(42)]
+        );
+        // Already watched targets aren't added again
+        let added = handle(r#"{"subscribe": {"tasks": [42]}}"#, &mut watched, 2).unwrap();
+        assert!(added.is_empty());
+        assert!(handle(r#"{"subscribe": {"tasks": [43]}}"#, &mut watched, 2).is_err());
+        handle(r#"{"unsubscribe": {"tasks": [42]}}"#, &mut watched, 2).unwrap();
+        assert_eq!(watched.len(), 1);
+        assert!(handle(r#"{"subscribe": 1}"#, &mut watched, 2).is_err());
+    }
+
+    #[test]
+    fn test_sse() {
+        let event = Event {
+            target: Target::Build("foo-1-1".to_string()),
+            state: None,
+            info: None,
+            error: Some("koji failed".to_string()),
+        };
+        assert_eq!(
+            sse(&event),
+            "event: error\ndata: {\"build\":\"foo-1-1\",\"error\":\"koji failed\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_event() {
+        let event = Event {
+            target: Target::Task(42),
+            state: Some("CLOSED".to_string()),
+            info: None,
+            error: None,
+        };
+        assert!(event.is_final());
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"task": 42, "state": "CLOSED"})
+        );
+    }
+}
@@ -0,0 +1,748 @@
+//! Webhooks: a `POST` to a registered URL when a build finishes or a new
+//! build is tagged, so CI systems needn't poll.  Payloads are signed with
+//! the webhook's secret, and failed deliveries retried with backoff.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use actix_web::error::{
+    ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ErrorServiceUnavailable,
+};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::{self, Permission};
+use crate::cache::{Cache, NvrMap};
+use crate::config::WebhookConfig;
+use crate::koji::{Backend, Hub, KojiBuildInfo};
+
+mod store;
+use store::Store;
+
+/// How many of a tag's most recently tagged builds are checked for new ones.
+const TAG_WINDOW: usize = 20;
+/// `sha256=` and the hex HMAC-SHA256 of the body, keyed with the secret.
+const SIGNATURE_HEADER: &str = "x-koji-api-signature";
+/// Identifies a delivery, the same across its retries.
+const DELIVERY_HEADER: &str = "x-koji-api-delivery";
+/// How many deliveries are kept per webhook.
+const HISTORY: usize = 20;
+/// The wait before the first retry, doubling for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    /// Where webhooks are never delivered: loopback, private, link-local
+    /// (e.g. cloud metadata at 169.254.169.254) and other non-public
+    /// ranges, including IPv4-mapped IPv6 addresses.
+    static ref INTERNAL: Vec<IpNet> = [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "::ffff:0:0/96",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|n| n.parse().unwrap())
+    .collect();
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    !INTERNAL.iter().any(|n| n.contains(&ip))
+}
+
+/// Resolve `netloc` (`host:port`), refusing hosts with any non-public
+/// address.  Deliveries resolve through this too, so a name can't be
+/// pointed elsewhere after registering.
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+    if let Some(a) = addrs.iter().find(|a| !is_public(a.ip())) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} resolves to non-public address {}", netloc, a.ip()),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Check that a webhook URL's host resolves to public addresses only.
+async fn check_destination(url: &str) -> actix_web::Result<()> {
+    let url =
+        reqwest::Url::parse(url).map_err(|e| ErrorBadRequest(format!("Invalid URL: {}", e)))?;
+    let netloc = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        _ => return Err(ErrorBadRequest("Expected a URL with a host")),
+    };
+    web::block(move || resolve_public(&netloc))
+        .await?
+        .map_err(|e| ErrorBadRequest(format!("Unusable webhook URL: {}", e)))?;
+    Ok(())
+}
+
+/// What a webhook fires on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Trigger {
+    /// The build (NVR or id) finishing, in whatever state; fires once.
+    BuildCompleted(String),
+    /// Each build newly tagged into the tag.
+    Tagged(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Subscription {
+    pub(crate) id: String,
+    pub(crate) url: String,
+    #[serde(flatten)]
+    pub(crate) trigger: Trigger,
+    pub(crate) created: String,
+    /// Set once a `BuildCompleted` webhook has fired.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) fired: bool,
+    /// Set by an administrator to stop checking the trigger.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) disabled: bool,
+    #[serde(skip)]
+    secret: String,
+}
+
+/// The body of `POST /webhooks`, e.g.
+/// `{"url": "https://ci.example.com/hook", "tagged": "f34-updates"}`.
+/// Without a `secret`, one is generated.
+#[derive(Debug, Deserialize)]
+struct NewSubscription {
+    url: String,
+    #[serde(flatten)]
+    trigger: Trigger,
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+/// The answer to `POST /webhooks`; the only time the secret is shown.
+#[derive(Serialize)]
+struct Created<'a> {
+    #[serde(flatten)]
+    sub: &'a Subscription,
+    secret: &'a str,
+}
+
+/// One event's delivery to a webhook, over all its attempts.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Delivery {
+    id: String,
+    event: &'static str,
+    build: String,
+    started: String,
+    attempts: u32,
+    delivered: bool,
+    /// The receiver's last response status, if it answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// What's delivered to a webhook.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Payload<'a> {
+    event: &'static str,
+    subscription: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    build: &'a KojiBuildInfo,
+}
+
+struct Entry {
+    sub: Subscription,
+    /// For `Tagged`, the builds seen in the tag so far; unset until the
+    /// first check.
+    seen: Option<HashSet<String>>,
+    /// The most recent deliveries, oldest first.
+    deliveries: VecDeque<Delivery>,
+}
+
+/// Registered webhooks.
+#[derive(Default)]
+pub(crate) struct Webhooks {
+    entries: Mutex<BTreeMap<String, Entry>>,
+    store: Option<Store>,
+}
+
+impl Webhooks {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load webhooks from `config.database`, if set, and keep it up to date.
+    pub(crate) fn open(config: &WebhookConfig) -> Result<Self> {
+        let path = match config.database.as_ref() {
+            Some(p) => p,
+            None => return Ok(Self::new()),
+        };
+        let store = Store::open(path)?;
+        let entries = store
+            .load()?
+            .into_iter()
+            .map(|(sub, seen)| {
+                let entry = Entry {
+                    sub,
+                    seen,
+                    deliveries: VecDeque::new(),
+                };
+                (entry.sub.id.clone(), entry)
+            })
+            .collect::<BTreeMap<_, _>>();
+        tracing::info!(path = %path.display(), count = entries.len(), "Loaded webhooks");
+        Ok(Self {
+            entries: Mutex::new(entries),
+            store: Some(store),
+        })
+    }
+
+    /// Save an entry's changed state, if there's a database.
+    fn save(&self, entry: &Entry) {
+        if let Some(store) = self.store.as_ref() {
+            if let Err(e) = store.update(&entry.sub, entry.seen.as_ref()) {
+                tracing::error!(id = %entry.sub.id, "Failed to save webhook: {:#}", e);
+            }
+        }
+    }
+
+    fn add(&self, new: NewSubscription, max: usize) -> actix_web::Result<Subscription> {
+        if !(new.url.starts_with("https://") || new.url.starts_with("http://")) {
+            return Err(ErrorBadRequest("Expected an http:// or https:// URL"));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= max {
+            return Err(ErrorServiceUnavailable("Too many webhooks"));
+        }
+        let secret = match new.secret {
+            Some(s) if s.is_empty() => return Err(ErrorBadRequest("Empty secret")),
+            Some(s) => s,
+            None => uuid::Uuid::new_v4().to_simple().to_string(),
+        };
+        let sub = Subscription {
+            id: uuid::Uuid::new_v4().to_string(),
+            url: new.url,
+            trigger: new.trigger,
+            created: chrono::Utc::now().to_rfc3339(),
+            fired: false,
+            disabled: false,
+            secret,
+        };
+        if let Some(store) = self.store.as_ref() {
+            store.insert(&sub).map_err(|e| {
+                tracing::error!("Failed to save webhook: {:#}", e);
+                ErrorInternalServerError("Failed to save webhook")
+            })?;
+        }
+        entries.insert(
+            sub.id.clone(),
+            Entry {
+                sub: sub.clone(),
+                seen: None,
+                deliveries: VecDeque::new(),
+            },
+        );
+        Ok(sub)
+    }
+
+    fn get(&self, id: &str) -> Option<Subscription> {
+        self.entries.lock().unwrap().get(id).map(|e| e.sub.clone())
+    }
+
+    /// Every webhook, for administrators.
+    pub(crate) fn list(&self) -> Vec<Subscription> {
+        let entries = self.entries.lock().unwrap();
+        entries.values().map(|e| e.sub.clone()).collect()
+    }
+
+    pub(crate) fn remove(&self, id: &str) -> Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(id) {
+            return Ok(false);
+        }
+        if let Some(store) = self.store.as_ref() {
+            store.delete(id)?;
+        }
+        entries.remove(id);
+        Ok(true)
+    }
+
+    /// Stop or resume checking a webhook's trigger.
+    pub(crate) fn set_disabled(&self, id: &str, disabled: bool) -> Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.get_mut(id) {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+        let mut sub = entry.sub.clone();
+        sub.disabled = disabled;
+        if let Some(store) = self.store.as_ref() {
+            store.update(&sub, entry.seen.as_ref())?;
+        }
+        entry.sub = sub;
+        Ok(true)
+    }
+
+    fn deliveries(&self, id: &str) -> Option<Vec<Delivery>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(id)
+            .map(|e| e.deliveries.iter().cloned().collect())
+    }
+
+    /// Webhooks still to be checked.
+    fn snapshot(&self) -> Vec<(Subscription, Option<HashSet<String>>)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| !(e.sub.fired || e.sub.disabled))
+            .map(|e| (e.sub.clone(), e.seen.clone()))
+            .collect()
+    }
+
+    fn set_fired(&self, id: &str) {
+        if let Some(e) = self.entries.lock().unwrap().get_mut(id) {
+            e.sub.fired = true;
+            self.save(e);
+        }
+    }
+
+    /// Add or update a delivery in a webhook's history.
+    fn record(&self, id: &str, delivery: &Delivery) {
+        let mut entries = self.entries.lock().unwrap();
+        let deliveries = match entries.get_mut(id) {
+            Some(e) => &mut e.deliveries,
+            None => return,
+        };
+        match deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            Some(d) => *d = delivery.clone(),
+            None => {
+                if deliveries.len() >= HISTORY {
+                    deliveries.pop_front();
+                }
+                deliveries.push_back(delivery.clone());
+            }
+        }
+    }
+
+    fn set_seen(&self, id: &str, seen: HashSet<String>) {
+        if let Some(e) = self.entries.lock().unwrap().get_mut(id) {
+            if e.seen.as_ref() != Some(&seen) {
+                e.seen = Some(seen);
+                self.save(e);
+            }
+        }
+    }
+}
+
+/// Look up a build through the cache, as `/buildinfo` would.
+fn build(hub: &Hub, cache: &Cache, nvrs: &NvrMap, buildid: &str) -> Result<KojiBuildInfo> {
+    let buildid = nvrs.canonicalize(buildid);
+    if let Some(body) = cache.get(&buildid) {
+        return Ok(serde_json::from_str(&body)?);
+    }
+    let info = hub.get_koji_build(&buildid)?;
+    cache.store_build(nvrs, &info)?;
+    Ok(info)
+}
+
+/// The signature header's value for `body`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The wait before retrying after `attempt` failed.
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF
+        .checked_mul(1 << attempt.saturating_sub(1).min(16))
+        .map_or(MAX_RETRY_BACKOFF, |d| d.min(MAX_RETRY_BACKOFF))
+}
+
+/// Whether a failed attempt is worth retrying; other client errors won't
+/// go away.
+fn retryable(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Checks webhooks' triggers and delivers their events.
+struct Checker {
+    agent: ureq::Agent,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+    webhooks: web::Data<Webhooks>,
+    max_attempts: u32,
+}
+
+impl Checker {
+    /// Deliver `payload` in the background, retrying as needed.
+    fn send(&self, sub: &Subscription, payload: &Payload) {
+        let body = serde_json::to_string(payload).expect("serializing payload");
+        let mut delivery = Delivery {
+            id: uuid::Uuid::new_v4().to_string(),
+            event: payload.event,
+            build: payload.build.nvr.clone(),
+            started: chrono::Utc::now().to_rfc3339(),
+            attempts: 0,
+            delivered: false,
+            status: None,
+            error: None,
+        };
+        let signature = sign(&sub.secret, body.as_bytes());
+        let agent = self.agent.clone();
+        let webhooks = self.webhooks.clone();
+        let max_attempts = self.max_attempts.max(1);
+        let (id, url) = (sub.id.clone(), sub.url.clone());
+        std::thread::spawn(move || loop {
+            delivery.attempts += 1;
+            let res = agent
+                .post(&url)
+                .set("Content-Type", "application/json")
+                .set(SIGNATURE_HEADER, &signature)
+                .set(DELIVERY_HEADER, &delivery.id)
+                .send_string(&body);
+            let retry = match res {
+                Ok(r) => {
+                    delivery.delivered = true;
+                    delivery.status = Some(r.status());
+                    delivery.error = None;
+                    tracing::debug!(%id, event = delivery.event, "Delivered webhook");
+                    false
+                }
+                Err(e) => {
+                    if let ureq::Error::Status(code, _) = &e {
+                        delivery.status = Some(*code);
+                    }
+                    delivery.error = Some(e.to_string());
+                    tracing::warn!(%id, %url, attempt = delivery.attempts, "Failed to deliver webhook: {}", e);
+                    retryable(&e) && delivery.attempts < max_attempts
+                }
+            };
+            webhooks.record(&id, &delivery);
+            if !retry {
+                break;
+            }
+            std::thread::sleep(backoff(delivery.attempts));
+        });
+    }
+
+    /// Check one webhook's trigger, delivering any events.
+    fn check(&self, sub: &Subscription, seen: Option<HashSet<String>>) -> Result<()> {
+        let hub = self.hub.read().unwrap().clone();
+        let (cache, nvrs) = (&self.cache, &self.nvrs);
+        match &sub.trigger {
+            Trigger::BuildCompleted(buildid) => {
+                let info = build(&hub, cache, nvrs, buildid)?;
+                if !info.is_in_progress() {
+                    let payload = Payload {
+                        event: "build-completed",
+                        subscription: &sub.id,
+                        tag: None,
+                        build: &info,
+                    };
+                    self.send(sub, &payload);
+                    self.webhooks.set_fired(&sub.id);
+                }
+            }
+            Trigger::Tagged(tag) => {
+                let tagged = hub.list_recently_tagged(tag, TAG_WINDOW)?;
+                // The first check only notes what's already there; oldest first
+                if let Some(seen) = seen.as_ref() {
+                    for nvr in tagged.iter().rev().filter(|n| !seen.contains(*n)) {
+                        let info = build(&hub, cache, nvrs, nvr)?;
+                        let payload = Payload {
+                            event: "tagged",
+                            subscription: &sub.id,
+                            tag: Some(tag),
+                            build: &info,
+                        };
+                        self.send(sub, &payload);
+                    }
+                }
+                self.webhooks
+                    .set_seen(&sub.id, tagged.into_iter().collect());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Start a thread checking registered webhooks' triggers.  Does nothing if
+/// webhooks are disabled.
+pub(crate) fn spawn(
+    config: &WebhookConfig,
+    hub: web::Data<RwLock<Hub>>,
+    cache: web::Data<Cache>,
+    nvrs: web::Data<NvrMap>,
+    webhooks: web::Data<Webhooks>,
+) {
+    if !config.enabled {
+        return;
+    }
+    let interval = Duration::from_secs(config.poll_interval.max(1));
+    let checker = Checker {
+        agent: ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .resolver(resolve_public)
+            .build(),
+        hub,
+        cache,
+        nvrs,
+        webhooks,
+        max_attempts: config.max_attempts,
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        for (sub, seen) in checker.webhooks.snapshot() {
+            if let Err(e) = checker.check(&sub, seen) {
+                tracing::warn!(id = %sub.id, "Failed to check webhook: {:#}", e);
+            }
+        }
+    });
+}
+
+fn require_enabled(config: &WebhookConfig) -> actix_web::Result<()> {
+    if config.enabled {
+        Ok(())
+    } else {
+        Err(ErrorNotFound("Webhooks are disabled"))
+    }
+}
+
+/// Register a webhook, answering with its id for later removal and the
+/// secret its deliveries are signed with.
+#[post("/webhooks")]
+async fn register(
+    req: HttpRequest,
+    config: web::Data<WebhookConfig>,
+    webhooks: web::Data<Webhooks>,
+    new: web::Json<NewSubscription>,
+) -> actix_web::Result<HttpResponse> {
+    require_enabled(&config)?;
+    auth::require(&req, Permission::Webhooks)?;
+    check_destination(&new.url).await?;
+    let sub = webhooks.add(new.into_inner(), config.max_subscriptions)?;
+    tracing::info!(id = %sub.id, url = %sub.url, "Registered webhook");
+    Ok(HttpResponse::Created().json(Created {
+        sub: &sub,
+        secret: &sub.secret,
+    }))
+}
+
+#[get("/webhooks/{id}")]
+async fn show(
+    req: HttpRequest,
+    config: web::Data<WebhookConfig>,
+    webhooks: web::Data<Webhooks>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    require_enabled(&config)?;
+    auth::require(&req, Permission::Webhooks)?;
+    let sub = webhooks
+        .get(&path.0)
+        .ok_or_else(|| ErrorNotFound("No such webhook"))?;
+    Ok(HttpResponse::Ok().json(sub))
+}
+
+/// A webhook's recent deliveries, oldest first.
+#[get("/webhooks/{id}/deliveries")]
+async fn list_deliveries(
+    req: HttpRequest,
+    config: web::Data<WebhookConfig>,
+    webhooks: web::Data<Webhooks>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    require_enabled(&config)?;
+    auth::require(&req, Permission::Webhooks)?;
+    let deliveries = webhooks
+        .deliveries(&path.0)
+        .ok_or_else(|| ErrorNotFound("No such webhook"))?;
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+#[delete("/webhooks/{id}")]
+async fn unregister(
+    req: HttpRequest,
+    config: web::Data<WebhookConfig>,
+    webhooks: web::Data<Webhooks>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<HttpResponse> {
+    require_enabled(&config)?;
+    auth::require(&req, Permission::Webhooks)?;
+    if webhooks.remove(&path.0).map_err(ErrorInternalServerError)? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ErrorNotFound("No such webhook"))
+    }
+}
+
+pub(crate) fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(register)
+        .service(show)
+        .service(list_deliveries)
+        .service(unregister);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_public() {
+        for internal in &[
+            "127.0.0.1:80",
+            "10.1.2.3:443",
+            "169.254.169.254:80",
+            "[::1]:80",
+            "[::ffff:127.0.0.1]:80",
+            "[fd00::1]:443",
+        ] {
+            assert!(resolve_public(internal).is_err(), "{}", internal);
+        }
+        assert!(resolve_public("93.184.216.34:443").is_ok());
+        assert!(resolve_public("[2606:2800:220:1::]:443").is_ok());
+    }
+
+    #[test]
+    fn test_add() {
+        let webhooks = Webhooks::new();
+        let new: NewSubscription =
+            serde_json::from_str(r#"{"url": "https://ci.example.com/hook", "tagged": "f34"}"#)
+                .unwrap();
+        let sub = webhooks.add(new, 1).unwrap();
+        assert_eq!(sub.trigger, Trigger::Tagged("f34".to_string()));
+        assert_eq!(
+            serde_json::to_value(&sub).unwrap()["tagged"],
+            serde_json::json!("f34")
+        );
+        let new: NewSubscription = serde_json::from_str(
+            r#"{"url": "https://ci.example.com/hook", "build-completed": "foo-1-1"}"#,
+        )
+        .unwrap();
+        assert!(webhooks.add(new, 1).is_err());
+        assert!(webhooks.remove(&sub.id).unwrap());
+        assert!(webhooks.get(&sub.id).is_none());
+
+        let new: NewSubscription =
+            serde_json::from_str(r#"{"url": "file:///etc/passwd", "tagged": "f34"}"#).unwrap();
+        assert!(webhooks.add(new, 1).is_err());
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WebhookConfig {
+            database: Some(dir.path().join("webhooks.sqlite")),
+            ..Default::default()
+        };
+        let webhooks = Webhooks::open(&config).unwrap();
+        let new: NewSubscription =
+            serde_json::from_str(r#"{"url": "https://ci.example.com/hook", "tagged": "f34"}"#)
+                .unwrap();
+        let sub = webhooks.add(new, 10).unwrap();
+        let new: NewSubscription = serde_json::from_str(
+            r#"{"url": "https://ci.example.com/hook", "build-completed": "foo-1-1"}"#,
+        )
+        .unwrap();
+        let other = webhooks.add(new, 10).unwrap();
+        let seen: HashSet<String> = vec!["bar-1-1".to_string()].into_iter().collect();
+        webhooks.set_seen(&sub.id, seen.clone());
+        assert!(webhooks.set_disabled(&sub.id, true).unwrap());
+        assert!(webhooks.remove(&other.id).unwrap());
+        drop(webhooks);
+
+        let webhooks = Webhooks::open(&config).unwrap();
+        let subs = webhooks.list();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].id, sub.id);
+        assert_eq!(subs[0].secret, sub.secret);
+        assert!(subs[0].disabled);
+        assert!(webhooks.snapshot().is_empty());
+        assert!(webhooks.set_disabled(&sub.id, false).unwrap());
+        let snapshot = webhooks.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].1, Some(seen));
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(10), MAX_RETRY_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_record() {
+        let webhooks = Webhooks::new();
+        let new: NewSubscription = serde_json::from_str(
+            r#"{"url": "https://ci.example.com/hook", "tagged": "f34", "secret": "s3kr1t"}"#,
+        )
+        .unwrap();
+        let sub = webhooks.add(new, 1).unwrap();
+        assert_eq!(sub.secret, "s3kr1t");
+        let mut delivery = Delivery {
+            id: "0".to_string(),
+            event: "tagged",
+            build: "foo-1-1".to_string(),
+            started: String::new(),
+            attempts: 1,
+            delivered: false,
+            status: Some(502),
+            error: None,
+        };
+        webhooks.record(&sub.id, &delivery);
+        delivery.attempts = 2;
+        delivery.delivered = true;
+        webhooks.record(&sub.id, &delivery);
+        let history = webhooks.deliveries(&sub.id).unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].delivered);
+        for i in 1..=HISTORY {
+            delivery.id = i.to_string();
+            webhooks.record(&sub.id, &delivery);
+        }
+        let history = webhooks.deliveries(&sub.id).unwrap();
+        assert_eq!(history.len(), HISTORY);
+        assert_eq!(history[0].id, "1");
+    }
+}
@@ -0,0 +1,109 @@
+//! SQLite storage for webhooks, so registrations survive restarts.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+
+use super::{Subscription, Trigger};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS webhooks (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    trigger TEXT NOT NULL,
+    created TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    fired INTEGER NOT NULL DEFAULT 0,
+    disabled INTEGER NOT NULL DEFAULT 0,
+    seen TEXT
+)";
+
+pub(super) struct Store(Mutex<Connection>);
+
+impl Store {
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Opening webhook database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .context("Creating webhook database schema")?;
+        Ok(Self(Mutex::new(conn)))
+    }
+
+    /// Every stored webhook, with the builds seen in its tag.
+    pub(super) fn load(&self) -> Result<Vec<(Subscription, Option<HashSet<String>>)>> {
+        let conn = self.0.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, url, trigger, created, secret, fired, disabled, seen FROM webhooks",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, bool>(5)?,
+                row.get::<_, bool>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        })?;
+        let mut r = Vec::new();
+        for row in rows {
+            let (id, url, trigger, created, secret, fired, disabled, seen) = row?;
+            let trigger: Trigger = serde_json::from_str(&trigger)
+                .with_context(|| format!("Parsing trigger of webhook {}", id))?;
+            let seen = seen
+                .map(|s| serde_json::from_str(&s))
+                .transpose()
+                .with_context(|| format!("Parsing seen builds of webhook {}", id))?;
+            let sub = Subscription {
+                id,
+                url,
+                trigger,
+                created,
+                fired,
+                disabled,
+                secret,
+            };
+            r.push((sub, seen));
+        }
+        Ok(r)
+    }
+
+    pub(super) fn insert(&self, sub: &Subscription) -> Result<()> {
+        self.0.lock().unwrap().execute(
+            "INSERT INTO webhooks (id, url, trigger, created, secret, fired, disabled)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                sub.id,
+                sub.url,
+                serde_json::to_string(&sub.trigger)?,
+                sub.created,
+                sub.secret,
+                sub.fired,
+                sub.disabled,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Save a webhook's state after it changes.
+    pub(super) fn update(&self, sub: &Subscription, seen: Option<&HashSet<String>>) -> Result<()> {
+        let seen = seen.map(serde_json::to_string).transpose()?;
+        self.0.lock().unwrap().execute(
+            "UPDATE webhooks SET fired = ?2, disabled = ?3, seen = ?4 WHERE id = ?1",
+            params![sub.id, sub.fired, sub.disabled, seen],
+        )?;
+        Ok(())
+    }
+
+    pub(super) fn delete(&self, id: &str) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM webhooks WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}